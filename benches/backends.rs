@@ -0,0 +1,237 @@
+//! Benchmarks that compare the throughput and latency of the backends.
+//!
+//! Every backend runs the same workloads, for various value sizes and
+//! concurrency levels. Run them with:
+//!
+//! ```sh
+//! cargo bench --features with-rocksdb
+//! ```
+//!
+//! The parallel batch operations are benchmarked with the `with-rayon`
+//! feature.
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use caves::{Cave, FileCave, MemoryCave, WriteStrategy};
+
+/// The value sizes, in bytes, that each workload is run for.
+const VALUE_SIZES: &[usize] = &[64, 4 * 1024, 64 * 1024];
+
+/// The number of threads that the concurrent workloads are run with.
+const THREADS: &[usize] = &[1, 2, 4, 8];
+
+/// The number of distinct keys that the workloads operate on.
+const NUM_KEYS: usize = 128;
+
+/// A backend under test, along with the temporary directory that it may use.
+struct Backend {
+    name: &'static str,
+    cave: Arc<dyn Cave>,
+    // Keep the directory around, until the backend is dropped.
+    _temp_dir: assert_fs::TempDir,
+}
+
+/// Construct every backend that is available, each in a new temp dir.
+fn backends() -> Vec<Backend> {
+    let mut backends = Vec::new();
+
+    let temp_dir = assert_fs::TempDir::new().unwrap();
+    backends.push(Backend {
+        name: "memory",
+        cave: Arc::new(MemoryCave::new()),
+        _temp_dir: temp_dir,
+    });
+
+    let temp_dir = assert_fs::TempDir::new().unwrap();
+    backends.push(Backend {
+        name: "file",
+        cave: Arc::new(FileCave::new(temp_dir.path()).unwrap()),
+        _temp_dir: temp_dir,
+    });
+
+    // Compare the default, atomic writes with the in-place ones, whose gap is
+    // larger for small values.
+    let temp_dir = assert_fs::TempDir::new().unwrap();
+    let cave = FileCave::new(temp_dir.path()).unwrap();
+    backends.push(Backend {
+        name: "file_truncate",
+        cave: Arc::new(cave.with_write_strategy(WriteStrategy::Truncate)),
+        _temp_dir: temp_dir,
+    });
+
+    #[cfg(feature = "with-rocksdb")]
+    {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        backends.push(Backend {
+            name: "rocksdb",
+            cave: Arc::new(caves::RocksDBCave::new(temp_dir.path()).unwrap()),
+            _temp_dir: temp_dir,
+        });
+    }
+
+    backends
+}
+
+fn key_name(i: usize) -> String {
+    format!("key{}", i % NUM_KEYS)
+}
+
+fn populate(cave: &dyn Cave, value: &[u8]) {
+    for i in 0..NUM_KEYS {
+        let _ = cave.set(&key_name(i), value).unwrap();
+    }
+}
+
+fn bench_set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set");
+    for size in VALUE_SIZES {
+        let value = vec![0xaa; *size];
+        let _ = group.throughput(Throughput::Bytes(*size as u64));
+        for backend in backends() {
+            let id = BenchmarkId::new(backend.name, size);
+            let _ = group.bench_with_input(id, &value, |b, value| {
+                let mut i = 0;
+                b.iter(|| {
+                    i += 1;
+                    backend.cave.set(&key_name(i), value).unwrap()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    for size in VALUE_SIZES {
+        let value = vec![0xaa; *size];
+        let _ = group.throughput(Throughput::Bytes(*size as u64));
+        for backend in backends() {
+            populate(backend.cave.as_ref(), &value);
+            let id = BenchmarkId::new(backend.name, size);
+            let _ = group.bench_function(id, |b| {
+                let mut i = 0;
+                b.iter(|| {
+                    i += 1;
+                    backend.cave.get(&key_name(i)).unwrap()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_delete(c: &mut Criterion) {
+    let mut group = c.benchmark_group("delete");
+    for size in VALUE_SIZES {
+        let value = vec![0xaa; *size];
+        for backend in backends() {
+            let id = BenchmarkId::new(backend.name, size);
+            // Only the deletion is measured, not the `set` that precedes it.
+            let _ = group.bench_with_input(id, &value, |b, value| {
+                b.iter_custom(|iters| {
+                    let mut elapsed = Duration::default();
+                    for i in 0..iters as usize {
+                        let name = key_name(i);
+                        let _ = backend.cave.set(&name, value).unwrap();
+                        let start = Instant::now();
+                        let _ = backend.cave.delete(&name).unwrap();
+                        elapsed += start.elapsed();
+                    }
+                    elapsed
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Run a mixed workload of 80% reads and 20% writes from multiple threads,
+/// and measure the total time.
+fn bench_concurrent(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_mixed_4KiB");
+    let value = Arc::new(vec![0xaa; 4 * 1024]);
+    for threads in THREADS {
+        for backend in backends() {
+            populate(backend.cave.as_ref(), &value);
+            let id = BenchmarkId::new(backend.name, threads);
+            let _ = group.bench_function(id, |b| {
+                b.iter_custom(|iters| {
+                    let per_thread = (iters as usize / threads).max(1);
+                    let start = Instant::now();
+                    let handles: Vec<_> = (0..*threads)
+                        .map(|t| {
+                            let cave = Arc::clone(&backend.cave);
+                            let value = Arc::clone(&value);
+                            thread::spawn(move || {
+                                for i in 0..per_thread {
+                                    let name = key_name(t * per_thread + i);
+                                    if i % 5 == 0 {
+                                        let _ = cave.set(&name, &value).unwrap();
+                                    } else {
+                                        let _ = cave.get(&name).unwrap();
+                                    }
+                                }
+                            })
+                        })
+                        .collect();
+                    for handle in handles {
+                        handle.join().unwrap();
+                    }
+                    start.elapsed()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Set a batch of small keys on `FileCave`, serially and in parallel, to
+/// measure the speedup of the parallel batch operations.
+#[cfg(feature = "with-rayon")]
+fn bench_set_many_parallel(c: &mut Criterion) {
+    const BATCH_SIZE: usize = 4096;
+
+    let mut group = c.benchmark_group("set_many_file_64B");
+    let _ = group.throughput(Throughput::Elements(BATCH_SIZE as u64));
+    let names: Vec<String> = (0..BATCH_SIZE).map(|i| format!("key{}", i)).collect();
+    let value = [0xaa; 64];
+    let entries: Vec<(&str, &[u8])> = names
+        .iter()
+        .map(|name| (name.as_str(), &value[..]))
+        .collect();
+
+    let temp_dir = assert_fs::TempDir::new().unwrap();
+    let cave = FileCave::new(temp_dir.path()).unwrap();
+    let _ = group.bench_function("serial", |b| {
+        b.iter(|| {
+            for (name, data) in &entries {
+                let _ = cave.set(name, data).unwrap();
+            }
+        })
+    });
+    for threads in THREADS {
+        let id = BenchmarkId::new("parallel", threads);
+        let _ = group.bench_function(id, |b| {
+            b.iter(|| caves::set_many_parallel(&cave, &entries, *threads).unwrap())
+        });
+    }
+    group.finish();
+}
+
+#[cfg(not(feature = "with-rayon"))]
+fn bench_set_many_parallel(_c: &mut Criterion) {}
+
+criterion_group!(
+    benches,
+    bench_set,
+    bench_get,
+    bench_delete,
+    bench_concurrent,
+    bench_set_many_parallel
+);
+criterion_main!(benches);
@@ -0,0 +1,110 @@
+//! Content-addressed storage
+//!
+//! In this module, we define a wrapper that turns any `Cave` into a
+//! content-addressed store, i.e., a store where each value is stored under
+//! the hash of its contents.
+
+use crate::errors::Error;
+use crate::res::Res;
+use crate::{content_hash, Cave};
+
+/// A wrapper that stores blobs in another `Cave`, under their SHA-256 hash.
+///
+/// Each blob is stored under the hex-encoded SHA-256 hash of its contents,
+/// which is returned to the caller. Storing the same contents twice results
+/// in a single key, so this wrapper deduplicates large, repeated payloads.
+///
+/// ## Usage
+///
+/// ```
+/// use caves::{BlobCave, MemoryCave};
+///
+/// let b = BlobCave::new(MemoryCave::new());
+///
+/// // Store a blob and retrieve it by its hash.
+/// let hash = b.put_blob(b"value").unwrap();
+/// assert_eq!(b.get_blob(&hash).unwrap(), b"value");
+///
+/// // Storing the same blob again returns the same hash.
+/// assert_eq!(b.put_blob(b"value").unwrap(), hash);
+/// ```
+#[derive(Debug)]
+pub struct BlobCave<C: Cave> {
+    inner: C,
+}
+
+impl<C: Cave> BlobCave<C> {
+    /// Create a new instance that stores blobs in the provided `Cave`.
+    pub fn new(inner: C) -> Self {
+        Self { inner }
+    }
+
+    /// Store a blob and return its hash.
+    ///
+    /// If a blob with the same hash already exists, this is a no-op.
+    pub fn put_blob(&self, data: &[u8]) -> Result<String, Error> {
+        let hash = content_hash(data);
+
+        if self.inner.exists_many(&[&hash])?[0] {
+            return Ok(hash);
+        }
+
+        let _ = self.inner.set(&hash, data)?;
+        Ok(hash)
+    }
+
+    /// Get a blob by its hash.
+    ///
+    /// If the blob does not exist, return an error. If the contents of the
+    /// blob don't match its hash, which means that they have been corrupted,
+    /// return an internal error.
+    pub fn get_blob(&self, hash: &str) -> Res {
+        let data = self.inner.get(hash)?;
+
+        if content_hash(&data) != hash {
+            return Err(Error::internal_from_msg(format!(
+                "Contents of blob `{}` do not match its hash",
+                hash
+            )));
+        }
+        Ok(data)
+    }
+
+    /// Return a reference to the inner `Cave`.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::MemoryCave;
+
+    #[test]
+    fn test_blobs() {
+        let b = BlobCave::new(MemoryCave::new());
+        let sha256_value = "cd42404d52ad55ccfa9aca4adc828aa5800ad9d385a0671fbcbf724118320619";
+
+        let res = b.put_blob(b"value");
+        assert_eq!(res.unwrap(), sha256_value);
+        let res = b.get_blob(sha256_value);
+        assert_eq!(res, Ok(b"value".to_vec()));
+
+        // Check that identical contents are stored once.
+        let res = b.put_blob(b"value");
+        assert_eq!(res.unwrap(), sha256_value);
+        let hash = b.put_blob(b"value2").unwrap();
+        assert_eq!(b.inner().keys().unwrap().len(), 2);
+        assert_eq!(b.get_blob(&hash), Ok(b"value2".to_vec()));
+
+        // Check that missing and corrupted blobs are reported.
+        let res = b.get_blob("missing");
+        assert_eq!(res, Err(Error::NotFound("missing".to_string())));
+        let res = b.inner().set(sha256_value, b"corrupted");
+        assert!(res.is_ok());
+        let res = b.get_blob(sha256_value);
+        assert_eq!(res, Err(Error::Internal(anyhow!(""))));
+    }
+}
@@ -0,0 +1,358 @@
+//! In-memory cache
+//!
+//! In this module, we define an in-memory kv that evicts its least recently
+//! used keys when it's full, and expires keys whose time-to-live has passed,
+//! which is what most applications need from a cache.
+
+use std::collections;
+use std::fmt;
+use std::sync;
+use std::time;
+
+use crate::check_value_size;
+use crate::errors::Error;
+use crate::res::{empty_ok, Res};
+use crate::Cave;
+
+struct Entry {
+    data: Vec<u8>,
+    /// The time when the key expires, if it has a time-to-live.
+    expires_at: Option<time::Instant>,
+    /// The tick of the last access to the key.
+    used: u64,
+}
+
+impl Entry {
+    fn is_expired(&self, now: time::Instant) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    entries: collections::HashMap<String, Entry>,
+    /// The names of the keys, ordered by the tick of their last access.
+    recency: collections::BTreeMap<u64, String>,
+    /// A counter that increases on every access.
+    tick: u64,
+    /// The total size of the contents of the keys.
+    bytes: usize,
+}
+
+impl State {
+    /// Mark a key as the most recently used one.
+    fn touch(&mut self, name: &str) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(name) {
+            let _ = self.recency.remove(&entry.used);
+            entry.used = tick;
+            let _ = self.recency.insert(tick, name.to_string());
+        }
+    }
+
+    fn insert(&mut self, name: &str, data: &[u8], expires_at: Option<time::Instant>) {
+        let _ = self.remove(name);
+        self.tick += 1;
+        let entry = Entry {
+            data: data.to_vec(),
+            expires_at,
+            used: self.tick,
+        };
+        self.bytes += data.len();
+        let _ = self.recency.insert(self.tick, name.to_string());
+        let _ = self.entries.insert(name.to_string(), entry);
+    }
+
+    fn remove(&mut self, name: &str) -> Option<Entry> {
+        let entry = self.entries.remove(name)?;
+        let _ = self.recency.remove(&entry.used);
+        self.bytes -= entry.data.len();
+        Some(entry)
+    }
+
+    /// Remove the least recently used key, and return whether there was one.
+    fn evict(&mut self) -> bool {
+        let name = match self.recency.values().next() {
+            Some(name) => name.clone(),
+            None => return false,
+        };
+        self.remove(&name).is_some()
+    }
+}
+
+/// An in-memory kv that evicts keys when it's full, and expires them after a
+/// time-to-live.
+///
+/// The kv can be bounded by the number of its keys (see [`with_max_entries`])
+/// and by the total size of their contents (see [`with_max_bytes`]). When a
+/// write exceeds a bound, the least recently used keys are evicted until the
+/// kv is within its bounds again. Both `get` and `set` count as a use of a
+/// key.
+///
+/// A key can also have a time-to-live (see [`set_with_ttl`]), after which it
+/// expires. Expired keys are not removed in the background. Instead, they are
+/// removed when they are accessed, when they are evicted as any other key, or
+/// when [`purge_expired`] is called. Either way, they are never returned.
+///
+/// ## Usage
+///
+/// ```
+/// use std::time::Duration;
+/// use caves::{CacheCave, Cave};
+///
+/// let b = CacheCave::new().with_max_entries(2);
+/// b.set("key1", b"value").unwrap();
+/// b.set("key2", b"value").unwrap();
+/// b.get("key1").unwrap();
+///
+/// // The least recently used key is evicted.
+/// b.set("key3", b"value").unwrap();
+/// assert!(b.get("key2").is_err());
+///
+/// b.set_with_ttl("key4", b"value", Duration::from_secs(60)).unwrap();
+/// assert_eq!(b.get("key4").unwrap(), b"value");
+/// ```
+///
+/// [`with_max_entries`]: #method.with_max_entries
+/// [`with_max_bytes`]: #method.with_max_bytes
+/// [`set_with_ttl`]: #method.set_with_ttl
+/// [`purge_expired`]: #method.purge_expired
+#[derive(Default)]
+pub struct CacheCave {
+    state: sync::Mutex<State>,
+    max_entries: Option<usize>,
+    max_bytes: Option<usize>,
+}
+
+impl CacheCave {
+    /// Create a new, unbounded instance.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Bound the number of keys that the kv holds. A limit of 0 is treated
+    /// as 1, so that the last written key is always kept.
+    pub fn with_max_entries(mut self, limit: usize) -> Self {
+        self.max_entries = Some(limit.max(1));
+        self
+    }
+
+    /// Bound the total size of the contents of the keys that the kv holds.
+    ///
+    /// Values that are larger than this bound are rejected with an
+    /// [`Error::ValueTooLarge`] error, since they would never fit.
+    ///
+    /// [`Error::ValueTooLarge`]: errors/enum.Error.html#variant.ValueTooLarge
+    pub fn with_max_bytes(mut self, limit: usize) -> Self {
+        self.max_bytes = Some(limit);
+        self
+    }
+
+    /// Set the contents of a key, and make it expire after a time-to-live.
+    ///
+    /// A subsequent `set` of the key removes its time-to-live. A time-to-live
+    /// that's too large to represent, e.g., `Duration::MAX`, means that the
+    /// key never expires.
+    pub fn set_with_ttl(&self, name: &str, data: &[u8], ttl: time::Duration) -> Res {
+        self.insert(name, data, time::Instant::now().checked_add(ttl))
+    }
+
+    /// Remove the keys that have expired, and return their number.
+    pub fn purge_expired(&self) -> usize {
+        let now = time::Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let expired: Vec<String> = state
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &expired {
+            let _ = state.remove(name);
+        }
+        expired.len()
+    }
+
+    fn insert(&self, name: &str, data: &[u8], expires_at: Option<time::Instant>) -> Res {
+        check_value_size(data, self.max_bytes)?;
+        let mut state = self.state.lock().unwrap();
+        state.insert(name, data, expires_at);
+
+        // The key that we just inserted is the most recently used one, and it
+        // fits on its own, so it's never evicted.
+        let max_entries = self.max_entries.unwrap_or(usize::MAX);
+        let max_bytes = self.max_bytes.unwrap_or(usize::MAX);
+        while state.entries.len() > max_entries || state.bytes > max_bytes {
+            if !state.evict() {
+                break;
+            }
+        }
+        empty_ok()
+    }
+}
+
+/// Print the number of keys and their total size, instead of the contents of
+/// the kv, so that it's safe to log.
+impl fmt::Debug for CacheCave {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("CacheCave")
+            .field("keys", &state.entries.len())
+            .field("bytes", &state.bytes)
+            .field("max_entries", &self.max_entries)
+            .field("max_bytes", &self.max_bytes)
+            .finish()
+    }
+}
+
+impl Cave for CacheCave {
+    fn get(&self, name: &str) -> Res {
+        let mut state = self.state.lock().unwrap();
+        match state.entries.get(name) {
+            Some(entry) if entry.is_expired(time::Instant::now()) => {
+                let _ = state.remove(name);
+                self.not_found(name)
+            }
+            Some(entry) => {
+                let data = entry.data.clone();
+                state.touch(name);
+                Ok(data)
+            }
+            None => self.not_found(name),
+        }
+    }
+
+    fn set(&self, name: &str, data: &[u8]) -> Res {
+        self.insert(name, data, None)
+    }
+
+    fn delete(&self, name: &str) -> Res {
+        let mut state = self.state.lock().unwrap();
+        match state.remove(name) {
+            Some(entry) if !entry.is_expired(time::Instant::now()) => empty_ok(),
+            _ => self.not_found(name),
+        }
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        let now = time::Instant::now();
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .entries
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(name, _)| name.clone())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use crate::errors::ErrorKind;
+    use crate::tests::{_test_concurrency, _test_keys, _test_simple};
+
+    #[test]
+    fn test_cache_backend_simple() {
+        _test_simple(Box::new(CacheCave::new()));
+        _test_keys(Box::new(CacheCave::new()));
+        _test_concurrency(Box::new(CacheCave::new()));
+    }
+
+    #[test]
+    fn test_cache_backend_debug() {
+        // The contents of the kv should not be printed.
+        let b = CacheCave::new().with_max_entries(2);
+        assert_eq!(b.set("key", b"secret"), empty_ok());
+        assert_eq!(
+            format!("{:?}", b),
+            "CacheCave { keys: 1, bytes: 6, max_entries: Some(2), max_bytes: None }"
+        );
+    }
+
+    #[test]
+    fn test_cache_backend_eviction() {
+        let b = CacheCave::new().with_max_entries(3);
+        for name in &["key1", "key2", "key3"] {
+            assert_eq!(b.set(name, b"value"), empty_ok());
+        }
+        assert_eq!(b.get("key1"), Ok(b"value".to_vec()));
+        assert_eq!(b.set("key2", b"value2"), empty_ok());
+
+        // The least recently used key should be evicted first.
+        assert_eq!(b.set("key4", b"value"), empty_ok());
+        assert_eq!(b.get("key3"), b.not_found("key3"));
+        assert_eq!(b.set("key5", b"value"), empty_ok());
+        assert_eq!(b.get("key1"), b.not_found("key1"));
+        let mut keys = b.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["key2", "key4", "key5"]);
+
+        // The total size of the contents should be bounded as well.
+        let b = CacheCave::new().with_max_bytes(10);
+        assert_eq!(b.set("key1", b"1234"), empty_ok());
+        assert_eq!(b.set("key2", b"1234"), empty_ok());
+        assert_eq!(b.set("key1", b"12"), empty_ok());
+        assert_eq!(b.set("key3", b"1234"), empty_ok());
+        assert_eq!(b.get("key1"), Ok(b"12".to_vec()));
+        assert_eq!(b.set("key4", b"12345"), empty_ok());
+        let mut keys = b.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["key1", "key4"]);
+
+        // Values that would never fit should be rejected.
+        let res = b.set("key5", b"12345678901");
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::ValueTooLarge);
+        assert_eq!(b.keys().unwrap().len(), 2);
+
+        // Deleted keys should free their space.
+        assert_eq!(b.delete("key4"), empty_ok());
+        assert_eq!(b.set("key6", b"12345678"), empty_ok());
+        assert_eq!(b.get("key1"), Ok(b"12".to_vec()));
+
+        // A limit of 0 keys should keep the last written key.
+        let b = CacheCave::new().with_max_entries(0);
+        assert_eq!(b.set("key1", b"value"), empty_ok());
+        assert_eq!(b.set("key2", b"value"), empty_ok());
+        assert_eq!(b.get("key2"), Ok(b"value".to_vec()));
+        assert_eq!(b.keys().unwrap(), vec!["key2"]);
+    }
+
+    #[test]
+    fn test_cache_backend_ttl() {
+        let b = CacheCave::new().with_max_entries(3);
+        let ttl = time::Duration::from_millis(100);
+        assert_eq!(b.set_with_ttl("key1", b"value", ttl), empty_ok());
+        assert_eq!(b.set_with_ttl("key2", b"value", ttl), empty_ok());
+        assert_eq!(b.set_with_ttl("key3", b"value", ttl), empty_ok());
+        assert_eq!(b.set("key3", b"value"), empty_ok());
+        assert_eq!(b.get("key1"), Ok(b"value".to_vec()));
+
+        thread::sleep(ttl);
+        assert_eq!(b.get("key1"), b.not_found("key1"));
+        assert_eq!(b.delete("key2"), b.not_found("key2"));
+        assert_eq!(b.keys().unwrap(), vec!["key3"]);
+
+        // Expired keys should be purged on demand.
+        assert_eq!(b.set_with_ttl("key4", b"value", ttl), empty_ok());
+        assert_eq!(
+            b.set_with_ttl("key5", b"value", time::Duration::ZERO),
+            empty_ok()
+        );
+        assert_eq!(b.purge_expired(), 1);
+        assert_eq!(b.purge_expired(), 0);
+        let mut keys = b.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["key3", "key4"]);
+
+        // A time-to-live that overflows should never expire.
+        let res = b.set_with_ttl("key6", b"value", time::Duration::MAX);
+        assert_eq!(res, empty_ok());
+        assert_eq!(b.purge_expired(), 0);
+        assert_eq!(b.get("key6"), Ok(b"value".to_vec()));
+    }
+}
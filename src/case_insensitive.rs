@@ -0,0 +1,277 @@
+//! Case-insensitive key names
+//!
+//! In this module, we define a wrapper that makes the names of the keys of
+//! any `Cave` case-insensitive, so that all kvs treat `Key` and `key` the
+//! same way, regardless of the filesystem that they use.
+
+use std::collections;
+
+use crate::errors::Error;
+use crate::res::Res;
+use crate::{Capabilities, Cave, ChangeReceiver, KeyGuard, Metadata, VisitFn};
+
+/// Return the lowercase form of a name.
+///
+/// Each character is lowercased on its own, so that the lowercase form of a
+/// prefix is always a prefix of the lowercase form of the name.
+fn normalize(name: &str) -> String {
+    name.chars().flat_map(char::to_lowercase).collect()
+}
+
+/// A wrapper that makes the names of the keys of another `Cave`
+/// case-insensitive.
+///
+/// Every name is converted to lowercase before it's passed to the inner
+/// `Cave`, so `Key`, `KEY` and `key` refer to the same key. Note that this
+/// changes which names are distinct: writing to `Key` overwrites `key`.
+///
+/// The names that this wrapper returns, e.g., from `keys()`, are in their
+/// lowercase form, which is how they are stored in the inner `Cave`. Keys
+/// with uppercase characters in their names that were written to the inner
+/// `Cave` directly cannot be accessed through this wrapper. Also, names that
+/// are not valid UTF-8 are not supported.
+///
+/// ## Usage
+///
+/// ```
+/// use caves::{CaseInsensitiveCave, Cave, MemoryCave};
+///
+/// let b = CaseInsensitiveCave::new(MemoryCave::new());
+/// b.set("Key", b"value").unwrap();
+/// assert_eq!(b.get("KEY").unwrap(), b"value");
+/// assert_eq!(b.keys().unwrap(), vec!["key"]);
+/// ```
+#[derive(Debug)]
+pub struct CaseInsensitiveCave<C: Cave> {
+    inner: C,
+}
+
+impl<C: Cave> CaseInsensitiveCave<C> {
+    /// Create a new instance that wraps the provided `Cave`.
+    pub fn new(inner: C) -> Self {
+        Self { inner }
+    }
+
+    /// Return a reference to the inner `Cave`.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C: Cave> Cave for CaseInsensitiveCave<C> {
+    fn get(&self, name: &str) -> Res {
+        self.inner.get(&normalize(name))
+    }
+
+    fn set(&self, name: &str, data: &[u8]) -> Res {
+        self.inner.set(&normalize(name), data)
+    }
+
+    fn delete(&self, name: &str) -> Res {
+        self.inner.delete(&normalize(name))
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        self.inner.keys()
+    }
+
+    fn get_or_load(&self, name: &str, loader: &mut dyn FnMut(&str) -> Res) -> Res {
+        self.inner.get_or_load(&normalize(name), loader)
+    }
+
+    fn get_existing(&self, names: &[&str]) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+        let names: Vec<String> = names.iter().map(|name| normalize(name)).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        self.inner.get_existing(&names)
+    }
+
+    fn exists_many(&self, names: &[&str]) -> Result<Vec<bool>, Error> {
+        let names: Vec<String> = names.iter().map(|name| normalize(name)).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        self.inner.exists_many(&names)
+    }
+
+    fn version(&self, name: &str) -> Result<String, Error> {
+        self.inner.version(&normalize(name))
+    }
+
+    fn get_if_changed(
+        &self,
+        name: &str,
+        known_version: &str,
+    ) -> Result<Option<(Vec<u8>, String)>, Error> {
+        self.inner.get_if_changed(&normalize(name), known_version)
+    }
+
+    fn stat(&self, name: &str) -> Result<Metadata, Error> {
+        self.inner.stat(&normalize(name))
+    }
+
+    fn delete_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        self.inner.delete_prefix(&normalize(prefix))
+    }
+
+    fn rename_prefix(&self, old: &str, new: &str) -> Result<usize, Error> {
+        self.inner.rename_prefix(&normalize(old), &normalize(new))
+    }
+
+    fn take(&self, name: &str) -> Res {
+        self.inner.take(&normalize(name))
+    }
+
+    fn update_with(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(Option<Vec<u8>>) -> Option<Vec<u8>>,
+    ) -> Res {
+        self.inner.update_with(&normalize(name), f)
+    }
+
+    fn replace(&self, name: &str, data: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.inner.replace(&normalize(name), data)
+    }
+
+    fn create(&self, name: &str, data: &[u8]) -> Res {
+        self.inner.create(&normalize(name), data)
+    }
+
+    fn watch(&self, name: &str) -> Result<ChangeReceiver, Error> {
+        self.inner.watch(&normalize(name))
+    }
+
+    fn watch_prefix(&self, prefix: &str) -> Result<ChangeReceiver, Error> {
+        self.inner.watch_prefix(&normalize(prefix))
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn Cave + '_>, Error> {
+        Ok(Box::new(CaseInsensitiveCave::new(self.inner.snapshot()?)))
+    }
+
+    fn first_key(&self) -> Result<Option<String>, Error> {
+        self.inner.first_key()
+    }
+
+    fn last_key(&self) -> Result<Option<String>, Error> {
+        self.inner.last_key()
+    }
+
+    /// Get the keys whose names are in the range [`start`, `end`).
+    ///
+    /// The bounds are converted to lowercase as well, and compared with the
+    /// lowercase names of the keys.
+    fn range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        self.inner.range(&normalize(start), &normalize(end))
+    }
+
+    fn swap(&self, a: &str, b: &str) -> Res {
+        self.inner.swap(&normalize(a), &normalize(b))
+    }
+
+    fn count_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        self.inner.count_prefix(&normalize(prefix))
+    }
+
+    fn keys_sorted(&self) -> Result<Vec<String>, Error> {
+        self.inner.keys_sorted()
+    }
+
+    fn for_each_sorted(&self, f: &mut VisitFn<'_>) -> Result<(), Error> {
+        self.inner.for_each_sorted(f)
+    }
+
+    fn for_each(&self, f: &mut VisitFn<'_>) -> Result<(), Error> {
+        self.inner.for_each(f)
+    }
+
+    fn dump(&self) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+        self.inner.dump()
+    }
+
+    fn flush(&self) -> Res {
+        self.inner.flush()
+    }
+
+    fn maintain(&self) -> Res {
+        self.inner.maintain()
+    }
+
+    fn disk_usage(&self) -> Result<u64, Error> {
+        self.inner.disk_usage()
+    }
+
+    fn lock(&self, name: &str) -> Result<KeyGuard<'_>, Error> {
+        self.inner.lock(&normalize(name))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            binary_keys: false,
+            ..self.inner.capabilities()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::res::empty_ok;
+    use crate::tests::{_test_keys, _test_simple, _test_swap, _test_take};
+    use crate::{FileCave, MemoryCave};
+
+    #[test]
+    fn test_case_insensitive_simple() {
+        let b = CaseInsensitiveCave::new(MemoryCave::new());
+        _test_simple(Box::new(b));
+        let b = CaseInsensitiveCave::new(MemoryCave::new());
+        _test_keys(Box::new(b));
+        let b = CaseInsensitiveCave::new(MemoryCave::new());
+        _test_take(Box::new(b));
+        let b = CaseInsensitiveCave::new(MemoryCave::new());
+        _test_swap(Box::new(b));
+    }
+
+    #[test]
+    fn test_case_insensitive_names() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let caves: Vec<Box<dyn Cave>> = vec![
+            Box::new(CaseInsensitiveCave::new(MemoryCave::new())),
+            Box::new(CaseInsensitiveCave::new(
+                FileCave::new(temp_dir.path()).unwrap(),
+            )),
+        ];
+
+        for b in caves {
+            assert_eq!(b.set("Key", b"value"), empty_ok());
+            assert_eq!(b.get("KEY"), Ok(b"value".to_vec()));
+            assert_eq!(b.set("kEy", b"value2"), empty_ok());
+            assert_eq!(b.get("key"), Ok(b"value2".to_vec()));
+            assert_eq!(b.keys().unwrap(), vec!["key"]);
+            assert_eq!(b.exists_many(&["KEY", "Other"]), Ok(vec![true, false]));
+            assert_eq!(b.get_existing(&["KEY"]).unwrap()["key"], b"value2");
+            assert_eq!(b.count_prefix("K"), Ok(1));
+            assert_eq!(b.range("K", "L").unwrap().len(), 1);
+
+            // Non-ASCII names should be lowercased as well.
+            assert_eq!(b.set("ΟΔΟΣ", b"value"), empty_ok());
+            assert_eq!(b.get("οδοσ"), Ok(b"value".to_vec()));
+            assert_eq!(b.count_prefix("ΟΔΟ"), Ok(1));
+
+            assert_eq!(b.delete("KEY"), empty_ok());
+            assert_eq!(b.get("key"), b.not_found("key"));
+            assert_eq!(b.delete_prefix("ΟΔ"), Ok(1));
+            assert_eq!(b.keys().unwrap(), Vec::<String>::new());
+        }
+    }
+
+    #[test]
+    fn test_case_insensitive_snapshot() {
+        let b = CaseInsensitiveCave::new(MemoryCave::new());
+        assert_eq!(b.set("Key", b"value"), empty_ok());
+        let snapshot = b.snapshot().unwrap();
+        assert_eq!(b.set("KEY", b"value2"), empty_ok());
+        assert_eq!(snapshot.get("kEY"), Ok(b"value".to_vec()));
+        assert!(!b.capabilities().binary_keys);
+        assert!(b.capabilities().snapshot);
+    }
+}
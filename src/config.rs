@@ -0,0 +1,437 @@
+//! Configuration
+//!
+//! In this module, we define helpers that construct a `Cave` from external
+//! configuration, so that applications can switch between backends without
+//! code changes.
+
+use std::env;
+use std::fmt;
+use std::path;
+use std::str;
+
+use crate::errors::Error;
+use crate::{Cave, FileCave, MemoryCave, NullCave, WriteStrategy};
+
+/// The names of the backends that `from_env` can construct, with the enabled
+/// features.
+fn supported_backends() -> Vec<&'static str> {
+    let mut backends = vec!["memory", "null", "file"];
+    if cfg!(feature = "with-rocksdb") {
+        backends.push("rocksdb");
+    }
+    if cfg!(feature = "with-lmdb") {
+        backends.push("lmdb");
+    }
+    if cfg!(feature = "with-postgres") {
+        backends.push("postgres");
+    }
+    if cfg!(feature = "with-memcached") {
+        backends.push("memcached");
+    }
+    if cfg!(feature = "with-http-client") {
+        backends.push("http");
+    }
+    backends
+}
+
+/// Open a persistent `Cave` in a directory, with the best backend that is
+/// compiled in.
+///
+/// This is `RocksDBCave`, if the `with-rocksdb` feature is enabled, or
+/// `FileCave` otherwise, which is always available. The directory is
+/// created if it does not exist. Note that the backends store keys
+/// differently, so a directory must always be opened with the same set of
+/// features.
+///
+/// ## Usage
+///
+/// ```
+/// let dir = tempfile::tempdir().unwrap();
+/// let b = caves::default_cave(dir.path()).unwrap();
+/// b.set("key", b"value").unwrap();
+/// ```
+pub fn default_cave(dir: &path::Path) -> Result<Box<dyn Cave>, Error> {
+    #[cfg(feature = "with-rocksdb")]
+    return Ok(Box::new(crate::RocksDBCave::new(dir)?));
+
+    #[cfg(not(feature = "with-rocksdb"))]
+    return Ok(Box::new(FileCave::create(dir)?));
+}
+
+/// Construct a `Cave` from environment variables.
+///
+/// The backend is selected with the `<prefix>_BACKEND` variable, and
+/// configured with the rest of the variables that start with `<prefix>_`.
+/// The following variables are supported:
+///
+/// * `<prefix>_BACKEND`: One of `memory`, `null`, `file`, `rocksdb`, `lmdb`,
+///   `postgres`, `memcached` and `http`. The backends that are behind a
+///   feature flag are available only if it's enabled. Required.
+/// * `<prefix>_PATH`: The directory of the `file`, `rocksdb` and `lmdb`
+///   backends. Required for them.
+/// * `<prefix>_URL`: The URL of the `postgres` and `http` backends, or a
+///   comma-separated list of URLs for the `memcached` backend. Required for
+///   them.
+/// * `<prefix>_MAX_VALUE_SIZE`: The maximum size of a value, in bytes. Used
+///   by the `memory`, `file`, `rocksdb`, `lmdb` and `memcached` backends.
+/// * `<prefix>_MAX_SIZE`: The maximum size of the database, in bytes.
+///   Required for the `lmdb` backend.
+/// * `<prefix>_CHUNK_SIZE`: The chunk size of the `rocksdb` backend.
+/// * `<prefix>_CREATE`: Create the directory of the `file` backend, if it
+///   does not exist.
+/// * `<prefix>_GZIP`: The gzip compression level of the `file` backend.
+/// * `<prefix>_WRITE_STRATEGY`: Either `atomic` or `truncate`, for the `file`
+///   backend.
+/// * `<prefix>_NO_FOLLOW`, `<prefix>_HASHED_NAMES`: Enable the respective
+///   options of the `file` backend.
+/// * `<prefix>_INLINE_THRESHOLD`: The inline threshold of the `file`
+///   backend.
+///
+/// Boolean variables accept `true`/`false`, `yes`/`no` and `1`/`0`. Unset and
+/// empty variables are treated the same. If a required variable is missing,
+/// or a variable has an invalid value, an [`Error::Internal`] error is
+/// returned, which names the variable.
+///
+/// ## Usage
+///
+/// ```
+/// use caves::from_env;
+///
+/// std::env::set_var("SESSIONS_BACKEND", "memory");
+/// std::env::set_var("SESSIONS_MAX_VALUE_SIZE", "4096");
+/// let b = from_env("SESSIONS").unwrap();
+/// assert!(b.set("key", &[0; 4097]).is_err());
+/// ```
+///
+/// [`Error::Internal`]: errors/enum.Error.html#variant.Internal
+pub fn from_env(prefix: &str) -> Result<Box<dyn Cave>, Error> {
+    from_vars(prefix, &|name| match env::var(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(Error::internal_from_msg(format!(
+            "Invalid value for environment variable `{}`: {}",
+            name, e
+        ))),
+    })
+}
+
+/// Construct a `Cave` from variables that are looked up with a function.
+fn from_vars(
+    prefix: &str,
+    lookup: &dyn Fn(&str) -> Result<Option<String>, Error>,
+) -> Result<Box<dyn Cave>, Error> {
+    let vars = Vars { prefix, lookup };
+    let backend = vars.require("BACKEND")?;
+    match backend.as_str() {
+        "memory" => {
+            let mut mb = MemoryCave::new();
+            if let Some(limit) = vars.parse("MAX_VALUE_SIZE")? {
+                mb = mb.with_max_value_size(limit);
+            }
+            Ok(Box::new(mb))
+        }
+        "null" => Ok(Box::new(NullCave::new())),
+        "file" => Ok(Box::new(file_from_vars(&vars)?)),
+        #[cfg(feature = "with-rocksdb")]
+        "rocksdb" => {
+            let mut rb = crate::RocksDBCave::new(&vars.require_path()?)?;
+            if let Some(limit) = vars.parse("MAX_VALUE_SIZE")? {
+                rb = rb.with_max_value_size(limit);
+            }
+            if let Some(chunk_size) = vars.parse("CHUNK_SIZE")? {
+                rb = rb.with_chunk_size(chunk_size);
+            }
+            Ok(Box::new(rb))
+        }
+        #[cfg(feature = "with-lmdb")]
+        "lmdb" => {
+            let max_size = match vars.parse("MAX_SIZE")? {
+                Some(max_size) => max_size,
+                None => return Err(vars.missing("MAX_SIZE")),
+            };
+            let mut lb = crate::LmdbCave::new(&vars.require_path()?, max_size)?;
+            if let Some(limit) = vars.parse("MAX_VALUE_SIZE")? {
+                lb = lb.with_max_value_size(limit);
+            }
+            Ok(Box::new(lb))
+        }
+        #[cfg(feature = "with-postgres")]
+        "postgres" => Ok(Box::new(crate::PostgresCave::new(&vars.require("URL")?)?)),
+        #[cfg(feature = "with-memcached")]
+        "memcached" => {
+            let urls = vars.require("URL")?;
+            let urls: Vec<&str> = urls.split(',').map(str::trim).collect();
+            let mut mb = crate::MemcachedCave::new(&urls)?;
+            if let Some(limit) = vars.parse("MAX_VALUE_SIZE")? {
+                mb = mb.with_max_value_size(limit);
+            }
+            Ok(Box::new(mb))
+        }
+        #[cfg(feature = "with-http-client")]
+        "http" => Ok(Box::new(crate::HttpCave::new(&vars.require("URL")?)?)),
+        _ => Err(vars.invalid(
+            "BACKEND",
+            &backend,
+            format!("expected one of {}", supported_backends().join(", ")),
+        )),
+    }
+}
+
+/// Construct a `FileCave` from variables.
+fn file_from_vars(vars: &Vars<'_>) -> Result<FileCave, Error> {
+    let dir = vars.require_path()?;
+    let mut fb = if vars.flag("CREATE")? {
+        FileCave::create(&dir)?
+    } else {
+        FileCave::new(&dir)?
+    };
+    if let Some(level) = vars.parse("GZIP")? {
+        fb = fb.with_gzip(level);
+    }
+    if let Some(limit) = vars.parse("MAX_VALUE_SIZE")? {
+        fb = fb.with_max_value_size(limit);
+    }
+    if let Some(strategy) = vars.get("WRITE_STRATEGY")? {
+        let strategy = match strategy.as_str() {
+            "atomic" => WriteStrategy::Atomic,
+            "truncate" => WriteStrategy::Truncate,
+            _ => {
+                let reason = "expected one of atomic, truncate".to_string();
+                return Err(vars.invalid("WRITE_STRATEGY", &strategy, reason));
+            }
+        };
+        fb = fb.with_write_strategy(strategy);
+    }
+    if vars.flag("NO_FOLLOW")? {
+        fb = fb.with_no_follow();
+    }
+    if vars.flag("HASHED_NAMES")? {
+        fb = fb.with_hashed_names();
+    }
+    if let Some(threshold) = vars.parse("INLINE_THRESHOLD")? {
+        fb = fb.with_inline_threshold(threshold);
+    }
+    Ok(fb)
+}
+
+/// The variables with a common prefix.
+struct Vars<'a> {
+    prefix: &'a str,
+    lookup: &'a dyn Fn(&str) -> Result<Option<String>, Error>,
+}
+
+impl Vars<'_> {
+    /// Return the full name of a variable.
+    fn name(&self, name: &str) -> String {
+        format!("{}_{}", self.prefix, name)
+    }
+
+    /// Return the value of a variable, if it's set and not empty.
+    fn get(&self, name: &str) -> Result<Option<String>, Error> {
+        match (self.lookup)(&self.name(name))? {
+            Some(value) if !value.is_empty() => Ok(Some(value)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Return the value of a variable, or an error if it's not set.
+    fn require(&self, name: &str) -> Result<String, Error> {
+        match self.get(name)? {
+            Some(value) => Ok(value),
+            None => Err(self.missing(name)),
+        }
+    }
+
+    /// Return the value of the `PATH` variable, or an error if it's not set.
+    fn require_path(&self) -> Result<path::PathBuf, Error> {
+        Ok(path::PathBuf::from(self.require("PATH")?))
+    }
+
+    /// Parse the value of a variable, if it's set.
+    fn parse<T>(&self, name: &str) -> Result<Option<T>, Error>
+    where
+        T: str::FromStr,
+        T::Err: fmt::Display,
+    {
+        match self.get(name)? {
+            Some(value) => match value.parse() {
+                Ok(parsed) => Ok(Some(parsed)),
+                Err(e) => Err(self.invalid(name, &value, e.to_string())),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Parse the value of a boolean variable, which is false if it's not set.
+    fn flag(&self, name: &str) -> Result<bool, Error> {
+        match self.get(name)? {
+            Some(value) => match value.to_lowercase().as_str() {
+                "true" | "yes" | "1" => Ok(true),
+                "false" | "no" | "0" => Ok(false),
+                _ => {
+                    let reason = "expected a boolean".to_string();
+                    Err(self.invalid(name, &value, reason))
+                }
+            },
+            None => Ok(false),
+        }
+    }
+
+    fn missing(&self, name: &str) -> Error {
+        Error::internal_from_msg(format!(
+            "Missing environment variable `{}`",
+            self.name(name)
+        ))
+    }
+
+    fn invalid(&self, name: &str, value: &str, reason: String) -> Error {
+        Error::internal_from_msg(format!(
+            "Invalid value `{}` for environment variable `{}`: {}",
+            value,
+            self.name(name),
+            reason
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections;
+
+    use crate::errors::ErrorKind;
+    use crate::res::empty_ok;
+
+    /// Construct a `Cave` from the provided variables, instead of the
+    /// environment.
+    fn from_map(vars: &[(&str, &str)]) -> Result<Box<dyn Cave>, Error> {
+        let vars: collections::HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        from_vars("CAVES", &|name| Ok(vars.get(name).cloned()))
+    }
+
+    fn assert_err(res: Result<Box<dyn Cave>, Error>, msg: &str) {
+        match res {
+            Err(e) => assert!(e.to_string().ends_with(msg), "{}", e),
+            Ok(_) => panic!("expected an error: {}", msg),
+        }
+    }
+
+    #[test]
+    fn test_from_vars() {
+        let b = from_map(&[("CAVES_BACKEND", "memory")]).unwrap();
+        assert_eq!(b.set("key", b"value"), empty_ok());
+        let b = from_map(&[("CAVES_BACKEND", "memory"), ("CAVES_MAX_VALUE_SIZE", "4")]);
+        let err = b.unwrap().set("key", b"value").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ValueTooLarge);
+        let b = from_map(&[("CAVES_BACKEND", "null")]).unwrap();
+        assert_eq!(b.get("key"), b.not_found("key"));
+
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let dir = temp_dir.path().join("file");
+        let dir = dir.to_str().unwrap();
+        let file_vars = [
+            ("CAVES_BACKEND", "file"),
+            ("CAVES_PATH", dir),
+            ("CAVES_CREATE", "yes"),
+            ("CAVES_GZIP", "6"),
+            ("CAVES_WRITE_STRATEGY", "truncate"),
+            ("CAVES_NO_FOLLOW", "true"),
+            ("CAVES_HASHED_NAMES", "0"),
+            ("CAVES_INLINE_THRESHOLD", ""),
+        ];
+        let b = from_map(&file_vars).unwrap();
+        assert_eq!(b.set("key", b"value"), empty_ok());
+        assert!(temp_dir.path().join("file").join("key.gz").exists());
+    }
+
+    #[test]
+    fn test_from_vars_errors() {
+        assert_err(
+            from_map(&[]),
+            "Missing environment variable `CAVES_BACKEND`",
+        );
+        assert_err(
+            from_map(&[("CAVES_BACKEND", "")]),
+            "Missing environment variable `CAVES_BACKEND`",
+        );
+        let res = from_map(&[("CAVES_BACKEND", "tape")]);
+        assert_err(
+            res,
+            &format!(
+                "Invalid value `tape` for environment variable `CAVES_BACKEND`: \
+                 expected one of {}",
+                supported_backends().join(", ")
+            ),
+        );
+        assert_err(
+            from_map(&[("CAVES_BACKEND", "file")]),
+            "Missing environment variable `CAVES_PATH`",
+        );
+        let res = from_map(&[("CAVES_BACKEND", "memory"), ("CAVES_MAX_VALUE_SIZE", "-1")]);
+        assert_err(
+            res,
+            "Invalid value `-1` for environment variable `CAVES_MAX_VALUE_SIZE`: \
+             invalid digit found in string",
+        );
+
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let dir = temp_dir.path().to_str().unwrap();
+        let res = from_map(&[
+            ("CAVES_BACKEND", "file"),
+            ("CAVES_PATH", dir),
+            ("CAVES_WRITE_STRATEGY", "fast"),
+        ]);
+        assert_err(
+            res,
+            "Invalid value `fast` for environment variable `CAVES_WRITE_STRATEGY`: \
+             expected one of atomic, truncate",
+        );
+        let res = from_map(&[
+            ("CAVES_BACKEND", "file"),
+            ("CAVES_PATH", dir),
+            ("CAVES_HASHED_NAMES", "maybe"),
+        ]);
+        assert_err(
+            res,
+            "Invalid value `maybe` for environment variable `CAVES_HASHED_NAMES`: \
+             expected a boolean",
+        );
+
+        // The directory of a file backend should not be created, unless
+        // requested.
+        let dir = temp_dir.path().join("missing");
+        let res = from_map(&[
+            ("CAVES_BACKEND", "file"),
+            ("CAVES_PATH", dir.to_str().unwrap()),
+        ]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_default_cave() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let dir = temp_dir.path().join("missing");
+        let b = default_cave(&dir).unwrap();
+        assert_eq!(b.set("key", b"value"), empty_ok());
+        drop(b);
+
+        // The keys should persist across instances.
+        let b = default_cave(&dir).unwrap();
+        assert_eq!(b.get("key"), Ok(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_from_env() {
+        env::set_var("CAVES_TEST_FROM_ENV_BACKEND", "memory");
+        let b = from_env("CAVES_TEST_FROM_ENV").unwrap();
+        assert_eq!(b.set("key", b"value"), empty_ok());
+        assert_err(
+            from_env("CAVES_TEST_FROM_ENV_MISSING"),
+            "Missing environment variable `CAVES_TEST_FROM_ENV_MISSING_BACKEND`",
+        );
+    }
+}
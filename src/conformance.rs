@@ -0,0 +1,211 @@
+//! Conformance testing
+//!
+//! In this module, we define a property-based test that checks whether a
+//! `Cave` behaves like a hash map, so that new backends can be validated
+//! against the contract of the `Cave` trait with a single call.
+
+use std::collections;
+
+use proptest::prelude::*;
+use proptest::test_runner::{Config, TestRunner};
+
+use crate::errors::Error;
+use crate::res::{empty_ok, Res};
+use crate::Cave;
+
+/// The number of distinct keys that the generated operations touch. It's
+/// kept small, so that operations on the same key are frequent.
+const NUM_KEYS: usize = 5;
+
+/// An operation on a key, which is identified by its index.
+#[derive(Debug, Clone)]
+enum Op {
+    Set(usize, Vec<u8>),
+    Get(usize),
+    Delete(usize),
+}
+
+fn op_strategy() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        (0..NUM_KEYS, prop::collection::vec(any::<u8>(), 0..64))
+            .prop_map(|(i, data)| Op::Set(i, data)),
+        (0..NUM_KEYS).prop_map(Op::Get),
+        (0..NUM_KEYS).prop_map(Op::Delete),
+    ]
+}
+
+fn key_name(i: usize) -> String {
+    format!("conformance_key{}", i)
+}
+
+/// Check that a `Cave` behaves like a hash map.
+///
+/// This function generates random sequences of `set`/`get`/`delete`
+/// operations, runs them against the provided `Cave` and a reference hash
+/// map, and panics if their results differ, e.g., if a `delete` of a missing
+/// key does not return an [`Error::NotFound`] error. The panic message
+/// contains a minimal sequence of operations that reproduces the problem.
+///
+/// If the `Cave` supports listing its keys, their names are checked as well.
+/// A `Cave` that can't list them must return an [`Error::Unsupported`]
+/// error, since any other error fails the check.
+/// The operations touch only keys whose names start with `conformance_`, and
+/// these keys are deleted before each sequence.
+///
+/// This function is available with the `test-util` feature.
+///
+/// ## Usage
+///
+/// ```
+/// use caves::{assert_cave_conformance, MemoryCave};
+///
+/// assert_cave_conformance(&MemoryCave::new());
+/// ```
+///
+/// [`Error::NotFound`]: errors/enum.Error.html#variant.NotFound
+/// [`Error::Unsupported`]: errors/enum.Error.html#variant.Unsupported
+pub fn assert_cave_conformance(cave: &dyn Cave) {
+    let config = Config {
+        cases: 64,
+        failure_persistence: None,
+        ..Config::default()
+    };
+    let mut runner = TestRunner::new(config);
+    let strategy = prop::collection::vec(op_strategy(), 1..50);
+
+    let res = runner.run(&strategy, |ops| {
+        for i in 0..NUM_KEYS {
+            match cave.delete(&key_name(i)) {
+                Ok(_) | Err(Error::NotFound(_)) => (),
+                Err(e) => panic!("Could not clean up key `{}`: {}", key_name(i), e),
+            }
+        }
+
+        let mut model = collections::HashMap::new();
+        for op in ops {
+            let (name, expected, actual): (String, Res, Res) = match op {
+                Op::Set(i, data) => {
+                    let _ = model.insert(i, data.clone());
+                    (key_name(i), empty_ok(), cave.set(&key_name(i), &data))
+                }
+                Op::Get(i) => {
+                    let expected = match model.get(&i) {
+                        Some(data) => Ok(data.clone()),
+                        None => Err(Error::NotFound(key_name(i))),
+                    };
+                    (key_name(i), expected, cave.get(&key_name(i)))
+                }
+                Op::Delete(i) => {
+                    let expected = match model.remove(&i) {
+                        Some(_) => empty_ok(),
+                        None => Err(Error::NotFound(key_name(i))),
+                    };
+                    (key_name(i), expected, cave.delete(&key_name(i)))
+                }
+            };
+            prop_assert_eq!(actual, expected, "Unexpected result for key `{}`", name);
+        }
+
+        match cave.keys() {
+            Ok(keys) => {
+                let mut actual: Vec<_> = keys
+                    .into_iter()
+                    .filter(|name| name.starts_with("conformance_"))
+                    .collect();
+                actual.sort();
+                let mut expected: Vec<_> = model.keys().map(|&i| key_name(i)).collect();
+                expected.sort();
+                prop_assert_eq!(actual, expected, "Unexpected key names");
+            }
+            // Kvs that can't list their keys are checked for the rest.
+            Err(Error::Unsupported { .. }) => (),
+            Err(e) => prop_assert!(false, "Could not list the keys: {}", e),
+        }
+        Ok(())
+    });
+
+    if let Err(e) = res {
+        panic!("The cave does not conform to the `Cave` contract: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{FileCave, MemoryCave, NullCave, WriteBehindCave};
+
+    /// A kv that can't list its keys, and returns the provided error instead.
+    struct NoKeys {
+        inner: MemoryCave,
+        error: fn() -> Error,
+    }
+
+    impl Cave for NoKeys {
+        fn get(&self, name: &str) -> Res {
+            self.inner.get(name)
+        }
+
+        fn set(&self, name: &str, data: &[u8]) -> Res {
+            self.inner.set(name, data)
+        }
+
+        fn delete(&self, name: &str) -> Res {
+            self.inner.delete(name)
+        }
+
+        fn keys(&self) -> Result<Vec<String>, Error> {
+            Err((self.error)())
+        }
+    }
+
+    #[test]
+    fn test_conformance() {
+        assert_cave_conformance(&MemoryCave::new());
+
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        assert_cave_conformance(&FileCave::new(temp_dir.path()).unwrap());
+        let gzip_dir = temp_dir.path().join("gzip");
+        assert_cave_conformance(&FileCave::create(&gzip_dir).unwrap().with_gzip(1));
+
+        assert_cave_conformance(&WriteBehindCave::new(MemoryCave::new()));
+
+        // Kvs that can't list their keys should be checked for the rest.
+        assert_cave_conformance(&NoKeys {
+            inner: MemoryCave::new(),
+            error: || Error::Unsupported { operation: "keys" },
+        });
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_conformance() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        assert_cave_conformance(&crate::RocksDBCave::new(temp_dir.path()).unwrap());
+    }
+
+    #[cfg(feature = "with-lmdb")]
+    #[test]
+    fn test_lmdb_conformance() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let lb = crate::LmdbCave::new(temp_dir.path(), 10 * 1024 * 1024).unwrap();
+        assert_cave_conformance(&lb);
+    }
+
+    #[test]
+    #[should_panic(expected = "does not conform")]
+    fn test_conformance_failure() {
+        // The null cave discards every write, so it can't pass the test.
+        assert_cave_conformance(&NullCave::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "Could not list the keys")]
+    fn test_conformance_keys_failure() {
+        // Errors other than `Unsupported` should not be ignored.
+        assert_cave_conformance(&NoKeys {
+            inner: MemoryCave::new(),
+            error: || Error::internal_from_msg("broken".to_string()),
+        });
+    }
+}
@@ -0,0 +1,214 @@
+//! Integer counters
+//!
+//! In this module, we define a typed handle to a key of a `Cave` that holds
+//! an integer counter, so that applications don't have to encode and decode
+//! the integer themselves.
+
+use std::fmt;
+use std::str;
+
+use crate::errors::Error;
+use crate::Cave;
+
+/// A handle to a key that holds a signed 64-bit counter.
+///
+/// The counter is stored as a decimal number in ASCII, e.g., `-42`, so that
+/// it can be inspected with external tools. A missing key counts as zero.
+///
+/// The counter is updated with [`Cave::update_with`], so the updates are
+/// atomic if the `Cave` implements it atomically, e.g., `MemoryCave` and
+/// `RocksDBCave` do. Otherwise, concurrent updates may be lost.
+///
+/// ## Usage
+///
+/// ```
+/// use caves::{Counter, MemoryCave};
+///
+/// let b = MemoryCave::new();
+/// let counter = Counter::new(&b, "visits");
+///
+/// assert_eq!(counter.get().unwrap(), 0);
+/// assert_eq!(counter.inc().unwrap(), 1);
+/// assert_eq!(counter.add(10).unwrap(), 11);
+/// assert_eq!(counter.dec().unwrap(), 10);
+/// counter.reset().unwrap();
+/// assert_eq!(counter.get().unwrap(), 0);
+/// ```
+///
+/// [`Cave::update_with`]: trait.Cave.html#method.update_with
+#[derive(Clone)]
+pub struct Counter<'a> {
+    cave: &'a dyn Cave,
+    key: String,
+}
+
+impl<'a> Counter<'a> {
+    /// Create a handle to the counter that is stored in a key.
+    pub fn new(cave: &'a dyn Cave, key: &str) -> Self {
+        Self {
+            cave,
+            key: key.to_string(),
+        }
+    }
+
+    /// Return the name of the key of the counter.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Return the value of the counter.
+    pub fn get(&self) -> Result<i64, Error> {
+        match self.cave.get(&self.key) {
+            Ok(data) => self.decode(&data),
+            Err(Error::NotFound(_)) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Add a value to the counter, and return its new value.
+    ///
+    /// If the key holds something other than a counter, or if the counter
+    /// would overflow, return an error and leave the counter intact. This is
+    /// checked before the update, so that the key is not written at all.
+    /// Only if the key changes in the meantime, and the check fails during
+    /// the update, are its contents written back as they were, which may
+    /// overwrite a concurrent write, if `update_with` is not atomic.
+    pub fn add(&self, delta: i64) -> Result<i64, Error> {
+        let _ = self.checked_add(self.get()?, delta)?;
+
+        // The closure cannot return an error, so it stores it here, and
+        // leaves the key as is.
+        let mut error = None;
+        let data = self.cave.update_with(&self.key, &mut |current| {
+            error = None;
+            let value = match &current {
+                Some(data) => self.decode(data),
+                None => Ok(0),
+            };
+            match value.and_then(|value| self.checked_add(value, delta)) {
+                Ok(value) => Some(value.to_string().into_bytes()),
+                Err(e) => {
+                    error = Some(e);
+                    current
+                }
+            }
+        })?;
+
+        match error {
+            Some(e) => Err(e),
+            None => self.decode(&data),
+        }
+    }
+
+    /// Increment the counter by one, and return its new value.
+    pub fn inc(&self) -> Result<i64, Error> {
+        self.add(1)
+    }
+
+    /// Decrement the counter by one, and return its new value.
+    pub fn dec(&self) -> Result<i64, Error> {
+        self.add(-1)
+    }
+
+    /// Reset the counter to zero, by deleting its key.
+    pub fn reset(&self) -> Result<(), Error> {
+        match self.cave.delete(&self.key) {
+            Ok(_) | Err(Error::NotFound(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn checked_add(&self, value: i64, delta: i64) -> Result<i64, Error> {
+        match value.checked_add(delta) {
+            Some(value) => Ok(value),
+            None => Err(Error::internal_from_msg(format!(
+                "Adding {} to the counter of key `{}` overflows it",
+                delta, self.key
+            ))),
+        }
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<i64, Error> {
+        match str::from_utf8(data).ok().and_then(|s| s.parse().ok()) {
+            Some(value) => Ok(value),
+            None => Err(Error::internal_from_msg(format!(
+                "The contents of key `{}` are not a counter",
+                self.key
+            ))),
+        }
+    }
+}
+
+impl fmt::Debug for Counter<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Counter")
+            .field("key", &self.key)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync;
+    use std::thread;
+
+    use crate::res::empty_ok;
+    use crate::{FileCave, MemoryCave};
+
+    #[test]
+    fn test_counter() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let caves: Vec<Box<dyn Cave>> = vec![
+            Box::new(MemoryCave::new()),
+            Box::new(FileCave::new(temp_dir.path()).unwrap()),
+        ];
+
+        for b in caves {
+            let counter = Counter::new(&b, "counter");
+            assert_eq!(counter.key(), "counter");
+            assert_eq!(counter.get(), Ok(0));
+            assert_eq!(counter.dec(), Ok(-1));
+            assert_eq!(counter.inc(), Ok(0));
+            assert_eq!(counter.inc(), Ok(1));
+            assert_eq!(counter.add(41), Ok(42));
+            assert_eq!(counter.get(), Ok(42));
+            assert_eq!(b.get("counter"), Ok(b"42".to_vec()));
+
+            assert_eq!(counter.reset(), Ok(()));
+            assert_eq!(counter.reset(), Ok(()));
+            assert_eq!(counter.get(), Ok(0));
+            assert_eq!(b.get("counter"), b.not_found("counter"));
+
+            // Overflows and contents other than counters should be rejected.
+            let internal_err = Err(Error::Internal(anyhow!("")));
+            assert_eq!(counter.add(i64::MAX), Ok(i64::MAX));
+            assert_eq!(counter.inc(), internal_err);
+            assert_eq!(counter.get(), Ok(i64::MAX));
+            assert_eq!(b.set("counter", b"value"), empty_ok());
+            assert_eq!(counter.get(), internal_err);
+            assert_eq!(counter.inc(), internal_err);
+            assert_eq!(b.get("counter"), Ok(b"value".to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_counter_concurrency() {
+        let b = MemoryCave::new();
+        let threads = 4;
+        let barrier = sync::Barrier::new(threads);
+        thread::scope(|s| {
+            for _ in 0..threads {
+                let _ = s.spawn(|| {
+                    let counter = Counter::new(&b, "counter");
+                    let _ = barrier.wait();
+                    for _ in 0..100 {
+                        assert!(counter.inc().is_ok());
+                    }
+                });
+            }
+        });
+        assert_eq!(Counter::new(&b, "counter").get(), Ok(400));
+    }
+}
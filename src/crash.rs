@@ -0,0 +1,194 @@
+//! Crash-consistency testing
+//!
+//! In this module, we define a check that simulates a crash of the process at
+//! every write of a `FileCave`, and verifies that the directory it leaves
+//! behind never exposes a half-written key.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path;
+use std::sync;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::errors::Error;
+use crate::{Cave, FileCave};
+
+/// The maximum number of bytes that are written between two crash points.
+const CRASH_STEP: usize = 16;
+
+/// A callback that runs after every write of a `FileCave`, i.e., at every
+/// point where a crash is simulated.
+#[derive(Clone)]
+pub(crate) struct CrashHook(sync::Arc<dyn Fn() + Send + Sync>);
+
+impl CrashHook {
+    /// Wrap a writer, so that it calls the hook after every write.
+    pub(crate) fn writer<W: Write>(&self, inner: W) -> CrashWriter<W> {
+        CrashWriter {
+            inner,
+            hook: self.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for CrashHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CrashHook")
+    }
+}
+
+/// A writer that splits the writes in small steps, and calls a hook after
+/// each one.
+#[derive(Debug)]
+pub(crate) struct CrashWriter<W> {
+    inner: W,
+    hook: CrashHook,
+}
+
+impl<W: Write> Write for CrashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(&buf[..buf.len().min(CRASH_STEP)])?;
+        (self.hook.0)();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Copy a directory recursively, e.g., to keep the state that a crash would
+/// leave behind.
+fn copy_dir(src: &path::Path, dst: &path::Path) -> io::Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            let _ = fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Check that a `FileCave` never exposes a half-written key after a crash.
+///
+/// This function opens a `FileCave` in a temporary directory with `open`,
+/// stores a few keys, and then overwrites one key with `set` and creates
+/// another one with `create`. During these operations, every write to a file
+/// is intercepted, and the directory is copied as it is at that point, as if
+/// the process had crashed. Then, each copy is opened with `open`, and the
+/// function panics if any key has contents other than its old or new ones,
+/// or if any unexpected key is listed, e.g., a temporary file.
+///
+/// The crashes are simulated in the order that the process issues the writes,
+/// so writes that the OS reorders or loses without an `fsync` are not
+/// covered. Also, values that are stored inline, see
+/// [`FileCave::with_inline_threshold`], are not intercepted. Return the number
+/// of crash points that were checked.
+///
+/// This function is available with the `test-util` feature.
+///
+/// ## Usage
+///
+/// ```
+/// use caves::{assert_crash_consistency, FileCave};
+///
+/// let checked = assert_crash_consistency(&|dir| FileCave::new(dir).unwrap().with_gzip(1));
+/// assert!(checked > 0);
+/// ```
+///
+/// [`FileCave::with_inline_threshold`]: struct.FileCave.html#method.with_inline_threshold
+pub fn assert_crash_consistency(open: &dyn Fn(&path::Path) -> FileCave) -> usize {
+    let temp_dir = tempfile::tempdir().expect("Could not create a temporary directory");
+    let dir = temp_dir.path().join("cave");
+    let images = temp_dir.path().join("crashes");
+    fs::create_dir(&dir).expect("Could not create the directory of the cave");
+
+    let other = b"other value".to_vec();
+    let old = vec![b'o'; 100];
+    let new = vec![b'n'; 150];
+    // The allowed contents of each key, where `None` means that it's missing.
+    let expected: Vec<(&str, Vec<Option<&[u8]>>)> = vec![
+        ("other", vec![Some(&other)]),
+        ("key", vec![Some(&old), Some(&new)]),
+        ("new_key", vec![None, Some(&new)]),
+    ];
+
+    let mut fb = open(&dir);
+    let _ = fb.set("other", &other).expect("Could not set key `other`");
+    let _ = fb.set("key", &old).expect("Could not set key `key`");
+
+    let crashes = sync::Arc::new(AtomicUsize::new(0));
+    let hook_crashes = sync::Arc::clone(&crashes);
+    let (src, dst) = (dir.clone(), images.clone());
+    fb.crash_hook = Some(CrashHook(sync::Arc::new(move || {
+        let i = hook_crashes.fetch_add(1, Ordering::SeqCst);
+        copy_dir(&src, &dst.join(i.to_string())).expect("Could not copy the directory");
+    })));
+    let _ = fb.set("key", &new).expect("Could not overwrite key `key`");
+    let _ = fb
+        .create("new_key", &new)
+        .expect("Could not create key `new_key`");
+
+    let crashes = crashes.load(Ordering::SeqCst);
+    for i in 0..crashes {
+        let crashed = open(&images.join(i.to_string()));
+        for (name, allowed) in &expected {
+            let data = match crashed.get(name) {
+                Ok(data) => Some(data),
+                Err(Error::NotFound(_)) => None,
+                Err(e) => panic!(
+                    "After a crash at write {}, key `{}` is unreadable: {}",
+                    i, name, e
+                ),
+            };
+            if !allowed.contains(&data.as_deref()) {
+                panic!(
+                    "After a crash at write {}, key `{}` is half-written",
+                    i, name
+                );
+            }
+        }
+
+        let keys = crashed.keys().expect("Could not list the keys");
+        for name in keys {
+            if !expected.iter().any(|(n, _)| *n == name) {
+                panic!("After a crash at write {}, key `{}` is listed", i, name);
+            }
+        }
+    }
+    crashes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::WriteStrategy;
+
+    #[test]
+    fn test_crash_consistency() {
+        assert!(assert_crash_consistency(&|dir| FileCave::new(dir).unwrap()) > 0);
+        assert!(assert_crash_consistency(&|dir| FileCave::new(dir).unwrap().with_gzip(1)) > 0);
+        let checked =
+            assert_crash_consistency(&|dir| FileCave::new(dir).unwrap().with_hashed_names());
+        // The header and the contents of both keys should be checked.
+        assert!(checked >= 2 * (150 / CRASH_STEP));
+    }
+
+    #[test]
+    #[should_panic(expected = "half-written")]
+    fn test_crash_consistency_truncate() {
+        // Truncating a file in place does not survive a crash.
+        let _ = assert_crash_consistency(&|dir| {
+            FileCave::new(dir)
+                .unwrap()
+                .with_write_strategy(WriteStrategy::Truncate)
+        });
+    }
+}
@@ -0,0 +1,127 @@
+//! Comparisons
+//!
+//! In this module, we define helpers that compare the contents of two
+//! `Cave`s, e.g., in order to validate a migration between backends.
+
+use crate::errors::Error;
+use crate::Cave;
+
+/// The number of keys whose existence is checked at once.
+const EXISTS_BATCH_SIZE: usize = 256;
+
+/// The differences between the contents of two `Cave`s.
+///
+/// See [`diff`] for more info. The names in each list are sorted.
+///
+/// [`diff`]: fn.diff.html
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CaveDiff {
+    /// The keys that exist only in the first `Cave`.
+    pub only_in_a: Vec<String>,
+    /// The keys that exist only in the second `Cave`.
+    pub only_in_b: Vec<String>,
+    /// The keys that exist in both `Cave`s, but with different values.
+    pub differing: Vec<String>,
+}
+
+impl CaveDiff {
+    /// Return whether the two `Cave`s have the same contents.
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty() && self.differing.is_empty()
+    }
+}
+
+/// Compare the contents of two `Cave`s.
+///
+/// The values of `a` are visited one by one, and compared with the values of
+/// the same keys in `b`. Then, the names of the keys of `b` are checked
+/// against `a`, in batches. So, no more than two values are held in memory at
+/// the same time, but the names of the keys of `b` are.
+///
+/// The comparison is not atomic, so if the `Cave`s are modified while
+/// they're compared, the result may be inaccurate. Keys that can't be listed,
+/// e.g., keys with binary names, are not compared.
+///
+/// ## Usage
+///
+/// ```
+/// use caves::{diff, Cave, MemoryCave};
+///
+/// let a = MemoryCave::new();
+/// let b = MemoryCave::new();
+/// a.set("key1", b"value").unwrap();
+/// b.set("key1", b"value").unwrap();
+/// assert!(diff(&a, &b).unwrap().is_empty());
+///
+/// b.set("key2", b"value").unwrap();
+/// assert_eq!(diff(&a, &b).unwrap().only_in_b, vec!["key2"]);
+/// ```
+pub fn diff(a: &dyn Cave, b: &dyn Cave) -> Result<CaveDiff, Error> {
+    let mut diff = CaveDiff::default();
+    a.for_each(&mut |name, data| {
+        match b.get(name) {
+            Ok(other) if other != data => diff.differing.push(name.to_string()),
+            Ok(_) => (),
+            Err(Error::NotFound(_)) => diff.only_in_a.push(name.to_string()),
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    })?;
+
+    let keys = b.keys()?;
+    for names in keys.chunks(EXISTS_BATCH_SIZE) {
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        for (name, exists) in names.iter().zip(a.exists_many(&names)?) {
+            if !exists {
+                diff.only_in_b.push(name.to_string());
+            }
+        }
+    }
+
+    diff.only_in_a.sort_unstable();
+    diff.only_in_b.sort_unstable();
+    diff.differing.sort_unstable();
+    Ok(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::res::empty_ok;
+    use crate::{FileCave, MemoryCave};
+
+    #[test]
+    fn test_diff() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let a = MemoryCave::new();
+        let b = FileCave::new(temp_dir.path()).unwrap();
+        assert_eq!(diff(&a, &b), Ok(CaveDiff::default()));
+
+        for i in 0..EXISTS_BATCH_SIZE * 2 {
+            let name = format!("key{}", i);
+            assert_eq!(a.set(&name, name.as_bytes()), empty_ok());
+            assert_eq!(b.set(&name, name.as_bytes()), empty_ok());
+        }
+        assert!(diff(&a, &b).unwrap().is_empty());
+
+        assert_eq!(a.set("a2", b"value"), empty_ok());
+        assert_eq!(a.set("a1", b"value"), empty_ok());
+        assert_eq!(b.set("b1", b""), empty_ok());
+        assert_eq!(b.set("key7", b"value"), empty_ok());
+        assert_eq!(a.set("key3", b""), empty_ok());
+        let expected = CaveDiff {
+            only_in_a: vec!["a1".to_string(), "a2".to_string()],
+            only_in_b: vec!["b1".to_string()],
+            differing: vec!["key3".to_string(), "key7".to_string()],
+        };
+        assert_eq!(diff(&a, &b), Ok(expected.clone()));
+        assert!(!expected.is_empty());
+
+        // The diff should be symmetric.
+        let reversed = diff(&b, &a).unwrap();
+        assert_eq!(reversed.only_in_a, expected.only_in_b);
+        assert_eq!(reversed.only_in_b, expected.only_in_a);
+        assert_eq!(reversed.differing, expected.differing);
+    }
+}
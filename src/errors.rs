@@ -5,6 +5,8 @@
 //! a helpful display message, to make error handling easier for users of this
 //! library.
 
+use std::io;
+
 use anyhow;
 use thiserror;
 
@@ -13,12 +15,22 @@ use thiserror;
 /// Each enum variant should apply to a different error that `caves` may
 /// encounter. Every variant has its own error message, which gives the
 /// context for the error.
+///
+/// New variants may be added in the future, same as with [`ErrorKind`], so
+/// matches on this enum need a wildcard arm.
+///
+/// [`ErrorKind`]: enum.ErrorKind.html
 #[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
     /// The key was not found.
     #[error("Key with name `{0}` was not found")]
     NotFound(String),
 
+    /// The key already exists.
+    #[error("Key with name `{0}` already exists")]
+    AlreadyExists(String),
+
     // FIXME: Should I add more context for the error here?
     /// An internal error occurred.
     ///
@@ -27,12 +39,96 @@ pub enum Error {
     #[error("An internal error occurred: {0}")]
     Internal(anyhow::Error),
 
+    /// The provided key name or prefix is not valid.
+    #[error("Invalid key name `{name}`: {reason}")]
+    InvalidName {
+        /// The offending name.
+        name: String,
+        /// Why the name is not valid.
+        reason: &'static str,
+    },
+
+    /// The value is larger than the maximum size that the kv accepts.
+    #[error("Value of {size} bytes exceeds the maximum size of {limit} bytes")]
+    ValueTooLarge {
+        /// The size of the value, in bytes.
+        size: usize,
+        /// The maximum size of a value, in bytes.
+        limit: usize,
+    },
+
+    /// The operation is not supported by this kv.
+    #[error("Operation `{operation}` is not supported")]
+    Unsupported {
+        /// The name of the unsupported operation.
+        operation: &'static str,
+    },
+
+    /// The write would exceed a quota of the kv.
+    #[error("The write exceeds the quota of {limit} {resource}")]
+    QuotaExceeded {
+        /// What the quota limits, e.g., `bytes` or `keys`.
+        resource: &'static str,
+        /// The quota.
+        limit: u64,
+    },
+
+    /// The kv cannot accept more work at the moment, e.g., because too many
+    /// writes are pending. The operation may be retried later.
+    #[error("The kv is busy with {limit} pending operations")]
+    Busy {
+        /// The maximum number of pending operations.
+        limit: usize,
+    },
+
     // FIXME: Should I add more context for the error here?
     /// An unexpected error occurred. This must be a bug on our side.
     #[error("An unexpected error occurred: {0}")]
     Bug(anyhow::Error),
 }
 
+/// The kind of an [`Error`].
+///
+/// Each variant of this enum corresponds to a variant of [`Error`], without
+/// the context that the latter carries. Users can match on it to handle
+/// errors programmatically, e.g., retry on internal errors, instead of
+/// inspecting the error messages.
+///
+/// New variants may be added in the future, as `caves` learns to report more
+/// problems.
+///
+/// [`Error`]: enum.Error.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The key was not found.
+    NotFound,
+
+    /// The key already exists.
+    AlreadyExists,
+
+    /// An internal error occurred.
+    Internal,
+
+    /// The provided key name or prefix is not valid.
+    InvalidName,
+
+    /// The value is larger than the maximum size that the kv accepts.
+    ValueTooLarge,
+
+    /// The operation is not supported.
+    Unsupported,
+
+    /// The write would exceed a quota of the kv.
+    QuotaExceeded,
+
+    /// The kv cannot accept more work at the moment.
+    Busy,
+
+    /// An unexpected error occurred.
+    Bug,
+}
+
 // FIXME: It's ugly to define all of our errors here.
 impl PartialEq for Error {
     fn eq(&self, other: &Error) -> bool {
@@ -40,15 +136,134 @@ impl PartialEq for Error {
             (Error::Bug(_), Error::Bug(_)) => true,
             (Error::Internal(_), Error::Internal(_)) => true,
             (Error::NotFound(s1), Error::NotFound(s2)) => s1 == s2,
+            (Error::AlreadyExists(s1), Error::AlreadyExists(s2)) => s1 == s2,
+            (Error::InvalidName { name: n1, .. }, Error::InvalidName { name: n2, .. }) => n1 == n2,
+            (
+                Error::ValueTooLarge {
+                    size: s1,
+                    limit: l1,
+                },
+                Error::ValueTooLarge {
+                    size: s2,
+                    limit: l2,
+                },
+            ) => s1 == s2 && l1 == l2,
+            (Error::Unsupported { operation: o1 }, Error::Unsupported { operation: o2 }) => {
+                o1 == o2
+            }
+            (
+                Error::QuotaExceeded {
+                    resource: r1,
+                    limit: l1,
+                },
+                Error::QuotaExceeded {
+                    resource: r2,
+                    limit: l2,
+                },
+            ) => r1 == r2 && l1 == l2,
+            (Error::Busy { limit: l1 }, Error::Busy { limit: l2 }) => l1 == l2,
             _ => false,
         }
     }
 }
 
 impl Error {
+    /// Return the kind of this error.
+    ///
+    /// ## Usage
+    ///
+    /// ```
+    /// use caves::errors::ErrorKind;
+    /// use caves::{Cave, MemoryCave};
+    ///
+    /// let b = MemoryCave::new();
+    /// match b.get("key") {
+    ///     Err(e) if e.kind() == ErrorKind::NotFound => (),
+    ///     _ => panic!("The key should not exist"),
+    /// }
+    /// ```
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::NotFound(_) => ErrorKind::NotFound,
+            Error::AlreadyExists(_) => ErrorKind::AlreadyExists,
+            Error::Internal(_) => ErrorKind::Internal,
+            Error::InvalidName { .. } => ErrorKind::InvalidName,
+            Error::ValueTooLarge { .. } => ErrorKind::ValueTooLarge,
+            Error::Unsupported { .. } => ErrorKind::Unsupported,
+            Error::QuotaExceeded { .. } => ErrorKind::QuotaExceeded,
+            Error::Busy { .. } => ErrorKind::Busy,
+            Error::Bug(_) => ErrorKind::Bug,
+        }
+    }
+
     /// Create an internal error from a string.
     pub fn internal_from_msg(msg: String) -> Self {
         let e = anyhow!(msg);
         Self::Internal(e)
     }
 }
+
+/// Convert an I/O error to a `caves` error.
+///
+/// If the I/O error was created from a `caves` error (see the reverse
+/// conversion), the original error is returned. Else, the I/O error is
+/// treated as an internal error. Note that this includes I/O errors of the
+/// `NotFound` kind, since they don't carry the name of a key.
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        let is_caves_error = match e.get_ref() {
+            Some(inner) => inner.is::<Error>(),
+            None => false,
+        };
+
+        if is_caves_error {
+            // The unwraps are safe, since we have checked the inner error
+            // above.
+            *e.into_inner().unwrap().downcast::<Error>().unwrap()
+        } else {
+            Error::Internal(e.into())
+        }
+    }
+}
+
+/// Convert a `caves` error to an I/O error.
+///
+/// The `NotFound` errors are converted to I/O errors of the same kind, and
+/// the rest to I/O errors of the `Other` kind. The original error is kept as
+/// the inner error, so it can be recovered by the reverse conversion.
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        let kind = match e.kind() {
+            ErrorKind::NotFound => io::ErrorKind::NotFound,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_error_conversion() {
+        // Test that the `NotFound` distinction survives a round-trip.
+        let err = Error::NotFound("test".to_string());
+        let io_err: io::Error = err.into();
+        assert_eq!(io_err.kind(), io::ErrorKind::NotFound);
+        let err: Error = io_err.into();
+        assert_eq!(err, Error::NotFound("test".to_string()));
+
+        let err = Error::internal_from_msg("test".to_string());
+        let io_err: io::Error = err.into();
+        assert_eq!(io_err.kind(), io::ErrorKind::Other);
+        let err: Error = io_err.into();
+        assert_eq!(err.kind(), ErrorKind::Internal);
+
+        // Test that I/O errors that don't come from `caves` are treated as
+        // internal errors.
+        let io_err = io::Error::from(io::ErrorKind::NotFound);
+        let err: Error = io_err.into();
+        assert_eq!(err.kind(), ErrorKind::Internal);
+    }
+}
@@ -0,0 +1,166 @@
+//! Convenience methods
+//!
+//! In this module, we define the helpers that are built purely on the core
+//! methods of a `Cave`, and that no kv needs to implement differently. They
+//! live in a separate trait, so that the `Cave` trait stays small.
+
+use std::collections;
+
+use crate::errors::Error;
+use crate::key_path::{join_key_path, DEFAULT_PATH_SEPARATOR};
+use crate::res::Res;
+use crate::Cave;
+
+/// Convenience methods for every `Cave`.
+///
+/// This trait is implemented for every type that implements [`Cave`],
+/// including `dyn Cave`, so it only needs to be imported. Since it's never
+/// used as a trait object itself, its methods may have type parameters,
+/// unlike the methods of `Cave`.
+///
+/// ## Usage
+///
+/// ```
+/// use caves::{Cave, CaveExt, MemoryCave};
+///
+/// let b = MemoryCave::new();
+/// assert_eq!(b.get_or("key", b"default").unwrap(), b"default");
+///
+/// b.set_path(&["user", "42"], b"value").unwrap();
+/// assert_eq!(b.get("user:42").unwrap(), b"value");
+/// ```
+///
+/// [`Cave`]: trait.Cave.html
+pub trait CaveExt: Cave {
+    /// Get a key by its name, and return its contents, or `None` if it does
+    /// not exist.
+    ///
+    /// Unlike `get`, a missing key is not an error, so the `Err` case is
+    /// reserved for actual failures, e.g., I/O errors.
+    ///
+    /// ## Usage
+    ///
+    /// ```
+    /// use caves::{Cave, CaveExt, MemoryCave};
+    ///
+    /// let b = MemoryCave::new();
+    /// if let Some(data) = b.try_get("key").unwrap() {
+    ///     unreachable!("unexpected contents: {:?}", data);
+    /// }
+    /// ```
+    fn try_get(&self, name: &str) -> Result<Option<Vec<u8>>, Error> {
+        match self.get(name) {
+            Ok(data) => Ok(Some(data)),
+            Err(Error::NotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Get a key by its name, and return its contents, or `default` if it
+    /// does not exist.
+    ///
+    /// Errors other than [`Error::NotFound`] are returned to the caller.
+    ///
+    /// [`Error::NotFound`]: errors/enum.Error.html#variant.NotFound
+    fn get_or(&self, name: &str, default: &[u8]) -> Res {
+        match self.get(name) {
+            Err(Error::NotFound(_)) => Ok(default.to_vec()),
+            res => res,
+        }
+    }
+
+    /// Get a key by its name, and return its contents, or empty contents if
+    /// it does not exist.
+    ///
+    /// Same as [`get_or`], with an empty default.
+    ///
+    /// [`get_or`]: #method.get_or
+    fn get_or_default(&self, name: &str) -> Res {
+        self.get_or(name, &[])
+    }
+
+    /// Create a key with the provided contents, if it does not exist, and
+    /// return whether it was created.
+    ///
+    /// Unlike [`Cave::create`], an existing key is not an error, so this is
+    /// handy for idempotent initialization. The existing key is left intact.
+    ///
+    /// This method is built on `create`, so it's atomic, i.e., only one of
+    /// multiple concurrent calls for the same key returns `true`, for
+    /// `MemoryCave`, `FileCave`, `RocksDBCave`, `LmdbCave` and
+    /// `PostgresCave`, and for the wrappers that forward `create` to them.
+    /// It's **not** atomic for `HttpCave`, `MemcachedCave`, `BlobCave`,
+    /// `CacheCave`, `OverlayCave`, `VersionedCave` and `WriteBehindCave`,
+    /// which use the default `create`, so concurrent calls may all return
+    /// `true`, and the last write wins.
+    ///
+    /// [`Cave::create`]: trait.Cave.html#method.create
+    fn set_if_absent(&self, name: &str, data: &[u8]) -> Result<bool, Error> {
+        match self.create(name, data) {
+            Ok(_) => Ok(true),
+            Err(Error::AlreadyExists(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Get a key whose name consists of multiple parts, e.g., `["user", id,
+    /// "profile"]`.
+    ///
+    /// The parts are joined with the [`DEFAULT_PATH_SEPARATOR`], and any
+    /// separator within them is escaped (see [`join_key_path`]). In order to
+    /// use another separator, join the parts with `join_key_path` and pass
+    /// the name to `get` instead.
+    ///
+    /// [`DEFAULT_PATH_SEPARATOR`]: constant.DEFAULT_PATH_SEPARATOR.html
+    /// [`join_key_path`]: fn.join_key_path.html
+    fn get_path(&self, parts: &[&str]) -> Res {
+        self.get(&join_key_path(parts, DEFAULT_PATH_SEPARATOR))
+    }
+
+    /// Set a key whose name consists of multiple parts.
+    ///
+    /// See [`get_path`] for more info.
+    ///
+    /// [`get_path`]: #method.get_path
+    fn set_path(&self, parts: &[&str], data: &[u8]) -> Res {
+        self.set(&join_key_path(parts, DEFAULT_PATH_SEPARATOR), data)
+    }
+
+    /// Delete a key whose name consists of multiple parts.
+    ///
+    /// See [`get_path`] for more info.
+    ///
+    /// [`get_path`]: #method.get_path
+    fn delete_path(&self, parts: &[&str]) -> Res {
+        self.delete(&join_key_path(parts, DEFAULT_PATH_SEPARATOR))
+    }
+
+    /// Return a histogram of the sizes of the contents of the keys.
+    ///
+    /// The sizes are grouped in buckets whose upper bounds are powers of two,
+    /// and the histogram consists of the upper bound of every non-empty
+    /// bucket and the number of keys in it, in ascending order, e.g.,
+    /// `[(1, 3), (1024, 2)]` for three keys of at most 1 byte and two keys
+    /// between 513 and 1024 bytes.
+    ///
+    /// The sizes are found with [`Cave::stat`], so the contents are not read
+    /// by the kvs that keep track of their size, e.g., `FileCave` reads the
+    /// metadata of every file instead. Keys that are deleted while the
+    /// histogram is computed are skipped.
+    ///
+    /// [`Cave::stat`]: trait.Cave.html#method.stat
+    fn size_histogram(&self) -> Result<Vec<(u64, usize)>, Error> {
+        let mut buckets = collections::BTreeMap::new();
+        for name in self.keys()? {
+            let size = match self.stat(&name) {
+                Ok(md) => md.size,
+                Err(Error::NotFound(_)) => continue,
+                Err(e) => return Err(e),
+            };
+            *buckets.entry(size.next_power_of_two()).or_insert(0) += 1;
+        }
+        Ok(buckets.into_iter().collect())
+    }
+}
+
+impl<C: Cave + ?Sized> CaveExt for C {}
@@ -0,0 +1,429 @@
+//! Fault injection
+//!
+//! In this module, we define a wrapper that makes any `Cave` fail on demand,
+//! so that users can test how their code handles errors without having to
+//! cause real I/O failures.
+
+use std::collections;
+use std::sync;
+
+use rand::{Rng, SeedableRng};
+
+use crate::errors::Error;
+use crate::res::Res;
+use crate::{Capabilities, Cave, ChangeReceiver, KeyGuard, Metadata, VisitFn};
+
+#[derive(Debug)]
+struct FaultState {
+    remaining: usize,
+    pattern: Option<String>,
+    probability: f64,
+    rng: rand::rngs::StdRng,
+    injected: usize,
+}
+
+/// A wrapper that injects faults in the operations of another `Cave`.
+///
+/// This wrapper forwards every operation to the inner `Cave`, unless it has
+/// been configured to fail it. It can be configured to fail:
+///
+/// * The next N operations, with [`fail_next`].
+/// * The operations on keys whose name contains a pattern, with
+///   [`fail_matching`].
+/// * Any operation with a given probability, with [`fail_with_probability`].
+///
+/// A failed operation returns an [`Error::Internal`] error and does not reach
+/// the inner `Cave`. All the options can be changed while the wrapper is in
+/// use, from any thread.
+///
+/// This wrapper is available with the `test-util` feature.
+///
+/// ## Usage
+///
+/// ```
+/// use caves::{Cave, FaultInjectingCave, MemoryCave};
+///
+/// let b = FaultInjectingCave::new(MemoryCave::new());
+///
+/// // Fail the next operation.
+/// b.fail_next(1);
+/// assert!(b.set("key", b"value").is_err());
+///
+/// // Subsequent operations should succeed.
+/// assert!(b.set("key", b"value").is_ok());
+/// ```
+///
+/// [`fail_next`]: #method.fail_next
+/// [`fail_matching`]: #method.fail_matching
+/// [`fail_with_probability`]: #method.fail_with_probability
+/// [`Error::Internal`]: errors/enum.Error.html#variant.Internal
+#[derive(Debug)]
+pub struct FaultInjectingCave<C: Cave> {
+    inner: C,
+    state: sync::Mutex<FaultState>,
+}
+
+impl<C: Cave> FaultInjectingCave<C> {
+    /// Create a new instance that wraps the provided `Cave`.
+    ///
+    /// The new instance does not inject any faults, until it's configured to
+    /// do so.
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            state: sync::Mutex::new(FaultState {
+                remaining: 0,
+                pattern: None,
+                probability: 0.0,
+                rng: rand::rngs::StdRng::seed_from_u64(0),
+                injected: 0,
+            }),
+        }
+    }
+
+    /// Fail the next `n` operations.
+    ///
+    /// This overrides any previous call to this method.
+    pub fn fail_next(&self, n: usize) {
+        self.state.lock().unwrap().remaining = n;
+    }
+
+    /// Fail every operation on a key whose name contains `pattern`.
+    ///
+    /// Operations on two keys, e.g., `swap`, fail if either name matches.
+    /// This overrides any previous call to this method. Note that `keys()`
+    /// calls are not affected by this option, since they don't refer to a
+    /// specific key.
+    pub fn fail_matching(&self, pattern: &str) {
+        self.state.lock().unwrap().pattern = Some(pattern.to_string());
+    }
+
+    /// Fail every operation with the given probability.
+    ///
+    /// The probability must be between 0.0 and 1.0, or else this method
+    /// panics. The faults are chosen by a pseudo-random generator that is
+    /// initialized with `seed`, so that the same sequence of operations
+    /// always fails in the same way.
+    pub fn fail_with_probability(&self, probability: f64, seed: u64) {
+        assert!(
+            (0.0..=1.0).contains(&probability),
+            "The `probability` must be between 0.0 and 1.0, not {}",
+            probability
+        );
+        let mut state = self.state.lock().unwrap();
+        state.probability = probability;
+        state.rng = rand::rngs::StdRng::seed_from_u64(seed);
+    }
+
+    /// Stop injecting faults.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.remaining = 0;
+        state.pattern = None;
+        state.probability = 0.0;
+    }
+
+    /// Return the number of faults that have been injected so far.
+    pub fn injected(&self) -> usize {
+        self.state.lock().unwrap().injected
+    }
+
+    /// Return a reference to the inner `Cave`.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    fn check(&self, op: &str, name: Option<&str>) -> Result<(), Error> {
+        let names: Vec<&str> = name.into_iter().collect();
+        self.check_names(op, &names)
+    }
+
+    /// Same as `check`, but for operations on multiple keys, which fail if
+    /// any of their names matches the pattern.
+    fn check_names(&self, op: &str, names: &[&str]) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+
+        let mut fail = false;
+        if state.remaining > 0 {
+            state.remaining -= 1;
+            fail = true;
+        }
+        // Report the name that matches the pattern, if any.
+        let mut name = names.first().copied();
+        if let Some(pattern) = &state.pattern {
+            if let Some(matching) = names.iter().find(|name| name.contains(pattern.as_str())) {
+                name = Some(matching);
+                fail = true;
+            }
+        }
+        if state.probability > 0.0 {
+            let p = state.probability;
+            fail |= state.rng.gen_bool(p);
+        }
+
+        if !fail {
+            return Ok(());
+        }
+
+        state.injected += 1;
+        Err(Error::internal_from_msg(match name {
+            Some(name) => format!("Injected fault in `{}` for key `{}`", op, name),
+            None => format!("Injected fault in `{}`", op),
+        }))
+    }
+}
+
+impl<C: Cave> Cave for FaultInjectingCave<C> {
+    fn get(&self, name: &str) -> Res {
+        self.check("get", Some(name))?;
+        self.inner.get(name)
+    }
+
+    fn set(&self, name: &str, data: &[u8]) -> Res {
+        self.check("set", Some(name))?;
+        self.inner.set(name, data)
+    }
+
+    fn delete(&self, name: &str) -> Res {
+        self.check("delete", Some(name))?;
+        self.inner.delete(name)
+    }
+
+    fn get_bytes(&self, name: &[u8]) -> Res {
+        self.check("get_bytes", Some(&String::from_utf8_lossy(name)))?;
+        self.inner.get_bytes(name)
+    }
+
+    fn set_bytes(&self, name: &[u8], data: &[u8]) -> Res {
+        self.check("set_bytes", Some(&String::from_utf8_lossy(name)))?;
+        self.inner.set_bytes(name, data)
+    }
+
+    fn delete_bytes(&self, name: &[u8]) -> Res {
+        self.check("delete_bytes", Some(&String::from_utf8_lossy(name)))?;
+        self.inner.delete_bytes(name)
+    }
+
+    fn exists_many(&self, names: &[&str]) -> Result<Vec<bool>, Error> {
+        self.check_names("exists_many", names)?;
+        self.inner.exists_many(names)
+    }
+
+    fn stat(&self, name: &str) -> Result<Metadata, Error> {
+        self.check("stat", Some(name))?;
+        self.inner.stat(name)
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        self.check("keys", None)?;
+        self.inner.keys()
+    }
+
+    fn take(&self, name: &str) -> Res {
+        self.check("take", Some(name))?;
+        self.inner.take(name)
+    }
+
+    fn update_with(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(Option<Vec<u8>>) -> Option<Vec<u8>>,
+    ) -> Res {
+        self.check("update_with", Some(name))?;
+        self.inner.update_with(name, f)
+    }
+
+    fn create(&self, name: &str, data: &[u8]) -> Res {
+        self.check("create", Some(name))?;
+        self.inner.create(name, data)
+    }
+
+    fn delete_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        self.check("delete_prefix", Some(prefix))?;
+        self.inner.delete_prefix(prefix)
+    }
+
+    fn rename_prefix(&self, old: &str, new: &str) -> Result<usize, Error> {
+        self.check_names("rename_prefix", &[old, new])?;
+        self.inner.rename_prefix(old, new)
+    }
+
+    fn watch(&self, name: &str) -> Result<ChangeReceiver, Error> {
+        self.check("watch", Some(name))?;
+        self.inner.watch(name)
+    }
+
+    fn watch_prefix(&self, prefix: &str) -> Result<ChangeReceiver, Error> {
+        self.check("watch_prefix", Some(prefix))?;
+        self.inner.watch_prefix(prefix)
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn Cave + '_>, Error> {
+        self.check("snapshot", None)?;
+        self.inner.snapshot()
+    }
+
+    fn first_key(&self) -> Result<Option<String>, Error> {
+        self.check("first_key", None)?;
+        self.inner.first_key()
+    }
+
+    fn last_key(&self) -> Result<Option<String>, Error> {
+        self.check("last_key", None)?;
+        self.inner.last_key()
+    }
+
+    fn range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        self.check("range", None)?;
+        self.inner.range(start, end)
+    }
+
+    fn keys_sorted(&self) -> Result<Vec<String>, Error> {
+        self.check("keys_sorted", None)?;
+        self.inner.keys_sorted()
+    }
+
+    fn for_each_sorted(&self, f: &mut VisitFn<'_>) -> Result<(), Error> {
+        self.check("for_each_sorted", None)?;
+        self.inner.for_each_sorted(f)
+    }
+
+    fn for_each(&self, f: &mut VisitFn<'_>) -> Result<(), Error> {
+        self.check("for_each", None)?;
+        self.inner.for_each(f)
+    }
+
+    fn count_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        self.check("count_prefix", Some(prefix))?;
+        self.inner.count_prefix(prefix)
+    }
+
+    fn swap(&self, a: &str, b: &str) -> Res {
+        self.check_names("swap", &[a, b])?;
+        self.inner.swap(a, b)
+    }
+
+    fn dump(&self) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+        self.check("dump", None)?;
+        self.inner.dump()
+    }
+
+    fn flush(&self) -> Res {
+        self.check("flush", None)?;
+        self.inner.flush()
+    }
+
+    fn maintain(&self) -> Res {
+        self.check("maintain", None)?;
+        self.inner.maintain()
+    }
+
+    fn disk_usage(&self) -> Result<u64, Error> {
+        self.check("disk_usage", None)?;
+        self.inner.disk_usage()
+    }
+
+    fn lock(&self, name: &str) -> Result<KeyGuard<'_>, Error> {
+        self.check("lock", Some(name))?;
+        self.inner.lock(name)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::res::empty_ok;
+    use crate::{CaveExt, MemoryCave};
+
+    #[test]
+    fn test_fail_next() {
+        let b = FaultInjectingCave::new(MemoryCave::new());
+        let internal_err = Err(Error::Internal(anyhow!("")));
+
+        b.fail_next(2);
+        assert_eq!(b.set("test", b"value"), internal_err);
+        assert!(b.keys().is_err());
+        assert_eq!(b.set("test", b"value"), empty_ok());
+        assert_eq!(b.get("test"), Ok(b"value".to_vec()));
+        assert_eq!(b.injected(), 2);
+
+        // The failed operations should not reach the inner cave.
+        b.fail_next(1);
+        assert_eq!(b.delete("test"), internal_err);
+        assert_eq!(b.inner().get("test"), Ok(b"value".to_vec()));
+
+        b.fail_next(1);
+        assert_eq!(b.lock("test").err(), Some(Error::Internal(anyhow!(""))));
+        assert!(b.lock("test").is_ok());
+    }
+
+    #[test]
+    fn test_fail_matching() {
+        let b = FaultInjectingCave::new(MemoryCave::new());
+        let internal_err = Err(Error::Internal(anyhow!("")));
+
+        b.fail_matching("bad");
+        assert_eq!(b.set("good_key", b"value"), empty_ok());
+        assert_eq!(b.set("bad_key", b"value"), internal_err);
+        assert_eq!(b.get("bad_key"), internal_err);
+        assert_eq!(b.keys().unwrap(), vec!["good_key"]);
+
+        // Only missing keys should fall back to the default.
+        assert_eq!(b.get_or("bad_key", b"default"), internal_err);
+        assert_eq!(b.try_get("bad_key"), Err(Error::Internal(anyhow!(""))));
+
+        // Operations on two keys should fail if either name matches.
+        let err = b.swap("good_key", "bad_key").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "An internal error occurred: Injected fault in `swap` for key `bad_key`"
+        );
+        assert_eq!(
+            b.rename_prefix("good", "bad"),
+            Err(Error::Internal(anyhow!("")))
+        );
+        assert_eq!(b.exists_many(&["good_key"]), Ok(vec![true]));
+        assert_eq!(
+            b.exists_many(&["good_key", "bad_key"]),
+            Err(Error::Internal(anyhow!("")))
+        );
+
+        b.clear();
+        assert_eq!(b.set("bad_key", b"value"), empty_ok());
+        assert_eq!(b.injected(), 7);
+    }
+
+    #[test]
+    fn test_fail_with_probability() {
+        let run = |seed| {
+            let b = FaultInjectingCave::new(MemoryCave::new());
+            b.fail_with_probability(0.5, seed);
+            (0..100)
+                .map(|_| b.set("test", b"value").is_ok())
+                .collect::<Vec<_>>()
+        };
+
+        // The same seed should produce the same faults.
+        let results = run(42);
+        assert_eq!(results, run(42));
+        let failures = results.iter().filter(|ok| !**ok).count();
+        assert!(failures > 0 && failures < 100);
+
+        // A probability of 1.0 should fail every operation.
+        let b = FaultInjectingCave::new(MemoryCave::new());
+        b.fail_with_probability(1.0, 0);
+        assert!((0..10).all(|_| b.get("test").is_err()));
+    }
+
+    #[test]
+    #[should_panic(expected = "The `probability` must be between 0.0 and 1.0")]
+    fn test_fail_with_invalid_probability() {
+        let b = FaultInjectingCave::new(MemoryCave::new());
+        b.fail_with_probability(1.5, 0);
+    }
+}
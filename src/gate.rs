@@ -0,0 +1,143 @@
+//! Operation gates
+//!
+//! In this module, we define a gate that operations pass through, and that
+//! can be closed in order to change the state that they rely on, once the
+//! operations in progress have finished.
+
+use std::cell;
+use std::sync;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The source of the unique IDs of the gates.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The IDs of the gates that the current thread is in, once for every
+    /// operation, since operations may call other operations.
+    static ENTERED: cell::RefCell<Vec<usize>> = const { cell::RefCell::new(Vec::new()) };
+}
+
+/// Return whether the current thread is in a gate.
+fn has_entered(id: usize) -> bool {
+    ENTERED.with(|entered| entered.borrow().contains(&id))
+}
+
+/// A gate that lets operations through, unless it's closed.
+///
+/// Operations hold a read lock, and closing the gate takes the write lock.
+/// Unlike a plain read-write lock, an operation can enter the gate again from
+/// the same thread, e.g., when it calls another operation, even if someone
+/// waits to close it.
+#[derive(Debug)]
+pub(crate) struct Gate {
+    id: usize,
+    lock: sync::RwLock<()>,
+}
+
+impl Default for Gate {
+    fn default() -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            lock: sync::RwLock::new(()),
+        }
+    }
+}
+
+impl Gate {
+    /// Enter the gate, and block while it's closed, unless the current thread
+    /// is already in it. The operation exits the gate when the guard is
+    /// dropped.
+    pub(crate) fn enter(&self) -> GateGuard<'_> {
+        // The gate can't close before the outer operation exits, so a nested
+        // one must not wait for it.
+        let read = if has_entered(self.id) {
+            None
+        } else {
+            Some(self.lock.read().unwrap())
+        };
+        ENTERED.with(|entered| entered.borrow_mut().push(self.id));
+        GateGuard {
+            id: self.id,
+            _read: read,
+        }
+    }
+
+    /// Close the gate, once the operations in progress have exited, and keep
+    /// it closed until the returned guard is dropped.
+    ///
+    /// If the current thread is in the gate, return `None`, since it would
+    /// wait for itself.
+    pub(crate) fn close(&self) -> Option<ClosedGate<'_>> {
+        if has_entered(self.id) {
+            return None;
+        }
+        Some(ClosedGate {
+            _write: self.lock.write().unwrap(),
+        })
+    }
+}
+
+/// A guard for an operation that is in the gate.
+#[derive(Debug)]
+pub(crate) struct GateGuard<'a> {
+    id: usize,
+    /// The read lock of the gate, which only the outer operation of a thread
+    /// holds.
+    _read: Option<sync::RwLockReadGuard<'a, ()>>,
+}
+
+impl Drop for GateGuard<'_> {
+    fn drop(&mut self) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(pos) = entered.iter().rposition(|&id| id == self.id) {
+                let _ = entered.remove(pos);
+            }
+        });
+    }
+}
+
+/// A guard that keeps the gate closed.
+#[derive(Debug)]
+pub(crate) struct ClosedGate<'a> {
+    _write: sync::RwLockWriteGuard<'a, ()>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+    use std::time;
+
+    #[test]
+    fn test_gate() {
+        let gate = Gate::default();
+        let outer = gate.enter();
+        assert!(gate.close().is_none());
+
+        // Another gate should not be affected.
+        let other = Gate::default();
+        drop(other.close().unwrap());
+
+        thread::scope(|s| {
+            let closer = s.spawn(|| {
+                let _closed = gate.close().unwrap();
+                // The operations in progress should have exited by now.
+                assert!(gate.lock.try_read().is_err());
+            });
+
+            // Give the other thread some time to try to close the gate.
+            thread::sleep(time::Duration::from_millis(50));
+            // A nested operation should still go through.
+            drop(gate.enter());
+            drop(outer);
+            closer.join().unwrap();
+        });
+
+        // The gate should be open again.
+        drop(gate.enter());
+        assert!(!has_entered(gate.id));
+        assert!(gate.close().is_some());
+    }
+}
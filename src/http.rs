@@ -0,0 +1,235 @@
+//! HTTP transport
+//!
+//! In this module, we define a `Cave` that stores keys in a remote `caves`
+//! server, over HTTP, as well as the helpers that both the client and the
+//! server use to encode keys in URLs.
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+#[cfg(feature = "with-http-client")]
+use reqwest::blocking;
+#[cfg(feature = "with-http-client")]
+use reqwest::StatusCode;
+
+use crate::errors::Error;
+#[cfg(feature = "with-http-client")]
+use crate::res::{empty_ok, Res};
+#[cfg(feature = "with-http-client")]
+use crate::Cave;
+
+/// The characters that must be percent-encoded in a key name.
+///
+/// These are all the characters except for the unreserved ones of [RFC
+/// 3986], so that a key name always maps to a single path segment. The only
+/// exception is the underscore, which is reserved for the special paths of
+/// the server, such as [`KEYS_PATH`], so that they never clash with a key.
+///
+/// [RFC 3986]: https://tools.ietf.org/html/rfc3986#section-2.3
+const KEY_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'~');
+
+/// The path under which the server lists its keys.
+///
+/// The server must compare it against the path of a request before decoding
+/// it, since only the encoded names are guaranteed to be distinct.
+pub(crate) const KEYS_PATH: &str = "_keys";
+
+/// Percent-encode a key name, so that it can be used as a path segment.
+pub(crate) fn encode_key(name: &str) -> String {
+    utf8_percent_encode(name, KEY_ENCODE_SET).to_string()
+}
+
+/// Decode a percent-encoded key name.
+///
+/// If the decoded name is not valid UTF-8, return an internal error.
+pub(crate) fn decode_key(encoded: &str) -> Result<String, Error> {
+    match percent_decode_str(encoded).decode_utf8() {
+        Ok(name) => Ok(name.into_owned()),
+        Err(e) => Err(Error::Internal(e.into())),
+    }
+}
+
+/// A key-value store that stores keys in a remote server, over HTTP.
+///
+/// This kv maps each operation to an HTTP request on `{base_url}/{name}`,
+/// where the name is percent-encoded:
+///
+/// * `get` sends a `GET` request and returns the response body.
+/// * `set` sends a `PUT` request with the contents as the request body.
+/// * `delete` sends a `DELETE` request.
+/// * `keys` sends a `GET` request on `{base_url}/_keys`, and expects a list of
+///   percent-encoded names, each terminated by a newline.
+///
+/// A `404 Not Found` response is treated as an [`Error::NotFound`] error, and
+/// any other non-2xx response as an [`Error::Internal`] error.
+///
+/// This kv is available with the `with-http-client` feature.
+///
+/// ## Caveats
+///
+/// This kv has the following caveats:
+///
+/// * Each operation requires a round-trip to the server, so its latency is
+///   much higher than that of the embedded kvs.
+/// * The `.` and `..` names are not allowed, since HTTP clients treat them as
+///   relative path segments, even when they are percent-encoded.
+///
+/// [`Error::NotFound`]: errors/enum.Error.html#variant.NotFound
+/// [`Error::Internal`]: errors/enum.Error.html#variant.Internal
+#[cfg(feature = "with-http-client")]
+#[derive(Debug)]
+pub struct HttpCave {
+    base_url: String,
+    client: blocking::Client,
+}
+
+#[cfg(feature = "with-http-client")]
+impl HttpCave {
+    /// Create a new instance that talks to the server at `base_url`.
+    ///
+    /// The instance uses an HTTP client with the default options, which is
+    /// reused across requests.
+    pub fn new(base_url: &str) -> Result<Self, Error> {
+        match blocking::Client::builder().build() {
+            Ok(client) => Ok(Self::with_client(base_url, client)),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    /// Create a new instance that talks to the server at `base_url`, with a
+    /// custom HTTP client.
+    ///
+    /// Use this constructor if you want to configure the client, e.g., set
+    /// its timeouts.
+    pub fn with_client(base_url: &str, client: blocking::Client) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client,
+        }
+    }
+
+    fn create_url(&self, name: &str) -> Result<String, Error> {
+        if name == "." || name == ".." {
+            return Err(Error::internal_from_msg(format!(
+                "Key name `{}` cannot be used over HTTP",
+                name
+            )));
+        }
+        Ok(format!("{}/{}", self.base_url, encode_key(name)))
+    }
+
+    fn send(&self, req: blocking::RequestBuilder, name: &str) -> Res {
+        let resp = match req.send() {
+            Ok(resp) => resp,
+            Err(e) => return Err(Error::Internal(e.into())),
+        };
+
+        let status = resp.status();
+        if status == StatusCode::NOT_FOUND {
+            return self.not_found(name);
+        } else if !status.is_success() {
+            return Err(Error::internal_from_msg(format!(
+                "Server responded with status {} for key `{}`",
+                status, name
+            )));
+        }
+
+        match resp.bytes() {
+            Ok(buf) => Ok(buf.to_vec()),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+}
+
+#[cfg(feature = "with-http-client")]
+impl Cave for HttpCave {
+    fn get(&self, name: &str) -> Res {
+        let req = self.client.get(self.create_url(name)?);
+        self.send(req, name)
+    }
+
+    fn set(&self, name: &str, data: &[u8]) -> Res {
+        let req = self.client.put(self.create_url(name)?).body(data.to_vec());
+        let _ = self.send(req, name)?;
+        empty_ok()
+    }
+
+    fn delete(&self, name: &str) -> Res {
+        let req = self.client.delete(self.create_url(name)?);
+        let _ = self.send(req, name)?;
+        empty_ok()
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        // The path must not be encoded, or else it will be treated as a key.
+        let req = self.client.get(format!("{}/{}", self.base_url, KEYS_PATH));
+        let buf = self.send(req, KEYS_PATH)?;
+        let text = match String::from_utf8(buf) {
+            Ok(text) => text,
+            Err(e) => return Err(Error::Internal(e.into())),
+        };
+
+        // Every name is terminated by a newline, so that empty names are
+        // preserved.
+        text.split_terminator('\n').map(decode_key).collect()
+    }
+}
+
+#[cfg(all(test, feature = "with-http-client"))]
+mod tests {
+    use super::*;
+
+    use std::net;
+
+    #[test]
+    fn test_key_encoding() {
+        let names = [
+            "key",
+            "",
+            " ",
+            "a/b c?d#e",
+            "%20",
+            "+&=;",
+            "ünïcödé",
+            "...",
+            "line\nbreak",
+            KEYS_PATH,
+        ];
+        for name in names.iter() {
+            let encoded = encode_key(name);
+            assert!(!encoded.contains('/'));
+            assert_ne!(encoded, KEYS_PATH);
+            assert_eq!(decode_key(&encoded), Ok(name.to_string()));
+        }
+
+        assert_eq!(encode_key("a/b c"), "a%2Fb%20c");
+        assert_eq!(encode_key("_keys"), "%5Fkeys");
+        assert_eq!(encode_key("ü"), "%C3%BC");
+        // Invalid UTF-8 sequences should be rejected.
+        assert!(decode_key("%FF").is_err());
+    }
+
+    #[test]
+    fn test_http_backend_urls() {
+        let hb = HttpCave::new("http://localhost:8000/caves/").unwrap();
+        let res = hb.create_url("key");
+        assert_eq!(res.unwrap(), "http://localhost:8000/caves/key");
+        let res = hb.create_url("a/b c?d");
+        assert_eq!(res.unwrap(), "http://localhost:8000/caves/a%2Fb%20c%3Fd");
+        assert!(hb.create_url(".").is_err());
+        assert!(hb.create_url("..").is_err());
+    }
+
+    #[test]
+    fn test_http_backend_errors() {
+        // Find a port that nobody listens to.
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let internal_err = Err(Error::Internal(anyhow!("")));
+        let hb = HttpCave::new(&format!("http://{}", addr)).unwrap();
+        assert_eq!(hb.get("test"), internal_err);
+        assert_eq!(hb.set("test", b"value"), internal_err);
+        assert_eq!(hb.delete("test"), internal_err);
+        assert!(hb.keys().is_err());
+    }
+}
@@ -0,0 +1,163 @@
+//! Imports
+//!
+//! In this module, we define helpers that load existing data into a `Cave`.
+
+use std::fs;
+use std::path;
+
+use crate::errors::Error;
+use crate::Cave;
+
+/// Import the files of a directory into a `Cave`, and return their number.
+///
+/// Each regular file in the directory is stored as a key, with the same name
+/// and contents. This is essentially the inverse of the [`FileCave`] layout.
+/// The files are read one by one, so they don't have to fit in memory at
+/// the same time. Subdirectories, symbolic links and other special files are
+/// skipped.
+///
+/// The import is aborted on the first error. If you want to continue on
+/// errors, use [`import_dir_with`] instead.
+///
+/// [`FileCave`]: struct.FileCave.html
+/// [`import_dir_with`]: fn.import_dir_with.html
+pub fn import_dir(cave: &dyn Cave, dir: &path::Path) -> Result<usize, Error> {
+    import_dir_with(cave, dir, &mut |_, e| Err(e))
+}
+
+/// Import the files of a directory into a `Cave`, and handle errors per file.
+///
+/// This function works like [`import_dir`], except that when a file cannot be
+/// imported, it calls `on_error` with the path of the file and the error. If
+/// `on_error` returns an error, the import is aborted with it. Else, the file
+/// is skipped and the import continues with the next one.
+///
+/// The returned number does not include the skipped files.
+///
+/// ## Usage
+///
+/// ```no_run
+/// use std::path::Path;
+/// use caves::{import_dir_with, MemoryCave};
+///
+/// let b = MemoryCave::new();
+/// let mut failed = Vec::new();
+/// let res = import_dir_with(&b, Path::new("/data"), &mut |path, e| {
+///     failed.push((path.to_owned(), e));
+///     Ok(())
+/// });
+/// ```
+///
+/// [`import_dir`]: fn.import_dir.html
+pub fn import_dir_with(
+    cave: &dyn Cave,
+    dir: &path::Path,
+    on_error: &mut dyn FnMut(&path::Path, Error) -> Result<(), Error>,
+) -> Result<usize, Error> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => return Err(Error::Internal(e.into())),
+    };
+
+    let mut count = 0;
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                on_error(dir, Error::Internal(e.into()))?;
+                continue;
+            }
+        };
+
+        let path = entry.path();
+        match import_file(cave, &entry) {
+            Ok(true) => count += 1,
+            Ok(false) => (),
+            Err(e) => on_error(&path, e)?,
+        }
+    }
+    Ok(count)
+}
+
+/// Import a directory entry, and return whether it was a regular file.
+fn import_file(cave: &dyn Cave, entry: &fs::DirEntry) -> Result<bool, Error> {
+    match entry.file_type() {
+        Ok(ft) if ft.is_file() => (),
+        Ok(_) => return Ok(false),
+        Err(e) => return Err(Error::Internal(e.into())),
+    }
+
+    let name = match entry.file_name().into_string() {
+        Ok(name) => name,
+        Err(name) => {
+            return Err(Error::internal_from_msg(format!(
+                "File name is not valid UTF-8: {:?}",
+                name
+            )))
+        }
+    };
+
+    let data = match fs::read(entry.path()) {
+        Ok(data) => data,
+        Err(e) => return Err(Error::Internal(e.into())),
+    };
+
+    let _ = cave.set(&name, &data)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{FileCave, MemoryCave};
+
+    #[test]
+    fn test_import_dir() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        fs::write(temp_dir.path().join("key1"), b"value1").unwrap();
+        fs::write(temp_dir.path().join("key2"), b"").unwrap();
+        fs::create_dir(temp_dir.path().join("subdir")).unwrap();
+        fs::write(temp_dir.path().join("subdir").join("key3"), b"").unwrap();
+
+        let b = MemoryCave::new();
+        let res = import_dir(&b, temp_dir.path());
+        assert_eq!(res.unwrap(), 2);
+        assert_eq!(b.get("key1"), Ok(b"value1".to_vec()));
+        assert_eq!(b.get("key2"), Ok(b"".to_vec()));
+        assert_eq!(b.keys().unwrap().len(), 2);
+
+        // Test for non-existent paths.
+        let no_path = temp_dir.path().join("nonexistent");
+        let res = import_dir(&b, &no_path);
+        assert_eq!(res, Err(Error::Internal(anyhow!(""))));
+    }
+
+    #[test]
+    fn test_import_dir_errors() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir(&src_dir).unwrap();
+        fs::write(src_dir.join("key1"), b"value1").unwrap();
+        fs::write(src_dir.join("key2"), b"value2").unwrap();
+
+        // Create a cave whose directory is removed under our feet, so that
+        // every set fails.
+        let dst_dir = temp_dir.path().join("dst");
+        fs::create_dir(&dst_dir).unwrap();
+        let fb = FileCave::new(&dst_dir).unwrap();
+        fs::remove_dir(&dst_dir).unwrap();
+
+        let res = import_dir(&fb, &src_dir);
+        assert_eq!(res, Err(Error::Internal(anyhow!(""))));
+
+        let mut failed = Vec::new();
+        let res = import_dir_with(&fb, &src_dir, &mut |path, _| {
+            failed.push(path.to_owned());
+            Ok(())
+        });
+        assert_eq!(res, Ok(0));
+        failed.sort();
+        assert_eq!(failed, vec![src_dir.join("key1"), src_dir.join("key2")]);
+    }
+}
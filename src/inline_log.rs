@@ -0,0 +1,357 @@
+//! Inline storage of small values
+//!
+//! In this module, we define an append-only log that a `FileCave` uses to
+//! store small values, so that they don't need a file, and an inode, each.
+//!
+//! The log starts with a byte that marks whether it's still in use, followed
+//! by a record for every write. A record consists of its kind (set or
+//! delete), the lengths of the name and the contents of the key as 8-byte
+//! big-endian integers, and then the name and the contents themselves.
+//!
+//! Every process keeps an index of the log in memory, and brings it up to
+//! date with the records that other processes appended, whenever it accesses
+//! the log. The log is locked while it's accessed, so that processes never
+//! read records that are partially written. When the log is compacted, it's
+//! replaced by a new one, and it's marked as stale, so that the processes
+//! that still have it open switch to the new one.
+
+use std::collections;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, Write};
+use std::path;
+use std::sync;
+
+/// The first byte of a log that is in use.
+const LOG_LIVE: u8 = 0;
+/// The first byte of a log that has been replaced by a compacted one.
+const LOG_STALE: u8 = 1;
+
+const RECORD_DELETE: u8 = 0;
+const RECORD_SET: u8 = 1;
+/// The length of the kind of a record and the lengths of its name and its
+/// contents.
+const RECORD_HEADER_LEN: u64 = 17;
+
+#[derive(Default)]
+struct State {
+    /// The log, if it's open.
+    file: Option<fs::File>,
+    /// The offset and the length of the contents of every key in the log.
+    index: collections::HashMap<String, (u64, u64)>,
+    /// The offset up to which the log has been indexed.
+    end: u64,
+}
+
+impl State {
+    fn file(&self) -> &fs::File {
+        self.file.as_ref().unwrap()
+    }
+
+    /// Forget the log, so that it's opened and indexed anew.
+    fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Index the records that were appended to the log since the last time,
+    /// and return `false` if the log has been replaced.
+    ///
+    /// If the last record is incomplete, e.g., because a process crashed
+    /// while appending it, it's discarded, if the log is locked exclusively.
+    fn refresh(&mut self, exclusive: bool) -> io::Result<bool> {
+        let mut file = self.file.as_ref().unwrap();
+        let len = file.metadata()?.len();
+        if len == 0 {
+            if exclusive {
+                file.write_all(&[LOG_LIVE])?;
+                self.end = 1;
+            }
+            return Ok(true);
+        }
+
+        let mut marker = [0; 1];
+        let _ = file.seek(io::SeekFrom::Start(0))?;
+        file.read_exact(&mut marker)?;
+        if marker[0] == LOG_STALE {
+            return Ok(false);
+        }
+
+        let mut pos = self.end.max(1);
+        let _ = file.seek(io::SeekFrom::Start(pos))?;
+        let mut reader = io::BufReader::new(file);
+        while pos + RECORD_HEADER_LEN <= len {
+            let mut header = [0; RECORD_HEADER_LEN as usize];
+            reader.read_exact(&mut header)?;
+            let kind = header[0];
+            let name_len = read_u64(&header[1..9]);
+            let data_len = read_u64(&header[9..17]);
+            let data_pos = match (pos + RECORD_HEADER_LEN).checked_add(name_len) {
+                Some(data_pos) if data_pos <= len && data_len <= len - data_pos => data_pos,
+                _ => break,
+            };
+
+            let mut name = vec![0; name_len as usize];
+            reader.read_exact(&mut name)?;
+            let name = match String::from_utf8(name) {
+                Ok(name) => name,
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            };
+            reader.seek_relative(data_len as i64)?;
+
+            match kind {
+                RECORD_SET => {
+                    let _ = self.index.insert(name, (data_pos, data_len));
+                }
+                RECORD_DELETE => {
+                    let _ = self.index.remove(&name);
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Unknown record kind {} at offset {}", kind, pos),
+                    ))
+                }
+            }
+            pos = data_pos + data_len;
+        }
+
+        self.end = pos;
+        if exclusive && pos < len {
+            self.file().set_len(pos)?;
+        }
+        Ok(true)
+    }
+
+    /// Read the contents of a key from the log.
+    fn read(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        let (pos, len) = match self.index.get(name) {
+            Some(&entry) => entry,
+            None => return Ok(None),
+        };
+        let mut file = self.file();
+        let _ = file.seek(io::SeekFrom::Start(pos))?;
+        let mut data = vec![0; len as usize];
+        file.read_exact(&mut data)?;
+        Ok(Some(data))
+    }
+
+    /// Append a record to the log. The log must be locked exclusively.
+    fn append(&mut self, kind: u8, name: &str, data: &[u8]) -> io::Result<()> {
+        let record = encode_record(kind, name, data);
+        let mut file = self.file.as_ref().unwrap();
+        let _ = file.seek(io::SeekFrom::Start(self.end))?;
+        file.write_all(&record)?;
+        file.sync_data()?;
+
+        let data_pos = self.end + RECORD_HEADER_LEN + name.len() as u64;
+        self.end += record.len() as u64;
+        match kind {
+            RECORD_SET => {
+                let _ = self
+                    .index
+                    .insert(name.to_string(), (data_pos, data.len() as u64));
+            }
+            _ => {
+                let _ = self.index.remove(name);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(buf);
+    u64::from_be_bytes(bytes)
+}
+
+fn encode_record(kind: u8, name: &str, data: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + name.len() + data.len());
+    record.push(kind);
+    record.extend_from_slice(&(name.len() as u64).to_be_bytes());
+    record.extend_from_slice(&(data.len() as u64).to_be_bytes());
+    record.extend_from_slice(name.as_bytes());
+    record.extend_from_slice(data);
+    record
+}
+
+/// An append-only log that stores the values of a `FileCave` that are
+/// smaller than a threshold.
+pub(crate) struct InlineLog {
+    path: path::PathBuf,
+    threshold: usize,
+    state: sync::Mutex<State>,
+}
+
+impl InlineLog {
+    /// Create a log at the provided path, for values smaller than
+    /// `threshold` bytes. The log is created on its first access.
+    pub(crate) fn new(path: path::PathBuf, threshold: usize) -> Self {
+        Self {
+            path,
+            threshold,
+            state: sync::Mutex::new(State::default()),
+        }
+    }
+
+    /// Return the size below which values are stored in the log.
+    pub(crate) fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    /// Return whether a value is small enough to be stored in the log.
+    pub(crate) fn fits(&self, data: &[u8]) -> bool {
+        data.len() < self.threshold
+    }
+
+    /// Call a function with the state of the log, while holding a shared or
+    /// an exclusive lock on it, after bringing its index up to date.
+    fn with_state<T, F>(&self, exclusive: bool, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut State) -> io::Result<T>,
+    {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.file.is_none() {
+                fs::create_dir_all(self.path.parent().unwrap())?;
+                let mut opts = fs::OpenOptions::new();
+                let _ = opts.read(true).write(true).create(true);
+                state.file = Some(opts.open(&self.path)?);
+            }
+
+            let res = if exclusive {
+                state.file().lock()
+            } else {
+                state.file().lock_shared()
+            };
+            match res {
+                Ok(_) => (),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+
+            // Closing the file releases the lock.
+            match state.refresh(exclusive) {
+                Ok(true) => break,
+                Ok(false) => state.reset(),
+                Err(e) => {
+                    state.reset();
+                    return Err(e);
+                }
+            }
+        }
+
+        let res = f(&mut state);
+        // The function may have replaced the log.
+        if let Some(file) = &state.file {
+            let _ = file.unlock();
+        }
+        // Index the log anew, in case it was modified partially.
+        if res.is_err() {
+            state.reset();
+        }
+        res
+    }
+
+    /// Return the contents of a key, or `None` if it's not in the log.
+    pub(crate) fn get(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        self.with_state(false, |state| state.read(name))
+    }
+
+    /// Return the size of the contents of a key, or `None` if it's not in the
+    /// log.
+    pub(crate) fn size(&self, name: &str) -> io::Result<Option<u64>> {
+        self.with_state(false, |state| {
+            Ok(state.index.get(name).map(|&(_, len)| len))
+        })
+    }
+
+    /// Call a function with the log locked exclusively, so that a `FileCave`
+    /// can update the file of a key along with the log, without other
+    /// writers in between.
+    pub(crate) fn locked<T, F>(&self, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut LockedLog<'_>) -> io::Result<T>,
+    {
+        self.with_state(true, |state| f(&mut LockedLog { state }))
+    }
+
+    /// Delete a key, and return its contents, or `None` if it was not in the
+    /// log.
+    pub(crate) fn take(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        self.with_state(true, |state| {
+            let data = state.read(name)?;
+            if data.is_some() {
+                state.append(RECORD_DELETE, name, &[])?;
+            }
+            Ok(data)
+        })
+    }
+
+    /// Return the names of the keys in the log.
+    pub(crate) fn names(&self) -> io::Result<Vec<String>> {
+        self.with_state(false, |state| Ok(state.index.keys().cloned().collect()))
+    }
+
+    /// Replace the log with one that has a single record for every key.
+    pub(crate) fn compact(&self) -> io::Result<()> {
+        self.with_state(true, |state| {
+            let mut names: Vec<&String> = state.index.keys().collect();
+            names.sort();
+            let mut live = vec![LOG_LIVE];
+            for name in names {
+                let data = state.read(name)?.unwrap();
+                live.extend_from_slice(&encode_record(RECORD_SET, name, &data));
+            }
+            if live.len() as u64 == state.end {
+                return Ok(());
+            }
+
+            let temp_path = self.path.with_extension("compact");
+            let mut temp_file = fs::File::create(&temp_path)?;
+            temp_file.write_all(&live)?;
+            temp_file.sync_all()?;
+            drop(temp_file);
+            fs::rename(&temp_path, &self.path)?;
+
+            // Mark the old log as stale, so that the processes that have it
+            // open move to the new one.
+            let mut file = state.file();
+            let _ = file.seek(io::SeekFrom::Start(0))?;
+            file.write_all(&[LOG_STALE])?;
+            file.sync_data()?;
+            state.reset();
+            Ok(())
+        })
+    }
+}
+
+/// A log that is locked exclusively, see `InlineLog::locked`.
+pub(crate) struct LockedLog<'a> {
+    state: &'a mut State,
+}
+
+impl LockedLog<'_> {
+    pub(crate) fn set(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        self.state.append(RECORD_SET, name, data)
+    }
+
+    /// Delete a key, and return whether it was in the log.
+    pub(crate) fn delete(&mut self, name: &str) -> io::Result<bool> {
+        if !self.state.index.contains_key(name) {
+            return Ok(false);
+        }
+        self.state.append(RECORD_DELETE, name, &[])?;
+        Ok(true)
+    }
+}
+
+impl fmt::Debug for InlineLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InlineLog")
+            .field("path", &self.path)
+            .field("threshold", &self.threshold)
+            .finish_non_exhaustive()
+    }
+}
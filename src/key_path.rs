@@ -0,0 +1,122 @@
+//! Hierarchical key names
+//!
+//! In this module, we define how the parts of a hierarchical key, e.g.,
+//! `["user", "42", "profile"]`, are joined into a single name, so that
+//! different parts of an application agree on it.
+
+/// The separator that [`CaveExt::get_path`] and friends use to join the parts
+/// of a key.
+///
+/// [`CaveExt::get_path`]: trait.CaveExt.html#method.get_path
+pub const DEFAULT_PATH_SEPARATOR: char = ':';
+
+/// The character that escapes separators within the parts of a key.
+const ESCAPE: char = '\\';
+
+/// Panic if the separator is the escape character.
+fn check_separator(separator: char) {
+    assert!(
+        separator != ESCAPE,
+        "The separator `{}` is the escape character of key paths",
+        separator
+    );
+}
+
+/// Join the parts of a hierarchical key into a single name, using the
+/// provided separator.
+///
+/// Any separator or backslash that appears within a part is escaped with a
+/// backslash, so that different parts never result in the same name. Use
+/// [`split_key_path`] to get the parts back.
+///
+/// ## Usage
+///
+/// ```
+/// use caves::join_key_path;
+///
+/// assert_eq!(join_key_path(&["user", "42", "profile"], ':'), "user:42:profile");
+/// assert_eq!(join_key_path(&["a:b", "c"], ':'), "a\\:b:c");
+/// assert_eq!(join_key_path(&["user", "42"], '/'), "user/42");
+/// ```
+///
+/// ## Panics
+///
+/// Panics if the separator is a backslash, since it's the escape character,
+/// and the parts could not be told apart.
+///
+/// [`split_key_path`]: fn.split_key_path.html
+pub fn join_key_path(parts: &[&str], separator: char) -> String {
+    check_separator(separator);
+    let mut name = String::new();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            name.push(separator);
+        }
+        for c in part.chars() {
+            if c == separator || c == ESCAPE {
+                name.push(ESCAPE);
+            }
+            name.push(c);
+        }
+    }
+    name
+}
+
+/// Split a name that [`join_key_path`] created back into its parts.
+///
+/// The separator must be the same as the one that the name was joined with.
+///
+/// ## Panics
+///
+/// Panics if the separator is a backslash, same as [`join_key_path`].
+///
+/// [`join_key_path`]: fn.join_key_path.html
+pub fn split_key_path(name: &str, separator: char) -> Vec<String> {
+    check_separator(separator);
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        if c == ESCAPE {
+            // A trailing escape cannot have been created by us, so we keep
+            // it as is.
+            part.push(chars.next().unwrap_or(ESCAPE));
+        } else if c == separator {
+            parts.push(std::mem::take(&mut part));
+        } else {
+            part.push(c);
+        }
+    }
+    parts.push(part);
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_path() {
+        let cases: &[(&[&str], &str)] = &[
+            (&["user", "42", "profile"], "user:42:profile"),
+            (&["user"], "user"),
+            (&["", ""], ":"),
+            (&["a:b", "c"], "a\\:b:c"),
+            (&["a\\", "b"], "a\\\\:b"),
+            (&["a\\:", ":b"], "a\\\\\\::\\:b"),
+        ];
+        for (parts, name) in cases {
+            assert_eq!(join_key_path(parts, ':'), *name);
+            assert_eq!(split_key_path(name, ':'), *parts);
+        }
+
+        // Other separators should be escaped instead.
+        assert_eq!(join_key_path(&["a:b", "c/d"], '/'), "a:b/c\\/d");
+        assert_eq!(split_key_path("a:b/c\\/d", '/'), vec!["a:b", "c/d"]);
+
+        // The escape character can't be a separator, since the parts could
+        // not be told apart from the escaped separators.
+        assert!(std::panic::catch_unwind(|| join_key_path(&["a", "b"], '\\')).is_err());
+        assert!(std::panic::catch_unwind(|| split_key_path("a\\b", '\\')).is_err());
+    }
+}
@@ -47,21 +47,167 @@
 #[macro_use]
 extern crate anyhow;
 
+mod blob;
+mod cache;
+mod case_insensitive;
+mod config;
+#[cfg(feature = "test-util")]
+mod conformance;
+mod counter;
+#[cfg(feature = "test-util")]
+mod crash;
+mod diff;
 pub mod errors;
+mod ext;
+#[cfg(feature = "test-util")]
+mod fault;
+mod gate;
+#[cfg(any(feature = "with-http-client", feature = "with-http-server"))]
+mod http;
+mod import;
+mod inline_log;
+mod key_path;
+#[cfg(feature = "with-lmdb")]
+mod lmdb;
+mod lock;
+mod map;
+mod map_err;
+#[cfg(feature = "with-memcached")]
+mod memcached;
+mod overlay;
+#[cfg(feature = "with-rayon")]
+mod parallel;
+#[cfg(feature = "with-postgres")]
+mod postgres;
+mod quota;
 pub mod res;
+#[cfg(feature = "with-http-server")]
+pub mod server;
+mod single_flight;
+mod validating;
+mod versioned;
+mod watch;
+mod write_behind;
+
+pub use crate::blob::BlobCave;
+pub use crate::cache::CacheCave;
+pub use crate::case_insensitive::CaseInsensitiveCave;
+pub use crate::config::{default_cave, from_env};
+#[cfg(feature = "test-util")]
+pub use crate::conformance::assert_cave_conformance;
+pub use crate::counter::Counter;
+#[cfg(feature = "test-util")]
+pub use crate::crash::assert_crash_consistency;
+pub use crate::diff::{diff, CaveDiff};
+pub use crate::ext::CaveExt;
+#[cfg(feature = "test-util")]
+pub use crate::fault::FaultInjectingCave;
+#[cfg(feature = "with-http-client")]
+pub use crate::http::HttpCave;
+pub use crate::import::{import_dir, import_dir_with};
+pub use crate::key_path::{join_key_path, split_key_path, DEFAULT_PATH_SEPARATOR};
+#[cfg(feature = "with-lmdb")]
+pub use crate::lmdb::LmdbCave;
+pub use crate::lock::KeyGuard;
+pub use crate::map::CaveMap;
+pub use crate::map_err::MapErrCave;
+#[cfg(feature = "with-memcached")]
+pub use crate::memcached::{MemcachedCave, MEMCACHED_MAX_KEY_LEN, MEMCACHED_MAX_VALUE_SIZE};
+pub use crate::overlay::OverlayCave;
+#[cfg(feature = "with-rayon")]
+pub use crate::parallel::{get_many_parallel, set_many_parallel};
+#[cfg(feature = "with-postgres")]
+pub use crate::postgres::PostgresCave;
+pub use crate::quota::QuotaCave;
+pub use crate::single_flight::SingleFlightCave;
+pub use crate::validating::{ValidatingCave, Validator};
+pub use crate::versioned::VersionedCave;
+pub use crate::watch::{ChangeEvent, ChangeKind, ChangeReceiver, WATCH_CAPACITY};
+pub use crate::write_behind::{Backpressure, WriteBehindCave};
+#[cfg(feature = "with-rocksdb")]
+pub use rocksdb::Cache as RocksDBCache;
+#[cfg(feature = "test-util")]
+pub use tempfile::TempDir;
 
 use std::collections;
+use std::fmt;
 use std::fs;
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
+use std::iter;
 use std::path;
 use std::sync;
+use std::time;
 
-use atomicwrites;
+use sha2::{Digest, Sha256};
 
 use crate::errors::Error;
 use crate::res::{empty_ok, Res};
 
+/// A function that [`Cave::for_each`] calls with the name and contents of
+/// every key.
+///
+/// [`Cave::for_each`]: trait.Cave.html#method.for_each
+pub type VisitFn<'a> = dyn FnMut(&str, &[u8]) -> Result<(), Error> + 'a;
+
+/// The optional operations that a `Cave` supports.
+///
+/// Kvs return an [`Error::Unsupported`] error for the operations that they
+/// don't support. Use [`Cave::capabilities`] to check for them beforehand,
+/// e.g., to fall back to polling if a kv cannot watch its keys.
+///
+/// New capabilities may be added in the future. Kvs that implement `Cave`
+/// outside this crate can start from the default capabilities, and enable the
+/// ones they support.
+///
+/// [`Error::Unsupported`]: errors/enum.Error.html#variant.Unsupported
+/// [`Cave::capabilities`]: trait.Cave.html#method.capabilities
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Capabilities {
+    /// Whether `keys` can list the stored keys.
+    pub keys: bool,
+
+    /// Whether `watch`/`watch_prefix` can send change notifications.
+    pub watch: bool,
+
+    /// Whether `snapshot` can return a point-in-time view of the kv.
+    pub snapshot: bool,
+
+    /// Whether `lock` can lock a key.
+    pub lock: bool,
+
+    /// Whether `get_bytes`/`set_bytes`/`delete_bytes` can access keys whose
+    /// names are not valid UTF-8.
+    pub binary_keys: bool,
+}
+
+/// The default capabilities match the default implementations of the `Cave`
+/// methods, i.e., only `keys` is supported.
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            keys: true,
+            watch: false,
+            snapshot: false,
+            lock: false,
+            binary_keys: false,
+        }
+    }
+}
+
+/// The metadata of a key, which [`Cave::stat`] returns.
+///
+/// [`Cave::stat`]: trait.Cave.html#method.stat
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// The size of the contents of the key, in bytes.
+    pub size: u64,
+
+    /// The last time that the key was modified, if the kv keeps track of it.
+    pub modified: Option<time::SystemTime>,
+}
+
 /// A simple interface for key-value stores.
 ///
 /// A `Cave` object must have support for the following actions:
@@ -69,6 +215,7 @@ use crate::res::{empty_ok, Res};
 /// * Get a key by name, or return an error if it doesn't exist.
 /// * Store a key by name; update it if it exists or create it if it doesn't.
 /// * Delete a key by name, or return an error if it doesn't exist.
+/// * List the names of all the stored keys.
 ///
 /// These actions must be able to happen concurrently, from any thread. This
 /// means that the objects must not rely on exclusive mutability references in
@@ -105,6 +252,37 @@ use crate::res::{empty_ok, Res};
 /// let res = b.get("key");
 /// assert_eq!(res, Err(Error::NotFound("key".to_string())));
 /// ```
+///
+/// ## Object safety
+///
+/// This trait must remain usable as a trait object, so that applications can
+/// pick a kv at runtime, e.g., from their config. Therefore, its methods must
+/// not have type parameters. Methods that need one, or that are built purely
+/// on the rest of the methods, belong to [`CaveExt`] instead, and methods
+/// that accept a function take it as a `&mut dyn FnMut`.
+///
+/// ```
+/// use caves::{Cave, CaveExt, FileCave, MemoryCave};
+///
+/// fn open(in_memory: bool, dir: &std::path::Path) -> Box<dyn Cave> {
+///     if in_memory {
+///         Box::new(MemoryCave::new())
+///     } else {
+///         Box::new(FileCave::new(dir).unwrap())
+///     }
+/// }
+///
+/// let dir = std::env::temp_dir();
+/// let b = open(true, &dir);
+/// b.set("key", b"value").unwrap();
+/// assert_eq!(b.get_or("key", b"default").unwrap(), b"value");
+/// assert_eq!(b.get_or_load("other", &mut |_| Ok(b"loaded".to_vec())).unwrap(), b"loaded");
+///
+/// let b: &dyn Cave = &b;
+/// assert_eq!(b.get_or_default("missing").unwrap(), b"");
+/// ```
+///
+/// [`CaveExt`]: trait.CaveExt.html
 pub trait Cave: Send + Sync {
     /// Get a key by its name, and return its contents.
     ///
@@ -116,275 +294,5779 @@ pub trait Cave: Send + Sync {
 
     /// Delete a key by its name.
     ///
-    /// If it does not exist, return an error.
+    /// If it does not exist, return an [`Error::NotFound`] error.
+    ///
+    /// Concurrent deletes of the same key behave as if they ran one after the
+    /// other: exactly one of them succeeds, and the rest return an
+    /// [`Error::NotFound`] error, even if the key existed when they were
+    /// called. A kv cannot tell apart a key that was deleted a moment ago from
+    /// a key that never existed, so this is the only way to report missing
+    /// keys consistently. Callers that only care that the key is gone should
+    /// treat this error as success.
+    ///
+    /// [`Error::NotFound`]: errors/enum.Error.html#variant.NotFound
     fn delete(&self, name: &str) -> Res;
 
-    /// A helper method to return an error for keys that could not be found.
-    fn not_found(&self, name: &str) -> Res {
-        Err(Error::NotFound(name.into()))
+    /// Return the names of all the stored keys.
+    ///
+    /// The names are returned in no particular order. Kvs that cannot list
+    /// their keys return an [`Error::Unsupported`] error, and report it in
+    /// their [`capabilities`].
+    ///
+    /// [`Error::Unsupported`]: errors/enum.Error.html#variant.Unsupported
+    /// [`capabilities`]: #method.capabilities
+    fn keys(&self) -> Result<Vec<String>, Error>;
+
+    /// Get a key by its name, or load its contents with a function, if it
+    /// does not exist.
+    ///
+    /// If the key does not exist, `loader` is called with its name, and the
+    /// contents that it returns are stored in the key and returned. Unlike
+    /// [`get_or`], the loader is called only when it's needed, and it can
+    /// fail, e.g., when it fetches the contents from a database. In this
+    /// case, its error is returned and nothing is stored. Errors other than
+    /// [`Error::NotFound`] are returned without calling the loader.
+    ///
+    /// By default, this method calls `get` and then `set`, which is not
+    /// atomic; concurrent calls for the same missing key call their loaders
+    /// in parallel, and the last `set` wins, even over other writes that
+    /// happened in the meantime. Wrap the kv in a [`SingleFlightCave`], so
+    /// that concurrent calls for the same key call only one loader.
+    ///
+    /// [`get_or`]: trait.CaveExt.html#method.get_or
+    /// [`Error::NotFound`]: errors/enum.Error.html#variant.NotFound
+    /// [`SingleFlightCave`]: struct.SingleFlightCave.html
+    fn get_or_load(&self, name: &str, loader: &mut dyn FnMut(&str) -> Res) -> Res {
+        load_missing(self, name, loader)
     }
-}
 
-/// A key-value store that stores keys in-memory.
-///
-/// This kv uses an in-memory hash table to store keys and their contents.
-///
-/// ## Caveats
-///
-/// This kv has the following caveats:
-///
-/// * Since it uses an in-memory hash table, in case of a power-cycle, all data
-///   will be lost.
-/// * In order to make the hash table thread-safe, we protect it with a
-///   read-write lock. This makes it prohibitive for write-intensive workloads.
-///
-/// Consider using this kv for testing purposes or short-lived installations,
-/// but avoid it for any other scenario.
-#[derive(Debug)]
-pub struct MemoryCave {
-    hash_map: sync::RwLock<collections::HashMap<String, Vec<u8>>>,
-}
+    /// Get the contents of a key whose name is a byte string.
+    ///
+    /// A name that is valid UTF-8 refers to the same key as the respective
+    /// string, e.g., `get_bytes(b"key")` is the same as `get("key")`. Other
+    /// names, e.g., binary hashes, are supported only by the kvs that report
+    /// the `binary_keys` capability. Note that the keys with such names can
+    /// be accessed only by their name, i.e., they are not returned by the
+    /// methods that list keys, such as `keys()` and `for_each()`.
+    ///
+    /// The default implementation accepts only names that are valid UTF-8,
+    /// and returns an [`Error::InvalidName`] error for the rest.
+    ///
+    /// [`Error::InvalidName`]: errors/enum.Error.html#variant.InvalidName
+    fn get_bytes(&self, name: &[u8]) -> Res {
+        self.get(utf8_name(name)?)
+    }
 
-impl MemoryCave {
-    /// Create a new instance.
-    pub fn new() -> Self {
-        Self {
-            hash_map: sync::RwLock::new(collections::HashMap::new()),
-        }
+    /// Set the contents of a key whose name is a byte string.
+    ///
+    /// See [`get_bytes`] for more info.
+    ///
+    /// [`get_bytes`]: #method.get_bytes
+    fn set_bytes(&self, name: &[u8], data: &[u8]) -> Res {
+        self.set(utf8_name(name)?, data)
     }
-}
 
-impl Cave for MemoryCave {
-    fn get(&self, name: &str) -> Res {
-        match self.hash_map.read().unwrap().get(name) {
-            Some(data) => Ok(data.to_vec()),
-            None => self.not_found(name),
-        }
+    /// Delete a key whose name is a byte string.
+    ///
+    /// See [`get_bytes`] for more info.
+    ///
+    /// [`get_bytes`]: #method.get_bytes
+    fn delete_bytes(&self, name: &[u8]) -> Res {
+        self.delete(utf8_name(name)?)
     }
 
-    fn set(&self, name: &str, data: &[u8]) -> Res {
-        let _ = self
-            .hash_map
-            .write()
-            .unwrap()
-            .insert(name.to_string(), data.to_vec());
-        empty_ok()
+    /// Get multiple keys by their names, and return the contents of those that
+    /// exist, in a map.
+    ///
+    /// Keys that do not exist are omitted from the map. Any other error aborts
+    /// the operation and is returned to the caller.
+    fn get_existing(&self, names: &[&str]) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+        collect_existing(self, names)
     }
 
-    fn delete(&self, name: &str) -> Res {
-        match self.hash_map.write().unwrap().remove(name) {
-            Some(_) => empty_ok(),
-            None => self.not_found(name),
+    /// Check whether multiple keys exist, and return the result for each
+    /// name, in the same order.
+    ///
+    /// Kvs override this method to check for the keys without reading their
+    /// contents, which is much cheaper for large values. The default
+    /// implementation gets each key. Any error other than a missing key
+    /// aborts the operation and is returned to the caller.
+    fn exists_many(&self, names: &[&str]) -> Result<Vec<bool>, Error> {
+        let mut exist = Vec::with_capacity(names.len());
+        for name in names {
+            match self.get(name) {
+                Ok(_) => exist.push(true),
+                Err(Error::NotFound(_)) => exist.push(false),
+                Err(e) => return Err(e),
+            }
         }
+        Ok(exist)
     }
-}
-
-/// A key-value store that stores keys in files.
-///
-/// This kv stores keys as files in a directory. Note that the directory must
-/// exist.
-///
-/// ## Caveats
-///
-/// This kv has the following caveats:
-///
-/// * It doesn't perform a sync operation after every set/delete.
-/// * It doesn't create multi-level directories, e.g., `fi/le/name`, to improve
-///   filesystem lookups.
-///
-/// Consider using it when you want to audit which keys are created using
-/// external tools, such as `ls`, `cat`.
-#[derive(Debug)]
-pub struct FileCave {
-    dir: path::PathBuf,
-}
 
-impl FileCave {
-    /// Create a new instance.
+    /// Return the version of a key.
     ///
-    /// Check if the provided path is a directory and that it exists.
-    pub fn new(dir: &path::Path) -> Result<Self, Error> {
-        // Return an error if the path is invalid or if we don't have enough
-        // permissions to get its metadata [1].
-        //
-        // [1]: https://doc.rust-lang.org/std/fs/fn.metadata.html#errors
-        let md = match fs::metadata(dir) {
-            Err(e) => return Err(Error::Internal(e.into())),
-            Ok(md) => md,
-        };
+    /// The version is an opaque string that changes whenever the contents of
+    /// the key change. Currently, it's the hex-encoded SHA-256 hash of the
+    /// contents. If the key does not exist, return an error.
+    fn version(&self, name: &str) -> Result<String, Error> {
+        Ok(content_hash(&self.get(name)?))
+    }
 
-        // Return an error if the path is valid, but is not a directory.
-        if !md.is_dir() {
-            return Err(Error::internal_from_msg(format!(
-                "Provided path is not a directory: {:?}",
-                dir
-            )));
+    /// Get a key by its name, if its version differs from a known one.
+    ///
+    /// If the version of the key matches `known_version`, return `None`, so
+    /// that the caller can keep using its copy of the contents. Else, return
+    /// the contents of the key along with their new version. If the key does
+    /// not exist, return an error.
+    ///
+    /// Note that the contents are still read from the underlying storage, in
+    /// order to compute their version. This method saves the caller from
+    /// transferring unchanged contents further, e.g., over the network.
+    fn get_if_changed(
+        &self,
+        name: &str,
+        known_version: &str,
+    ) -> Result<Option<(Vec<u8>, String)>, Error> {
+        let data = self.get(name)?;
+        let version = content_hash(&data);
+        if version == known_version {
+            Ok(None)
+        } else {
+            Ok(Some((data, version)))
         }
+    }
 
-        Ok(Self {
-            dir: dir.to_owned(),
+    /// Return the size and the modification time of a key, without its
+    /// contents.
+    ///
+    /// `FileCave` reads them from the metadata of the file of the key, and
+    /// `MemoryCave` keeps track of the modification times of its keys. If the
+    /// key does not exist, return an error.
+    ///
+    /// The default implementation reads the contents of the key in order to
+    /// find their size, and does not report a modification time. This is the
+    /// case for `RocksDBCave`, for instance.
+    fn stat(&self, name: &str) -> Result<Metadata, Error> {
+        Ok(Metadata {
+            size: self.get(name)?.len() as u64,
+            modified: None,
         })
     }
 
-    fn create_path(&self, name: &str) -> path::PathBuf {
-        self.dir.join(name)
+    /// Delete all the keys whose names start with `prefix`, and return the
+    /// number of deleted keys.
+    ///
+    /// An empty prefix would match every key, so it's rejected with an
+    /// [`Error::InvalidName`] error, to guard against accidental wipes.
+    ///
+    /// By default, this method lists the keys and deletes the matching ones
+    /// one by one. Keys that are deleted concurrently are not counted.
+    ///
+    /// [`Error::InvalidName`]: errors/enum.Error.html#variant.InvalidName
+    fn delete_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        delete_prefix_by_keys(self, prefix)
     }
 
-    fn convert_io_error(e: io::Error, name: &str) -> Error {
-        match e.kind() {
-            io::ErrorKind::NotFound => Error::NotFound(name.into()),
-            _ => Error::Internal(e.into()),
-        }
+    /// Rename all the keys whose names start with `old`, so that they start
+    /// with `new` instead, and return the number of renamed keys.
+    ///
+    /// If a key with a new name already exists, it's overwritten, like with
+    /// `set`. The keys are renamed as if they were all read before any of
+    /// them is written, so the new names may start with `old` as well, e.g.,
+    /// when renaming `a:` to `a:b:`. An empty `old` prefix is rejected with an
+    /// [`Error::InvalidName`] error, like in [`delete_prefix`].
+    ///
+    /// By default, this method reads the matching keys, deletes them, and
+    /// then writes them under their new names. This is not atomic, and the
+    /// contents of the matching keys must fit in memory. Kvs that can rename
+    /// keys in place override it.
+    ///
+    /// [`Error::InvalidName`]: errors/enum.Error.html#variant.InvalidName
+    /// [`delete_prefix`]: #method.delete_prefix
+    fn rename_prefix(&self, old: &str, new: &str) -> Result<usize, Error> {
+        rename_prefix_by_copy(self, old, new)
     }
-}
 
-impl Cave for FileCave {
-    fn get(&self, name: &str) -> Res {
-        let path = self.create_path(name);
+    /// Get a key by its name, delete it, and return its contents.
+    ///
+    /// If it does not exist, return an error. This is useful for work-queue
+    /// patterns, where a value must be consumed only once.
+    ///
+    /// By default, this method calls `get` and then `delete`, which is not
+    /// atomic; two concurrent calls may return the same value. Kvs that can
+    /// do it in one atomic step override it.
+    fn take(&self, name: &str) -> Res {
+        let data = self.get(name)?;
+        let _ = self.delete(name)?;
+        Ok(data)
+    }
 
-        match fs::read(path) {
-            Ok(buf) => Ok(buf),
-            Err(e) => Err(Self::convert_io_error(e, name)),
-        }
+    /// Update a key with a closure, and return its new contents.
+    ///
+    /// The closure receives the current contents of the key, or `None` if it
+    /// does not exist, and returns the new contents, or `None` to delete the
+    /// key. If the key is deleted, or was already missing, return empty
+    /// contents. This is a general-purpose primitive for read-modify-write
+    /// operations, such as increments, appends and compare-and-swap.
+    ///
+    /// By default, this method calls `get` and then `set`/`delete`, which is
+    /// not atomic; concurrent updates may be lost. Kvs that can do it in one
+    /// atomic step override it. Note that the closure may be called more than
+    /// once, e.g., by kvs that retry on conflicts, so it should not have side
+    /// effects.
+    fn update_with(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(Option<Vec<u8>>) -> Option<Vec<u8>>,
+    ) -> Res {
+        update_by_get_set(self, name, f)
     }
 
-    fn set(&self, name: &str, data: &[u8]) -> Res {
-        let path = self.create_path(name);
+    /// Create or update a key by its name, and return its previous contents,
+    /// or `None` if it did not exist.
+    ///
+    /// By default, this method is implemented on top of `update_with`, so
+    /// it's atomic for the kvs that implement `update_with` atomically.
+    fn replace(&self, name: &str, data: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        replace_by_update(self, name, data)
+    }
 
-        let af = atomicwrites::AtomicFile::new(path, atomicwrites::AllowOverwrite);
-        match af.write(|f| f.write_all(data)) {
-            Ok(_) => empty_ok(),
-            // The `atomicwrites` crate provides two types of errors [1]:
-            //
-            // * Internal: This is a library error that happens when the
-            //   tempfile cannot be created or moved. We treat it as an
-            //   internal error, because it's essentially an io:Error that can
-            //   happen, e.g., if there are no proper permissions in the
-            //   directory.
-            // * User: This the error of the lambda expression. In our case,
-            //   our lambda is very simple so we can't have a bug. If it fails,
-            //   it may be due to a ENOSPC error, which is also an internal
-            //   error.
-            //
-            // So, that's why we treat all the `atomicwrites` errors as
-            // internal errors.
-            //
-            // [1]: https://docs.rs/atomicwrites/0.2.5/atomicwrites/enum.Error.html
-            Err(e) => Err(Error::Internal(e.into())),
+    /// Create a key with the provided contents, only if it does not exist.
+    ///
+    /// If the key exists, return an [`Error::AlreadyExists`] error, and leave
+    /// it intact. Of multiple concurrent calls for the same key, only one
+    /// succeeds, so that, e.g., processes can initialize a key only once, or
+    /// elect a leader.
+    ///
+    /// By default, this method checks whether the key exists with `get`,
+    /// and then writes it with `set`, so it's **not** atomic: a concurrent
+    /// write between the two calls is overwritten, and multiple concurrent
+    /// calls may all succeed. It never writes a key that it has found,
+    /// though. `MemoryCave` and `RocksDBCave` check and create the key while
+    /// holding their write lock, `LmdbCave` does so in a write transaction,
+    /// `PostgresCave` uses an `INSERT ... ON CONFLICT DO NOTHING` statement,
+    /// and `FileCave` creates the file of the key exclusively, which is
+    /// atomic across processes as well.
+    ///
+    /// [`Error::AlreadyExists`]: errors/enum.Error.html#variant.AlreadyExists
+    fn create(&self, name: &str, data: &[u8]) -> Res {
+        match self.get(name) {
+            Ok(_) => Err(Error::AlreadyExists(name.to_string())),
+            Err(Error::NotFound(_)) => self.set(name, data),
+            Err(e) => Err(e),
         }
     }
 
-    fn delete(&self, name: &str) -> Res {
-        let path = self.create_path(name);
-        match fs::remove_file(path) {
-            Ok(_) => empty_ok(),
-            Err(e) => Err(Self::convert_io_error(e, name)),
-        }
+    /// Watch a key for changes, and return a channel that receives an event
+    /// for each change.
+    ///
+    /// The events are delivered on a best-effort basis. Kvs may coalesce
+    /// multiple changes into one event, and events are not persisted, so
+    /// changes that happen while no one is watching are not reported.
+    /// Kvs that buffer the events, such as `MemoryCave`, drop them once the
+    /// receiver falls behind by [`WATCH_CAPACITY`] events. Dropping the
+    /// receiver stops watching the key.
+    ///
+    /// Kvs that cannot watch their keys return an [`Error::Unsupported`]
+    /// error, which is the default behavior.
+    ///
+    /// [`Error::Unsupported`]: errors/enum.Error.html#variant.Unsupported
+    /// [`WATCH_CAPACITY`]: constant.WATCH_CAPACITY.html
+    fn watch(&self, name: &str) -> Result<ChangeReceiver, Error> {
+        let _ = name;
+        Err(Error::Unsupported { operation: "watch" })
     }
-}
 
-/// A key-value store that stores keys in [RocksDB].
-///
-/// [RocksDB]: https://github.com/facebook/rocksdb
-#[cfg(feature = "with-rocksdb")]
-#[derive(Debug)]
-pub struct RocksDBCave {
-    db: rocksdb::DB,
-}
+    /// Watch all the keys whose names start with `prefix` for changes.
+    ///
+    /// Same as [`watch`], but for multiple keys.
+    ///
+    /// [`watch`]: #method.watch
+    fn watch_prefix(&self, prefix: &str) -> Result<ChangeReceiver, Error> {
+        let _ = prefix;
+        Err(Error::Unsupported {
+            operation: "watch_prefix",
+        })
+    }
 
-#[cfg(feature = "with-rocksdb")]
-impl RocksDBCave {
-    /// Create a new instance.
+    /// Return a read-only, point-in-time view of the kv.
     ///
-    /// If the provided directory does not exist, it will be created.
-    pub fn new(dir: &path::Path) -> Result<Self, Error> {
-        match rocksdb::DB::open_default(dir) {
-            Ok(db) => Ok(Self { db }),
-            Err(e) => Err(Error::Internal(e.into())),
-        }
+    /// The view reflects the state of the kv at the time of the call, and is
+    /// not affected by subsequent writes. Its `set`/`delete` methods return an
+    /// [`Error::Unsupported`] error. Use it to read multiple keys
+    /// consistently, e.g., for backups.
+    ///
+    /// Kvs that cannot create such views return an [`Error::Unsupported`]
+    /// error, which is the default behavior.
+    ///
+    /// [`Error::Unsupported`]: errors/enum.Error.html#variant.Unsupported
+    fn snapshot(&self) -> Result<Box<dyn Cave + '_>, Error> {
+        Err(Error::Unsupported {
+            operation: "snapshot",
+        })
     }
-}
 
-#[cfg(feature = "with-rocksdb")]
-impl Cave for RocksDBCave {
-    fn get(&self, name: &str) -> Res {
-        match self.db.get(name.as_bytes()) {
-            Ok(o) => match o {
-                Some(buf) => Ok(buf),
-                None => self.not_found(name),
-            },
-            Err(e) => Err(Error::Internal(e.into())),
-        }
+    /// Return the name of the first key, or `None` if the kv is empty.
+    ///
+    /// The keys are ordered lexicographically by the byte value of their
+    /// names, e.g., `"B"` comes before `"a"`, and `"a"` before `"ab"`. Use it
+    /// to initialize a cursor for range-based pagination.
+    ///
+    /// The default implementation lists all the keys with [`keys`] and
+    /// returns the smallest one. Ordered kvs, e.g., `RocksDBCave`, find it
+    /// without a full scan.
+    ///
+    /// [`keys`]: #tymethod.keys
+    fn first_key(&self) -> Result<Option<String>, Error> {
+        Ok(self.keys()?.into_iter().min())
     }
 
-    fn set(&self, name: &str, data: &[u8]) -> Res {
-        match self.db.put(name.as_bytes(), data) {
-            Ok(_) => empty_ok(),
-            Err(e) => Err(Error::Internal(e.into())),
-        }
+    /// Return the name of the last key, or `None` if the kv is empty.
+    ///
+    /// Same as [`first_key`], but returns the largest name.
+    ///
+    /// [`first_key`]: #method.first_key
+    fn last_key(&self) -> Result<Option<String>, Error> {
+        Ok(self.keys()?.into_iter().max())
     }
 
-    fn delete(&self, name: &str) -> Res {
-        // XXX: We should find a better way to check if a value exists or not.
-        match self.get(name) {
-            Ok(_) => (),
-            e => return e,
-        }
+    /// Return the keys whose names are in the range [`start`, `end`), along
+    /// with their contents.
+    ///
+    /// The entries are sorted by name, in the same lexicographic order as in
+    /// [`first_key`]. If `start` is not less than `end`, the range is empty.
+    ///
+    /// The default implementation lists all the keys with [`keys`], and gets
+    /// the ones in the range one by one. Keys that are deleted in the
+    /// meantime are omitted. Ordered kvs, e.g., `RocksDBCave`, read only the
+    /// keys in the range.
+    ///
+    /// [`first_key`]: #method.first_key
+    /// [`keys`]: #tymethod.keys
+    fn range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        collect_range(self, start, end)
+    }
 
-        match self.db.delete(name.as_bytes()) {
-            Ok(_) => empty_ok(),
-            Err(e) => Err(Error::Internal(e.into())),
-        }
+    /// Swap the contents of two keys.
+    ///
+    /// If either key does not exist, return an error and leave both keys
+    /// intact.
+    ///
+    /// By default, this method gets both keys and then sets them, which is
+    /// not atomic; a concurrent write to either key may be lost. Kvs that can
+    /// do it in one atomic step override it.
+    fn swap(&self, a: &str, b: &str) -> Res {
+        let data_a = self.get(a)?;
+        let data_b = self.get(b)?;
+        let _ = self.set(a, &data_b)?;
+        let _ = self.set(b, &data_a)?;
+        empty_ok()
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Return the number of keys whose names start with `prefix`.
+    ///
+    /// An empty prefix matches every key. The default implementation lists
+    /// all the keys with [`keys`] and counts the matching ones. Kvs that can
+    /// count them without transferring their names override it.
+    ///
+    /// [`keys`]: #tymethod.keys
+    fn count_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        let keys = self.keys()?;
+        Ok(keys.iter().filter(|name| name.starts_with(prefix)).count())
+    }
 
-    use assert_fs;
+    /// Call a function with the name and contents of every key.
+    ///
+    /// The keys are visited in no particular order, and one at a time, so
+    /// that they don't have to fit in memory at the same time. If the
+    /// function returns an error, the iteration stops and the error is
+    /// returned.
+    ///
+    /// The default implementation lists the names of the keys with [`keys`],
+    /// and gets them one by one. Keys that are deleted in the meantime are
+    /// skipped.
+    ///
+    /// [`keys`]: #tymethod.keys
+    fn for_each(&self, f: &mut VisitFn<'_>) -> Result<(), Error> {
+        visit_keys(self, self.keys()?, f)
+    }
 
-    fn _test_simple(b: Box<dyn Cave>) {
-        let not_found_err = Err(Error::NotFound("test".to_string()));
-        let value1 = Ok("value".as_bytes().to_vec());
+    /// Return the names of all the stored keys, in lexicographic byte order.
+    ///
+    /// Unlike [`keys`], the order is the same for every kv, so that the
+    /// output is reproducible, e.g., in tests and diffs.
+    ///
+    /// The default implementation sorts the names that [`keys`] returns.
+    /// `RocksDBCave` already stores the keys in this order, so it sorts them
+    /// only if it splits the values into chunks.
+    ///
+    /// [`keys`]: #tymethod.keys
+    fn keys_sorted(&self) -> Result<Vec<String>, Error> {
+        let mut keys = self.keys()?;
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    /// Call a function with the name and contents of every key, in
+    /// lexicographic byte order of their names.
+    ///
+    /// Same as [`for_each`], but the keys are visited in the order that
+    /// [`keys_sorted`] returns them.
+    ///
+    /// [`for_each`]: #method.for_each
+    /// [`keys_sorted`]: #method.keys_sorted
+    fn for_each_sorted(&self, f: &mut VisitFn<'_>) -> Result<(), Error> {
+        visit_keys(self, self.keys_sorted()?, f)
+    }
+
+    /// Return the names and contents of all the keys, in a map.
+    ///
+    /// This is meant for small kvs, e.g., in tests, since every key must fit
+    /// in memory. The default implementation collects the keys with
+    /// [`for_each`].
+    ///
+    /// [`for_each`]: #method.for_each
+    fn dump(&self) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+        dump_by_visit(self)
+    }
+
+    /// Make the writes that the kv has acknowledged so far durable.
+    ///
+    /// Kvs that buffer writes, e.g., `WriteBehindCave`, apply them before
+    /// returning, and kvs that keep recent writes in memory, e.g.,
+    /// `RocksDBCave`, persist them to disk. For kvs that don't do either,
+    /// this is a no-op, which is the default behavior.
+    fn flush(&self) -> Res {
+        empty_ok()
+    }
+
+    /// Flush the kv and release its resources.
+    ///
+    /// Dropping a kv cleans up as well, but it can't report errors, e.g., a
+    /// failed flush of buffered writes. Call this method to release the kv at
+    /// a well-defined point and handle any final error.
+    ///
+    /// The default implementation calls [`flush`] and then drops the kv.
+    ///
+    /// [`flush`]: #method.flush
+    fn close(self) -> Result<(), Error>
+    where
+        Self: Sized,
+    {
+        let _ = self.flush()?;
+        Ok(())
+    }
+
+    /// Tidy up the kv, e.g., compact its storage or remove leftover files.
+    ///
+    /// Kvs accumulate cruft over time, which this method cleans up on
+    /// demand. `RocksDBCave` compacts its whole key range, and `FileCave`
+    /// removes the temporary files that interrupted writes left behind. For
+    /// other kvs, this is a no-op, which is the default behavior.
+    fn maintain(&self) -> Res {
+        empty_ok()
+    }
+
+    /// Return the number of bytes that the kv occupies on disk.
+    ///
+    /// Each kv measures this differently, so it's meant for capacity
+    /// planning, not for exact accounting:
+    ///
+    /// * `FileCave` sums the sizes of the files in its directory. These are
+    ///   the stored bytes of the values, i.e., after compression, plus any
+    ///   lock files and temporary files.
+    /// * `RocksDBCave` reports the total size of its SST files. Writes that
+    ///   have not been flushed from memory yet are not included.
+    /// * `MemoryCave` does not use the disk, so it returns 0.
+    ///
+    /// Kvs that cannot measure their size return an [`Error::Unsupported`]
+    /// error, which is the default behavior.
+    ///
+    /// [`Error::Unsupported`]: errors/enum.Error.html#variant.Unsupported
+    fn disk_usage(&self) -> Result<u64, Error> {
+        Err(Error::Unsupported {
+            operation: "disk_usage",
+        })
+    }
+
+    /// Lock a key, and return a guard that keeps it locked until it's
+    /// dropped.
+    ///
+    /// If the key is already locked, wait until it's unlocked. The lock is
+    /// advisory; it only excludes other holders of the lock of the same key,
+    /// and does not block any other operation. The key does not have to
+    /// exist. Use it to serialize multi-step operations on a key, e.g., a
+    /// read-modify-write in application code.
+    ///
+    /// Kvs that cannot lock their keys return an [`Error::Unsupported`]
+    /// error, which is the default behavior.
+    ///
+    /// [`Error::Unsupported`]: errors/enum.Error.html#variant.Unsupported
+    fn lock(&self, name: &str) -> Result<KeyGuard<'_>, Error> {
+        let _ = name;
+        Err(Error::Unsupported { operation: "lock" })
+    }
+
+    /// Return the optional operations that the kv supports.
+    ///
+    /// See [`Capabilities`] for more info.
+    ///
+    /// [`Capabilities`]: struct.Capabilities.html
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    /// A helper method to return an error for keys that could not be found.
+    fn not_found(&self, name: &str) -> Res {
+        Err(Error::NotFound(name.into()))
+    }
+}
+
+/// Implement `Cave` for a pointer type, by forwarding every method to the
+/// `Cave` it points to.
+///
+/// Every method must be forwarded, so that the pointer does not fall back to
+/// the default, possibly non-atomic, implementation of a method that the
+/// pointee overrides.
+macro_rules! forward_cave_impl {
+    ($($ptr:ty),*) => {$(
+        impl<C: Cave + ?Sized> Cave for $ptr {
+            fn get(&self, name: &str) -> Res {
+                (**self).get(name)
+            }
+
+            fn set(&self, name: &str, data: &[u8]) -> Res {
+                (**self).set(name, data)
+            }
+
+            fn delete(&self, name: &str) -> Res {
+                (**self).delete(name)
+            }
+
+            fn keys(&self) -> Result<Vec<String>, Error> {
+                (**self).keys()
+            }
+
+            fn get_or_load(&self, name: &str, loader: &mut dyn FnMut(&str) -> Res) -> Res {
+                (**self).get_or_load(name, loader)
+            }
+
+            fn get_bytes(&self, name: &[u8]) -> Res {
+                (**self).get_bytes(name)
+            }
+
+            fn set_bytes(&self, name: &[u8], data: &[u8]) -> Res {
+                (**self).set_bytes(name, data)
+            }
+
+            fn delete_bytes(&self, name: &[u8]) -> Res {
+                (**self).delete_bytes(name)
+            }
+
+            fn get_existing(
+                &self,
+                names: &[&str],
+            ) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+                (**self).get_existing(names)
+            }
+
+            fn exists_many(&self, names: &[&str]) -> Result<Vec<bool>, Error> {
+                (**self).exists_many(names)
+            }
+
+            fn version(&self, name: &str) -> Result<String, Error> {
+                (**self).version(name)
+            }
+
+            fn get_if_changed(
+                &self,
+                name: &str,
+                known_version: &str,
+            ) -> Result<Option<(Vec<u8>, String)>, Error> {
+                (**self).get_if_changed(name, known_version)
+            }
+
+            fn stat(&self, name: &str) -> Result<Metadata, Error> {
+                (**self).stat(name)
+            }
+
+            fn delete_prefix(&self, prefix: &str) -> Result<usize, Error> {
+                (**self).delete_prefix(prefix)
+            }
+
+            fn rename_prefix(&self, old: &str, new: &str) -> Result<usize, Error> {
+                (**self).rename_prefix(old, new)
+            }
+
+            fn take(&self, name: &str) -> Res {
+                (**self).take(name)
+            }
+
+            fn update_with(
+                &self,
+                name: &str,
+                f: &mut dyn FnMut(Option<Vec<u8>>) -> Option<Vec<u8>>,
+            ) -> Res {
+                (**self).update_with(name, f)
+            }
+
+            fn replace(&self, name: &str, data: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+                (**self).replace(name, data)
+            }
+
+            fn create(&self, name: &str, data: &[u8]) -> Res {
+                (**self).create(name, data)
+            }
+
+            fn watch(&self, name: &str) -> Result<ChangeReceiver, Error> {
+                (**self).watch(name)
+            }
+
+            fn watch_prefix(&self, prefix: &str) -> Result<ChangeReceiver, Error> {
+                (**self).watch_prefix(prefix)
+            }
+
+            fn snapshot(&self) -> Result<Box<dyn Cave + '_>, Error> {
+                (**self).snapshot()
+            }
+
+            fn first_key(&self) -> Result<Option<String>, Error> {
+                (**self).first_key()
+            }
+
+            fn last_key(&self) -> Result<Option<String>, Error> {
+                (**self).last_key()
+            }
+
+            fn range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+                (**self).range(start, end)
+            }
+
+            fn swap(&self, a: &str, b: &str) -> Res {
+                (**self).swap(a, b)
+            }
+
+            fn count_prefix(&self, prefix: &str) -> Result<usize, Error> {
+                (**self).count_prefix(prefix)
+            }
+
+            fn for_each(
+                &self,
+                f: &mut VisitFn<'_>,
+            ) -> Result<(), Error> {
+                (**self).for_each(f)
+            }
+
+            fn keys_sorted(&self) -> Result<Vec<String>, Error> {
+                (**self).keys_sorted()
+            }
+
+            fn for_each_sorted(
+                &self,
+                f: &mut VisitFn<'_>,
+            ) -> Result<(), Error> {
+                (**self).for_each_sorted(f)
+            }
+
+            fn dump(&self) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+                (**self).dump()
+            }
+
+            fn flush(&self) -> Res {
+                (**self).flush()
+            }
+
+            fn maintain(&self) -> Res {
+                (**self).maintain()
+            }
+
+            fn disk_usage(&self) -> Result<u64, Error> {
+                (**self).disk_usage()
+            }
+
+            fn lock(&self, name: &str) -> Result<KeyGuard<'_>, Error> {
+                (**self).lock(name)
+            }
+
+            fn capabilities(&self) -> Capabilities {
+                (**self).capabilities()
+            }
+        }
+    )*};
+}
+
+forward_cave_impl!(&C, Box<C>, sync::Arc<C>);
+
+/// Get a key of a `Cave`, or load its contents and store them, if it does not
+/// exist.
+fn load_missing<C: Cave + ?Sized>(
+    cave: &C,
+    name: &str,
+    loader: &mut dyn FnMut(&str) -> Res,
+) -> Res {
+    match cave.get(name) {
+        Err(Error::NotFound(_)) => {
+            let data = loader(name)?;
+            let _ = cave.set(name, &data)?;
+            Ok(data)
+        }
+        res => res,
+    }
+}
+
+/// Get the provided keys of a `Cave` one by one, skipping the missing ones.
+fn collect_existing<C: Cave + ?Sized>(
+    cave: &C,
+    names: &[&str],
+) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+    let mut map = collections::HashMap::new();
+    for name in names {
+        match cave.get(name) {
+            Ok(data) => {
+                let _ = map.insert(name.to_string(), data);
+            }
+            Err(Error::NotFound(_)) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(map)
+}
+
+/// Delete the keys of a `Cave` whose names start with `prefix`, by listing
+/// all of its keys.
+fn delete_prefix_by_keys<C: Cave + ?Sized>(cave: &C, prefix: &str) -> Result<usize, Error> {
+    check_prefix(prefix)?;
+    let mut count = 0;
+    for name in cave.keys()? {
+        if !name.starts_with(prefix) {
+            continue;
+        }
+        match cave.delete(&name) {
+            Ok(_) => count += 1,
+            Err(Error::NotFound(_)) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(count)
+}
+
+/// Update a key of a `Cave` with a `get`, followed by a `set` or a `delete`.
+fn update_by_get_set<C: Cave + ?Sized>(
+    cave: &C,
+    name: &str,
+    f: &mut dyn FnMut(Option<Vec<u8>>) -> Option<Vec<u8>>,
+) -> Res {
+    let current = match cave.get(name) {
+        Ok(data) => Some(data),
+        Err(Error::NotFound(_)) => None,
+        Err(e) => return Err(e),
+    };
+
+    let existed = current.is_some();
+    match f(current) {
+        Some(data) => {
+            let _ = cave.set(name, &data)?;
+            Ok(data)
+        }
+        None if existed => match cave.delete(name) {
+            Ok(_) | Err(Error::NotFound(_)) => empty_ok(),
+            Err(e) => Err(e),
+        },
+        None => empty_ok(),
+    }
+}
+
+/// Replace the contents of a key of a `Cave` with `update_with`, and return
+/// the previous ones.
+fn replace_by_update<C: Cave + ?Sized>(
+    cave: &C,
+    name: &str,
+    data: &[u8],
+) -> Result<Option<Vec<u8>>, Error> {
+    let mut previous = None;
+    let _ = cave.update_with(name, &mut |current| {
+        previous = current;
+        Some(data.to_vec())
+    })?;
+    Ok(previous)
+}
+
+/// Collect the keys of a `Cave` in a map, with `for_each`.
+fn dump_by_visit<C: Cave + ?Sized>(
+    cave: &C,
+) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+    let mut map = collections::HashMap::new();
+    cave.for_each(&mut |name, data| {
+        let _ = map.insert(name.to_string(), data.to_vec());
+        Ok(())
+    })?;
+    Ok(map)
+}
+
+/// Get the keys of a `Cave` whose names are in the range [`start`, `end`), by
+/// listing all of its keys.
+fn collect_range<C: Cave + ?Sized>(
+    cave: &C,
+    start: &str,
+    end: &str,
+) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let mut names: Vec<String> = cave
+        .keys()?
+        .into_iter()
+        .filter(|name| start <= name.as_str() && name.as_str() < end)
+        .collect();
+    names.sort();
+
+    let mut entries = Vec::with_capacity(names.len());
+    for name in names {
+        match cave.get(&name) {
+            Ok(data) => entries.push((name, data)),
+            Err(Error::NotFound(_)) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(entries)
+}
+
+/// Call a function with the name and contents of the provided keys of a
+/// `Cave`, one by one, skipping the keys that don't exist.
+fn visit_keys<C: Cave + ?Sized>(
+    cave: &C,
+    names: Vec<String>,
+    f: &mut VisitFn<'_>,
+) -> Result<(), Error> {
+    for name in names {
+        match cave.get(&name) {
+            Ok(data) => f(&name, &data)?,
+            Err(Error::NotFound(_)) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Rename the keys of a `Cave` whose names start with `old`, by reading them,
+/// deleting them, and then writing them under their new names.
+fn rename_prefix_by_copy<C: Cave + ?Sized>(cave: &C, old: &str, new: &str) -> Result<usize, Error> {
+    check_prefix(old)?;
+    if old == new {
+        return cave.count_prefix(old);
+    }
+
+    let mut entries = Vec::new();
+    for name in cave.keys()? {
+        if !name.starts_with(old) {
+            continue;
+        }
+        match cave.get(&name) {
+            Ok(data) => entries.push((name, data)),
+            Err(Error::NotFound(_)) => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    // Delete all the keys before writing any of them, so that a key whose new
+    // name is the old name of another key is not deleted afterwards.
+    for (name, _) in &entries {
+        match cave.delete(name) {
+            Ok(_) | Err(Error::NotFound(_)) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    for (name, data) in &entries {
+        let _ = cave.set(&replace_prefix(name, old, new), data)?;
+    }
+    Ok(entries.len())
+}
+
+/// Replace the `old` prefix of a name with `new`.
+fn replace_prefix(name: &str, old: &str, new: &str) -> String {
+    format!("{}{}", new, &name[old.len()..])
+}
+
+/// Convert the name of a key to a string, or reject it if it's not valid
+/// UTF-8.
+fn utf8_name(name: &[u8]) -> Result<&str, Error> {
+    match std::str::from_utf8(name) {
+        Ok(name) => Ok(name),
+        Err(_) => Err(Error::InvalidName {
+            name: String::from_utf8_lossy(name).into_owned(),
+            reason: "the name of the key is not valid UTF-8",
+        }),
+    }
+}
+
+/// Reject prefixes that would match every key.
+fn check_prefix(prefix: &str) -> Result<(), Error> {
+    if prefix.is_empty() {
+        return Err(Error::InvalidName {
+            name: prefix.to_string(),
+            reason: "the prefix must not be empty",
+        });
+    }
+    Ok(())
+}
+
+/// Reject values that are larger than the maximum size of a kv, if any.
+pub(crate) fn check_value_size(data: &[u8], limit: Option<usize>) -> Result<(), Error> {
+    match limit {
+        Some(limit) if data.len() > limit => Err(Error::ValueTooLarge {
+            size: data.len(),
+            limit,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Return the hex-encoded SHA-256 hash of some contents.
+fn content_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// A key-value store that stores keys in-memory.
+///
+/// This kv uses an in-memory hash table to store keys and their contents.
+///
+/// ## Caveats
+///
+/// This kv has the following caveats:
+///
+/// * Since it uses an in-memory hash table, in case of a power-cycle, all data
+///   will be lost.
+/// * In order to make the hash table thread-safe, we protect it with a
+///   read-write lock. This makes it prohibitive for write-intensive workloads.
+/// * The keys whose names are not valid UTF-8 (see [`Cave::get_bytes`]) are
+///   stored in a separate hash table, so they are not included in snapshots,
+///   and they cannot be watched or locked.
+///
+/// Consider using this kv for testing purposes or short-lived installations,
+/// but avoid it for any other scenario.
+///
+/// [`Cave::get_bytes`]: trait.Cave.html#method.get_bytes
+pub struct MemoryCave {
+    hash_map: sync::RwLock<collections::HashMap<String, Vec<u8>>>,
+    binary_map: sync::RwLock<collections::HashMap<Vec<u8>, Vec<u8>>>,
+    modified: sync::Mutex<collections::HashMap<String, time::SystemTime>>,
+    max_value_size: Option<usize>,
+    watchers: watch::Watchers,
+    locks: lock::KeyLocks,
+}
+
+impl MemoryCave {
+    /// Create a new instance.
+    pub fn new() -> Self {
+        Self {
+            hash_map: sync::RwLock::new(collections::HashMap::new()),
+            binary_map: sync::RwLock::new(collections::HashMap::new()),
+            modified: sync::Mutex::new(collections::HashMap::new()),
+            max_value_size: None,
+            watchers: watch::Watchers::default(),
+            locks: lock::KeyLocks::default(),
+        }
+    }
+
+    /// Create a new instance, with space preallocated for `capacity` keys.
+    ///
+    /// The hash table of the kv grows as keys are added, which means that
+    /// the existing keys are rehashed every time it does. If the number of
+    /// the keys is known in advance, e.g., before a bulk load, this
+    /// constructor avoids the rehashing. Note that the contents of the keys
+    /// are not preallocated.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            hash_map: sync::RwLock::new(collections::HashMap::with_capacity(capacity)),
+            modified: sync::Mutex::new(collections::HashMap::with_capacity(capacity)),
+            ..Self::new()
+        }
+    }
+
+    /// Create a new instance that contains the keys of a map.
+    ///
+    /// This is the inverse of [`dump`], and it's handy for seeding a kv in
+    /// tests. See also the `FromIterator` implementation, which creates an
+    /// instance from any iterator of names and contents.
+    ///
+    /// [`dump`]: trait.Cave.html#method.dump
+    pub fn from_map(map: collections::HashMap<String, Vec<u8>>) -> Self {
+        let now = time::SystemTime::now();
+        let modified = map.keys().map(|name| (name.clone(), now)).collect();
+        Self {
+            hash_map: sync::RwLock::new(map),
+            modified: sync::Mutex::new(modified),
+            ..Self::new()
+        }
+    }
+
+    /// Copy all the keys of this kv into another `Cave`, e.g., a persistent
+    /// one, and return the number of copied keys.
+    ///
+    /// The keys are copied from a point-in-time copy of the kv, which is
+    /// taken under a read lock, so concurrent writes are not blocked while
+    /// the keys are being written, and do not affect the result. Keys that
+    /// already exist in the other `Cave` are overwritten. Once all the keys
+    /// are written, the other `Cave` is flushed.
+    ///
+    /// The first error aborts the copy and is returned to the caller, in
+    /// which case only some of the keys may have been copied.
+    ///
+    /// ## Usage
+    ///
+    /// ```
+    /// use caves::{Cave, FileCave, MemoryCave};
+    ///
+    /// let mb = MemoryCave::new();
+    /// mb.set("key", b"value").unwrap();
+    ///
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let fb = FileCave::new(dir.path()).unwrap();
+    /// assert_eq!(mb.persist_to(&fb).unwrap(), 1);
+    /// assert_eq!(fb.get("key").unwrap(), b"value");
+    /// ```
+    pub fn persist_to(&self, dst: &dyn Cave) -> Result<usize, Error> {
+        let (hash_map, binary_map) = {
+            let hash_map = self.hash_map.read().unwrap();
+            let binary_map = self.binary_map.read().unwrap();
+            (hash_map.clone(), binary_map.clone())
+        };
+
+        for (name, data) in &hash_map {
+            let _ = dst.set(name, data)?;
+        }
+        for (name, data) in &binary_map {
+            let _ = dst.set_bytes(name, data)?;
+        }
+        let _ = dst.flush()?;
+        Ok(hash_map.len() + binary_map.len())
+    }
+
+    /// Call a function with the name and contents of every key, without
+    /// copying them.
+    ///
+    /// This is a lighter version of [`Cave::for_each`] for read-only scans,
+    /// since the function can't fail, and it can be inlined. The keys are
+    /// visited in no particular order. The keys whose names are not valid
+    /// UTF-8 are not visited.
+    ///
+    /// The read lock of the kv is held until all the keys are visited, so
+    /// writes are blocked in the meantime. For this reason, the function must
+    /// not write to the kv, or else it deadlocks.
+    ///
+    /// ## Usage
+    ///
+    /// ```
+    /// use caves::{Cave, MemoryCave};
+    ///
+    /// let mb = MemoryCave::new();
+    /// mb.set("key1", b"value").unwrap();
+    /// mb.set("key2", b"other value").unwrap();
+    ///
+    /// let mut total = 0;
+    /// mb.for_each_ref(|_, data| total += data.len());
+    /// assert_eq!(total, 16);
+    /// ```
+    ///
+    /// [`Cave::for_each`]: trait.Cave.html#method.for_each
+    pub fn for_each_ref<F: FnMut(&str, &[u8])>(&self, mut f: F) {
+        let hash_map = self.hash_map.read().unwrap();
+        for (name, data) in hash_map.iter() {
+            f(name, data);
+        }
+    }
+
+    /// Reject values that are larger than `limit` bytes.
+    ///
+    /// A `set` with a larger value returns an [`Error::ValueTooLarge`] error,
+    /// and leaves the kv untouched. The keys that the kv already contains are
+    /// not checked.
+    ///
+    /// [`Error::ValueTooLarge`]: errors/enum.Error.html#variant.ValueTooLarge
+    pub fn with_max_value_size(mut self, limit: usize) -> Self {
+        self.max_value_size = Some(limit);
+        self
+    }
+
+    /// Record a change to a key, and notify its watchers.
+    ///
+    /// This must be called while holding the write lock of the hash table,
+    /// so that the watchers receive the changes in the order that they
+    /// happened.
+    fn changed(&self, name: &str, kind: ChangeKind) {
+        let mut modified = self.modified.lock().unwrap();
+        match kind {
+            ChangeKind::Set => {
+                let _ = modified.insert(name.to_string(), time::SystemTime::now());
+            }
+            ChangeKind::Delete => {
+                let _ = modified.remove(name);
+            }
+        }
+        drop(modified);
+        self.watchers.notify(name, kind);
+    }
+}
+
+/// Print the number of keys, instead of the contents of the kv, so that it's
+/// safe to log.
+impl fmt::Debug for MemoryCave {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let keys = self.hash_map.read().unwrap().len() + self.binary_map.read().unwrap().len();
+        f.debug_struct("MemoryCave")
+            .field("keys", &keys)
+            .field("max_value_size", &self.max_value_size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for MemoryCave {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Create a `MemoryCave` from pairs of names and contents.
+///
+/// If a name appears more than once, the last contents win.
+///
+/// ## Usage
+///
+/// ```
+/// use caves::{Cave, MemoryCave};
+///
+/// let b: MemoryCave = vec![("key".to_string(), b"value".to_vec())]
+///     .into_iter()
+///     .collect();
+/// assert_eq!(b.get("key").unwrap(), b"value");
+/// ```
+impl iter::FromIterator<(String, Vec<u8>)> for MemoryCave {
+    fn from_iter<I: IntoIterator<Item = (String, Vec<u8>)>>(iter: I) -> Self {
+        Self::from_map(iter.into_iter().collect())
+    }
+}
+
+impl Cave for MemoryCave {
+    fn get(&self, name: &str) -> Res {
+        match self.hash_map.read().unwrap().get(name) {
+            Some(data) => Ok(data.to_vec()),
+            None => self.not_found(name),
+        }
+    }
+
+    fn set(&self, name: &str, data: &[u8]) -> Res {
+        let _ = self.replace(name, data)?;
+        empty_ok()
+    }
+
+    fn delete(&self, name: &str) -> Res {
+        let _ = self.take(name)?;
+        empty_ok()
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        Ok(self.hash_map.read().unwrap().keys().cloned().collect())
+    }
+
+    fn get_bytes(&self, name: &[u8]) -> Res {
+        if let Ok(name) = std::str::from_utf8(name) {
+            return self.get(name);
+        }
+        match self.binary_map.read().unwrap().get(name) {
+            Some(data) => Ok(data.to_vec()),
+            None => self.not_found(&String::from_utf8_lossy(name)),
+        }
+    }
+
+    fn set_bytes(&self, name: &[u8], data: &[u8]) -> Res {
+        if let Ok(name) = std::str::from_utf8(name) {
+            return self.set(name, data);
+        }
+        check_value_size(data, self.max_value_size)?;
+        let mut binary_map = self.binary_map.write().unwrap();
+        let _ = binary_map.insert(name.to_vec(), data.to_vec());
+        empty_ok()
+    }
+
+    fn delete_bytes(&self, name: &[u8]) -> Res {
+        if let Ok(name) = std::str::from_utf8(name) {
+            return self.delete(name);
+        }
+        match self.binary_map.write().unwrap().remove(name) {
+            Some(_) => empty_ok(),
+            None => self.not_found(&String::from_utf8_lossy(name)),
+        }
+    }
+
+    fn get_existing(&self, names: &[&str]) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+        let hash_map = self.hash_map.read().unwrap();
+        Ok(names
+            .iter()
+            .filter_map(|&name| hash_map.get_key_value(name))
+            .map(|(name, data)| (name.clone(), data.clone()))
+            .collect())
+    }
+
+    fn exists_many(&self, names: &[&str]) -> Result<Vec<bool>, Error> {
+        let hash_map = self.hash_map.read().unwrap();
+        Ok(names
+            .iter()
+            .map(|&name| hash_map.contains_key(name))
+            .collect())
+    }
+
+    fn take(&self, name: &str) -> Res {
+        // Notify the watchers while holding the lock, so that they receive
+        // the changes in the order that they happened.
+        let mut hash_map = self.hash_map.write().unwrap();
+        match hash_map.remove(name) {
+            Some(data) => {
+                self.changed(name, ChangeKind::Delete);
+                Ok(data)
+            }
+            None => self.not_found(name),
+        }
+    }
+
+    fn replace(&self, name: &str, data: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        check_value_size(data, self.max_value_size)?;
+        let mut hash_map = self.hash_map.write().unwrap();
+        let previous = hash_map.insert(name.to_string(), data.to_vec());
+        self.changed(name, ChangeKind::Set);
+        Ok(previous)
+    }
+
+    fn create(&self, name: &str, data: &[u8]) -> Res {
+        check_value_size(data, self.max_value_size)?;
+        let mut hash_map = self.hash_map.write().unwrap();
+        if hash_map.contains_key(name) {
+            return Err(Error::AlreadyExists(name.to_string()));
+        }
+        let _ = hash_map.insert(name.to_string(), data.to_vec());
+        self.changed(name, ChangeKind::Set);
+        empty_ok()
+    }
+
+    fn update_with(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(Option<Vec<u8>>) -> Option<Vec<u8>>,
+    ) -> Res {
+        let mut hash_map = self.hash_map.write().unwrap();
+        match f(hash_map.get(name).cloned()) {
+            Some(data) => {
+                check_value_size(&data, self.max_value_size)?;
+                let _ = hash_map.insert(name.to_string(), data.clone());
+                self.changed(name, ChangeKind::Set);
+                Ok(data)
+            }
+            None => {
+                if hash_map.remove(name).is_some() {
+                    self.changed(name, ChangeKind::Delete);
+                }
+                empty_ok()
+            }
+        }
+    }
+
+    fn delete_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        check_prefix(prefix)?;
+        let mut hash_map = self.hash_map.write().unwrap();
+        let names: Vec<String> = hash_map
+            .keys()
+            .filter(|name| name.starts_with(prefix))
+            .cloned()
+            .collect();
+        for name in &names {
+            let _ = hash_map.remove(name);
+            self.changed(name, ChangeKind::Delete);
+        }
+        Ok(names.len())
+    }
+
+    fn rename_prefix(&self, old: &str, new: &str) -> Result<usize, Error> {
+        check_prefix(old)?;
+        let mut hash_map = self.hash_map.write().unwrap();
+        let names: Vec<String> = hash_map
+            .keys()
+            .filter(|name| name.starts_with(old))
+            .cloned()
+            .collect();
+        if old == new {
+            return Ok(names.len());
+        }
+
+        // Remove all the keys before inserting any of them, so that a key
+        // whose new name is the old name of another key is not removed
+        // afterwards.
+        let mut entries = Vec::with_capacity(names.len());
+        for name in names {
+            let data = hash_map.remove(&name).unwrap();
+            self.changed(&name, ChangeKind::Delete);
+            entries.push((name, data));
+        }
+        let count = entries.len();
+        for (name, data) in entries {
+            let name = replace_prefix(&name, old, new);
+            let _ = hash_map.insert(name.clone(), data);
+            self.changed(&name, ChangeKind::Set);
+        }
+        Ok(count)
+    }
+
+    fn watch(&self, name: &str) -> Result<ChangeReceiver, Error> {
+        Ok(self.watchers.watch(name, false))
+    }
+
+    fn watch_prefix(&self, prefix: &str) -> Result<ChangeReceiver, Error> {
+        Ok(self.watchers.watch(prefix, true))
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn Cave + '_>, Error> {
+        Ok(Box::new(MemorySnapshot {
+            hash_map: self.hash_map.read().unwrap().clone(),
+        }))
+    }
+
+    fn lock(&self, name: &str) -> Result<KeyGuard<'_>, Error> {
+        Ok(self.locks.lock(name))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            watch: true,
+            snapshot: true,
+            lock: true,
+            binary_keys: true,
+            ..Capabilities::default()
+        }
+    }
+
+    /// Return 0, since this kv does not use the disk.
+    fn disk_usage(&self) -> Result<u64, Error> {
+        Ok(0)
+    }
+
+    fn stat(&self, name: &str) -> Result<Metadata, Error> {
+        let hash_map = self.hash_map.read().unwrap();
+        match hash_map.get(name) {
+            Some(data) => Ok(Metadata {
+                size: data.len() as u64,
+                modified: self.modified.lock().unwrap().get(name).copied(),
+            }),
+            None => Err(Error::NotFound(name.into())),
+        }
+    }
+
+    fn first_key(&self) -> Result<Option<String>, Error> {
+        Ok(self.hash_map.read().unwrap().keys().min().cloned())
+    }
+
+    fn last_key(&self) -> Result<Option<String>, Error> {
+        Ok(self.hash_map.read().unwrap().keys().max().cloned())
+    }
+
+    /// Call a function with the name and contents of every key.
+    ///
+    /// The keys are visited while holding a read lock, which blocks the
+    /// writes to the kv until the iteration is over. Therefore, the function
+    /// must not write to the kv, else it will deadlock.
+    fn for_each(&self, f: &mut VisitFn<'_>) -> Result<(), Error> {
+        let hash_map = self.hash_map.read().unwrap();
+        for (name, data) in hash_map.iter() {
+            f(name, data)?;
+        }
+        Ok(())
+    }
+
+    fn dump(&self) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+        Ok(self.hash_map.read().unwrap().clone())
+    }
+
+    fn count_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        let hash_map = self.hash_map.read().unwrap();
+        Ok(hash_map
+            .keys()
+            .filter(|name| name.starts_with(prefix))
+            .count())
+    }
+
+    fn swap(&self, a: &str, b: &str) -> Res {
+        let mut hash_map = self.hash_map.write().unwrap();
+        let mut data = match hash_map.remove(a) {
+            Some(data) => data,
+            None => return self.not_found(a),
+        };
+
+        match hash_map.get_mut(b) {
+            Some(data_b) => std::mem::swap(&mut data, data_b),
+            None if a == b => (),
+            None => {
+                let _ = hash_map.insert(a.to_string(), data);
+                return self.not_found(b);
+            }
+        }
+        let _ = hash_map.insert(a.to_string(), data);
+
+        self.changed(a, ChangeKind::Set);
+        if a != b {
+            self.changed(b, ChangeKind::Set);
+        }
+        empty_ok()
+    }
+
+    fn range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        let hash_map = self.hash_map.read().unwrap();
+        let mut entries: Vec<_> = hash_map
+            .iter()
+            .filter(|(name, _)| start <= name.as_str() && name.as_str() < end)
+            .map(|(name, data)| (name.clone(), data.clone()))
+            .collect();
+        entries.sort();
+        Ok(entries)
+    }
+}
+
+/// A read-only copy of the hash table of a `MemoryCave`.
+struct MemorySnapshot {
+    hash_map: collections::HashMap<String, Vec<u8>>,
+}
+
+impl fmt::Debug for MemorySnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemorySnapshot")
+            .field("keys", &self.hash_map.len())
+            .finish()
+    }
+}
+
+impl Cave for MemorySnapshot {
+    fn get(&self, name: &str) -> Res {
+        match self.hash_map.get(name) {
+            Some(data) => Ok(data.to_vec()),
+            None => self.not_found(name),
+        }
+    }
+
+    fn set(&self, _name: &str, _data: &[u8]) -> Res {
+        Err(Error::Unsupported { operation: "set" })
+    }
+
+    fn delete(&self, _name: &str) -> Res {
+        Err(Error::Unsupported {
+            operation: "delete",
+        })
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        Ok(self.hash_map.keys().cloned().collect())
+    }
+}
+
+/// A key-value store that stores nothing.
+///
+/// This kv accepts every `set`/`delete` call without storing anything, so
+/// `get` always returns an [`Error::NotFound`] error and `keys` always returns
+/// an empty list. Note that `delete` succeeds even though the key does not
+/// exist.
+///
+/// Consider using this kv as a baseline when benchmarking, or as a stand-in
+/// in tests where the storage behavior is irrelevant.
+///
+/// [`Error::NotFound`]: errors/enum.Error.html#variant.NotFound
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NullCave;
+
+impl NullCave {
+    /// Create a new instance.
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Cave for NullCave {
+    fn get(&self, name: &str) -> Res {
+        self.not_found(name)
+    }
+
+    fn set(&self, _name: &str, _data: &[u8]) -> Res {
+        empty_ok()
+    }
+
+    fn delete(&self, _name: &str) -> Res {
+        empty_ok()
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        Ok(Vec::new())
+    }
+}
+
+/// The subdirectory where a `FileCave` keeps the files that it locks.
+const FILE_LOCKS_DIR: &str = ".locks";
+
+/// The subdirectory where a `FileCave` keeps the keys whose names are not
+/// valid UTF-8.
+const FILE_BINARY_DIR: &str = ".binary";
+
+/// The subdirectory where a `FileCave` keeps the log of its small values.
+const FILE_INLINE_DIR: &str = ".inline";
+
+/// The prefixes of the temporary directories that a `FileCave` creates, and
+/// that may be left behind if an operation is interrupted. The first one is
+/// used by `atomicwrites` during a `set`, and the rest during a `take`, a
+/// `swap` and a `rename_prefix` respectively.
+const FILE_TEMP_PREFIXES: [&str; 4] = [".atomicwrite", ".take", ".swap", ".rename"];
+
+/// How a `FileCave` writes the file of a key.
+///
+/// See the [atomicity](struct.FileCave.html#atomicity) section of `FileCave`
+/// for the differences between the strategies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteStrategy {
+    /// Write the contents in a temporary file, sync it, and then rename it
+    /// over the file of the key. This is the default strategy.
+    #[default]
+    Atomic,
+
+    /// Truncate the file of the key, and write the contents in it directly.
+    Truncate,
+}
+
+/// A key-value store that stores keys in files.
+///
+/// This kv stores keys as files in a directory. Note that the directory must
+/// exist.
+///
+/// ## Caveats
+///
+/// This kv has the following caveats:
+///
+/// * Writes with the default [`WriteStrategy::Atomic`] strategy and writes of
+///   inline values are synced (see [atomicity](#atomicity)), but deletes and
+///   writes with the `Truncate` strategy are not, so a crash may lose them.
+/// * It doesn't create multi-level directories, e.g., `fi/le/name`, to improve
+///   filesystem lookups.
+///
+/// Consider using it when you want to audit which keys are created using
+/// external tools, such as `ls`, `cat`.
+///
+/// ## Atomicity
+///
+/// By default, a `set` writes the contents of a key in a temporary file, and
+/// then renames it over the file of the key. Since renames are atomic, a
+/// concurrent `get` reads either the old or the new contents, but never a mix
+/// of them. This holds as long as the files are not modified by external
+/// tools. Also, the temporary file is synced before the rename, so a crash
+/// leaves either the old or the new contents on disk.
+///
+/// For small values, the extra file and the syncs can dominate the cost of a
+/// `set`. If you don't need these guarantees, you can use the
+/// [`WriteStrategy::Truncate`] strategy (see [`with_write_strategy`]), which
+/// writes the file of the key in place. In this case, a concurrent `get` may
+/// read partially written contents, and a crash may leave the file of the
+/// key empty or partially written.
+///
+/// A `create` creates the file of the key exclusively, so that, of multiple
+/// processes that create the same key, only one succeeds. With the default
+/// strategy, the contents are written in a temporary file, which is then
+/// hard-linked to the path of the key, so the file never appears partially
+/// written. With the `Truncate` strategy, the file of the key is opened with
+/// `O_CREAT | O_EXCL`, and written in place. If values are stored inline (see
+/// below), a `create` is serialized with the lock of the key instead.
+///
+/// A `swap` renames the files of the two keys in three steps, through a
+/// temporary file. Each key always has either its old or its new contents,
+/// but a concurrent `get` of the first key may briefly find it missing.
+///
+/// ## Compression
+///
+/// This kv can optionally store the values gzip-compressed (see
+/// [`with_gzip`]), to reduce its disk footprint. In this case, a key is
+/// stored in a file with the `.gz` extension, e.g., `key.gz`, so that it can
+/// still be inspected with external tools, such as `zcat`.
+///
+/// ## Locking
+///
+/// The keys can be locked with `lock`, which locks a file with the name of
+/// the key, or its hash, in the `.locks` subdirectory, using the file locks
+/// of the OS. The locks exclude other processes that use the same directory
+/// as well. The lock files are not removed when they are unlocked.
+///
+/// ## Binary keys
+///
+/// The keys whose names are not valid UTF-8 (see [`Cave::get_bytes`]) cannot
+/// be used as file names as is. Instead, they are stored in the `.binary`
+/// subdirectory, in files named after the hex encoding of their names.
+///
+/// ## Hashed names
+///
+/// Most filesystems limit the names of files to 255 bytes, so keys with
+/// longer names, e.g., URLs, cannot be stored in files named after them. With
+/// [`with_hashed_names`], a key is stored in a file named after the
+/// hex-encoded SHA-256 hash of its name instead, and its name is stored in a
+/// header at the start of the file, before the contents, so that `keys()`
+/// can return it.
+///
+/// The stored name is compared with the requested one whenever a file is
+/// read, so two names with the same hash never get mixed up. A `get` of a
+/// key whose file holds another name reports the key as missing, and a `set`
+/// returns an internal error instead of overwriting the other key. Since the
+/// stored names must match the names of the files, a `swap` rewrites the
+/// two files, instead of renaming them.
+///
+/// ## Inline values
+///
+/// Every key takes a file, and therefore an inode, regardless of its size.
+/// With [`with_inline_threshold`], the values that are smaller than a
+/// threshold are appended to a log in the `.inline` subdirectory instead,
+/// while the larger ones are stored in files as usual. A `set` moves a key
+/// between the log and its file, when its size crosses the threshold, and
+/// holds the lock of the log while doing so, so that concurrent writes of the
+/// key don't remove each other's contents.
+///
+/// Each instance keeps an index of the log in memory, and updates it with the
+/// records that other instances, or processes, append to the log, so the
+/// directory can still be shared. The log grows with every write of a small
+/// value, until `maintain` compacts it. The inline values are not compressed,
+/// and they don't have a modification time. Also, a `swap` or a
+/// `rename_prefix` rewrites the keys, instead of renaming their files.
+///
+/// [`Cave::get_bytes`]: trait.Cave.html#method.get_bytes
+/// [`with_hashed_names`]: #method.with_hashed_names
+/// [`with_inline_threshold`]: #method.with_inline_threshold
+/// [`with_gzip`]: #method.with_gzip
+/// [`with_write_strategy`]: #method.with_write_strategy
+/// [`WriteStrategy::Atomic`]: enum.WriteStrategy.html#variant.Atomic
+/// [`WriteStrategy::Truncate`]: enum.WriteStrategy.html#variant.Truncate
+#[derive(Debug)]
+pub struct FileCave {
+    dir: sync::RwLock<path::PathBuf>,
+    gzip_level: Option<u32>,
+    max_value_size: Option<usize>,
+    write_strategy: WriteStrategy,
+    no_follow: bool,
+    hashed_names: bool,
+    inline: sync::RwLock<Option<sync::Arc<inline_log::InlineLog>>>,
+    ignore: Vec<String>,
+    /// Every operation passes through the gate, which `reopen` closes while
+    /// it switches to another directory.
+    gate: gate::Gate,
+    #[cfg(feature = "test-util")]
+    crash_hook: Option<crash::CrashHook>,
+}
+
+impl FileCave {
+    /// Create a new instance.
+    ///
+    /// Check if the provided path is a directory and that it exists. Use
+    /// [`create`] to create it instead.
+    ///
+    /// [`create`]: #method.create
+    pub fn new(dir: &path::Path) -> Result<Self, Error> {
+        // Return an error if the path is invalid or if we don't have enough
+        // permissions to get its metadata [1].
+        //
+        // [1]: https://doc.rust-lang.org/std/fs/fn.metadata.html#errors
+        let md = match fs::metadata(dir) {
+            Err(e) => return Err(Error::Internal(e.into())),
+            Ok(md) => md,
+        };
+
+        // Return an error if the path is valid, but is not a directory.
+        if !md.is_dir() {
+            return Err(Error::internal_from_msg(format!(
+                "Provided path is not a directory: {:?}",
+                dir
+            )));
+        }
+
+        Ok(Self {
+            dir: sync::RwLock::new(dir.to_owned()),
+            gzip_level: None,
+            max_value_size: None,
+            write_strategy: WriteStrategy::default(),
+            no_follow: false,
+            hashed_names: false,
+            inline: sync::RwLock::new(None),
+            ignore: Vec::new(),
+            gate: gate::Gate::default(),
+            #[cfg(feature = "test-util")]
+            crash_hook: None,
+        })
+    }
+
+    /// Create a new instance, and create its directory if it does not exist.
+    ///
+    /// Any missing parent directories are created as well, which matches the
+    /// behavior of `RocksDBCave::new`. If the path exists but is not a
+    /// directory, return an error.
+    pub fn create(dir: &path::Path) -> Result<Self, Error> {
+        if let Err(e) = fs::create_dir_all(dir) {
+            return Err(Error::Internal(e.into()));
+        }
+        Self::new(dir)
+    }
+
+    /// Create a new instance in a temporary directory.
+    ///
+    /// Return the instance along with the guard of the directory, which
+    /// removes it and the stored keys when it's dropped. This is meant for
+    /// tests, so it's available only with the `test-util` feature.
+    ///
+    /// ## Usage
+    ///
+    /// ```
+    /// use caves::{Cave, FileCave};
+    ///
+    /// let (fb, temp_dir) = FileCave::temporary().unwrap();
+    /// fb.set("key", b"value").unwrap();
+    /// assert!(temp_dir.path().join("key").exists());
+    /// ```
+    #[cfg(feature = "test-util")]
+    pub fn temporary() -> Result<(Self, TempDir), Error> {
+        let temp_dir = match tempfile::tempdir() {
+            Ok(temp_dir) => temp_dir,
+            Err(e) => return Err(Error::Internal(e.into())),
+        };
+        let fb = Self::new(temp_dir.path())?;
+        Ok((fb, temp_dir))
+    }
+
+    /// Store the values gzip-compressed, using the provided compression level.
+    ///
+    /// The compression level must be between 0 (no compression) and 9 (best
+    /// compression). Higher levels are treated as 9.
+    ///
+    /// Note that a gzip-compressed kv ignores any files in its directory that
+    /// don't have the `.gz` extension, and vice versa.
+    pub fn with_gzip(mut self, level: u32) -> Self {
+        self.gzip_level = Some(level.min(9));
+        self
+    }
+
+    /// Reject values that are larger than `limit` bytes.
+    ///
+    /// A `set` with a larger value returns an [`Error::ValueTooLarge`] error,
+    /// before any file is created. The limit applies to the uncompressed
+    /// size of the value.
+    ///
+    /// [`Error::ValueTooLarge`]: errors/enum.Error.html#variant.ValueTooLarge
+    pub fn with_max_value_size(mut self, limit: usize) -> Self {
+        self.max_value_size = Some(limit);
+        self
+    }
+
+    /// Set how the files of the keys are written.
+    ///
+    /// See the [atomicity](#atomicity) section for more info.
+    pub fn with_write_strategy(mut self, strategy: WriteStrategy) -> Self {
+        self.write_strategy = strategy;
+        self
+    }
+
+    /// Do not follow symbolic links in the place of key files.
+    ///
+    /// By default, if the file of a key is a symbolic link, e.g., one that
+    /// another user created in a shared directory, `get` reads the file that
+    /// it points to, which can be any file. With this option, accessing such
+    /// a key returns an [`Error::InvalidName`] error instead.
+    ///
+    /// On Unix, the files are opened with the `O_NOFOLLOW` flag, so the check
+    /// is atomic. On other platforms, the path of a key is checked before it's
+    /// opened, which is racy. Note that a `set` replaces a symbolic link with
+    /// a regular file, unless the [`WriteStrategy::Truncate`] strategy is
+    /// used, in which case it's rejected as well.
+    ///
+    /// [`Error::InvalidName`]: errors/enum.Error.html#variant.InvalidName
+    /// [`WriteStrategy::Truncate`]: enum.WriteStrategy.html#variant.Truncate
+    pub fn with_no_follow(mut self) -> Self {
+        self.no_follow = true;
+        self
+    }
+
+    /// Store the keys in files named after the hash of their names.
+    ///
+    /// This allows keys with arbitrarily long names. See the
+    /// [hashed names](#hashed-names) section for more info. Note that a
+    /// directory must always be opened with the same option, since the files
+    /// of the keys are named and written differently with it.
+    pub fn with_hashed_names(mut self) -> Self {
+        self.hashed_names = true;
+        self
+    }
+
+    /// Store the values that are smaller than `threshold` bytes in a shared
+    /// log, instead of a file each.
+    ///
+    /// See the [inline values](#inline-values) section for more info. Note
+    /// that a directory must always be opened with this option, once it has
+    /// been used, since the keys in the log are not visible without it.
+    pub fn with_inline_threshold(mut self, threshold: usize) -> Self {
+        let path = self.dir().join(FILE_INLINE_DIR).join("log");
+        let log = inline_log::InlineLog::new(path, threshold);
+        *self.inline.get_mut().unwrap() = Some(sync::Arc::new(log));
+        self
+    }
+
+    /// Skip the files that match any of the provided patterns, when listing
+    /// the keys.
+    ///
+    /// This allows the directory of the kv to contain files that are not
+    /// keys, e.g., lock or metadata files of the application, without them
+    /// appearing in `keys()` and the other methods that list keys. The
+    /// patterns are matched against the whole file name, and may contain `*`,
+    /// which matches any sequence of characters, and `?`, which matches a
+    /// single character. Other characters match themselves.
+    ///
+    /// Note that the ignored files can still be accessed as keys by name.
+    ///
+    /// ## Usage
+    ///
+    /// ```
+    /// use caves::{Cave, FileCave};
+    ///
+    /// let dir = tempfile::tempdir().unwrap();
+    /// std::fs::write(dir.path().join("app.lock"), b"").unwrap();
+    ///
+    /// let fb = FileCave::new(dir.path()).unwrap().with_ignore(&["*.lock"]);
+    /// fb.set("key", b"value").unwrap();
+    /// assert_eq!(fb.keys().unwrap(), vec!["key"]);
+    /// ```
+    pub fn with_ignore(mut self, patterns: &[&str]) -> Self {
+        self.ignore
+            .extend(patterns.iter().map(|pattern| pattern.to_string()));
+        self
+    }
+
+    /// Switch to another directory at runtime, e.g., in order to move the
+    /// storage of a long-running process to another disk.
+    ///
+    /// The new directory is checked the same way as in [`new`], and if it's
+    /// invalid, an error is returned and the current directory is kept. The
+    /// keys are not moved. From then on, the instance reads and writes the
+    /// keys of the new directory only, with the same options. If small values
+    /// are stored inline, the log of the new directory is used as well.
+    ///
+    /// The switch waits for the operations that are in progress in other
+    /// threads to finish, and the operations that start in the meantime block
+    /// until it's complete, so no operation spans both directories. This
+    /// includes the methods of [`Cave`] that consist of multiple operations,
+    /// e.g., `replace`, `delete_prefix` or `for_each`, which the switch waits
+    /// for as a whole, along with their callbacks. Since a `FileCave` does not
+    /// buffer writes, every completed write is in the old directory by the
+    /// time the switch happens. However, the key locks (see [`Cave::lock`])
+    /// that have been requested before the switch still lock the keys of the
+    /// old directory, and the switch does not wait for the ones that are
+    /// still blocked. If this method is called by a thread that is in the
+    /// middle of an operation of the same instance, e.g., from the callback of
+    /// `for_each`, it returns an error, instead of waiting for itself.
+    ///
+    /// ## Usage
+    ///
+    /// ```
+    /// use caves::{Cave, FileCave};
+    ///
+    /// let old_dir = tempfile::tempdir().unwrap();
+    /// let new_dir = tempfile::tempdir().unwrap();
+    /// let fb = FileCave::new(old_dir.path()).unwrap();
+    /// fb.set("key", b"value").unwrap();
+    ///
+    /// fb.reopen(new_dir.path()).unwrap();
+    /// assert!(fb.get("key").is_err());
+    /// fb.set("key", b"new value").unwrap();
+    /// assert!(new_dir.path().join("key").exists());
+    /// ```
+    ///
+    /// [`new`]: #method.new
+    /// [`Cave`]: trait.Cave.html
+    /// [`Cave::lock`]: trait.Cave.html#method.lock
+    pub fn reopen(&self, new_dir: &path::Path) -> Result<(), Error> {
+        // Check the new directory before waiting for the operations.
+        let _ = Self::new(new_dir)?;
+        let _closed = match self.gate.close() {
+            Some(closed) => closed,
+            None => {
+                return Err(Error::internal_from_msg(
+                    "Cannot switch directories from within an operation".to_string(),
+                ))
+            }
+        };
+
+        let mut inline = self.inline.write().unwrap();
+        if let Some(threshold) = inline.as_ref().map(|log| log.threshold()) {
+            let path = new_dir.join(FILE_INLINE_DIR).join("log");
+            let log = inline_log::InlineLog::new(path, threshold);
+            *inline = Some(sync::Arc::new(log));
+        }
+        *self.dir.write().unwrap() = new_dir.to_owned();
+        Ok(())
+    }
+
+    /// Return the current directory.
+    fn dir(&self) -> path::PathBuf {
+        self.dir.read().unwrap().clone()
+    }
+
+    /// Return the log of small values of the current directory, if any.
+    fn inline(&self) -> Option<sync::Arc<inline_log::InlineLog>> {
+        self.inline.read().unwrap().clone()
+    }
+
+    /// Open the lock file of a key, and return it along with the directory
+    /// it's in.
+    ///
+    /// Only this step passes through the gate, since waiting for the lock
+    /// within it could block `reopen`, and in turn the holder of the lock.
+    fn open_lock_file(&self, name: &str) -> Result<(path::PathBuf, fs::File), Error> {
+        let _gate = self.gate.enter();
+        let dir = self.dir();
+        // The lock files are kept in a subdirectory, which `keys()` skips.
+        let locks_dir = dir.join(FILE_LOCKS_DIR);
+        if let Err(e) = fs::create_dir_all(&locks_dir) {
+            return Err(Error::Internal(e.into()));
+        }
+        let mut opts = fs::OpenOptions::new();
+        let _ = opts.write(true).create(true);
+        match opts.open(locks_dir.join(self.file_name(name))) {
+            Ok(file) => Ok((dir, file)),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    /// Return the name of the file of a key, without any extension.
+    fn file_name(&self, name: &str) -> String {
+        if self.hashed_names {
+            content_hash(name.as_bytes())
+        } else {
+            name.to_string()
+        }
+    }
+
+    fn create_path(&self, name: &str) -> path::PathBuf {
+        let file_name = self.file_name(name);
+        match self.gzip_level {
+            Some(_) => self.dir().join(format!("{}.gz", file_name)),
+            None => self.dir().join(file_name),
+        }
+    }
+
+    /// Call a function with the name of the file that stores a key whose name
+    /// is not valid UTF-8, relative to the directory of the kv.
+    ///
+    /// The errors that the function returns refer to the name of the file, so
+    /// we report them with the original name of the key instead.
+    fn with_binary_name(&self, name: &[u8], f: impl FnOnce(&str) -> Res) -> Res {
+        let hex: String = name.iter().map(|b| format!("{:02x}", b)).collect();
+        match f(&format!("{}/{}", FILE_BINARY_DIR, hex)) {
+            Err(Error::NotFound(_)) => self.not_found(&String::from_utf8_lossy(name)),
+            Err(Error::InvalidName { reason, .. }) => Err(Error::InvalidName {
+                name: String::from_utf8_lossy(name).into_owned(),
+                reason,
+            }),
+            res => res,
+        }
+    }
+
+    /// Return the name of the key that is stored in a file, or `None` if the
+    /// file does not belong to this kv.
+    fn key_from_file_name<'a>(&self, file_name: &'a str) -> Option<&'a str> {
+        match self.gzip_level {
+            Some(_) => file_name.strip_suffix(".gz"),
+            None => Some(file_name),
+        }
+    }
+
+    /// Write the contents of a key in its file, compressing them if needed.
+    ///
+    /// If the names of the files are hashed, the name of the key is written
+    /// first, as part of the contents.
+    fn encode<W: Write>(&self, f: W, name: &str, data: &[u8]) -> io::Result<()> {
+        #[cfg(feature = "test-util")]
+        {
+            if let Some(hook) = &self.crash_hook {
+                return self.encode_to(hook.writer(f), name, data);
+            }
+        }
+        self.encode_to(f, name, data)
+    }
+
+    /// Same as `encode`, but without the crash hook of the tests.
+    fn encode_to<W: Write>(&self, f: W, name: &str, data: &[u8]) -> io::Result<()> {
+        match self.gzip_level {
+            Some(level) => {
+                let level = flate2::Compression::new(level);
+                let mut encoder = flate2::write::GzEncoder::new(f, level);
+                self.write_header(&mut encoder, name)?;
+                encoder.write_all(data)?;
+                encoder.finish().map(|_| ())
+            }
+            None => {
+                let mut f = f;
+                self.write_header(&mut f, name)?;
+                f.write_all(data)
+            }
+        }
+    }
+
+    /// Write the name of a key, prefixed with its length, if the names of
+    /// the files are hashed.
+    fn write_header<W: Write>(&self, f: &mut W, name: &str) -> io::Result<()> {
+        if !self.hashed_names {
+            return Ok(());
+        }
+        f.write_all(&(name.len() as u64).to_be_bytes())?;
+        f.write_all(name.as_bytes())
+    }
+
+    /// Return a reader that decompresses the contents of a file, if needed.
+    fn decoder<'r, R: Read + 'r>(&self, r: R) -> Box<dyn Read + 'r> {
+        match self.gzip_level {
+            Some(_) => Box::new(flate2::read::GzDecoder::new(r)),
+            None => Box::new(r),
+        }
+    }
+
+    /// Return the contents of a key, given the contents of its file.
+    fn decode(&self, name: &str, buf: Vec<u8>) -> Res {
+        if self.gzip_level.is_none() && !self.hashed_names {
+            return Ok(buf);
+        }
+
+        let mut reader = self.decoder(&buf[..]);
+        if self.hashed_names {
+            match read_header(&mut reader) {
+                Ok(stored) if stored == name => (),
+                // The file stores another key, whose name has the same hash.
+                Ok(_) => return self.not_found(name),
+                Err(e) => return Err(Error::Internal(e.into())),
+            }
+        }
+
+        let mut data = Vec::new();
+        match reader.read_to_end(&mut data) {
+            Ok(_) => Ok(data),
+            // The file is not a valid gzip file, which means that it was
+            // corrupted or modified externally.
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    /// Return the name of the key that a file stores in its header.
+    fn stored_name(&self, path: &path::Path) -> io::Result<String> {
+        let file = self.open_file(path, fs::OpenOptions::new().read(true))?;
+        read_header(self.decoder(file))
+    }
+
+    /// Check whether the file of a key exists, without reading its contents.
+    ///
+    /// If the names of the files are hashed, the header of the file is read
+    /// as well, to check that it belongs to the key.
+    fn exists(&self, name: &str) -> Result<bool, Error> {
+        if self.inline_size(name)?.is_some() {
+            return Ok(true);
+        }
+
+        let res = if self.hashed_names {
+            self.check_stored_name(name)
+        } else {
+            match fs::metadata(self.create_path(name)) {
+                Ok(md) if md.is_file() => Ok(()),
+                Ok(_) => self.check_not_dir(name),
+                Err(e) => Err(self.convert_io_error(e, name)),
+            }
+        };
+
+        match res {
+            Ok(_) => Ok(true),
+            Err(Error::NotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Return the size of the contents of a key, if they are stored in the
+    /// log of small values.
+    fn inline_size(&self, name: &str) -> Result<Option<u64>, Error> {
+        match self.inline() {
+            Some(log) => log.size(name).map_err(|e| Error::Internal(e.into())),
+            None => Ok(None),
+        }
+    }
+
+    /// Write the contents of a key in its file.
+    fn set_file(&self, name: &str, data: &[u8]) -> Res {
+        let path = self.create_path(name);
+
+        // Don't overwrite a key whose name has the same hash. Any other error
+        // is reported by the write below.
+        if self.hashed_names {
+            match self.stored_name(&path) {
+                Ok(stored) if stored != name => {
+                    return Err(Error::internal_from_msg(format!(
+                        "The hash of key `{}` collides with key `{}`",
+                        name, stored
+                    )))
+                }
+                _ => (),
+            }
+        }
+
+        if self.write_strategy == WriteStrategy::Truncate {
+            let mut opts = fs::OpenOptions::new();
+            let _ = opts.write(true).create(true).truncate(true);
+            let res = self
+                .open_file(&path, &mut opts)
+                .and_then(|f| self.encode(f, name, data));
+            return match res {
+                Ok(_) => empty_ok(),
+                // Same as below, a missing directory is an internal error.
+                Err(e) => {
+                    self.check_not_symlink(name)?;
+                    self.check_not_dir(name)?;
+                    Err(Error::Internal(e.into()))
+                }
+            };
+        }
+
+        let af = atomicwrites::AtomicFile::new(path, atomicwrites::AllowOverwrite);
+        let res = af.write(|f| self.encode(f, name, data));
+        match res {
+            Ok(_) => empty_ok(),
+            // The `atomicwrites` crate provides two types of errors [1]:
+            //
+            // * Internal: This is a library error that happens when the
+            //   tempfile cannot be created or moved. We treat it as an
+            //   internal error, because it's essentially an io:Error that can
+            //   happen, e.g., if there are no proper permissions in the
+            //   directory.
+            // * User: This the error of the lambda expression. In our case,
+            //   our lambda is very simple so we can't have a bug. If it fails,
+            //   it may be due to a ENOSPC error, which is also an internal
+            //   error.
+            //
+            // So, that's why we treat all the `atomicwrites` errors as
+            // internal errors.
+            //
+            // The only exception is when the key is a directory, which is a
+            // user error.
+            //
+            // [1]: https://docs.rs/atomicwrites/0.2.5/atomicwrites/enum.Error.html
+            Err(e) => {
+                self.check_not_dir(name)?;
+                Err(Error::Internal(e.into()))
+            }
+        }
+    }
+
+    /// Create the file of a key exclusively, i.e., fail if it exists.
+    fn create_file(&self, name: &str, data: &[u8]) -> Res {
+        let path = self.create_path(name);
+        let res = if self.write_strategy == WriteStrategy::Truncate {
+            // This is equivalent to `O_CREAT | O_EXCL`.
+            let mut opts = fs::OpenOptions::new();
+            let _ = opts.write(true).create_new(true);
+            let f = self.open_file(&path, &mut opts);
+            f.and_then(|f| match self.encode(f, name, data) {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    // Don't leave a partially written key behind.
+                    let _ = fs::remove_file(&path);
+                    Err(e)
+                }
+            })
+        } else {
+            // The temporary file is hard-linked to the path of the key, which
+            // fails if the path exists, same as `O_EXCL`, but the file of the
+            // key never appears partially written.
+            let af = atomicwrites::AtomicFile::new(&path, atomicwrites::DisallowOverwrite);
+            af.write(|f| self.encode(f, name, data))
+                .map_err(io::Error::from)
+        };
+
+        match res {
+            Ok(_) => empty_ok(),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                self.check_not_symlink(name)?;
+                self.check_not_dir(name)?;
+                if self.hashed_names {
+                    match self.stored_name(&path) {
+                        Ok(stored) if stored != name => {
+                            return Err(Error::internal_from_msg(format!(
+                                "The hash of key `{}` collides with key `{}`",
+                                name, stored
+                            )))
+                        }
+                        _ => (),
+                    }
+                }
+                Err(Error::AlreadyExists(name.to_string()))
+            }
+            Err(e) => {
+                self.check_not_dir(name)?;
+                Err(Error::Internal(e.into()))
+            }
+        }
+    }
+
+    /// Delete the file of a key.
+    fn delete_file(&self, name: &str) -> Res {
+        self.check_stored_name(name)?;
+        let path = self.create_path(name);
+        match fs::remove_file(path) {
+            Ok(_) => empty_ok(),
+            Err(e) => Err(self.convert_io_error(e, name)),
+        }
+    }
+
+    /// Return a `NotFound` error if the file of a key stores another key,
+    /// whose name has the same hash.
+    fn check_stored_name(&self, name: &str) -> Result<(), Error> {
+        if !self.hashed_names {
+            return Ok(());
+        }
+        match self.stored_name(&self.create_path(name)) {
+            Ok(stored) if stored == name => Ok(()),
+            Ok(_) => Err(Error::NotFound(name.into())),
+            Err(e) => Err(self.convert_io_error(e, name)),
+        }
+    }
+
+    /// Return an error if the path of a key is a directory.
+    ///
+    /// Directories can be created in the kv by external tools, but they
+    /// cannot be accessed as keys.
+    fn check_not_dir(&self, name: &str) -> Result<(), Error> {
+        if self.create_path(name).is_dir() {
+            return Err(Error::InvalidName {
+                name: name.to_string(),
+                reason: "the path of the key is a directory",
+            });
+        }
+        Ok(())
+    }
+
+    /// Call a function with the name of every key in the directory.
+    fn for_each_key<F: FnMut(&str)>(&self, mut f: F) -> Result<(), Error> {
+        // The keys in the log of small values may still have a file, if they
+        // are being moved to the log, so we must not report them twice.
+        let inline: collections::HashSet<String> = match self.inline() {
+            Some(log) => match log.names() {
+                Ok(names) => names.into_iter().collect(),
+                Err(e) => return Err(Error::Internal(e.into())),
+            },
+            None => collections::HashSet::new(),
+        };
+        let binary_prefix = format!("{}/", FILE_BINARY_DIR);
+        for name in &inline {
+            if !name.starts_with(&binary_prefix) {
+                f(name);
+            }
+        }
+        let mut f = |name: &str| {
+            if !inline.contains(name) {
+                f(name)
+            }
+        };
+
+        let entries = match fs::read_dir(self.dir()) {
+            Ok(entries) => entries,
+            Err(e) => return Err(Error::Internal(e.into())),
+        };
+
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => return Err(Error::Internal(e.into())),
+            };
+
+            // Skip anything that is not a regular file, such as the temporary
+            // directories that `atomicwrites` creates during a `set`.
+            match entry.file_type() {
+                Ok(ft) if ft.is_file() => (),
+                Ok(_) => continue,
+                Err(e) => return Err(Error::Internal(e.into())),
+            }
+
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            if self.ignore.iter().any(|p| glob_match(p, &file_name)) {
+                continue;
+            }
+
+            // The names of the files are hashes, so we read the names of the
+            // keys from the files. Skip the files that we cannot read, e.g.,
+            // because they don't have a header or they were just deleted.
+            if self.hashed_names {
+                if let Ok(name) = self.stored_name(&entry.path()) {
+                    if !name.starts_with(&binary_prefix) {
+                        f(&name);
+                    }
+                }
+                continue;
+            }
+
+            // Skip files whose names are not valid UTF-8. These cannot have
+            // been created by us, and they cannot be accessed by name anyway.
+            if let Ok(file_name) = entry.file_name().into_string() {
+                if let Some(name) = self.key_from_file_name(&file_name) {
+                    f(name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Return an error if the path of a key is a symbolic link, and the kv
+    /// must not follow it.
+    fn check_not_symlink(&self, name: &str) -> Result<(), Error> {
+        if !self.no_follow {
+            return Ok(());
+        }
+
+        match fs::symlink_metadata(self.create_path(name)) {
+            Ok(md) if md.file_type().is_symlink() => Err(Error::InvalidName {
+                name: name.to_string(),
+                reason: "the path of the key is a symbolic link",
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Open the file of a key, without following symbolic links if the kv
+    /// must not follow them.
+    fn open_file(&self, path: &path::Path, opts: &mut fs::OpenOptions) -> io::Result<fs::File> {
+        if self.no_follow {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                let _ = opts.custom_flags(libc::O_NOFOLLOW);
+            }
+
+            #[cfg(not(unix))]
+            {
+                if let Ok(md) = fs::symlink_metadata(path) {
+                    if md.file_type().is_symlink() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            "the path is a symbolic link",
+                        ));
+                    }
+                }
+            }
+        }
+        opts.open(path)
+    }
+
+    fn convert_io_error(&self, e: io::Error, name: &str) -> Error {
+        // Find out if the operation failed because the key is a directory or
+        // a symbolic link, since the OS reports it with various, confusing
+        // errors.
+        if let Err(e) = self.check_not_symlink(name) {
+            return e;
+        }
+        if let Err(e) = self.check_not_dir(name) {
+            return e;
+        }
+
+        match e.kind() {
+            io::ErrorKind::NotFound => Error::NotFound(name.into()),
+            _ => Error::Internal(e.into()),
+        }
+    }
+}
+
+/// Read an open file until EOF.
+///
+/// Unlike `fs::read`, this function does not rely on the size of the file
+/// that its metadata report, since the file may be truncated or extended by
+/// external tools after we've checked it. The file is opened once, so an
+/// atomic rename over its path does not affect us.
+fn read_file(mut file: fs::File) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    let _ = file.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Read the name of a key from the header of a file, which `FileCave` writes
+/// if the names of the files are hashed.
+fn read_header<R: Read>(mut r: R) -> io::Result<String> {
+    let mut len = [0; 8];
+    r.read_exact(&mut len)?;
+    let len = u64::from_be_bytes(len);
+
+    // Don't trust the length to allocate the buffer, since the file may not
+    // have a header at all.
+    let mut name = Vec::new();
+    let _ = r.take(len).read_to_end(&mut name)?;
+    if name.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    match String::from_utf8(name) {
+        Ok(name) => Ok(name),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+/// Sum the sizes of the files in a directory and its subdirectories.
+///
+/// Symbolic links are not followed, and count as the size of the link
+/// itself.
+fn dir_size(dir: &path::Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = fs::symlink_metadata(entry.path())?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Check if a name matches a pattern, where `*` matches any sequence of
+/// characters, and `?` matches a single character.
+/// Lock the lock file of a key, and block until the lock is acquired.
+///
+/// Every call opens the lock file anew, so the lock excludes the other
+/// threads of this process, as well as other processes. The lock is released
+/// when the file is closed.
+fn lock_file<'a>(name: &str, file: fs::File) -> Result<KeyGuard<'a>, Error> {
+    loop {
+        match file.lock() {
+            Ok(_) => return Ok(KeyGuard::new(name, file)),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Error::Internal(e.into())),
+        }
+    }
+}
+
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // The position of the last `*` in the pattern, and of the name when we
+    // reached it, so that we can backtrack and let it match one more
+    // character.
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+impl Cave for FileCave {
+    fn get(&self, name: &str) -> Res {
+        let _gate = self.gate.enter();
+        if let Some(log) = self.inline() {
+            match log.get(name) {
+                Ok(Some(data)) => return Ok(data),
+                Ok(None) => (),
+                Err(e) => return Err(Error::Internal(e.into())),
+            }
+        }
+
+        let path = self.create_path(name);
+        let res = self
+            .open_file(&path, fs::OpenOptions::new().read(true))
+            .and_then(read_file);
+
+        match res {
+            Ok(buf) => self.decode(name, buf),
+            Err(e) => Err(self.convert_io_error(e, name)),
+        }
+    }
+
+    fn set(&self, name: &str, data: &[u8]) -> Res {
+        let _gate = self.gate.enter();
+        check_value_size(data, self.max_value_size)?;
+        let log = match self.inline() {
+            Some(log) => log,
+            None => return self.set_file(name, data),
+        };
+
+        // Write the new contents before removing the old ones, so that the
+        // key never appears missing. The contents in the log take precedence
+        // over the ones in the file. Both steps hold the exclusive lock of
+        // the log, so that concurrent writes of the key, even from other
+        // processes, don't remove each other's contents.
+        let fits = log.fits(data);
+        if fits {
+            self.check_not_dir(name)?;
+        }
+        log.locked(|log| {
+            if fits {
+                log.set(name, data)?;
+                match self.delete_file(name) {
+                    Ok(_) | Err(Error::NotFound(_)) => Ok(()),
+                    Err(e) => Err(e.into()),
+                }
+            } else {
+                let _ = self.set_file(name, data)?;
+                let _ = log.delete(name)?;
+                Ok(())
+            }
+        })?;
+        empty_ok()
+    }
+
+    fn create(&self, name: &str, data: &[u8]) -> Res {
+        check_value_size(data, self.max_value_size)?;
+        {
+            let _gate = self.gate.enter();
+            if self.inline().is_none() {
+                return self.create_file(name, data);
+            }
+        }
+
+        // The key may be stored either in the log or in a file, so creating
+        // the file exclusively is not enough. Instead, the creation is
+        // serialized with the lock of the key, which other processes respect
+        // as well. The lock is acquired outside the gate, so that `reopen`
+        // doesn't wait for it, and if the directory has been switched in the
+        // meantime, it's acquired again.
+        loop {
+            let (dir, file) = self.open_lock_file(name)?;
+            let _guard = lock_file(name, file)?;
+            let _gate = self.gate.enter();
+            if self.dir() != dir {
+                continue;
+            }
+            if self.exists(name)? {
+                return Err(Error::AlreadyExists(name.to_string()));
+            }
+            return self.set(name, data);
+        }
+    }
+
+    fn delete(&self, name: &str) -> Res {
+        let _gate = self.gate.enter();
+        let log = match self.inline() {
+            Some(log) => log,
+            None => return self.delete_file(name),
+        };
+
+        // An interrupted `set` may leave the key both in the log and in its
+        // file, so both are removed, and the key is missing only if neither
+        // existed.
+        log.locked(|log| {
+            let in_log = log.delete(name)?;
+            Ok(match self.delete_file(name) {
+                Err(Error::NotFound(_)) if in_log => empty_ok(),
+                res => res,
+            })
+        })?
+    }
+
+    fn take(&self, name: &str) -> Res {
+        let _gate = self.gate.enter();
+        if let Some(log) = self.inline() {
+            match log.take(name) {
+                Ok(Some(data)) => return Ok(data),
+                Ok(None) => (),
+                Err(e) => return Err(Error::Internal(e.into())),
+            }
+        }
+
+        // Move the file of the key in a temporary directory, before reading
+        // it. The rename is atomic, so only one caller can succeed, and the
+        // key disappears from the kv at once. The temporary directory is
+        // skipped by `keys()`, and is removed when it goes out of scope.
+        let temp_dir = match tempfile::Builder::new()
+            .prefix(".take")
+            .tempdir_in(self.dir())
+        {
+            Ok(temp_dir) => temp_dir,
+            Err(e) => return Err(Error::Internal(e.into())),
+        };
+
+        // A directory or a symbolic link could be renamed as well, so we must
+        // reject them beforehand, along with files of other keys.
+        self.check_not_symlink(name)?;
+        self.check_not_dir(name)?;
+        self.check_stored_name(name)?;
+        let temp_path = temp_dir.path().join("value");
+        if let Err(e) = fs::rename(self.create_path(name), &temp_path) {
+            return Err(self.convert_io_error(e, name));
+        }
+
+        let res = self
+            .open_file(&temp_path, fs::OpenOptions::new().read(true))
+            .and_then(read_file);
+        match res {
+            Ok(buf) => self.decode(name, buf),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    fn lock(&self, name: &str) -> Result<KeyGuard<'_>, Error> {
+        let (_, file) = self.open_lock_file(name)?;
+        lock_file(name, file)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            lock: true,
+            binary_keys: true,
+            ..Capabilities::default()
+        }
+    }
+
+    /// Remove the temporary directories that interrupted operations left
+    /// behind, and compact the log of the inline values, if any.
+    ///
+    /// Operations that are in progress use temporary directories as well, so
+    /// call this method when no other process writes to the directory.
+    fn maintain(&self) -> Res {
+        let _gate = self.gate.enter();
+        if let Some(log) = self.inline() {
+            if let Err(e) = log.compact() {
+                return Err(Error::Internal(e.into()));
+            }
+        }
+
+        let entries = match fs::read_dir(self.dir()) {
+            Ok(entries) => entries,
+            Err(e) => return Err(Error::Internal(e.into())),
+        };
+
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => return Err(Error::Internal(e.into())),
+            };
+            match entry.file_type() {
+                Ok(ft) if ft.is_dir() => (),
+                Ok(_) => continue,
+                Err(e) => return Err(Error::Internal(e.into())),
+            }
+
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            if !FILE_TEMP_PREFIXES.iter().any(|p| file_name.starts_with(p)) {
+                continue;
+            }
+            match fs::remove_dir_all(entry.path()) {
+                Ok(_) => (),
+                // Someone else removed it in the meantime.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(Error::Internal(e.into())),
+            }
+        }
+        empty_ok()
+    }
+
+    fn disk_usage(&self) -> Result<u64, Error> {
+        let _gate = self.gate.enter();
+        match dir_size(&self.dir()) {
+            Ok(size) => Ok(size),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    fn exists_many(&self, names: &[&str]) -> Result<Vec<bool>, Error> {
+        let _gate = self.gate.enter();
+        names.iter().map(|name| self.exists(name)).collect()
+    }
+
+    fn stat(&self, name: &str) -> Result<Metadata, Error> {
+        let _gate = self.gate.enter();
+        if let Some(size) = self.inline_size(name)? {
+            return Ok(Metadata {
+                size,
+                modified: None,
+            });
+        }
+
+        let path = self.create_path(name);
+        let res = if self.no_follow {
+            fs::symlink_metadata(&path)
+        } else {
+            fs::metadata(&path)
+        };
+        let md = match res {
+            Ok(md) => md,
+            Err(e) => return Err(self.convert_io_error(e, name)),
+        };
+        if !md.is_file() {
+            self.check_not_symlink(name)?;
+            self.check_not_dir(name)?;
+        }
+
+        // The size of a compressed file, or of a file with a header, differs
+        // from the size of the contents, so we have to read them.
+        let size = if self.gzip_level.is_some() || self.hashed_names {
+            self.get(name)?.len() as u64
+        } else {
+            md.len()
+        };
+        Ok(Metadata {
+            size,
+            modified: md.modified().ok(),
+        })
+    }
+
+    fn get_bytes(&self, name: &[u8]) -> Res {
+        let _gate = self.gate.enter();
+        match std::str::from_utf8(name) {
+            Ok(name) => self.get(name),
+            Err(_) => self.with_binary_name(name, |name| self.get(name)),
+        }
+    }
+
+    fn set_bytes(&self, name: &[u8], data: &[u8]) -> Res {
+        let _gate = self.gate.enter();
+        if let Ok(name) = std::str::from_utf8(name) {
+            return self.set(name, data);
+        }
+        if let Err(e) = fs::create_dir_all(self.dir().join(FILE_BINARY_DIR)) {
+            return Err(Error::Internal(e.into()));
+        }
+        self.with_binary_name(name, |name| self.set(name, data))
+    }
+
+    fn delete_bytes(&self, name: &[u8]) -> Res {
+        let _gate = self.gate.enter();
+        match std::str::from_utf8(name) {
+            Ok(name) => self.delete(name),
+            Err(_) => self.with_binary_name(name, |name| self.delete(name)),
+        }
+    }
+
+    fn swap(&self, a: &str, b: &str) -> Res {
+        let _gate = self.gate.enter();
+        // The files store the names of their keys, so they can't be renamed.
+        // The same goes for the inline values, which don't have files.
+        if self.hashed_names || self.inline().is_some() {
+            let data_a = self.get(a)?;
+            let data_b = self.get(b)?;
+            let _ = self.set(a, &data_b)?;
+            let _ = self.set(b, &data_a)?;
+            return empty_ok();
+        }
+
+        self.check_not_dir(a)?;
+        self.check_not_dir(b)?;
+        let path_a = self.create_path(a);
+        let path_b = self.create_path(b);
+        if a == b {
+            return match fs::metadata(&path_a) {
+                Ok(_) => empty_ok(),
+                Err(e) => Err(self.convert_io_error(e, a)),
+            };
+        }
+
+        let temp_dir = match tempfile::Builder::new()
+            .prefix(".swap")
+            .tempdir_in(self.dir())
+        {
+            Ok(temp_dir) => temp_dir,
+            Err(e) => return Err(Error::Internal(e.into())),
+        };
+        let temp_path = temp_dir.path().join("value");
+
+        if let Err(e) = fs::rename(&path_a, &temp_path) {
+            return Err(self.convert_io_error(e, a));
+        }
+        if let Err(e) = fs::rename(&path_b, &path_a) {
+            // Put the first key back, before reporting the error.
+            let _ = fs::rename(&temp_path, &path_a);
+            return Err(self.convert_io_error(e, b));
+        }
+        match fs::rename(&temp_path, &path_b) {
+            Ok(_) => empty_ok(),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    /// Rename all the keys whose names start with `old`, so that they start
+    /// with `new` instead, and return the number of renamed keys.
+    ///
+    /// The files of the keys are renamed, instead of rewritten, unless their
+    /// names are hashed, or small values are stored inline. If a file cannot
+    /// be renamed, e.g., because the new name of its key is a directory, an
+    /// error is returned, and the keys that have not been renamed yet keep
+    /// their old names.
+    fn rename_prefix(&self, old: &str, new: &str) -> Result<usize, Error> {
+        let _gate = self.gate.enter();
+        // The files store the names of their keys, so they can't be renamed.
+        // The same goes for the inline values, which don't have files.
+        if self.hashed_names || self.inline().is_some() {
+            return rename_prefix_by_copy(self, old, new);
+        }
+
+        check_prefix(old)?;
+        let names: Vec<String> = self
+            .keys()?
+            .into_iter()
+            .filter(|name| name.starts_with(old))
+            .collect();
+        if old == new {
+            return Ok(names.len());
+        }
+
+        let temp_dir = match tempfile::Builder::new()
+            .prefix(".rename")
+            .tempdir_in(self.dir())
+        {
+            Ok(temp_dir) => temp_dir,
+            Err(e) => return Err(Error::Internal(e.into())),
+        };
+        let restore = |moved: &[(&String, path::PathBuf)]| {
+            for (name, temp_path) in moved {
+                let _ = fs::rename(temp_path, self.create_path(name));
+            }
+        };
+
+        // Move all the files in a temporary directory before moving any of
+        // them to their new paths, so that a key whose new name is the old
+        // name of another key does not overwrite it.
+        let mut moved = Vec::with_capacity(names.len());
+        for (index, name) in names.iter().enumerate() {
+            let temp_path = temp_dir.path().join(index.to_string());
+            match fs::rename(self.create_path(name), &temp_path) {
+                Ok(_) => moved.push((name, temp_path)),
+                // The key was deleted in the meantime.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => {
+                    restore(&moved);
+                    return Err(self.convert_io_error(e, name));
+                }
+            }
+        }
+
+        for (index, (name, temp_path)) in moved.iter().enumerate() {
+            let new_name = replace_prefix(name, old, new);
+            if let Err(e) = fs::rename(temp_path, self.create_path(&new_name)) {
+                restore(&moved[index..]);
+                self.check_not_dir(&new_name)?;
+                return Err(Error::Internal(e.into()));
+            }
+        }
+        Ok(moved.len())
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        let _gate = self.gate.enter();
+        let mut keys = Vec::new();
+        self.for_each_key(|name| keys.push(name.to_string()))?;
+        Ok(keys)
+    }
+
+    // The following methods are the same as the default ones, but they pass
+    // through the gate once, so that a `reopen` can't happen between the
+    // operations that they consist of.
+
+    fn get_or_load(&self, name: &str, loader: &mut dyn FnMut(&str) -> Res) -> Res {
+        let _gate = self.gate.enter();
+        load_missing(self, name, loader)
+    }
+
+    fn get_existing(&self, names: &[&str]) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+        let _gate = self.gate.enter();
+        collect_existing(self, names)
+    }
+
+    fn delete_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        let _gate = self.gate.enter();
+        delete_prefix_by_keys(self, prefix)
+    }
+
+    fn update_with(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(Option<Vec<u8>>) -> Option<Vec<u8>>,
+    ) -> Res {
+        let _gate = self.gate.enter();
+        update_by_get_set(self, name, f)
+    }
+
+    fn replace(&self, name: &str, data: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let _gate = self.gate.enter();
+        replace_by_update(self, name, data)
+    }
+
+    fn first_key(&self) -> Result<Option<String>, Error> {
+        let _gate = self.gate.enter();
+        Ok(self.keys()?.into_iter().min())
+    }
+
+    fn last_key(&self) -> Result<Option<String>, Error> {
+        let _gate = self.gate.enter();
+        Ok(self.keys()?.into_iter().max())
+    }
+
+    fn range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        let _gate = self.gate.enter();
+        collect_range(self, start, end)
+    }
+
+    fn for_each(&self, f: &mut VisitFn<'_>) -> Result<(), Error> {
+        let _gate = self.gate.enter();
+        visit_keys(self, self.keys()?, f)
+    }
+
+    fn for_each_sorted(&self, f: &mut VisitFn<'_>) -> Result<(), Error> {
+        let _gate = self.gate.enter();
+        visit_keys(self, self.keys_sorted()?, f)
+    }
+
+    fn dump(&self) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+        let _gate = self.gate.enter();
+        dump_by_visit(self)
+    }
+
+    fn count_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        let _gate = self.gate.enter();
+        let mut count = 0;
+        self.for_each_key(|name| {
+            if name.starts_with(prefix) {
+                count += 1;
+            }
+        })?;
+        Ok(count)
+    }
+}
+
+/// A key-value store that stores keys in [RocksDB].
+///
+/// ## Chunked storage
+///
+/// Updating a part of a large value normally requires rewriting the whole
+/// value. In order to avoid this, this kv can optionally split the values in
+/// chunks of a fixed size (see [`with_chunk_size`]), which are stored in
+/// separate RocksDB keys, named `{name}#000000`, `{name}#000001`, etc. Then,
+/// [`set_range`] rewrites only the chunks that it affects.
+///
+/// The trade-off is that each value is stored in more keys, so `get` needs to
+/// look up every chunk of a value. The chunks are an implementation detail,
+/// so `keys()` returns the logical names of the keys. Note that a database
+/// must always be opened in the same mode, since the chunked and non-chunked
+/// modes store keys differently.
+///
+/// ## Binary keys
+///
+/// RocksDB keys are byte strings, so this kv stores the keys whose names are
+/// not valid UTF-8 (see [`Cave::get_bytes`]) as is. The methods that list
+/// keys skip them, but `delete_prefix` deletes them as well, if their names
+/// start with the bytes of the prefix. In chunked mode, the names of the keys
+/// must be valid UTF-8.
+///
+/// [`Cave::get_bytes`]: trait.Cave.html#method.get_bytes
+/// [RocksDB]: https://github.com/facebook/rocksdb
+/// [`with_chunk_size`]: #method.with_chunk_size
+/// [`set_range`]: #method.set_range
+#[cfg(feature = "with-rocksdb")]
+#[derive(Debug)]
+pub struct RocksDBCave {
+    db: rocksdb::DB,
+    max_value_size: Option<usize>,
+    chunk_size: Option<usize>,
+    // RocksDB does not provide transactions in the version that we use, so
+    // we serialize the writes with a lock, in order to make read-modify-write
+    // operations atomic. A RocksDB database can be opened by one process at a
+    // time, so an in-process lock is enough.
+    write_lock: sync::Mutex<()>,
+}
+
+/// The maximum number of chunks of a value, which is limited by the number
+/// of digits in the names of the chunks.
+#[cfg(feature = "with-rocksdb")]
+const MAX_CHUNKS: usize = 1_000_000;
+
+/// The size in bytes after which a bulk load writes its batch to RocksDB.
+#[cfg(feature = "with-rocksdb")]
+const BULK_LOAD_BATCH_SIZE: usize = 4 * 1024 * 1024;
+
+/// The suffix of the name of the first chunk of a value.
+#[cfg(feature = "with-rocksdb")]
+const FIRST_CHUNK_SUFFIX: &str = "#000000";
+
+#[cfg(feature = "with-rocksdb")]
+fn chunk_name(name: &str, index: usize) -> String {
+    format!("{}#{:06}", name, index)
+}
+
+/// Read a value from RocksDB, using a function that reads a single RocksDB
+/// key. This function may belong to a database or a snapshot.
+#[cfg(feature = "with-rocksdb")]
+fn read_rocksdb_value<F>(
+    get: F,
+    name: &str,
+    chunk_size: Option<usize>,
+) -> Result<Option<Vec<u8>>, Error>
+where
+    F: Fn(&[u8]) -> Result<Option<Vec<u8>>, rocksdb::Error>,
+{
+    let get = |key: &str| match get(key.as_bytes()) {
+        Ok(o) => Ok(o),
+        Err(e) => Err(Error::Internal(e.into())),
+    };
+
+    if chunk_size.is_none() {
+        return get(name);
+    }
+
+    let mut value = match get(&chunk_name(name, 0))? {
+        Some(chunk) => chunk,
+        None => return Ok(None),
+    };
+    for index in 1..MAX_CHUNKS {
+        match get(&chunk_name(name, index))? {
+            Some(chunk) => value.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+    Ok(Some(value))
+}
+
+/// Collect the names of the keys in RocksDB, hiding the chunks of the values
+/// if they are chunked.
+#[cfg(feature = "with-rocksdb")]
+fn collect_rocksdb_keys(
+    iter: impl Iterator<Item = (Box<[u8]>, Box<[u8]>)>,
+    chunk_size: Option<usize>,
+) -> Result<Vec<String>, Error> {
+    let mut keys = Vec::new();
+    for (key, _) in iter {
+        // Keys whose names are not valid UTF-8 can be accessed only by name.
+        let name = match String::from_utf8(key.into_vec()) {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+
+        if chunk_size.is_none() {
+            keys.push(name);
+        } else if let Some(name) = name.strip_suffix(FIRST_CHUNK_SUFFIX) {
+            // Every value has a first chunk, so we use it to find the
+            // logical names.
+            keys.push(name.to_string());
+        }
+    }
+    Ok(keys)
+}
+
+#[cfg(feature = "with-rocksdb")]
+impl RocksDBCave {
+    /// Create a new instance.
+    ///
+    /// If the provided directory does not exist, it will be created.
+    pub fn new(dir: &path::Path) -> Result<Self, Error> {
+        Self::open(dir, Self::default_options())
+    }
+
+    /// Create a new instance, whose block cache is shared with other
+    /// instances.
+    ///
+    /// By default, every instance allocates its own block cache, which keeps
+    /// the recently read blocks in memory. If you open many instances, e.g.,
+    /// one per tenant, create a single cache with
+    /// [`RocksDBCache::new_lru_cache`] and pass it to each of them, so that
+    /// they stay within a single memory budget. The instances keep the cache
+    /// alive, so it can be dropped after they are created.
+    ///
+    /// If the provided directory does not exist, it will be created.
+    ///
+    /// [`RocksDBCache::new_lru_cache`]: struct.RocksDBCache.html#method.new_lru_cache
+    pub fn with_shared_cache(dir: &path::Path, cache: &RocksDBCache) -> Result<Self, Error> {
+        let mut block_opts = rocksdb::BlockBasedOptions::default();
+        block_opts.set_block_cache(cache);
+        let mut opts = Self::default_options();
+        opts.set_block_based_table_factory(&block_opts);
+        Self::open(dir, opts)
+    }
+
+    fn default_options() -> rocksdb::Options {
+        let mut opts = rocksdb::Options::default();
+        opts.create_if_missing(true);
+        opts
+    }
+
+    fn open(dir: &path::Path, opts: rocksdb::Options) -> Result<Self, Error> {
+        match rocksdb::DB::open(&opts, dir) {
+            Ok(db) => Ok(Self {
+                db,
+                max_value_size: None,
+                chunk_size: None,
+                write_lock: sync::Mutex::new(()),
+            }),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    /// Reject values that are larger than `limit` bytes.
+    ///
+    /// A `set` with a larger value returns an [`Error::ValueTooLarge`] error,
+    /// without reaching RocksDB.
+    ///
+    /// [`Error::ValueTooLarge`]: errors/enum.Error.html#variant.ValueTooLarge
+    pub fn with_max_value_size(mut self, limit: usize) -> Self {
+        self.max_value_size = Some(limit);
+        self
+    }
+
+    /// Store the values in chunks of `chunk_size` bytes.
+    ///
+    /// See the [chunked storage](#chunked-storage) section for more info. A
+    /// value can have at most one million chunks, so the chunk size limits the
+    /// maximum size of a value as well. A chunk size of 0 is treated as 1.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = Some(chunk_size.max(1));
+        self
+    }
+
+    /// Overwrite a part of a value, starting at byte `offset`.
+    ///
+    /// The value is extended if the new data exceed its end. If the key does
+    /// not exist, return an error. If the offset is past the end of the value,
+    /// return an internal error, since it would leave a gap in the value.
+    ///
+    /// In chunked mode, only the chunks that the new data touch are
+    /// rewritten. Otherwise, this operation is not supported.
+    pub fn set_range(&self, name: &str, offset: usize, data: &[u8]) -> Res {
+        let chunk_size = match self.chunk_size {
+            Some(chunk_size) => chunk_size,
+            None => {
+                return Err(Error::Unsupported {
+                    operation: "set_range",
+                })
+            }
+        };
+
+        let _guard = self.write_lock.lock().unwrap();
+        let chunks = self.chunk_count(name)?;
+        if chunks == 0 {
+            return self.not_found(name);
+        }
+
+        let last_chunk = self.read_chunk(name, chunks - 1)?;
+        let len = (chunks - 1) * chunk_size + last_chunk.len();
+        if offset > len {
+            return Err(Error::internal_from_msg(format!(
+                "Offset {} is past the end of key `{}` ({} bytes)",
+                offset, name, len
+            )));
+        }
+
+        let new_len = len.max(offset + data.len());
+        let limit = self
+            .max_value_size
+            .unwrap_or(usize::MAX)
+            .min(chunk_size * MAX_CHUNKS);
+        if new_len > limit {
+            return Err(Error::ValueTooLarge {
+                size: new_len,
+                limit,
+            });
+        }
+
+        let mut batch = rocksdb::WriteBatch::default();
+        let mut written = 0;
+        while written < data.len() {
+            let pos = offset + written;
+            let index = pos / chunk_size;
+            let mut chunk = if index < chunks {
+                self.read_chunk(name, index)?
+            } else {
+                Vec::new()
+            };
+
+            let start = pos % chunk_size;
+            let count = (chunk_size - start).min(data.len() - written);
+            if chunk.len() < start + count {
+                chunk.resize(start + count, 0);
+            }
+            chunk[start..start + count].copy_from_slice(&data[written..written + count]);
+            batch.put(chunk_name(name, index).as_bytes(), &chunk);
+            written += count;
+        }
+
+        match self.db.write(batch) {
+            Ok(_) => empty_ok(),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    /// Create or update a key, and return the contents that were stored, as
+    /// read back from the database.
+    ///
+    /// The write and the read happen while holding the write lock, so no
+    /// other write can land in between, and the returned contents are
+    /// exactly what this call wrote. This is useful for confirming that a
+    /// value was stored as is, e.g., after it went through chunking.
+    ///
+    /// Read-after-write consistency requires that the write and the read form
+    /// a transaction. The version of RocksDB that we use does not provide
+    /// transactions, but a database can be opened by a single process at a
+    /// time, and all the writes of this kv are serialized with the write
+    /// lock, which has the same effect.
+    pub fn set_and_get(&self, name: &str, data: &[u8]) -> Res {
+        check_value_size(data, self.max_value_size)?;
+        let _guard = self.write_lock.lock().unwrap();
+        self.write(name, Some(data))?;
+        match self.read(name)? {
+            Some(data) => Ok(data),
+            None => Err(Error::internal_from_msg(format!(
+                "Key `{}` is missing right after it was written",
+                name
+            ))),
+        }
+    }
+
+    /// Load many keys at once, without the overhead of the write-ahead log.
+    ///
+    /// The function `f` receives a [`RocksDBBulkLoader`], through which it
+    /// sets and deletes keys. The writes are grouped in large batches, which
+    /// skip the write-ahead log of RocksDB, and once `f` returns, the
+    /// database is flushed, so that the written keys are persisted in SST
+    /// files. This is considerably faster than calling `set` for each key.
+    ///
+    /// Other writes are blocked until the load completes, but reads are not,
+    /// so they may observe some of the written keys before the load
+    /// completes. If `f` returns an error, the keys that were written in
+    /// batches up to that point are kept and flushed, and the error is
+    /// returned.
+    ///
+    /// ## Crash semantics
+    ///
+    /// Since the write-ahead log is skipped, the keys that are written during
+    /// the load are durable only after the final flush. If the process
+    /// crashes during the load, any number of them may be lost, whereas the
+    /// keys that were written before the load are not affected. Therefore, a
+    /// crashed load should be retried from the start, which is safe, since
+    /// setting a key again is idempotent.
+    ///
+    /// ## Usage
+    ///
+    /// ```no_run
+    /// use std::path::Path;
+    /// use caves::{Cave, RocksDBCave};
+    ///
+    /// let rb = RocksDBCave::new(Path::new("/data")).unwrap();
+    /// rb.bulk_load(|loader| {
+    ///     for i in 0..100_000 {
+    ///         loader.set(&format!("key{}", i), b"value")?;
+    ///     }
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// ```
+    ///
+    /// [`RocksDBBulkLoader`]: struct.RocksDBBulkLoader.html
+    pub fn bulk_load<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut RocksDBBulkLoader<'_>) -> Result<(), Error>,
+    {
+        let _guard = self.write_lock.lock().unwrap();
+        let mut loader = RocksDBBulkLoader {
+            cave: self,
+            batch: rocksdb::WriteBatch::default(),
+        };
+        let res = f(&mut loader).and_then(|_| loader.write_batch());
+        // The keys that have been written are visible, so we persist them
+        // even if the load failed.
+        let flushed = self.flush();
+        res.and(flushed).map(|_| ())
+    }
+
+    /// Return the first key that an iterator in the provided mode yields.
+    fn edge_key(&self, mode: rocksdb::IteratorMode) -> Result<Option<String>, Error> {
+        // The names of the chunks are not ordered like the logical names,
+        // e.g., `a!#000000` comes before `a#000000`, so we have to scan them.
+        if self.chunk_size.is_some() {
+            let keys = self.keys()?.into_iter();
+            return Ok(match mode {
+                rocksdb::IteratorMode::End => keys.max(),
+                _ => keys.min(),
+            });
+        }
+
+        let iter = self
+            .db
+            .iterator(mode)
+            .filter(|(key, _)| std::str::from_utf8(key).is_ok())
+            .take(1);
+        Ok(collect_rocksdb_keys(iter, None)?.pop())
+    }
+
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>, Error> {
+        read_rocksdb_value(|key| self.db.get(key), name, self.chunk_size)
+    }
+
+    fn read_chunk(&self, name: &str, index: usize) -> Res {
+        match self.db.get(chunk_name(name, index).as_bytes()) {
+            Ok(Some(chunk)) => Ok(chunk),
+            Ok(None) => Err(Error::internal_from_msg(format!(
+                "Chunk {} of key `{}` is missing",
+                index, name
+            ))),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    /// Return the number of chunks of a value, or 0 if it does not exist.
+    fn chunk_count(&self, name: &str) -> Result<usize, Error> {
+        for index in 0..MAX_CHUNKS {
+            match self.db.get_pinned(chunk_name(name, index).as_bytes()) {
+                Ok(Some(_)) => (),
+                Ok(None) => return Ok(index),
+                Err(e) => return Err(Error::Internal(e.into())),
+            }
+        }
+        Ok(MAX_CHUNKS)
+    }
+
+    /// Create, update or delete (if `data` is `None`) a value. The caller must
+    /// hold the write lock.
+    fn write(&self, name: &str, data: Option<&[u8]>) -> Result<(), Error> {
+        let mut batch = rocksdb::WriteBatch::default();
+        self.add_write(&mut batch, name, data)?;
+        match self.db.write(batch) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    /// Add a write to a batch, so that it's applied atomically along with
+    /// the other writes of the batch. The caller must hold the write lock.
+    fn add_write(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        name: &str,
+        data: Option<&[u8]>,
+    ) -> Result<(), Error> {
+        if let Some(data) = data {
+            check_value_size(data, self.max_value_size)?;
+        }
+
+        match (self.chunk_size, data) {
+            (None, Some(data)) => batch.put(name.as_bytes(), data),
+            (None, None) => batch.delete(name.as_bytes()),
+            (Some(chunk_size), data) => {
+                let chunks: Vec<&[u8]> = match data {
+                    // An empty value still needs its first chunk.
+                    Some(data) if data.is_empty() => vec![data],
+                    Some(data) => data.chunks(chunk_size).collect(),
+                    None => Vec::new(),
+                };
+                if chunks.len() > MAX_CHUNKS {
+                    check_value_size(data.unwrap(), Some(chunk_size * MAX_CHUNKS))?;
+                }
+
+                let old_chunks = self.chunk_count(name)?;
+                for (index, chunk) in chunks.iter().enumerate() {
+                    batch.put(chunk_name(name, index).as_bytes(), chunk);
+                }
+                for index in chunks.len()..old_chunks {
+                    batch.delete(chunk_name(name, index).as_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A handle for writing keys during a [`RocksDBCave::bulk_load`].
+///
+/// The writes are buffered in a batch, which is written to RocksDB once it
+/// grows large enough, and when the load completes.
+///
+/// [`RocksDBCave::bulk_load`]: struct.RocksDBCave.html#method.bulk_load
+#[cfg(feature = "with-rocksdb")]
+pub struct RocksDBBulkLoader<'a> {
+    cave: &'a RocksDBCave,
+    batch: rocksdb::WriteBatch,
+}
+
+#[cfg(feature = "with-rocksdb")]
+impl RocksDBBulkLoader<'_> {
+    /// Set a key with the provided contents.
+    pub fn set(&mut self, name: &str, data: &[u8]) -> Result<(), Error> {
+        self.add(name, Some(data))
+    }
+
+    /// Delete a key, if it exists.
+    ///
+    /// Unlike `Cave::delete`, this method does not check if the key exists,
+    /// so it does not return an [`Error::NotFound`] error.
+    ///
+    /// [`Error::NotFound`]: errors/enum.Error.html#variant.NotFound
+    pub fn delete(&mut self, name: &str) -> Result<(), Error> {
+        self.add(name, None)
+    }
+
+    fn add(&mut self, name: &str, data: Option<&[u8]>) -> Result<(), Error> {
+        self.cave.add_write(&mut self.batch, name, data)?;
+        // In chunked mode, the old chunks of a value are looked up in the
+        // database, so the batch must be written before the next write.
+        if self.cave.chunk_size.is_some() || self.batch.size_in_bytes() >= BULK_LOAD_BATCH_SIZE {
+            self.write_batch()?;
+        }
+        Ok(())
+    }
+
+    /// Write the buffered batch to RocksDB, skipping the write-ahead log.
+    fn write_batch(&mut self) -> Result<(), Error> {
+        let batch = std::mem::take(&mut self.batch);
+        let mut opts = rocksdb::WriteOptions::default();
+        opts.disable_wal(true);
+        match self.cave.db.write_opt(batch, &opts) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+}
+
+/// Print the number of buffered writes, instead of their contents.
+#[cfg(feature = "with-rocksdb")]
+impl fmt::Debug for RocksDBBulkLoader<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RocksDBBulkLoader")
+            .field("pending", &self.batch.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "with-rocksdb")]
+impl Cave for RocksDBCave {
+    fn get(&self, name: &str) -> Res {
+        match self.read(name)? {
+            Some(buf) => Ok(buf),
+            None => self.not_found(name),
+        }
+    }
+
+    fn set(&self, name: &str, data: &[u8]) -> Res {
+        check_value_size(data, self.max_value_size)?;
+        let _guard = self.write_lock.lock().unwrap();
+        self.write(name, Some(data))?;
+        empty_ok()
+    }
+
+    fn delete(&self, name: &str) -> Res {
+        let _ = self.take(name)?;
+        empty_ok()
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        let iter = self.db.iterator(rocksdb::IteratorMode::Start);
+        collect_rocksdb_keys(iter, self.chunk_size)
+    }
+
+    fn keys_sorted(&self) -> Result<Vec<String>, Error> {
+        let mut keys = self.keys()?;
+        // The suffixes of the chunks may change the order of the names, e.g.,
+        // `a!` is stored before `a`.
+        if self.chunk_size.is_some() {
+            keys.sort_unstable();
+        }
+        Ok(keys)
+    }
+
+    fn for_each_sorted(&self, f: &mut VisitFn<'_>) -> Result<(), Error> {
+        match self.chunk_size {
+            None => self.for_each(f),
+            Some(_) => visit_keys(self, self.keys_sorted()?, f),
+        }
+    }
+
+    fn take(&self, name: &str) -> Res {
+        let _guard = self.write_lock.lock().unwrap();
+        let data = self.get(name)?;
+        self.write(name, None)?;
+        Ok(data)
+    }
+
+    fn create(&self, name: &str, data: &[u8]) -> Res {
+        check_value_size(data, self.max_value_size)?;
+        // The database can be opened by a single process, so the write lock
+        // is enough to make the check and the write atomic.
+        let _guard = self.write_lock.lock().unwrap();
+        if self.read(name)?.is_some() {
+            return Err(Error::AlreadyExists(name.to_string()));
+        }
+        self.write(name, Some(data))?;
+        empty_ok()
+    }
+
+    fn update_with(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(Option<Vec<u8>>) -> Option<Vec<u8>>,
+    ) -> Res {
+        // The writes are serialized, so the closure is called exactly once.
+        let _guard = self.write_lock.lock().unwrap();
+        let current = self.read(name)?;
+        let existed = current.is_some();
+
+        match f(current) {
+            Some(data) => {
+                self.write(name, Some(&data))?;
+                Ok(data)
+            }
+            None if existed => {
+                self.write(name, None)?;
+                empty_ok()
+            }
+            None => empty_ok(),
+        }
+    }
+
+    fn delete_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        check_prefix(prefix)?;
+        let _guard = self.write_lock.lock().unwrap();
+
+        // The names of the chunks don't match the prefix in the same way as
+        // the logical names, so we delete the values one by one.
+        if self.chunk_size.is_some() {
+            let mut count = 0;
+            for name in self.keys()? {
+                if name.starts_with(prefix) {
+                    self.write(&name, None)?;
+                    count += 1;
+                }
+            }
+            return Ok(count);
+        }
+
+        // Count the matching keys, since `delete_range` does not report how
+        // many keys it deleted.
+        let mode = rocksdb::IteratorMode::From(prefix.as_bytes(), rocksdb::Direction::Forward);
+        let count = self
+            .db
+            .iterator(mode)
+            .take_while(|(key, _)| key.starts_with(prefix.as_bytes()))
+            .count();
+
+        // The keys that start with the prefix are the ones in the range
+        // [prefix, end), where `end` is the prefix with its last byte
+        // incremented. The last byte of a UTF-8 string is never 0xff, so it
+        // can always be incremented.
+        let mut end = prefix.as_bytes().to_vec();
+        *end.last_mut().unwrap() += 1;
+
+        let mut batch = rocksdb::WriteBatch::default();
+        batch.delete_range(prefix.as_bytes(), &end[..]);
+        match self.db.write(batch) {
+            Ok(_) => Ok(count),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    fn rename_prefix(&self, old: &str, new: &str) -> Result<usize, Error> {
+        check_prefix(old)?;
+        let _guard = self.write_lock.lock().unwrap();
+        let names: Vec<String> = self
+            .keys()?
+            .into_iter()
+            .filter(|name| name.starts_with(old))
+            .collect();
+        if old == new {
+            return Ok(names.len());
+        }
+
+        // Add the deletes of all the keys to the batch before their writes,
+        // since the later writes of a batch take precedence over the earlier
+        // ones. This way, a key whose new name is the old name of another key
+        // is not deleted afterwards.
+        let mut batch = rocksdb::WriteBatch::default();
+        let mut entries = Vec::with_capacity(names.len());
+        for name in names {
+            if let Some(data) = self.read(&name)? {
+                self.add_write(&mut batch, &name, None)?;
+                entries.push((name, data));
+            }
+        }
+        for (name, data) in &entries {
+            self.add_write(&mut batch, &replace_prefix(name, old, new), Some(data))?;
+        }
+        match self.db.write(batch) {
+            Ok(_) => Ok(entries.len()),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    fn first_key(&self) -> Result<Option<String>, Error> {
+        self.edge_key(rocksdb::IteratorMode::Start)
+    }
+
+    fn last_key(&self) -> Result<Option<String>, Error> {
+        self.edge_key(rocksdb::IteratorMode::End)
+    }
+
+    fn range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        // The names of the chunks are not ordered like the logical names, so
+        // we can't seek to them.
+        if self.chunk_size.is_some() {
+            return collect_range(self, start, end);
+        }
+
+        let mode = rocksdb::IteratorMode::From(start.as_bytes(), rocksdb::Direction::Forward);
+        let mut entries = Vec::new();
+        for (key, value) in self.db.iterator(mode) {
+            if &*key >= end.as_bytes() {
+                break;
+            }
+            if let Ok(name) = String::from_utf8(key.into_vec()) {
+                entries.push((name, value.into_vec()));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn for_each(&self, f: &mut VisitFn<'_>) -> Result<(), Error> {
+        for (key, value) in self.db.iterator(rocksdb::IteratorMode::Start) {
+            let name = match std::str::from_utf8(&key) {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+
+            if self.chunk_size.is_none() {
+                f(name, &value)?;
+                continue;
+            }
+
+            // Read the rest of the chunks of a key, once we find its first
+            // chunk.
+            if let Some(name) = name.strip_suffix(FIRST_CHUNK_SUFFIX) {
+                if let Some(data) = self.read(name)? {
+                    f(name, &data)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn count_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        // The chunks of a key start with its name, so the chunks of the
+        // matching keys start with the prefix as well.
+        let mode = rocksdb::IteratorMode::From(prefix.as_bytes(), rocksdb::Direction::Forward);
+        let iter = self
+            .db
+            .iterator(mode)
+            .take_while(|(key, _)| key.starts_with(prefix.as_bytes()));
+
+        if self.chunk_size.is_none() {
+            return Ok(iter
+                .filter(|(key, _)| std::str::from_utf8(key).is_ok())
+                .count());
+        }
+        let names = collect_rocksdb_keys(iter, self.chunk_size)?;
+        Ok(names.iter().filter(|name| name.starts_with(prefix)).count())
+    }
+
+    /// Check whether multiple keys exist.
+    ///
+    /// The version of RocksDB that we use does not support `multi_get`, so
+    /// the keys are checked one by one. Still, their contents are not copied
+    /// out of RocksDB.
+    fn exists_many(&self, names: &[&str]) -> Result<Vec<bool>, Error> {
+        let mut exist = Vec::with_capacity(names.len());
+        for name in names {
+            // Every value has a first chunk, so we check only that.
+            let key = match self.chunk_size {
+                Some(_) => chunk_name(name, 0),
+                None => name.to_string(),
+            };
+            match self.db.get_pinned(key.as_bytes()) {
+                Ok(data) => exist.push(data.is_some()),
+                Err(e) => return Err(Error::Internal(e.into())),
+            }
+        }
+        Ok(exist)
+    }
+
+    fn get_bytes(&self, name: &[u8]) -> Res {
+        // The names of the chunks are built from the names of the keys, so
+        // they must be strings.
+        if self.chunk_size.is_some() || std::str::from_utf8(name).is_ok() {
+            return self.get(utf8_name(name)?);
+        }
+        match self.db.get(name) {
+            Ok(Some(data)) => Ok(data),
+            Ok(None) => self.not_found(&String::from_utf8_lossy(name)),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    fn set_bytes(&self, name: &[u8], data: &[u8]) -> Res {
+        if self.chunk_size.is_some() || std::str::from_utf8(name).is_ok() {
+            return self.set(utf8_name(name)?, data);
+        }
+        check_value_size(data, self.max_value_size)?;
+        let _guard = self.write_lock.lock().unwrap();
+        match self.db.put(name, data) {
+            Ok(_) => empty_ok(),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    fn delete_bytes(&self, name: &[u8]) -> Res {
+        if self.chunk_size.is_some() || std::str::from_utf8(name).is_ok() {
+            return self.delete(utf8_name(name)?);
+        }
+        let _guard = self.write_lock.lock().unwrap();
+        let _ = self.get_bytes(name)?;
+        match self.db.delete(name) {
+            Ok(_) => empty_ok(),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    fn swap(&self, a: &str, b: &str) -> Res {
+        let _guard = self.write_lock.lock().unwrap();
+        let data_a = self.get(a)?;
+        let data_b = self.get(b)?;
+
+        let mut batch = rocksdb::WriteBatch::default();
+        self.add_write(&mut batch, a, Some(&data_b))?;
+        self.add_write(&mut batch, b, Some(&data_a))?;
+        match self.db.write(batch) {
+            Ok(_) => empty_ok(),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    fn flush(&self) -> Res {
+        // The write-ahead log is not synced after every write, so we persist
+        // the memtables to SST files instead.
+        match self.db.flush() {
+            Ok(_) => empty_ok(),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    fn maintain(&self) -> Res {
+        self.db.compact_range::<&[u8], &[u8]>(None, None);
+        empty_ok()
+    }
+
+    fn disk_usage(&self) -> Result<u64, Error> {
+        match self.db.property_int_value("rocksdb.total-sst-files-size") {
+            Ok(Some(size)) => Ok(size),
+            Ok(None) => Err(Error::internal_from_msg(
+                "RocksDB did not report the size of its SST files".to_string(),
+            )),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn Cave + '_>, Error> {
+        Ok(Box::new(RocksDBSnapshot {
+            snapshot: self.db.snapshot(),
+            chunk_size: self.chunk_size,
+        }))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            snapshot: true,
+            binary_keys: self.chunk_size.is_none(),
+            ..Capabilities::default()
+        }
+    }
+}
+
+/// A read-only view of a `RocksDBCave`, backed by a RocksDB snapshot.
+#[cfg(feature = "with-rocksdb")]
+struct RocksDBSnapshot<'a> {
+    snapshot: rocksdb::Snapshot<'a>,
+    chunk_size: Option<usize>,
+}
+
+#[cfg(feature = "with-rocksdb")]
+impl fmt::Debug for RocksDBSnapshot<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RocksDBSnapshot")
+            .field("chunk_size", &self.chunk_size)
+            .finish()
+    }
+}
+
+#[cfg(feature = "with-rocksdb")]
+impl Cave for RocksDBSnapshot<'_> {
+    fn get(&self, name: &str) -> Res {
+        let get = |key: &[u8]| self.snapshot.get(key);
+        match read_rocksdb_value(get, name, self.chunk_size)? {
+            Some(buf) => Ok(buf),
+            None => self.not_found(name),
+        }
+    }
+
+    fn set(&self, _name: &str, _data: &[u8]) -> Res {
+        Err(Error::Unsupported { operation: "set" })
+    }
+
+    fn delete(&self, _name: &str) -> Res {
+        Err(Error::Unsupported {
+            operation: "delete",
+        })
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        let iter = self.snapshot.iterator(rocksdb::IteratorMode::Start);
+        collect_rocksdb_keys(iter, self.chunk_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+
+    use crate::errors::ErrorKind;
+
+    pub(crate) fn _test_simple(b: Box<dyn Cave>) {
+        let not_found_err = Err(Error::NotFound("test".to_string()));
+        let value1 = Ok("value".as_bytes().to_vec());
         let value2 = Ok("value2".as_bytes().to_vec());
         let value3 = Ok("value3".as_bytes().to_vec());
 
-        let res = b.get("test");
-        assert_eq!(res, not_found_err);
-        let res = b.delete("test");
-        assert_eq!(res, not_found_err);
-        let res = b.set("test", "value".as_bytes());
-        assert_eq!(res, empty_ok());
-        let res = b.get("test");
-        assert_eq!(res, value1);
-        let res = b.set("test", "value2".as_bytes());
-        assert_eq!(res, empty_ok());
-        let res = b.get("test");
-        assert_eq!(res, value2);
-        let res = b.delete("test");
-        assert_eq!(res, empty_ok());
-        let res = b.get("test");
-        assert_eq!(res, not_found_err);
-        let res = b.delete("test");
-        assert_eq!(res, not_found_err);
-        let res = b.set("test", "value3".as_bytes());
-        assert_eq!(res, empty_ok());
-        let res = b.get("test");
-        assert_eq!(res, value3);
+        let res = b.get("test");
+        assert_eq!(res, not_found_err);
+        let res = b.delete("test");
+        assert_eq!(res, not_found_err);
+        let res = b.set("test", "value".as_bytes());
+        assert_eq!(res, empty_ok());
+        let res = b.get("test");
+        assert_eq!(res, value1);
+        let res = b.set("test", "value2".as_bytes());
+        assert_eq!(res, empty_ok());
+        let res = b.get("test");
+        assert_eq!(res, value2);
+        let res = b.delete("test");
+        assert_eq!(res, empty_ok());
+        let res = b.get("test");
+        assert_eq!(res, not_found_err);
+        let res = b.delete("test");
+        assert_eq!(res, not_found_err);
+        let res = b.set("test", "value3".as_bytes());
+        assert_eq!(res, empty_ok());
+        let res = b.get("test");
+        assert_eq!(res, value3);
+    }
+
+    pub(crate) fn _test_keys(b: Box<dyn Cave>) {
+        let res = b.keys();
+        assert_eq!(res.unwrap(), Vec::<String>::new());
+
+        assert_eq!(b.set("key1", b"value1"), empty_ok());
+        assert_eq!(b.set("key2", b"value2"), empty_ok());
+        assert_eq!(b.set("key1", b"value3"), empty_ok());
+        let mut keys = b.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["key1", "key2"]);
+
+        assert_eq!(b.delete("key1"), empty_ok());
+        let res = b.keys();
+        assert_eq!(res.unwrap(), vec!["key2"]);
+    }
+
+    fn _test_first_last_key(b: Box<dyn Cave>) {
+        assert_eq!(b.first_key(), Ok(None));
+        assert_eq!(b.last_key(), Ok(None));
+
+        // The names are ordered by byte value, so uppercase letters come
+        // first, and prefixes come before the names they are a prefix of.
+        for name in &["b", "ab", "a", "B", "a!"] {
+            assert_eq!(b.set(name, b"value"), empty_ok());
+        }
+        assert_eq!(b.first_key(), Ok(Some("B".to_string())));
+        assert_eq!(b.last_key(), Ok(Some("b".to_string())));
+
+        assert_eq!(b.delete("B"), empty_ok());
+        assert_eq!(b.delete("b"), empty_ok());
+        assert_eq!(b.first_key(), Ok(Some("a".to_string())));
+        assert_eq!(b.last_key(), Ok(Some("ab".to_string())));
+    }
+
+    pub(crate) fn _test_swap(b: Box<dyn Cave>) {
+        assert_eq!(b.set("key1", b"value1"), empty_ok());
+        assert_eq!(b.set("key2", b"value2"), empty_ok());
+        assert_eq!(b.swap("key1", "key2"), empty_ok());
+        assert_eq!(b.get("key1"), Ok(b"value2".to_vec()));
+        assert_eq!(b.get("key2"), Ok(b"value1".to_vec()));
+
+        // Swapping a key with itself should leave it intact.
+        assert_eq!(b.swap("key1", "key1"), empty_ok());
+        assert_eq!(b.get("key1"), Ok(b"value2".to_vec()));
+
+        // If either key is missing, both keys should be left intact.
+        let not_found_err = Err(Error::NotFound("missing".to_string()));
+        assert_eq!(b.swap("key1", "missing"), not_found_err);
+        assert_eq!(b.swap("missing", "key2"), not_found_err);
+        assert_eq!(b.swap("missing", "missing"), not_found_err);
+        assert_eq!(b.get("key1"), Ok(b"value2".to_vec()));
+        assert_eq!(b.get("key2"), Ok(b"value1".to_vec()));
+        assert_eq!(b.keys().unwrap().len(), 2);
+    }
+
+    fn _test_count_prefix(b: Box<dyn Cave>) {
+        assert_eq!(b.count_prefix("tenant1_"), Ok(0));
+
+        assert_eq!(b.set("tenant1_key1", b"value"), empty_ok());
+        assert_eq!(b.set("tenant1_key2", b"value"), empty_ok());
+        assert_eq!(b.set("tenant2_key1", b"value"), empty_ok());
+        assert_eq!(b.set("tenant1", b"value"), empty_ok());
+        assert_eq!(b.count_prefix("tenant1_"), Ok(2));
+        assert_eq!(b.count_prefix("tenant1"), Ok(3));
+        assert_eq!(b.count_prefix("tenant3"), Ok(0));
+        assert_eq!(b.count_prefix(""), Ok(4));
+
+        assert_eq!(b.delete("tenant1_key1"), empty_ok());
+        assert_eq!(b.count_prefix("tenant1_"), Ok(1));
+    }
+
+    fn _test_keys_sorted(b: Box<dyn Cave>) {
+        assert_eq!(b.keys_sorted(), Ok(Vec::<String>::new()));
+        let names = ["b", "a!", "é", "B", "a", "ab"];
+        for name in &names {
+            assert_eq!(b.set(name, name.as_bytes()), empty_ok());
+        }
+        let expected = vec!["B", "a", "a!", "ab", "b", "é"];
+        assert_eq!(b.keys_sorted().unwrap(), expected);
+
+        let mut visited = Vec::new();
+        let res = b.for_each_sorted(&mut |name, data| {
+            assert_eq!(name.as_bytes(), data);
+            visited.push(name.to_string());
+            Ok(())
+        });
+        assert_eq!(res, Ok(()));
+        assert_eq!(visited, expected);
+    }
+
+    fn _test_for_each(b: Box<dyn Cave>) {
+        let mut entries = Vec::new();
+        let res = b.for_each(&mut |name, data| {
+            entries.push((name.to_string(), data.to_vec()));
+            Ok(())
+        });
+        assert_eq!(res, Ok(()));
+        assert!(entries.is_empty());
+
+        assert_eq!(b.set("key1", b"value1"), empty_ok());
+        assert_eq!(b.set("key2", b""), empty_ok());
+        let res = b.for_each(&mut |name, data| {
+            entries.push((name.to_string(), data.to_vec()));
+            Ok(())
+        });
+        assert_eq!(res, Ok(()));
+        entries.sort();
+        let expected = vec![
+            ("key1".to_string(), b"value1".to_vec()),
+            ("key2".to_string(), b"".to_vec()),
+        ];
+        assert_eq!(entries, expected);
+
+        // An error should stop the iteration.
+        let mut calls = 0;
+        let res = b.for_each(&mut |_, _| {
+            calls += 1;
+            Err(Error::internal_from_msg("stop".to_string()))
+        });
+        assert_eq!(res, Err(Error::Internal(anyhow!(""))));
+        assert_eq!(calls, 1);
+    }
+
+    fn _test_dump(b: Box<dyn Cave>) {
+        assert!(b.dump().unwrap().is_empty());
+
+        assert_eq!(b.set("key1", b"value1"), empty_ok());
+        assert_eq!(b.set("key2", b""), empty_ok());
+        let map = b.dump().unwrap();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map["key1"], b"value1");
+        assert_eq!(map["key2"], b"");
+    }
+
+    fn _test_range(b: Box<dyn Cave>) {
+        assert_eq!(b.range("a", "z"), Ok(vec![]));
+
+        for name in &["b", "ab", "a", "B", "c"] {
+            assert_eq!(b.set(name, name.as_bytes()), empty_ok());
+        }
+        let entry = |name: &str| (name.to_string(), name.as_bytes().to_vec());
+
+        // The start of the range is inclusive, and its end exclusive.
+        let res = b.range("a", "c");
+        assert_eq!(res, Ok(vec![entry("a"), entry("ab"), entry("b")]));
+        let res = b.range("A", "a");
+        assert_eq!(res, Ok(vec![entry("B")]));
+        let res = b.range("aa", "b");
+        assert_eq!(res, Ok(vec![entry("ab")]));
+
+        assert_eq!(b.range("c", "c"), Ok(vec![]));
+        assert_eq!(b.range("c", "a"), Ok(vec![]));
+    }
+
+    fn _test_get_or(b: Box<dyn Cave>) {
+        assert_eq!(b.try_get("key"), Ok(None));
+        assert_eq!(b.get_or("key", b"default"), Ok(b"default".to_vec()));
+        assert_eq!(b.get_or_default("key"), Ok(vec![]));
+
+        assert_eq!(b.set("key", b"value"), empty_ok());
+        assert_eq!(b.try_get("key"), Ok(Some(b"value".to_vec())));
+        assert_eq!(b.get_or("key", b"default"), Ok(b"value".to_vec()));
+        assert_eq!(b.get_or_default("key"), Ok(b"value".to_vec()));
+    }
+
+    fn _test_get_or_load(b: Box<dyn Cave>) {
+        let mut loads = 0;
+        let mut loader = |name: &str| {
+            loads += 1;
+            Ok(format!("loaded {}", name).into_bytes())
+        };
+        assert_eq!(
+            b.get_or_load("key", &mut loader),
+            Ok(b"loaded key".to_vec())
+        );
+        assert_eq!(
+            b.get_or_load("key", &mut loader),
+            Ok(b"loaded key".to_vec())
+        );
+        assert_eq!(b.get("key"), Ok(b"loaded key".to_vec()));
+        assert_eq!(loads, 1);
+
+        // The errors of the loader should be returned, without storing
+        // anything.
+        let res = b.get_or_load("key2", &mut |_| {
+            Err(Error::internal_from_msg("error".to_string()))
+        });
+        assert_eq!(res, Err(Error::Internal(anyhow!(""))));
+        assert_eq!(b.get("key2"), b.not_found("key2"));
+    }
+
+    fn _test_size_histogram(b: Box<dyn Cave>) {
+        assert_eq!(b.size_histogram(), Ok(vec![]));
+        assert_eq!(b.set("empty", b""), empty_ok());
+        assert_eq!(b.set("one", b"1"), empty_ok());
+        assert_eq!(b.set("three", b"123"), empty_ok());
+        assert_eq!(b.set("four", b"1234"), empty_ok());
+        assert_eq!(b.set("large", &[0; 1000]), empty_ok());
+        assert_eq!(b.size_histogram(), Ok(vec![(1, 2), (4, 2), (1024, 1)]));
+    }
+
+    fn _test_path(b: Box<dyn Cave>) {
+        let parts = ["user", "a:b", "profile"];
+        assert_eq!(b.get_path(&parts), b.not_found("user:a\\:b:profile"));
+        assert_eq!(b.set_path(&parts, b"value"), empty_ok());
+        assert_eq!(b.get_path(&parts), Ok(b"value".to_vec()));
+        assert_eq!(b.get("user:a\\:b:profile"), Ok(b"value".to_vec()));
+        assert_eq!(b.keys().unwrap(), vec!["user:a\\:b:profile"]);
+        assert_eq!(
+            b.get_path(&["user", "a", "b", "profile"]),
+            b.not_found("user:a:b:profile")
+        );
+        assert_eq!(b.delete_path(&parts), empty_ok());
+        assert_eq!(b.get_path(&parts), b.not_found("user:a\\:b:profile"));
+    }
+
+    fn _test_stat(b: Box<dyn Cave>) {
+        assert_eq!(b.stat("test"), Err(Error::NotFound("test".to_string())));
+        assert_eq!(b.set("test", b"value"), empty_ok());
+        assert_eq!(b.stat("test").unwrap().size, 5);
+        assert_eq!(b.set("test", b""), empty_ok());
+        assert_eq!(b.stat("test").unwrap().size, 0);
+        assert_eq!(b.delete("test"), empty_ok());
+        assert_eq!(b.stat("test"), Err(Error::NotFound("test".to_string())));
+    }
+
+    pub(crate) fn _test_bytes(b: Box<dyn Cave>) {
+        let binary_name = b"\xffbinary\x00/";
+        let invalid_name_err = Err(Error::InvalidName {
+            name: String::from_utf8_lossy(binary_name).into_owned(),
+            reason: "",
+        });
+
+        // Names that are valid UTF-8 refer to the same keys as strings.
+        assert_eq!(b.set_bytes(b"test", b"value"), empty_ok());
+        assert_eq!(b.get("test"), Ok(b"value".to_vec()));
+        assert_eq!(b.set("test", b"value2"), empty_ok());
+        assert_eq!(b.get_bytes(b"test"), Ok(b"value2".to_vec()));
+        assert_eq!(b.delete_bytes(b"test"), empty_ok());
+        assert_eq!(b.get("test"), b.not_found("test"));
+
+        if !b.capabilities().binary_keys {
+            assert_eq!(b.set_bytes(binary_name, b"value"), invalid_name_err);
+            assert_eq!(b.get_bytes(binary_name), invalid_name_err);
+            assert_eq!(b.delete_bytes(binary_name), invalid_name_err);
+            return;
+        }
+
+        let not_found_err = b.not_found(&String::from_utf8_lossy(binary_name));
+        assert_eq!(b.get_bytes(binary_name), not_found_err);
+        assert_eq!(b.delete_bytes(binary_name), not_found_err);
+        assert_eq!(b.set_bytes(binary_name, b"value"), empty_ok());
+        assert_eq!(b.get_bytes(binary_name), Ok(b"value".to_vec()));
+        assert_eq!(b.set_bytes(binary_name, b"value2"), empty_ok());
+        assert_eq!(b.get_bytes(binary_name), Ok(b"value2".to_vec()));
+
+        // Keys with binary names are not listed.
+        assert_eq!(b.set("test", b"value"), empty_ok());
+        assert_eq!(b.keys().unwrap(), vec!["test"]);
+        assert_eq!(b.count_prefix("").unwrap(), 1);
+        assert_eq!(b.first_key(), Ok(Some("test".to_string())));
+        assert_eq!(b.last_key(), Ok(Some("test".to_string())));
+        assert_eq!(b.dump().unwrap().len(), 1);
+
+        assert_eq!(b.delete_bytes(binary_name), empty_ok());
+        assert_eq!(b.get_bytes(binary_name), not_found_err);
+        assert_eq!(b.get("test"), Ok(b"value".to_vec()));
+    }
+
+    /// Increment a counter from multiple threads, with a non-atomic
+    /// read-modify-write that is serialized by the lock of the key.
+    fn _test_lock(b: Box<dyn Cave>) {
+        assert_eq!(b.set("counter", &[0]), empty_ok());
+        let b = sync::Arc::new(b);
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let b = sync::Arc::clone(&b);
+                std::thread::spawn(move || {
+                    for _ in 0..25 {
+                        let guard = b.lock("counter").unwrap();
+                        assert_eq!(guard.name(), "counter");
+                        let count = b.get("counter").unwrap()[0];
+                        std::thread::yield_now();
+                        assert_eq!(b.set("counter", &[count + 1]), empty_ok());
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(b.get("counter"), Ok(vec![100]));
+
+        // Locks of different keys should not exclude each other, and the
+        // keys don't have to exist.
+        let _guard1 = b.lock("missing1").unwrap();
+        let _guard2 = b.lock("missing2").unwrap();
+    }
+
+    fn _test_get_existing(b: Box<dyn Cave>) {
+        assert_eq!(b.set("key1", b"value1"), empty_ok());
+        assert_eq!(b.set("key2", b"value2"), empty_ok());
+
+        let map = b.get_existing(&["key1", "missing", "key2"]).unwrap();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map["key1"], b"value1");
+        assert_eq!(map["key2"], b"value2");
+
+        let map = b.get_existing(&["missing"]).unwrap();
+        assert!(map.is_empty());
+    }
+
+    pub(crate) fn _test_exists_many(b: Box<dyn Cave>) {
+        assert_eq!(b.exists_many(&[]), Ok(vec![]));
+        assert_eq!(b.exists_many(&["key1"]), Ok(vec![false]));
+        assert_eq!(b.set("key1", b"value1"), empty_ok());
+        assert_eq!(b.set("key2", b""), empty_ok());
+
+        let names = ["key2", "missing", "key1", "key2"];
+        assert_eq!(b.exists_many(&names), Ok(vec![true, false, true, true]));
+        assert_eq!(b.delete("key2"), empty_ok());
+        assert_eq!(b.exists_many(&names), Ok(vec![false, false, true, false]));
+    }
+
+    pub(crate) fn _test_delete_prefix(b: Box<dyn Cave>) {
+        assert_eq!(b.set("tenant1_key1", b"value"), empty_ok());
+        assert_eq!(b.set("tenant1_key2", b"value"), empty_ok());
+        assert_eq!(b.set("tenant10_key1", b"value"), empty_ok());
+        assert_eq!(b.set("tenant2_key1", b"value"), empty_ok());
+
+        assert_eq!(b.delete_prefix("tenant1_"), Ok(2));
+        assert_eq!(b.delete_prefix("tenant1_"), Ok(0));
+        let mut keys = b.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["tenant10_key1", "tenant2_key1"]);
+
+        // An empty prefix should be rejected.
+        let res = b.delete_prefix("");
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::InvalidName);
+        assert_eq!(b.keys().unwrap().len(), 2);
+    }
+
+    pub(crate) fn _test_rename_prefix(b: Box<dyn Cave>) {
+        assert_eq!(b.set("tenant1_key1", b"value1"), empty_ok());
+        assert_eq!(b.set("tenant1_key2", b"value2"), empty_ok());
+        assert_eq!(b.set("tenant10_key1", b"value3"), empty_ok());
+        assert_eq!(b.set("tenant2_key1", b"value4"), empty_ok());
+
+        // Existing keys with the new names should be overwritten.
+        assert_eq!(b.rename_prefix("tenant1_", "tenant2_"), Ok(2));
+        assert_eq!(b.rename_prefix("tenant1_", "tenant2_"), Ok(0));
+        let mut keys = b.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["tenant10_key1", "tenant2_key1", "tenant2_key2"]);
+        assert_eq!(b.get("tenant2_key1"), Ok(b"value1".to_vec()));
+        assert_eq!(b.get("tenant2_key2"), Ok(b"value2".to_vec()));
+        assert_eq!(b.rename_prefix("tenant2_", "tenant2_"), Ok(2));
+
+        // The new names may match the old prefix as well.
+        assert_eq!(b.rename_prefix("tenant", "tenanttenant"), Ok(3));
+        let mut keys = b.keys().unwrap();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![
+                "tenanttenant10_key1",
+                "tenanttenant2_key1",
+                "tenanttenant2_key2"
+            ]
+        );
+        assert_eq!(b.get("tenanttenant10_key1"), Ok(b"value3".to_vec()));
+
+        // A key whose new name is the old name of another key should not
+        // overwrite it.
+        assert_eq!(b.set("k1", b"value1"), empty_ok());
+        assert_eq!(b.set("kk1", b"value2"), empty_ok());
+        assert_eq!(b.rename_prefix("k", "kk"), Ok(2));
+        assert_eq!(b.get("k1"), b.not_found("k1"));
+        assert_eq!(b.get("kk1"), Ok(b"value1".to_vec()));
+        assert_eq!(b.get("kkk1"), Ok(b"value2".to_vec()));
+
+        // An empty prefix should be rejected.
+        let res = b.rename_prefix("", "tenant_");
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::InvalidName);
+        assert_eq!(b.keys().unwrap().len(), 5);
+    }
+
+    pub(crate) fn _test_take(b: Box<dyn Cave>) {
+        let res = b.take("test");
+        assert_eq!(res, Err(Error::NotFound("test".to_string())));
+
+        assert_eq!(b.set("test", b"value"), empty_ok());
+        assert_eq!(b.take("test"), Ok(b"value".to_vec()));
+        assert_eq!(b.get("test"), Err(Error::NotFound("test".to_string())));
+        assert_eq!(b.take("test"), Err(Error::NotFound("test".to_string())));
+        assert_eq!(b.keys().unwrap(), Vec::<String>::new());
+    }
+
+    /// Append a byte to a key, or delete it once it reaches 3 bytes.
+    fn append_byte(current: Option<Vec<u8>>) -> Option<Vec<u8>> {
+        let mut data = current.unwrap_or_default();
+        if data.len() == 3 {
+            return None;
+        }
+        data.push(b'a');
+        Some(data)
+    }
+
+    pub(crate) fn _test_update_with(b: Box<dyn Cave>) {
+        assert_eq!(b.update_with("test", &mut append_byte), Ok(b"a".to_vec()));
+        assert_eq!(b.update_with("test", &mut append_byte), Ok(b"aa".to_vec()));
+        assert_eq!(b.update_with("test", &mut append_byte), Ok(b"aaa".to_vec()));
+        assert_eq!(b.get("test"), Ok(b"aaa".to_vec()));
+        assert_eq!(b.update_with("test", &mut append_byte), empty_ok());
+        assert_eq!(b.get("test"), Err(Error::NotFound("test".to_string())));
+
+        // Returning `None` for a missing key should be a no-op.
+        assert_eq!(b.update_with("test", &mut |_| None), empty_ok());
+        assert_eq!(b.keys().unwrap(), Vec::<String>::new());
+    }
+
+    /// Test that concurrent updates are not lost.
+    pub(crate) fn _test_update_with_atomic(b: Box<dyn Cave>) {
+        let b = sync::Arc::new(b);
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let b = sync::Arc::clone(&b);
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        let res = b.update_with("counter", &mut |current| {
+                            let mut count = [0; 8];
+                            if let Some(data) = current {
+                                count.copy_from_slice(&data);
+                            }
+                            Some((u64::from_le_bytes(count) + 1).to_le_bytes().to_vec())
+                        });
+                        assert!(res.is_ok());
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut count = [0; 8];
+        count.copy_from_slice(&b.get("counter").unwrap());
+        assert_eq!(u64::from_le_bytes(count), 400);
+    }
+
+    pub(crate) fn _test_replace(b: Box<dyn Cave>) {
+        assert_eq!(b.replace("test", b"value1"), Ok(None));
+        assert_eq!(b.replace("test", b"value2"), Ok(Some(b"value1".to_vec())));
+        assert_eq!(b.get("test"), Ok(b"value2".to_vec()));
+    }
+
+    /// Race many threads to delete the same keys, and check that exactly one
+    /// of them succeeds for each key, while the rest get a `NotFound` error.
+    pub(crate) fn _test_delete_race(b: Box<dyn Cave>) {
+        let threads = 8;
+        let names: Vec<String> = (0..50).map(|i| format!("key{}", i)).collect();
+        for name in &names {
+            assert_eq!(b.set(name, b"value"), empty_ok());
+        }
+
+        let b = sync::Arc::new(b);
+        let names = sync::Arc::new(names);
+        let barrier = sync::Arc::new(sync::Barrier::new(threads));
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let b = sync::Arc::clone(&b);
+                let names = sync::Arc::clone(&names);
+                let barrier = sync::Arc::clone(&barrier);
+                std::thread::spawn(move || {
+                    let _ = barrier.wait();
+                    let mut deleted = 0;
+                    for name in names.iter() {
+                        match b.delete(name) {
+                            Ok(_) => deleted += 1,
+                            res => assert_eq!(res, b.not_found(name)),
+                        }
+                    }
+                    deleted
+                })
+            })
+            .collect();
+
+        let deleted: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(deleted, names.len());
+        assert_eq!(b.keys(), Ok(vec![]));
+    }
+
+    pub(crate) fn _test_create(b: Box<dyn Cave>) {
+        assert_eq!(b.create("test", b"value1"), empty_ok());
+        assert_eq!(
+            b.create("test", b"value2"),
+            Err(Error::AlreadyExists("test".to_string()))
+        );
+        assert_eq!(b.get("test"), Ok(b"value1".to_vec()));
+
+        // A deleted key should be created anew.
+        assert_eq!(b.delete("test"), empty_ok());
+        assert_eq!(b.create("test", b"value2"), empty_ok());
+        assert_eq!(b.get("test"), Ok(b"value2".to_vec()));
+
+        assert_eq!(b.set_if_absent("test", b"value3"), Ok(false));
+        assert_eq!(b.get("test"), Ok(b"value2".to_vec()));
+        assert_eq!(b.set_if_absent("other", b"value3"), Ok(true));
+        assert_eq!(b.get("other"), Ok(b"value3".to_vec()));
+    }
+
+    /// Race many threads to create the same key, each with its own instance
+    /// of the kv, and check that only one of them succeeds.
+    fn _test_create_race(new_cave: impl Fn() -> Box<dyn Cave>) {
+        let threads = 8;
+        let barrier = sync::Arc::new(sync::Barrier::new(threads));
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                let b = new_cave();
+                let barrier = sync::Arc::clone(&barrier);
+                std::thread::spawn(move || {
+                    let _ = barrier.wait();
+                    (t, b.create("leader", &[t as u8; 1024]))
+                })
+            })
+            .collect();
+
+        let mut winners = Vec::new();
+        for handle in handles {
+            match handle.join().unwrap() {
+                (t, Ok(_)) => winners.push(t),
+                (_, res) => assert_eq!(res, Err(Error::AlreadyExists("leader".to_string()))),
+            }
+        }
+        assert_eq!(winners.len(), 1);
+        let b = new_cave();
+        assert_eq!(b.get("leader"), Ok(vec![winners[0] as u8; 1024]));
+    }
+
+    /// Check that a value was written by `_test_concurrency`, and not torn by
+    /// concurrent writes.
+    fn assert_untorn(data: &[u8]) {
+        assert!(data.len() >= 256);
+        assert!(data.iter().all(|&byte| byte == data[0]));
+    }
+
+    /// Hammer the same keys from many threads, with interleaved operations,
+    /// and check that the kv ends up in a consistent state.
+    pub(crate) fn _test_concurrency(b: Box<dyn Cave>) {
+        let names = ["key0", "key1", "key2", "key3"];
+        let b = sync::Arc::new(b);
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let b = sync::Arc::clone(&b);
+                std::thread::spawn(move || {
+                    for i in 0..200 {
+                        let name = names[i % names.len()];
+                        match (t + i) % 3 {
+                            0 => {
+                                let data = vec![(t * 16 + i % 16) as u8; 256 + i];
+                                assert_eq!(b.set(name, &data), empty_ok());
+                            }
+                            1 => match b.get(name) {
+                                Ok(data) => assert_untorn(&data),
+                                Err(e) => assert_eq!(e, Error::NotFound(name.to_string())),
+                            },
+                            _ => match b.delete(name) {
+                                Ok(_) => (),
+                                Err(e) => assert_eq!(e, Error::NotFound(name.to_string())),
+                            },
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        // A thread that panicked or deadlocked would fail the test here.
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let keys = b.keys().unwrap();
+        for name in names.iter() {
+            match b.get(name) {
+                Ok(data) => {
+                    assert_untorn(&data);
+                    assert!(keys.contains(&name.to_string()));
+                }
+                Err(e) => {
+                    assert_eq!(e, Error::NotFound(name.to_string()));
+                    assert!(!keys.contains(&name.to_string()));
+                }
+            }
+        }
+    }
+
+    /// Test a kv that rejects values larger than 5 bytes.
+    pub(crate) fn _test_max_value_size(b: Box<dyn Cave>) {
+        assert_eq!(b.set("test", b"value"), empty_ok());
+        let res = b.set("test", b"value2");
+        assert_eq!(res, Err(Error::ValueTooLarge { size: 6, limit: 5 }));
+        assert_eq!(b.get("test"), Ok(b"value".to_vec()));
+        let res = b.set("test2", b"value2");
+        assert_eq!(res, Err(Error::ValueTooLarge { size: 6, limit: 5 }));
+        assert_eq!(b.keys().unwrap(), vec!["test"]);
+    }
+
+    fn _test_snapshot(b: Box<dyn Cave>) {
+        assert_eq!(b.set("key1", b"value1"), empty_ok());
+        assert_eq!(b.set("key2", b"value2"), empty_ok());
+
+        let snapshot = b.snapshot().unwrap();
+        assert_eq!(b.set("key1", b"value3"), empty_ok());
+        assert_eq!(b.delete("key2"), empty_ok());
+        assert_eq!(b.set("key3", b"value3"), empty_ok());
+
+        // The snapshot should not be affected by the writes.
+        assert_eq!(snapshot.get("key1"), Ok(b"value1".to_vec()));
+        assert_eq!(snapshot.get("key2"), Ok(b"value2".to_vec()));
+        let res = snapshot.get("key3");
+        assert_eq!(res, Err(Error::NotFound("key3".to_string())));
+        let mut keys = snapshot.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["key1", "key2"]);
+
+        // The snapshot should be read-only.
+        let res = snapshot.set("key1", b"value4");
+        assert_eq!(res, Err(Error::Unsupported { operation: "set" }));
+        let res = snapshot.delete("key1");
+        let unsupported_err = Error::Unsupported {
+            operation: "delete",
+        };
+        assert_eq!(res, Err(unsupported_err));
+        assert_eq!(b.get("key1"), Ok(b"value3".to_vec()));
+    }
+
+    #[test]
+    fn test_pointers() {
+        fn set_through<C: Cave>(b: C) {
+            assert_eq!(b.set("test", b"value"), empty_ok());
+        }
+
+        let mb = MemoryCave::new();
+        set_through(&mb);
+        assert_eq!(mb.get("test"), Ok(b"value".to_vec()));
+
+        let mb: sync::Arc<dyn Cave> = sync::Arc::new(MemoryCave::new());
+        set_through(sync::Arc::clone(&mb));
+        assert_eq!(mb.get("test"), Ok(b"value".to_vec()));
+
+        _test_simple(Box::new(sync::Arc::new(MemoryCave::new())));
+        let mb: Box<dyn Cave> = Box::new(MemoryCave::new());
+        _test_simple(Box::new(mb));
+
+        // Overridden methods should be forwarded, instead of falling back to
+        // the default implementation.
+        let mb = sync::Arc::new(MemoryCave::new());
+        assert!(mb.snapshot().is_ok());
+    }
+
+    #[test]
+    fn test_versions() {
+        let b = MemoryCave::new();
+        let not_found_err = Error::NotFound("test".to_string());
+
+        assert_eq!(b.version("test"), Err(not_found_err));
+        let res = b.get_if_changed("test", "");
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::NotFound);
+
+        assert_eq!(b.set("test", b"value"), empty_ok());
+        let version = b.version("test").unwrap();
+        let res = b.get_if_changed("test", "unknown");
+        assert_eq!(res, Ok(Some((b"value".to_vec(), version.clone()))));
+        let res = b.get_if_changed("test", &version);
+        assert_eq!(res, Ok(None));
+
+        // The version should change along with the contents.
+        assert_eq!(b.set("test", b"value2"), empty_ok());
+        let res = b.get_if_changed("test", &version).unwrap();
+        let (data, new_version) = res.unwrap();
+        assert_eq!(data, b"value2");
+        assert_ne!(new_version, version);
+        assert_eq!(b.version("test"), Ok(new_version));
+    }
+
+    #[test]
+    fn test_memory_backend_simple() {
+        let mb = MemoryCave::new();
+        _test_simple(Box::new(mb))
+    }
+
+    #[test]
+    fn test_memory_backend_with_capacity() {
+        let mb = MemoryCave::with_capacity(1000);
+        assert!(mb.hash_map.read().unwrap().capacity() >= 1000);
+        assert_eq!(MemoryCave::new().hash_map.read().unwrap().capacity(), 0);
+        _test_simple(Box::new(mb))
+    }
+
+    #[test]
+    fn test_memory_backend_keys() {
+        let mb = MemoryCave::new();
+        _test_keys(Box::new(mb))
+    }
+
+    #[test]
+    fn test_memory_backend_first_last_key() {
+        let mb = MemoryCave::new();
+        _test_first_last_key(Box::new(mb))
+    }
+
+    #[test]
+    fn test_memory_backend_range() {
+        let mb = MemoryCave::new();
+        _test_range(Box::new(mb))
+    }
+
+    #[test]
+    fn test_memory_backend_swap() {
+        let mb = MemoryCave::new();
+        _test_swap(Box::new(mb))
+    }
+
+    #[test]
+    fn test_memory_backend_count_prefix() {
+        let mb = MemoryCave::new();
+        _test_count_prefix(Box::new(mb))
+    }
+
+    #[test]
+    fn test_memory_backend_for_each() {
+        let mb = MemoryCave::new();
+        _test_for_each(Box::new(mb));
+
+        let mb = MemoryCave::new();
+        assert_eq!(mb.set("key1", b"value1"), empty_ok());
+        assert_eq!(mb.set("key2", b"value2"), empty_ok());
+        assert_eq!(mb.set_bytes(b"\xff", b"binary"), empty_ok());
+        let mut visited = Vec::new();
+        mb.for_each_ref(|name, data| visited.push((name.to_string(), data.to_vec())));
+        visited.sort();
+        assert_eq!(
+            visited,
+            vec![
+                ("key1".to_string(), b"value1".to_vec()),
+                ("key2".to_string(), b"value2".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let caps = MemoryCave::new().capabilities();
+        assert!(caps.keys && caps.watch && caps.snapshot);
+        let caps = Box::new(MemoryCave::new()).capabilities();
+        assert!(caps.keys && caps.watch && caps.snapshot);
+
+        // The capabilities that are not reported should match the default
+        // implementations.
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        let caps = fb.capabilities();
+        assert!(caps.keys && caps.lock && !caps.watch && !caps.snapshot);
+        let res = fb.watch("test");
+        assert_eq!(res.err(), Some(Error::Unsupported { operation: "watch" }));
+        let res = fb.snapshot();
+        let err = Error::Unsupported {
+            operation: "snapshot",
+        };
+        assert_eq!(res.err(), Some(err));
+    }
+
+    #[test]
+    fn test_memory_backend_dump() {
+        let mb = MemoryCave::new();
+        _test_dump(Box::new(mb))
+    }
+
+    #[test]
+    fn test_memory_backend_from_map() {
+        let mut map = collections::HashMap::new();
+        let _ = map.insert("key1".to_string(), b"value1".to_vec());
+        let _ = map.insert("key2".to_string(), b"value2".to_vec());
+
+        let mb = MemoryCave::from_map(map.clone());
+        assert_eq!(mb.dump(), Ok(map.clone()));
+
+        let mb: MemoryCave = map.clone().into_iter().collect();
+        assert_eq!(mb.dump(), Ok(map));
+
+        let entries = vec![
+            ("key".to_string(), b"value1".to_vec()),
+            ("key".to_string(), b"value2".to_vec()),
+        ];
+        let mb: MemoryCave = entries.into_iter().collect();
+        assert_eq!(mb.get("key"), Ok(b"value2".to_vec()));
+    }
+
+    #[test]
+    fn test_memory_backend_debug() {
+        // The contents of the kv should not be printed.
+        let mb = MemoryCave::new();
+        assert_eq!(mb.set("key", b"secret"), empty_ok());
+        assert_eq!(mb.set_bytes(b"\xff", b"secret"), empty_ok());
+        assert_eq!(
+            format!("{:?}", mb),
+            "MemoryCave { keys: 2, max_value_size: None, .. }"
+        );
+    }
+
+    #[test]
+    fn test_memory_backend_get_or() {
+        let mb = MemoryCave::new();
+        _test_get_or(Box::new(mb))
+    }
+
+    #[test]
+    fn test_memory_backend_keys_sorted() {
+        let mb = MemoryCave::new();
+        _test_keys_sorted(Box::new(mb))
+    }
+
+    #[test]
+    fn test_memory_backend_size_histogram() {
+        let mb = MemoryCave::new();
+        _test_size_histogram(Box::new(mb))
+    }
+
+    #[test]
+    fn test_memory_backend_get_or_load() {
+        let mb = MemoryCave::new();
+        _test_get_or_load(Box::new(mb))
+    }
+
+    #[test]
+    fn test_memory_backend_lock() {
+        let mb = sync::Arc::new(MemoryCave::new());
+        _test_lock(Box::new(sync::Arc::clone(&mb)));
+
+        // The registry of locked keys should not grow indefinitely.
+        assert!(mb.locks.is_empty());
+    }
+
+    #[test]
+    fn test_memory_backend_get_existing() {
+        let mb = MemoryCave::new();
+        _test_get_existing(Box::new(mb))
+    }
+
+    #[test]
+    fn test_memory_backend_exists_many() {
+        let mb = MemoryCave::new();
+        _test_exists_many(Box::new(mb))
+    }
+
+    #[test]
+    fn test_memory_backend_delete_prefix() {
+        let mb = MemoryCave::new();
+        _test_delete_prefix(Box::new(mb))
+    }
+
+    #[test]
+    fn test_memory_backend_rename_prefix() {
+        let mb = MemoryCave::new();
+        _test_rename_prefix(Box::new(mb))
+    }
+
+    #[test]
+    fn test_memory_backend_take() {
+        let mb = MemoryCave::new();
+        _test_take(Box::new(mb))
+    }
+
+    #[test]
+    fn test_memory_backend_update_with() {
+        _test_update_with(Box::new(MemoryCave::new()));
+        _test_update_with_atomic(Box::new(MemoryCave::new()));
+    }
+
+    #[test]
+    fn test_memory_backend_replace() {
+        _test_replace(Box::new(MemoryCave::new()));
+    }
+
+    #[test]
+    fn test_memory_backend_delete_race() {
+        _test_delete_race(Box::new(MemoryCave::new()));
+    }
+
+    #[test]
+    fn test_memory_backend_create() {
+        _test_create(Box::new(MemoryCave::new()));
+        let mb = MemoryCave::new().with_max_value_size(4);
+        assert_eq!(
+            mb.create("test", b"value"),
+            Err(Error::ValueTooLarge { size: 5, limit: 4 })
+        );
+    }
+
+    #[test]
+    fn test_memory_backend_watch() {
+        let mb = MemoryCave::new();
+        let event = |key: &str, kind| ChangeEvent {
+            key: key.to_string(),
+            kind,
+        };
+
+        let key_rx = mb.watch("tenant1_key1").unwrap();
+        let prefix_rx = mb.watch_prefix("tenant1_").unwrap();
+        assert_eq!(mb.set("tenant1_key1", b"value"), empty_ok());
+        assert_eq!(mb.set("tenant1_key2", b"value"), empty_ok());
+        assert_eq!(mb.set("tenant2_key1", b"value"), empty_ok());
+        assert_eq!(mb.delete("tenant1_key1"), empty_ok());
+        assert_eq!(mb.delete_prefix("tenant1_"), Ok(1));
+        // Failed operations should not send events.
+        assert!(mb.delete("tenant1_key1").is_err());
+
+        let events: Vec<_> = key_rx.try_iter().collect();
+        assert_eq!(
+            events,
+            vec![
+                event("tenant1_key1", ChangeKind::Set),
+                event("tenant1_key1", ChangeKind::Delete),
+            ]
+        );
+        let events: Vec<_> = prefix_rx.try_iter().collect();
+        assert_eq!(
+            events,
+            vec![
+                event("tenant1_key1", ChangeKind::Set),
+                event("tenant1_key2", ChangeKind::Set),
+                event("tenant1_key1", ChangeKind::Delete),
+                event("tenant1_key2", ChangeKind::Delete),
+            ]
+        );
+
+        // Dropped receivers should be unregistered, even if no key they
+        // watch changes.
+        drop(key_rx);
+        drop(prefix_rx);
+        assert_eq!(mb.set("tenant2_key1", b"value"), empty_ok());
+        assert!(mb.watchers.is_empty());
+
+        // Backends that can't watch their keys should report it.
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        let unsupported_err = Error::Unsupported { operation: "watch" };
+        assert_eq!(fb.watch("test").err(), Some(unsupported_err));
+    }
+
+    #[test]
+    fn test_memory_backend_concurrency() {
+        _test_concurrency(Box::new(MemoryCave::new()));
+    }
+
+    #[test]
+    fn test_memory_backend_max_value_size() {
+        let mb = MemoryCave::new().with_max_value_size(5);
+        _test_max_value_size(Box::new(mb))
+    }
+
+    #[test]
+    fn test_memory_backend_snapshot() {
+        let mb = MemoryCave::new();
+        _test_snapshot(Box::new(mb))
+    }
+
+    #[test]
+    fn test_null_backend() {
+        let nb = NullCave::new();
+        let not_found_err = Err(Error::NotFound("test".to_string()));
+
+        assert_eq!(nb.set("test", b"value"), empty_ok());
+        assert_eq!(nb.get("test"), not_found_err);
+        assert_eq!(nb.delete("test"), empty_ok());
+        assert_eq!(nb.keys().unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_file_backend_simple() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_simple(Box::new(fb))
+    }
+
+    #[test]
+    fn test_file_backend_keys() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_keys(Box::new(fb))
+    }
+
+    #[test]
+    fn test_file_backend_first_last_key() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_first_last_key(Box::new(fb))
+    }
+
+    #[test]
+    fn test_file_backend_range() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_range(Box::new(fb))
+    }
+
+    #[test]
+    fn test_file_backend_count_prefix() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_count_prefix(Box::new(fb));
+    }
+
+    #[test]
+    fn test_file_backend_for_each() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_for_each(Box::new(fb));
+    }
+
+    #[test]
+    fn test_file_backend_dump() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_dump(Box::new(fb));
+    }
+
+    #[test]
+    fn test_file_backend_lock() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_lock(Box::new(fb));
+
+        // The lock files should not be listed as keys.
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        assert_eq!(fb.keys().unwrap(), vec!["counter"]);
+        assert!(temp_dir.path().join(".locks").join("counter").exists());
+    }
+
+    #[test]
+    fn test_file_backend_keys_sorted() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_keys_sorted(Box::new(fb));
+    }
+
+    #[test]
+    fn test_file_backend_size_histogram() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_size_histogram(Box::new(fb));
+
+        // The sizes of compressed values should be the uncompressed ones.
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap().with_gzip(6);
+        _test_size_histogram(Box::new(fb));
+    }
+
+    #[test]
+    fn test_file_backend_get_or_load() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_get_or_load(Box::new(fb));
+    }
+
+    #[test]
+    fn test_memory_backend_bytes() {
+        let mb = MemoryCave::new();
+        _test_bytes(Box::new(mb))
+    }
+
+    #[test]
+    fn test_memory_backend_path() {
+        let mb = MemoryCave::new();
+        _test_path(Box::new(mb))
+    }
+
+    #[test]
+    fn test_memory_backend_stat() {
+        let mb = MemoryCave::new();
+        _test_stat(Box::new(mb));
+
+        // The modification time should be tracked.
+        let mb = MemoryCave::new();
+        let before = time::SystemTime::now();
+        assert_eq!(mb.set("test", b"value"), empty_ok());
+        let modified = mb.stat("test").unwrap().modified.unwrap();
+        assert!(before <= modified && modified <= time::SystemTime::now());
+        assert_eq!(mb.set("test2", b"value"), empty_ok());
+        assert_eq!(mb.swap("test", "test2"), empty_ok());
+        assert!(mb.stat("test").unwrap().modified.unwrap() >= modified);
+
+        let mut map = collections::HashMap::new();
+        let _ = map.insert("test".to_string(), b"value".to_vec());
+        let mb = MemoryCave::from_map(map);
+        assert!(mb.stat("test").unwrap().modified.is_some());
+    }
+
+    #[test]
+    fn test_memory_backend_persist_to() {
+        let mb = MemoryCave::new();
+        assert_eq!(mb.set("key1", b"value1"), empty_ok());
+        assert_eq!(mb.set("key2", b"value2"), empty_ok());
+        assert_eq!(mb.set_bytes(b"\xffkey3", b"value3"), empty_ok());
+
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        assert_eq!(fb.set("key1", b"old"), empty_ok());
+        assert_eq!(mb.persist_to(&fb), Ok(3));
+        assert_eq!(fb.dump().unwrap(), mb.dump().unwrap());
+        assert_eq!(fb.get_bytes(b"\xffkey3"), Ok(b"value3".to_vec()));
+
+        // Errors of the other `Cave` should be returned.
+        let wb = WriteBehindCave::new(MemoryCave::new());
+        let invalid_name_err = Err(Error::InvalidName {
+            name: String::from_utf8_lossy(b"\xffkey3").into_owned(),
+            reason: "",
+        });
+        assert_eq!(mb.persist_to(&wb), invalid_name_err);
+    }
+
+    #[test]
+    fn test_memory_backend_disk_usage() {
+        let mb = MemoryCave::new();
+        assert_eq!(mb.set("test", b"value"), empty_ok());
+        assert_eq!(mb.disk_usage(), Ok(0));
+    }
+
+    #[test]
+    fn test_file_backend_bytes() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        assert_eq!(fb.set_bytes(b"\xffkey", b"value"), empty_ok());
+        assert!(temp_dir.path().join(".binary").join("ff6b6579").is_file());
+        assert_eq!(fb.delete_bytes(b"\xffkey"), empty_ok());
+        _test_bytes(Box::new(fb));
+
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap().with_gzip(6);
+        _test_bytes(Box::new(fb));
+    }
+
+    #[test]
+    fn test_file_backend_hashed_names() {
+        let tests: [fn(Box<dyn Cave>); 6] = [
+            _test_simple,
+            _test_keys,
+            _test_swap,
+            _test_take,
+            _test_bytes,
+            _test_lock,
+        ];
+        for test in &tests {
+            let temp_dir = assert_fs::TempDir::new().unwrap();
+            let fb = FileCave::new(temp_dir.path()).unwrap().with_hashed_names();
+            test(Box::new(fb));
+        }
+
+        // Names that are too long for a file name should be accepted.
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap().with_hashed_names();
+        let long_name = format!("https://example.com/{}", "a".repeat(300));
+        assert_eq!(fb.set(&long_name, b"value"), empty_ok());
+        assert_eq!(fb.get(&long_name), Ok(b"value".to_vec()));
+        assert_eq!(fb.keys().unwrap(), vec![long_name.clone()]);
+        let file_name = content_hash(long_name.as_bytes());
+        assert!(temp_dir.path().join(&file_name).is_file());
+
+        // Simulate a hash collision, by storing the file of another key in
+        // the place of the file of our key.
+        let other_name = "other";
+        let other_path = temp_dir.path().join(content_hash(other_name.as_bytes()));
+        let _ = fs::copy(temp_dir.path().join(&file_name), &other_path).unwrap();
+        assert_eq!(fb.get(other_name), fb.not_found(other_name));
+        assert_eq!(fb.delete(other_name), fb.not_found(other_name));
+        assert_eq!(fb.take(other_name), fb.not_found(other_name));
+        let internal_err = Err(Error::Internal(anyhow!("")));
+        assert_eq!(fb.set(other_name, b"value2"), internal_err);
+        assert_eq!(fb.get(&long_name), Ok(b"value".to_vec()));
+        fs::remove_file(&other_path).unwrap();
+
+        // Files without a header are ignored.
+        fs::write(temp_dir.path().join("external"), b"value").unwrap();
+        assert_eq!(fb.keys().unwrap(), vec![long_name.clone()]);
+
+        // Compressed files should store the header as well.
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path())
+            .unwrap()
+            .with_hashed_names()
+            .with_gzip(6);
+        assert_eq!(fb.set(&long_name, b"value"), empty_ok());
+        assert_eq!(fb.get(&long_name), Ok(b"value".to_vec()));
+        assert_eq!(fb.keys().unwrap(), vec![long_name.clone()]);
+        assert!(temp_dir.path().join(format!("{}.gz", file_name)).is_file());
+        _test_simple(Box::new(fb));
+    }
+
+    #[test]
+    fn test_file_backend_inline() {
+        let tests: [fn(Box<dyn Cave>); 8] = [
+            _test_simple,
+            _test_keys,
+            _test_swap,
+            _test_take,
+            _test_bytes,
+            _test_stat,
+            _test_rename_prefix,
+            _test_concurrency,
+        ];
+        for test in &tests {
+            let temp_dir = assert_fs::TempDir::new().unwrap();
+            let fb = FileCave::new(temp_dir.path())
+                .unwrap()
+                .with_inline_threshold(6);
+            test(Box::new(fb));
+        }
+
+        // Small values should be stored in the log, and large values in
+        // files. A key should move between them when its size changes.
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path())
+            .unwrap()
+            .with_inline_threshold(6)
+            .with_gzip(1);
+        let key_path = temp_dir.path().join("key.gz");
+        assert_eq!(fb.set("key", b"small"), empty_ok());
+        assert!(!key_path.exists());
+        assert_eq!(fb.set("key", b"large value"), empty_ok());
+        assert!(key_path.is_file());
+        assert_eq!(fb.get("key"), Ok(b"large value".to_vec()));
+        assert_eq!(fb.keys().unwrap(), vec!["key"]);
+        assert_eq!(fb.set("key", b"small"), empty_ok());
+        assert!(!key_path.exists());
+        assert_eq!(fb.get("key"), Ok(b"small".to_vec()));
+        assert_eq!(fb.keys().unwrap(), vec!["key"]);
+        assert_eq!(fb.stat("key").unwrap().modified, None);
+
+        // Another instance, e.g., in another process, should see the changes
+        // of this one, and vice versa.
+        let fb2 = FileCave::new(temp_dir.path())
+            .unwrap()
+            .with_inline_threshold(6)
+            .with_gzip(1);
+        assert_eq!(fb2.get("key"), Ok(b"small".to_vec()));
+        assert_eq!(fb2.set("key2", b"value"), empty_ok());
+        assert_eq!(fb.get("key2"), Ok(b"value".to_vec()));
+        assert_eq!(fb.delete("key2"), empty_ok());
+        assert_eq!(fb2.get("key2"), fb2.not_found("key2"));
+        assert_eq!(fb2.delete("key2"), fb2.not_found("key2"));
+
+        // Compacting the log should drop the old records, and the other
+        // instance should move to the compacted log.
+        let log_path = temp_dir.path().join(FILE_INLINE_DIR).join("log");
+        let log_len = || fs::metadata(&log_path).unwrap().len();
+        let old_len = log_len();
+        assert_eq!(fb.maintain(), empty_ok());
+        assert!(log_len() < old_len);
+        assert_eq!(fb2.get("key"), Ok(b"small".to_vec()));
+        assert_eq!(fb2.set("key3", b"value"), empty_ok());
+        assert_eq!(fb.get("key3"), Ok(b"value".to_vec()));
+        let mut keys = fb.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["key", "key3"]);
+
+        // A record that was not written fully should be ignored, and then
+        // overwritten by the next write.
+        let old_len = log_len();
+        let mut log = fs::OpenOptions::new().append(true).open(&log_path).unwrap();
+        log.write_all(&[1, 0, 0]).unwrap();
+        drop(log);
+        let fb3 = FileCave::new(temp_dir.path())
+            .unwrap()
+            .with_inline_threshold(6)
+            .with_gzip(1);
+        assert_eq!(fb3.keys().unwrap().len(), 2);
+        assert_eq!(fb3.delete("key3"), empty_ok());
+        assert_eq!(fb.get("key3"), fb.not_found("key3"));
+        assert!(log_len() > old_len);
+        assert_eq!(fb.maintain(), empty_ok());
+        assert_eq!(fb3.get("key"), Ok(b"small".to_vec()));
+
+        // A `set` that was interrupted after moving a key to the log may
+        // leave its file behind, which a `delete` should remove as well.
+        let plain = FileCave::new(temp_dir.path()).unwrap().with_gzip(1);
+        assert_eq!(plain.set("key4", b"stale value"), empty_ok());
+        assert_eq!(fb.set("key4", b"new"), empty_ok());
+        assert_eq!(plain.set("key4", b"stale value"), empty_ok());
+        assert_eq!(fb.get("key4"), Ok(b"new".to_vec()));
+        assert_eq!(fb.delete("key4"), empty_ok());
+        assert_eq!(fb.get("key4"), fb.not_found("key4"));
+        assert_eq!(fb.delete("key4"), fb.not_found("key4"));
+        // The same should hold if only the file is left.
+        assert_eq!(plain.set("key4", b"stale value"), empty_ok());
+        assert_eq!(fb.delete("key4"), empty_ok());
+        assert_eq!(fb.get("key4"), fb.not_found("key4"));
+
+        // Concurrent writes that move a key in opposite directions should
+        // never make it disappear.
+        std::thread::scope(|s| {
+            for (fb, data) in [(&fb, &b"small"[..]), (&fb2, &b"large value"[..])] {
+                let _ = s.spawn(move || {
+                    for _ in 0..100 {
+                        assert_eq!(fb.set("key5", data), empty_ok());
+                        assert!(fb.get("key5").is_ok());
+                    }
+                });
+            }
+        });
+        let data = fb.get("key5").unwrap();
+        assert!(data == b"small" || data == b"large value");
+    }
+
+    #[test]
+    fn test_file_backend_path() {
+        // Windows does not allow the separator in file names, so we hash
+        // them.
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap().with_hashed_names();
+        _test_path(Box::new(fb))
+    }
+
+    #[test]
+    fn test_file_backend_stat() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_stat(Box::new(fb));
+
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        assert_eq!(fb.set("test", b"value"), empty_ok());
+        let md = fs::metadata(temp_dir.path().join("test")).unwrap();
+        let metadata = Metadata {
+            size: 5,
+            modified: Some(md.modified().unwrap()),
+        };
+        assert_eq!(fb.stat("test"), Ok(metadata));
+
+        // The size should not include the compression or the header.
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path())
+            .unwrap()
+            .with_gzip(6)
+            .with_hashed_names();
+        _test_stat(Box::new(fb));
+
+        // Directories cannot be accessed as keys.
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        fs::create_dir(temp_dir.path().join("dir")).unwrap();
+        let invalid_name_err = Err(Error::InvalidName {
+            name: "dir".to_string(),
+            reason: "",
+        });
+        assert_eq!(fb.stat("dir"), invalid_name_err);
+    }
+
+    #[test]
+    fn test_file_backend_disk_usage() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        assert_eq!(fb.disk_usage(), Ok(0));
+        assert_eq!(fb.set("test", b"value"), empty_ok());
+        assert_eq!(fb.set("test2", b"value2"), empty_ok());
+        assert_eq!(fb.disk_usage(), Ok(11));
+
+        // Files in subdirectories, e.g., lock files, count as well.
+        let _guard = fb.lock("test").unwrap();
+        fs::write(temp_dir.path().join(".locks").join("test"), b"x").unwrap();
+        assert_eq!(fb.disk_usage(), Ok(12));
+    }
+
+    #[test]
+    fn test_file_backend_maintain() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        assert_eq!(fb.set("test", b"value"), empty_ok());
+
+        // Simulate the leftovers of interrupted operations, and a directory
+        // that is not ours.
+        for dir in &[".atomicwriteAbC", ".takeXyZ", ".swap123", "other"] {
+            let path = temp_dir.path().join(dir);
+            fs::create_dir(&path).unwrap();
+            fs::write(path.join("value"), b"stale").unwrap();
+        }
+
+        assert_eq!(fb.maintain(), empty_ok());
+        assert!(!temp_dir.path().join(".atomicwriteAbC").exists());
+        assert!(!temp_dir.path().join(".takeXyZ").exists());
+        assert!(!temp_dir.path().join(".swap123").exists());
+        assert!(temp_dir.path().join("other").join("value").exists());
+        assert_eq!(fb.keys().unwrap(), vec!["test"]);
+        assert_eq!(fb.get("test"), Ok(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_file_backend_swap() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_swap(Box::new(fb));
+
+        let gzip_dir = temp_dir.path().join("gzip");
+        let fb = FileCave::create(&gzip_dir).unwrap().with_gzip(1);
+        _test_swap(Box::new(fb));
+    }
+
+    #[test]
+    fn test_file_backend_get_existing() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_get_existing(Box::new(fb))
+    }
+
+    #[test]
+    fn test_file_backend_exists_many() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_exists_many(Box::new(fb));
+
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path())
+            .unwrap()
+            .with_hashed_names()
+            .with_gzip(6);
+        _test_exists_many(Box::new(fb));
+
+        // Directories cannot be accessed as keys.
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        fs::create_dir(temp_dir.path().join("dir")).unwrap();
+        let invalid_name_err = Err(Error::InvalidName {
+            name: "dir".to_string(),
+            reason: "",
+        });
+        assert_eq!(fb.exists_many(&["dir"]), invalid_name_err);
+    }
+
+    #[test]
+    fn test_file_backend_delete_prefix() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_delete_prefix(Box::new(fb));
+    }
+
+    #[test]
+    fn test_file_backend_rename_prefix() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_rename_prefix(Box::new(fb));
+
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap().with_gzip(1);
+        _test_rename_prefix(Box::new(fb));
+
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap().with_hashed_names();
+        _test_rename_prefix(Box::new(fb));
+
+        // If a key cannot be renamed, the keys that have not been renamed
+        // should keep their old names, and no temporary directory should be
+        // left behind.
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        assert_eq!(fb.set("old_key", b"value"), empty_ok());
+        fs::create_dir(temp_dir.path().join("new_key")).unwrap();
+        let res = fb.rename_prefix("old_", "new_");
+        assert_eq!(
+            res,
+            Err(Error::InvalidName {
+                name: "new_key".to_string(),
+                reason: "",
+            })
+        );
+        assert_eq!(fb.get("old_key"), Ok(b"value".to_vec()));
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_file_backend_take() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_take(Box::new(fb));
+
+        // Values should be decompressed, and no temporary files should be
+        // left behind.
+        let fb = FileCave::new(temp_dir.path()).unwrap().with_gzip(6);
+        assert_eq!(fb.set("test", b"value"), empty_ok());
+        assert_eq!(fb.take("test"), Ok(b"value".to_vec()));
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_file_backend_update_with() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_update_with(Box::new(fb));
+    }
+
+    #[test]
+    fn test_file_backend_replace() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_replace(Box::new(fb));
+    }
+
+    #[cfg(feature = "test-util")]
+    #[test]
+    fn test_file_backend_temporary() {
+        let (fb, temp_dir) = FileCave::temporary().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        _test_simple(Box::new(fb));
+
+        // The directory should be removed along with the guard.
+        assert!(dir.is_dir());
+        drop(temp_dir);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("LOCK", "LOCK"));
+        assert!(!glob_match("LOCK", "LOCK2"));
+        assert!(!glob_match("LOCK", "LOC"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*.lock", "app.lock"));
+        assert!(glob_match("*.lock", ".lock"));
+        assert!(!glob_match("*.lock", "app.lock.gz"));
+        assert!(glob_match("meta-??.json", "meta-01.json"));
+        assert!(!glob_match("meta-??.json", "meta-1.json"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+        assert!(glob_match("**", "anything"));
+    }
+
+    #[test]
+    fn test_file_backend_ignore() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path())
+            .unwrap()
+            .with_ignore(&["LOCK", "*.meta"]);
+        fs::write(temp_dir.path().join("LOCK"), b"").unwrap();
+        fs::write(temp_dir.path().join("app.meta"), b"").unwrap();
+        assert_eq!(fb.set("key", b"value"), empty_ok());
+        assert_eq!(fb.set("LOCKS", b"value"), empty_ok());
+
+        let mut keys = fb.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["LOCKS", "key"]);
+        assert_eq!(fb.count_prefix("LOCK"), Ok(1));
+        // The ignored files should still be accessible by name.
+        assert_eq!(fb.get("LOCK"), Ok(vec![]));
+
+        // The patterns should match the names of the files, which have an
+        // extension if they are compressed.
+        let fb = FileCave::new(temp_dir.path())
+            .unwrap()
+            .with_gzip(6)
+            .with_ignore(&["*.meta.gz"]);
+        fs::write(temp_dir.path().join("app.meta.gz"), b"").unwrap();
+        assert_eq!(fb.set("key", b"value"), empty_ok());
+        assert_eq!(fb.keys(), Ok(vec!["key".to_string()]));
+    }
+
+    #[test]
+    fn test_file_backend_reopen() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let dir1 = temp_dir.path().join("dir1");
+        let dir2 = temp_dir.path().join("dir2");
+        fs::create_dir(&dir2).unwrap();
+        let fb = FileCave::create(&dir1).unwrap().with_inline_threshold(4);
+        assert_eq!(fb.set("small", b"abc"), empty_ok());
+        assert_eq!(fb.set("large", b"abcdef"), empty_ok());
+
+        // An invalid directory should be rejected, and the current one should
+        // be kept.
+        let res = fb.reopen(&temp_dir.path().join("missing"));
+        assert_eq!(res, Err(Error::Internal(anyhow!(""))));
+        assert_eq!(fb.get("small"), Ok(b"abc".to_vec()));
+
+        // The new directory should be used for the files and the inline
+        // values.
+        assert_eq!(fb.reopen(&dir2), Ok(()));
+        assert_eq!(fb.keys(), Ok(vec![]));
+        assert_eq!(fb.set("small", b"xyz"), empty_ok());
+        assert_eq!(fb.set("large", b"uvwxyz"), empty_ok());
+        assert!(dir2.join(FILE_INLINE_DIR).join("log").is_file());
+        assert!(dir2.join("large").is_file());
+
+        // The old directory should keep its keys.
+        assert_eq!(fb.reopen(&dir1), Ok(()));
+        assert_eq!(fb.get("small"), Ok(b"abc".to_vec()));
+        assert_eq!(fb.get("large"), Ok(b"abcdef".to_vec()));
+
+        // Every write that runs during a switch should end up whole in one of
+        // the directories.
+        let name = |t, i| format!("key{}_{}", t, i);
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let fb = &fb;
+                let _ = s.spawn(move || {
+                    for i in 0..50 {
+                        assert_eq!(fb.set(&name(t, i), b"value"), empty_ok());
+                    }
+                });
+            }
+            assert_eq!(fb.reopen(&dir2), Ok(()));
+        });
+        let fb1 = FileCave::new(&dir1).unwrap().with_inline_threshold(4);
+        let fb2 = FileCave::new(&dir2).unwrap().with_inline_threshold(4);
+        for t in 0..4 {
+            for i in 0..50 {
+                let found = [fb1.get(&name(t, i)), fb2.get(&name(t, i))];
+                let found: Vec<_> = found.iter().filter_map(|res| res.as_ref().ok()).collect();
+                assert_eq!(found, vec![b"value"]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_file_backend_reopen_race() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let dir1 = temp_dir.path().join("dir1");
+        let dir2 = temp_dir.path().join("dir2");
+        let fb = FileCave::create(&dir1).unwrap();
+        let fb1 = FileCave::new(&dir1).unwrap();
+        let fb2 = FileCave::create(&dir2).unwrap();
+        let names: Vec<_> = (0..50).map(|i| format!("key{}", i)).collect();
+        for name in &names {
+            assert_eq!(fb1.set(name, b"old1"), empty_ok());
+            assert_eq!(fb2.set(name, b"old2"), empty_ok());
+            assert_eq!(fb1.set(&format!("prefix_{}", name), b""), empty_ok());
+            assert_eq!(fb2.set(&format!("prefix_{}", name), b""), empty_ok());
+        }
+
+        // Every `replace` should write the directory that it read from, and
+        // the `delete_prefix` should delete the keys of one directory only.
+        let (previous, deleted) = std::thread::scope(|s| {
+            let replacer = s.spawn(|| {
+                let replace = |name: &String| fb.replace(name, b"new").unwrap().unwrap();
+                names.iter().map(replace).collect::<Vec<_>>()
+            });
+            let deleter = s.spawn(|| fb.delete_prefix("prefix_").unwrap());
+            assert_eq!(fb.reopen(&dir2), Ok(()));
+            (replacer.join().unwrap(), deleter.join().unwrap())
+        });
+
+        for (name, previous) in names.iter().zip(previous) {
+            let expected = match previous.as_slice() {
+                b"old1" => (b"new".to_vec(), b"old2".to_vec()),
+                b"old2" => (b"old1".to_vec(), b"new".to_vec()),
+                _ => panic!("Unexpected contents for key `{}`", name),
+            };
+            assert_eq!((fb1.get(name).unwrap(), fb2.get(name).unwrap()), expected);
+        }
+        assert_eq!(deleted, names.len());
+        let counts = (
+            fb1.count_prefix("prefix_").unwrap(),
+            fb2.count_prefix("prefix_").unwrap(),
+        );
+        assert!(counts == (0, names.len()) || counts == (names.len(), 0));
+
+        // A switch that's requested in the middle of an update, which
+        // `replace` is built on, should wait for it to finish.
+        assert_eq!(fb.reopen(&dir1), Ok(()));
+        let (tx, rx) = mpsc::channel();
+        std::thread::scope(|s| {
+            let updater = s.spawn(|| {
+                fb.update_with("key0", &mut |_| {
+                    tx.send(()).unwrap();
+                    std::thread::sleep(time::Duration::from_millis(50));
+                    Some(b"updated".to_vec())
+                })
+            });
+            rx.recv().unwrap();
+            assert_eq!(fb.reopen(&dir2), Ok(()));
+            assert_eq!(updater.join().unwrap(), Ok(b"updated".to_vec()));
+        });
+        assert_eq!(fb1.get("key0"), Ok(b"updated".to_vec()));
+        assert_ne!(fb2.get("key0"), Ok(b"updated".to_vec()));
+    }
+
+    #[test]
+    fn test_file_backend_reopen_lock() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let dir1 = temp_dir.path().join("dir1");
+        let dir2 = temp_dir.path().join("dir2");
+        let fb = FileCave::create(&dir1).unwrap().with_inline_threshold(4);
+        let _ = FileCave::create(&dir2).unwrap();
+
+        // A switch should not wait for the callers that are blocked on a key
+        // lock, since the holder of the lock may need the kv to release it.
+        let guard = fb.lock("key").unwrap();
+        std::thread::scope(|s| {
+            let locker = s.spawn(|| fb.lock("key").map(|_| ()));
+            let creator = s.spawn(|| fb.create("key", b"abc"));
+            std::thread::sleep(time::Duration::from_millis(50));
+            assert_eq!(fb.reopen(&dir2), Ok(()));
+            assert_eq!(fb.set("key", b"xyz"), empty_ok());
+            drop(guard);
+            assert_eq!(locker.join().unwrap(), Ok(()));
+            // The creation should check the key in the new directory.
+            let already_exists_err = Err(Error::AlreadyExists("key".to_string()));
+            assert_eq!(creator.join().unwrap(), already_exists_err);
+        });
+        assert_eq!(fb.get("key"), Ok(b"xyz".to_vec()));
+    }
+
+    #[test]
+    fn test_file_backend_delete_race() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let new_cave = |dir: &str| FileCave::create(&temp_dir.path().join(dir)).unwrap();
+        _test_delete_race(Box::new(new_cave("plain")));
+        _test_delete_race(Box::new(new_cave("hashed").with_hashed_names()));
+        _test_delete_race(Box::new(new_cave("inline").with_inline_threshold(16)));
+        _test_delete_race(Box::new(new_cave("gzip").with_gzip(6)));
+    }
+
+    #[test]
+    fn test_file_backend_create() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let new_cave = |dir: &str| FileCave::create(&temp_dir.path().join(dir)).unwrap();
+        _test_create(Box::new(new_cave("atomic")));
+        _test_create(Box::new(
+            new_cave("truncate").with_write_strategy(WriteStrategy::Truncate),
+        ));
+        _test_create(Box::new(new_cave("hashed").with_hashed_names()));
+        _test_create(Box::new(new_cave("inline").with_inline_threshold(16)));
+        _test_create(Box::new(new_cave("gzip").with_gzip(6)));
+
+        // Directories should not be mistaken for existing keys.
+        let fb = new_cave("atomic");
+        fs::create_dir(temp_dir.path().join("atomic").join("dir")).unwrap();
+        let res = fb.create("dir", b"value");
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::InvalidName);
+
+        // Only one of the processes that create a key should succeed.
+        let path = temp_dir.path().to_path_buf();
+        _test_create_race(|| Box::new(FileCave::new(&path.join("atomic")).unwrap()));
+        _test_create_race(|| {
+            Box::new(
+                FileCave::new(&path.join("truncate"))
+                    .unwrap()
+                    .with_write_strategy(WriteStrategy::Truncate),
+            )
+        });
     }
 
     #[test]
-    fn test_memory_backend_simple() {
-        let mb = MemoryCave::new();
-        _test_simple(Box::new(mb))
+    fn test_file_backend_concurrency() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        _test_concurrency(Box::new(fb));
+        let fb = FileCave::create(&temp_dir.path().join("gzip")).unwrap();
+        _test_concurrency(Box::new(fb.with_gzip(1)));
     }
 
     #[test]
-    fn test_file_backend_simple() {
+    fn test_file_backend_torn_reads() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = sync::Arc::new(FileCave::new(temp_dir.path()).unwrap());
+        let old = vec![b'a'; 100 * 1024];
+        let new = vec![b'b'; 200 * 1024];
+        assert_eq!(fb.set("test", &old), empty_ok());
+
+        // Overwrite the key while reading it. The reader must see either the
+        // old or the new contents.
+        let writer = {
+            let fb = sync::Arc::clone(&fb);
+            let (old, new) = (old.clone(), new.clone());
+            std::thread::spawn(move || {
+                for i in 0..100 {
+                    let data = if i % 2 == 0 { &new } else { &old };
+                    assert_eq!(fb.set("test", data), empty_ok());
+                }
+            })
+        };
+        for _ in 0..100 {
+            let data = fb.get("test").unwrap();
+            assert!(data == old || data == new);
+        }
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn test_file_backend_max_value_size() {
         let temp_dir = assert_fs::TempDir::new().unwrap();
         let fb = FileCave::new(temp_dir.path()).unwrap();
-        _test_simple(Box::new(fb))
+        _test_max_value_size(Box::new(fb.with_max_value_size(5)));
+    }
+
+    #[test]
+    fn test_file_backend_snapshot() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        let res = fb.snapshot();
+        let unsupported_err = Error::Unsupported {
+            operation: "snapshot",
+        };
+        assert_eq!(res.err(), Some(unsupported_err));
+    }
+
+    #[test]
+    fn test_file_backend_gzip() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap().with_gzip(6);
+        _test_simple(Box::new(fb));
+
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap().with_gzip(6);
+        _test_keys(Box::new(fb));
+
+        // Check that the values are stored compressed, in files with the
+        // `.gz` extension.
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fb = FileCave::new(temp_dir.path()).unwrap().with_gzip(9);
+        let value = "value".repeat(100);
+        assert_eq!(fb.set("test", value.as_bytes()), empty_ok());
+        let buf = fs::read(temp_dir.path().join("test.gz")).unwrap();
+        assert!(buf.len() < value.len());
+        let mut data = String::new();
+        let res = flate2::read::GzDecoder::new(&buf[..]).read_to_string(&mut data);
+        assert!(res.is_ok());
+        assert_eq!(data, value);
+
+        // Check that files without the `.gz` extension are ignored.
+        fs::write(temp_dir.path().join("other"), b"value").unwrap();
+        assert_eq!(fb.keys().unwrap(), vec!["test"]);
+        assert_eq!(fb.get("other"), Err(Error::NotFound("other".to_string())));
+
+        // Check that corrupted files are reported as internal errors.
+        fs::write(temp_dir.path().join("test.gz"), b"corrupted").unwrap();
+        assert_eq!(fb.get("test"), Err(Error::Internal(anyhow!(""))));
+    }
+
+    #[test]
+    fn test_file_backend_truncate() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let new_cave = |dir: &str| {
+            FileCave::create(&temp_dir.path().join(dir))
+                .unwrap()
+                .with_write_strategy(WriteStrategy::Truncate)
+        };
+        _test_simple(Box::new(new_cave("simple")));
+        _test_keys(Box::new(new_cave("keys")));
+        _test_simple(Box::new(new_cave("gzip").with_gzip(6)));
+
+        // Overwriting a key with shorter contents should truncate its file.
+        let fb = new_cave("overwrite");
+        assert_eq!(fb.set("test", b"long value"), empty_ok());
+        assert_eq!(fb.set("test", b"short"), empty_ok());
+        assert_eq!(fb.get("test"), Ok(b"short".to_vec()));
+
+        // Errors should be reported in the same way as with atomic writes.
+        fs::create_dir(temp_dir.path().join("overwrite").join("dir")).unwrap();
+        let res = fb.set("dir", b"value");
+        assert_eq!(
+            res,
+            Err(Error::InvalidName {
+                name: "dir".to_string(),
+                reason: ""
+            })
+        );
+        fs::remove_dir_all(temp_dir.path().join("overwrite")).unwrap();
+        assert_eq!(fb.set("test", b"value"), Err(Error::Internal(anyhow!(""))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_backend_no_follow() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let dir = temp_dir.path().join("cave");
+        let secret = temp_dir.path().join("secret");
+        fs::write(&secret, b"secret").unwrap();
+        let new_cave = || FileCave::create(&dir).unwrap();
+        let symlink = || std::os::unix::fs::symlink(&secret, dir.join("link")).unwrap();
+        let symlink_err = Err(Error::InvalidName {
+            name: "link".to_string(),
+            reason: "",
+        });
+
+        // By default, symbolic links are followed.
+        let fb = new_cave();
+        symlink();
+        assert_eq!(fb.get("link"), Ok(b"secret".to_vec()));
+
+        let fb = new_cave().with_no_follow();
+        assert_eq!(fb.get("link"), symlink_err);
+        assert_eq!(fb.take("link"), symlink_err);
+        assert!(dir.join("link").exists());
+
+        // Writing in place must not write through the link.
+        let fb = new_cave()
+            .with_no_follow()
+            .with_write_strategy(WriteStrategy::Truncate);
+        assert_eq!(fb.set("link", b"value"), symlink_err);
+        assert_eq!(fs::read(&secret).unwrap(), b"secret");
+
+        // Atomic writes replace the link with a regular file.
+        let fb = new_cave().with_no_follow();
+        assert_eq!(fb.set("link", b"value"), empty_ok());
+        assert_eq!(fb.get("link"), Ok(b"value".to_vec()));
+        assert_eq!(fs::read(&secret).unwrap(), b"secret");
+
+        // Deleting a link removes the link, not its target.
+        fs::remove_file(dir.join("link")).unwrap();
+        symlink();
+        assert_eq!(fb.delete("link"), empty_ok());
+        assert!(secret.exists());
     }
 
     #[test]
@@ -395,7 +6077,7 @@ mod tests {
         // Test for non-existent paths.
         let no_path = temp_dir.path().join("nonexistent");
         let res = FileCave::new(&no_path);
-        assert_eq!(res.is_err(), true);
+        assert!(res.is_err());
         let err = res.unwrap_err();
         assert_eq!(err, internal_err);
         // XXX: In order to see if the error is ENOENT, we have to somehow get
@@ -407,9 +6089,9 @@ mod tests {
         // Test for files instead of directories.
         let empty_file = temp_dir.path().join("empty_file");
         let res = fs::File::create(&empty_file);
-        assert_eq!(res.is_ok(), true);
+        assert!(res.is_ok());
         let res = FileCave::new(&empty_file);
-        assert_eq!(res.is_err(), true);
+        assert!(res.is_err());
         let err = res.unwrap_err();
         assert_eq!(err, internal_err);
         // XXX: We can't check the string representation of the error. See
@@ -417,12 +6099,38 @@ mod tests {
         //let msg = format!("{:?}", err);
         //assert_eq!(msg.contains("is not a directory"), true);
 
+        // Test that `create` creates missing directories, but still rejects
+        // files.
+        let nested_path = no_path.join("nested");
+        let fb = FileCave::create(&nested_path).unwrap();
+        assert!(nested_path.is_dir());
+        assert_eq!(fb.set("test", b"value"), empty_ok());
+        let fb = FileCave::create(&nested_path).unwrap();
+        assert_eq!(fb.get("test"), Ok(b"value".to_vec()));
+        let res = FileCave::create(&empty_file);
+        assert_eq!(res.unwrap_err(), internal_err);
+
+        // Test that directories named like keys are rejected with a clear
+        // error, and are not listed as keys.
+        let fb = FileCave::new(&nested_path).unwrap();
+        fs::create_dir(nested_path.join("dir_key")).unwrap();
+        let invalid_name_err = Err(Error::InvalidName {
+            name: "dir_key".to_string(),
+            reason: "",
+        });
+        assert_eq!(fb.get("dir_key"), invalid_name_err);
+        assert_eq!(fb.set("dir_key", b"value"), invalid_name_err);
+        assert_eq!(fb.delete("dir_key"), invalid_name_err);
+        assert_eq!(fb.take("dir_key"), invalid_name_err);
+        assert!(nested_path.join("dir_key").is_dir());
+        assert_eq!(fb.keys().unwrap(), vec!["test"]);
+
         // Test for removed directory under our feet.
         let internal_err = Err(internal_err);
         let not_found_err: Res = Err(Error::NotFound("test".to_string()));
         let dir = temp_dir.path().join("dir");
         let res = fs::create_dir(&dir);
-        assert_eq!(res.is_ok(), true);
+        assert!(res.is_ok());
         let fb = FileCave::new(&dir).unwrap();
         fs::remove_dir(&dir).unwrap();
         // We can detect this error in case of set, due to atomic writes.
@@ -444,6 +6152,379 @@ mod tests {
         _test_simple(Box::new(rb));
     }
 
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_keys() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        _test_keys(Box::new(rb));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_first_last_key() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        _test_first_last_key(Box::new(rb));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_flush() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        assert_eq!(rb.set("test", b"value"), empty_ok());
+        assert_eq!(rb.flush(), empty_ok());
+        drop(rb);
+
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        assert_eq!(rb.get("test"), Ok(b"value".to_vec()));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_maintain() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        assert_eq!(rb.set("test", b"value"), empty_ok());
+        assert_eq!(rb.delete("test"), empty_ok());
+        assert_eq!(rb.set("test2", b"value2"), empty_ok());
+        assert_eq!(rb.maintain(), empty_ok());
+        assert_eq!(rb.get("test"), rb.not_found("test"));
+        assert_eq!(rb.get("test2"), Ok(b"value2".to_vec()));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_exists_many() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        _test_exists_many(Box::new(rb));
+
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path())
+            .unwrap()
+            .with_chunk_size(4);
+        _test_exists_many(Box::new(rb));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_bytes() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        _test_bytes(Box::new(rb));
+
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path())
+            .unwrap()
+            .with_chunk_size(4);
+        _test_bytes(Box::new(rb));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_disk_usage() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        assert_eq!(rb.disk_usage(), Ok(0));
+
+        // Unflushed writes are not included.
+        assert_eq!(rb.set("test", b"value"), empty_ok());
+        assert_eq!(rb.disk_usage(), Ok(0));
+        assert_eq!(rb.flush(), empty_ok());
+        assert!(rb.disk_usage().unwrap() > 0);
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_range() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        _test_range(Box::new(rb));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_count_prefix() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        _test_count_prefix(Box::new(rb));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_for_each() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        _test_for_each(Box::new(rb));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_keys_sorted() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(&temp_dir.path().join("plain")).unwrap();
+        _test_keys_sorted(Box::new(rb));
+        let rb = RocksDBCave::new(&temp_dir.path().join("chunks"))
+            .unwrap()
+            .with_chunk_size(4);
+        _test_keys_sorted(Box::new(rb));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_size_histogram() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        _test_size_histogram(Box::new(rb));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_swap() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        _test_swap(Box::new(rb));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_delete_prefix() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        _test_delete_prefix(Box::new(rb));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_rename_prefix() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        _test_rename_prefix(Box::new(rb));
+
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path())
+            .unwrap()
+            .with_chunk_size(4);
+        _test_rename_prefix(Box::new(rb));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_take() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        _test_take(Box::new(rb));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_update_with() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(&temp_dir.path().join("1")).unwrap();
+        _test_update_with(Box::new(rb));
+        let rb = RocksDBCave::new(&temp_dir.path().join("2")).unwrap();
+        _test_update_with_atomic(Box::new(rb));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_replace() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        _test_replace(Box::new(rb));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_set_and_get() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(&temp_dir.path().join("plain"))
+            .unwrap()
+            .with_max_value_size(10);
+        assert_eq!(rb.set_and_get("test", b"value"), Ok(b"value".to_vec()));
+        assert_eq!(rb.set_and_get("test", b""), Ok(b"".to_vec()));
+        assert_eq!(
+            rb.set_and_get("test", b"larger value"),
+            Err(Error::ValueTooLarge {
+                size: 12,
+                limit: 10
+            })
+        );
+        assert_eq!(rb.get("test"), Ok(b"".to_vec()));
+
+        // The chunks of a value should be read back as a whole.
+        let rb = RocksDBCave::new(&temp_dir.path().join("chunked"))
+            .unwrap()
+            .with_chunk_size(4);
+        assert_eq!(
+            rb.set_and_get("test", b"long value"),
+            Ok(b"long value".to_vec())
+        );
+        assert_eq!(rb.set_and_get("test", b"value"), Ok(b"value".to_vec()));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_delete_race() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        _test_delete_race(Box::new(rb));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_bulk_load() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(&temp_dir.path().join("plain")).unwrap();
+        assert_eq!(rb.set("old", b"value"), empty_ok());
+        let res = rb.bulk_load(|loader| {
+            for i in 0..1000 {
+                loader.set(&format!("key{}", i), &[i as u8; 8192])?;
+            }
+            loader.delete("old")?;
+            loader.delete("missing")
+        });
+        assert_eq!(res, Ok(()));
+        assert_eq!(rb.keys().unwrap().len(), 1000);
+        assert_eq!(rb.get("key999"), Ok(vec![231; 8192]));
+        assert_eq!(rb.get("old"), rb.not_found("old"));
+
+        // The writes before an error should be kept, and the error should be
+        // returned.
+        let res = rb.bulk_load(|loader| {
+            loader.set("key0", b"new value")?;
+            Err(Error::internal_from_msg("stop".to_string()))
+        });
+        assert_eq!(res, Err(Error::Internal(anyhow!(""))));
+        assert_eq!(rb.get("key0"), Ok(vec![0; 8192]));
+
+        // Values should be checked and chunked, as with regular writes.
+        let rb = RocksDBCave::new(&temp_dir.path().join("chunked"))
+            .unwrap()
+            .with_chunk_size(4)
+            .with_max_value_size(10);
+        let res = rb.bulk_load(|loader| {
+            loader.set("test", b"long value")?;
+            loader.set("test", b"value")?;
+            loader.set("large", b"larger value")
+        });
+        assert_eq!(
+            res,
+            Err(Error::ValueTooLarge {
+                size: 12,
+                limit: 10
+            })
+        );
+        assert_eq!(rb.get("test"), Ok(b"value".to_vec()));
+        assert_eq!(rb.keys(), Ok(vec!["test".to_string()]));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_create() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        _test_create(Box::new(rb));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_concurrency() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        _test_concurrency(Box::new(rb));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_max_value_size() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        _test_max_value_size(Box::new(rb.with_max_value_size(5)));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_snapshot() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let rb = RocksDBCave::new(temp_dir.path()).unwrap();
+        _test_snapshot(Box::new(rb));
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_chunks() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let new_cave = |dir: &str| {
+            RocksDBCave::new(&temp_dir.path().join(dir))
+                .unwrap()
+                .with_chunk_size(4)
+        };
+        _test_simple(Box::new(new_cave("simple")));
+        _test_keys(Box::new(new_cave("keys")));
+        _test_delete_prefix(Box::new(new_cave("delete_prefix")));
+        _test_take(Box::new(new_cave("take")));
+        _test_update_with(Box::new(new_cave("update_with")));
+        _test_snapshot(Box::new(new_cave("snapshot")));
+        _test_first_last_key(Box::new(new_cave("first_last_key")));
+        _test_range(Box::new(new_cave("range")));
+        _test_swap(Box::new(new_cave("swap")));
+        _test_count_prefix(Box::new(new_cave("count_prefix")));
+        _test_for_each(Box::new(new_cave("for_each")));
+        _test_dump(Box::new(new_cave("dump")));
+
+        let rb = new_cave("chunks");
+        assert_eq!(rb.set("test", b"0123456789"), empty_ok());
+        assert_eq!(rb.get("test"), Ok(b"0123456789".to_vec()));
+        assert_eq!(rb.keys(), Ok(vec!["test".to_string()]));
+
+        // Shrinking a value should remove its stale chunks.
+        assert_eq!(rb.set("test", b"01234"), empty_ok());
+        assert_eq!(rb.get("test"), Ok(b"01234".to_vec()));
+        assert_eq!(rb.db.get(chunk_name("test", 2)).unwrap(), None);
+
+        // Overwrite a range that spans chunks, and then extend the value.
+        assert_eq!(rb.set_range("test", 3, b"ab"), empty_ok());
+        assert_eq!(rb.get("test"), Ok(b"012ab".to_vec()));
+        assert_eq!(rb.set_range("test", 5, b"cdefgh"), empty_ok());
+        assert_eq!(rb.get("test"), Ok(b"012abcdefgh".to_vec()));
+
+        // Empty values are stored as a single chunk.
+        assert_eq!(rb.set("empty", b""), empty_ok());
+        assert_eq!(rb.get("empty"), Ok(b"".to_vec()));
+
+        let not_found_err = Err(Error::NotFound("missing".to_string()));
+        assert_eq!(rb.set_range("missing", 0, b"a"), not_found_err);
+        let res = rb.set_range("test", 12, b"a");
+        assert_eq!(res, Err(Error::Internal(anyhow!(""))));
+
+        // Ranges are supported only in chunked mode.
+        let rb = RocksDBCave::new(&temp_dir.path().join("unchunked")).unwrap();
+        assert_eq!(rb.set("test", b"data"), empty_ok());
+        let res = rb.set_range("test", 0, b"a");
+        assert_eq!(
+            res,
+            Err(Error::Unsupported {
+                operation: "set_range"
+            })
+        );
+    }
+
+    #[cfg(feature = "with-rocksdb")]
+    #[test]
+    fn test_rocksdb_backend_shared_cache() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let cache = RocksDBCache::new_lru_cache(1024 * 1024).unwrap();
+        let rb1 = RocksDBCave::with_shared_cache(&temp_dir.path().join("1"), &cache).unwrap();
+        let rb2 = RocksDBCave::with_shared_cache(&temp_dir.path().join("2"), &cache).unwrap();
+        drop(cache);
+
+        _test_simple(Box::new(rb1));
+        _test_keys(Box::new(rb2));
+    }
+
     #[cfg(feature = "with-rocksdb")]
     #[test]
     fn test_rocksdb_backend_errors() {
@@ -454,11 +6535,11 @@ mod tests {
         let empty_file = temp_dir.path().join("empty_file");
         let _ = fs::File::create(&empty_file).unwrap();
         let res = RocksDBCave::new(&empty_file);
-        assert_eq!(res.is_err(), true);
+        assert!(res.is_err());
         let err = res.unwrap_err();
         assert_eq!(err, internal_err);
         let msg = format!("{:?}", err);
-        assert_eq!(msg.contains("Failed to create RocksDB directory"), true);
+        assert!(msg.contains("Failed to create RocksDB directory"));
 
         // Test for corrupted dirs.
         let temp_dir = assert_fs::TempDir::new().unwrap();
@@ -466,11 +6547,11 @@ mod tests {
         let mut file = fs::File::create(&corrupted_file).unwrap();
         file.write_all(b"corrupted").unwrap();
         let res = RocksDBCave::new(&corrupted_file);
-        assert_eq!(res.is_err(), true);
+        assert!(res.is_err());
         let err = res.unwrap_err();
         assert_eq!(err, internal_err);
         let msg = format!("{:?}", err);
-        assert_eq!(msg.contains("Failed to create RocksDB directory"), true);
+        assert!(msg.contains("Failed to create RocksDB directory"));
 
         // FIXME: Check for runtime errors.
     }
@@ -0,0 +1,385 @@
+//! LMDB backend
+//!
+//! In this module, we define a `Cave` that stores keys in [LMDB].
+//!
+//! [LMDB]: http://www.lmdb.tech/doc/
+
+use std::fmt;
+use std::fs;
+use std::path;
+
+use heed::types::{ByteSlice, Str};
+
+use crate::errors::Error;
+use crate::res::{empty_ok, Res};
+use crate::{check_value_size, Cave};
+
+/// A key-value store that stores keys in [LMDB].
+///
+/// LMDB is a memory-mapped, crash-safe embedded database. This kv stores the
+/// keys in a single database of an LMDB environment. Each `get` runs in a
+/// read transaction and each `set`/`delete` in a write transaction, so the
+/// operations are atomic.
+///
+/// This kv is available with the `with-lmdb` feature.
+///
+/// ## Caveats
+///
+/// This kv has the following caveats:
+///
+/// * LMDB memory-maps its data file, and the size of the map must be fixed
+///   when the environment is opened. This size is the maximum size that the
+///   database can grow to. Once the database reaches it, every write fails
+///   with an internal error (`MDB_MAP_FULL`), until the kv is reopened with
+///   a larger size.
+/// * LMDB allows only one write transaction at a time, so writes from
+///   multiple threads are serialized.
+///
+/// [LMDB]: http://www.lmdb.tech/doc/
+pub struct LmdbCave {
+    dir: path::PathBuf,
+    env: heed::Env,
+    db: heed::Database<Str, ByteSlice>,
+    max_value_size: Option<usize>,
+}
+
+impl LmdbCave {
+    /// Create a new instance.
+    ///
+    /// If the provided directory does not exist, it will be created. The
+    /// `max_size` is the maximum size of the database in bytes, and should be
+    /// a multiple of the OS page size.
+    pub fn new(dir: &path::Path, max_size: usize) -> Result<Self, Error> {
+        if let Err(e) = fs::create_dir_all(dir) {
+            return Err(Error::Internal(e.into()));
+        }
+
+        let env = match heed::EnvOpenOptions::new().map_size(max_size).open(dir) {
+            Ok(env) => env,
+            Err(e) => return Err(Self::convert_heed_error(e)),
+        };
+
+        match env.create_database(None) {
+            Ok(db) => Ok(Self {
+                dir: dir.to_owned(),
+                env,
+                db,
+                max_value_size: None,
+            }),
+            Err(e) => Err(Self::convert_heed_error(e)),
+        }
+    }
+
+    /// Reject values that are larger than `limit` bytes.
+    ///
+    /// A `set` with a larger value returns an [`Error::ValueTooLarge`] error,
+    /// without opening a write transaction.
+    ///
+    /// [`Error::ValueTooLarge`]: errors/enum.Error.html#variant.ValueTooLarge
+    pub fn with_max_value_size(mut self, limit: usize) -> Self {
+        self.max_value_size = Some(limit);
+        self
+    }
+
+    fn convert_heed_error(e: heed::Error) -> Error {
+        match e {
+            heed::Error::Mdb(heed::MdbError::MapFull) => Error::internal_from_msg(
+                "The LMDB map is full; reopen the database with a larger size".to_string(),
+            ),
+            // Some heed errors are not thread-safe, so we can only keep their
+            // message.
+            e => Error::internal_from_msg(e.to_string()),
+        }
+    }
+}
+
+impl fmt::Debug for LmdbCave {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LmdbCave").field("dir", &self.dir).finish()
+    }
+}
+
+impl Cave for LmdbCave {
+    fn get(&self, name: &str) -> Res {
+        let rtxn = match self.env.read_txn() {
+            Ok(rtxn) => rtxn,
+            Err(e) => return Err(Self::convert_heed_error(e)),
+        };
+
+        match self.db.get(&rtxn, name) {
+            Ok(Some(buf)) => Ok(buf.to_vec()),
+            Ok(None) => self.not_found(name),
+            Err(e) => Err(Self::convert_heed_error(e)),
+        }
+    }
+
+    fn set(&self, name: &str, data: &[u8]) -> Res {
+        check_value_size(data, self.max_value_size)?;
+        let mut wtxn = match self.env.write_txn() {
+            Ok(wtxn) => wtxn,
+            Err(e) => return Err(Self::convert_heed_error(e)),
+        };
+
+        if let Err(e) = self.db.put(&mut wtxn, name, data) {
+            return Err(Self::convert_heed_error(e));
+        }
+
+        match wtxn.commit() {
+            Ok(_) => empty_ok(),
+            Err(e) => Err(Self::convert_heed_error(e)),
+        }
+    }
+
+    fn delete(&self, name: &str) -> Res {
+        let mut wtxn = match self.env.write_txn() {
+            Ok(wtxn) => wtxn,
+            Err(e) => return Err(Self::convert_heed_error(e)),
+        };
+
+        // If the key does not exist, the transaction is aborted when it's
+        // dropped.
+        match self.db.delete(&mut wtxn, name) {
+            Ok(true) => (),
+            Ok(false) => return self.not_found(name),
+            Err(e) => return Err(Self::convert_heed_error(e)),
+        }
+
+        match wtxn.commit() {
+            Ok(_) => empty_ok(),
+            Err(e) => Err(Self::convert_heed_error(e)),
+        }
+    }
+
+    fn take(&self, name: &str) -> Res {
+        let mut wtxn = match self.env.write_txn() {
+            Ok(wtxn) => wtxn,
+            Err(e) => return Err(Self::convert_heed_error(e)),
+        };
+
+        let data = match self.db.get(&wtxn, name) {
+            Ok(Some(buf)) => buf.to_vec(),
+            Ok(None) => return self.not_found(name),
+            Err(e) => return Err(Self::convert_heed_error(e)),
+        };
+
+        if let Err(e) = self.db.delete(&mut wtxn, name) {
+            return Err(Self::convert_heed_error(e));
+        }
+
+        match wtxn.commit() {
+            Ok(_) => Ok(data),
+            Err(e) => Err(Self::convert_heed_error(e)),
+        }
+    }
+
+    fn update_with(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(Option<Vec<u8>>) -> Option<Vec<u8>>,
+    ) -> Res {
+        // LMDB allows one write transaction at a time, so the closure is
+        // called exactly once.
+        let mut wtxn = match self.env.write_txn() {
+            Ok(wtxn) => wtxn,
+            Err(e) => return Err(Self::convert_heed_error(e)),
+        };
+
+        let current = match self.db.get(&wtxn, name) {
+            Ok(current) => current.map(|buf| buf.to_vec()),
+            Err(e) => return Err(Self::convert_heed_error(e)),
+        };
+
+        let res = match f(current) {
+            Some(data) => {
+                check_value_size(&data, self.max_value_size)?;
+                self.db.put(&mut wtxn, name, &data).map(|_| data)
+            }
+            None => self.db.delete(&mut wtxn, name).map(|_| Vec::new()),
+        };
+        let data = match res {
+            Ok(data) => data,
+            Err(e) => return Err(Self::convert_heed_error(e)),
+        };
+
+        match wtxn.commit() {
+            Ok(_) => Ok(data),
+            Err(e) => Err(Self::convert_heed_error(e)),
+        }
+    }
+
+    fn create(&self, name: &str, data: &[u8]) -> Res {
+        check_value_size(data, self.max_value_size)?;
+        let mut wtxn = match self.env.write_txn() {
+            Ok(wtxn) => wtxn,
+            Err(e) => return Err(Self::convert_heed_error(e)),
+        };
+
+        match self.db.get(&wtxn, name) {
+            Ok(Some(_)) => return Err(Error::AlreadyExists(name.to_string())),
+            Ok(None) => (),
+            Err(e) => return Err(Self::convert_heed_error(e)),
+        }
+        if let Err(e) = self.db.put(&mut wtxn, name, data) {
+            return Err(Self::convert_heed_error(e));
+        }
+
+        match wtxn.commit() {
+            Ok(_) => empty_ok(),
+            Err(e) => Err(Self::convert_heed_error(e)),
+        }
+    }
+
+    fn swap(&self, a: &str, b: &str) -> Res {
+        let mut wtxn = match self.env.write_txn() {
+            Ok(wtxn) => wtxn,
+            Err(e) => return Err(Self::convert_heed_error(e)),
+        };
+
+        let mut values = Vec::new();
+        for name in &[a, b] {
+            match self.db.get(&wtxn, name) {
+                Ok(Some(buf)) => values.push(buf.to_vec()),
+                Ok(None) => return self.not_found(name),
+                Err(e) => return Err(Self::convert_heed_error(e)),
+            }
+        }
+
+        if let Err(e) = self.db.put(&mut wtxn, a, &values[1]) {
+            return Err(Self::convert_heed_error(e));
+        }
+        if let Err(e) = self.db.put(&mut wtxn, b, &values[0]) {
+            return Err(Self::convert_heed_error(e));
+        }
+
+        match wtxn.commit() {
+            Ok(_) => empty_ok(),
+            Err(e) => Err(Self::convert_heed_error(e)),
+        }
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        let rtxn = match self.env.read_txn() {
+            Ok(rtxn) => rtxn,
+            Err(e) => return Err(Self::convert_heed_error(e)),
+        };
+
+        let iter = match self.db.iter(&rtxn) {
+            Ok(iter) => iter,
+            Err(e) => return Err(Self::convert_heed_error(e)),
+        };
+
+        let mut keys = Vec::new();
+        for res in iter {
+            match res {
+                Ok((name, _)) => keys.push(name.to_string()),
+                Err(e) => return Err(Self::convert_heed_error(e)),
+            }
+        }
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::{
+        _test_concurrency, _test_create, _test_delete_race, _test_keys, _test_max_value_size,
+        _test_replace, _test_simple, _test_swap, _test_take, _test_update_with,
+        _test_update_with_atomic,
+    };
+
+    const MAX_SIZE: usize = 10 * 1024 * 1024;
+
+    #[test]
+    fn test_lmdb_backend_simple() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let lb = LmdbCave::new(temp_dir.path(), MAX_SIZE).unwrap();
+        _test_simple(Box::new(lb));
+
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let lb = LmdbCave::new(temp_dir.path(), MAX_SIZE).unwrap();
+        _test_keys(Box::new(lb));
+    }
+
+    #[test]
+    fn test_lmdb_backend_take() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let lb = LmdbCave::new(temp_dir.path(), MAX_SIZE).unwrap();
+        _test_take(Box::new(lb));
+    }
+
+    #[test]
+    fn test_lmdb_backend_update_with() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let lb = LmdbCave::new(&temp_dir.path().join("1"), MAX_SIZE).unwrap();
+        _test_update_with(Box::new(lb));
+        let lb = LmdbCave::new(&temp_dir.path().join("2"), MAX_SIZE).unwrap();
+        _test_update_with_atomic(Box::new(lb));
+    }
+
+    #[test]
+    fn test_lmdb_backend_replace() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let lb = LmdbCave::new(temp_dir.path(), MAX_SIZE).unwrap();
+        _test_replace(Box::new(lb));
+    }
+
+    #[test]
+    fn test_lmdb_backend_delete_race() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let lb = LmdbCave::new(temp_dir.path(), MAX_SIZE).unwrap();
+        _test_delete_race(Box::new(lb));
+    }
+
+    #[test]
+    fn test_lmdb_backend_create() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let lb = LmdbCave::new(temp_dir.path(), MAX_SIZE).unwrap();
+        _test_create(Box::new(lb));
+    }
+
+    #[test]
+    fn test_lmdb_backend_swap() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let lb = LmdbCave::new(temp_dir.path(), MAX_SIZE).unwrap();
+        _test_swap(Box::new(lb));
+    }
+
+    #[test]
+    fn test_lmdb_backend_concurrency() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let lb = LmdbCave::new(temp_dir.path(), MAX_SIZE).unwrap();
+        _test_concurrency(Box::new(lb));
+    }
+
+    #[test]
+    fn test_lmdb_backend_max_value_size() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let lb = LmdbCave::new(temp_dir.path(), MAX_SIZE).unwrap();
+        _test_max_value_size(Box::new(lb.with_max_value_size(5)));
+    }
+
+    #[test]
+    fn test_lmdb_backend_errors() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let internal_err = Error::Internal(anyhow!(""));
+
+        // Test for files instead of directories.
+        let empty_file = temp_dir.path().join("empty_file");
+        let _ = fs::File::create(&empty_file).unwrap();
+        let res = LmdbCave::new(&empty_file, MAX_SIZE);
+        assert_eq!(res.unwrap_err(), internal_err);
+
+        // Test that a full map is reported as an internal error, and that it
+        // doesn't affect the existing keys.
+        let dir = temp_dir.path().join("dir");
+        let lb = LmdbCave::new(&dir, MAX_SIZE).unwrap();
+        assert_eq!(lb.set("test", b"value"), empty_ok());
+        let res = lb.set("test", &vec![0; MAX_SIZE]);
+        let err = res.unwrap_err();
+        assert_eq!(err, internal_err);
+        assert!(err.to_string().contains("map is full"));
+        assert_eq!(lb.get("test"), Ok(b"value".to_vec()));
+    }
+}
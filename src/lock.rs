@@ -0,0 +1,94 @@
+//! Advisory key locks
+//!
+//! In this module, we define the guard that `Cave::lock` returns, as well as
+//! an in-process registry of locked keys that backends can use to implement
+//! it.
+
+use std::collections;
+use std::fmt;
+use std::sync;
+
+/// Anything that releases a lock when it's dropped.
+trait Held {}
+
+impl<T> Held for T {}
+
+/// A guard that holds the advisory lock of a key, until it's dropped.
+///
+/// See [`Cave::lock`] for more info.
+///
+/// [`Cave::lock`]: trait.Cave.html#method.lock
+pub struct KeyGuard<'a> {
+    name: String,
+    _held: Box<dyn Held + 'a>,
+}
+
+impl<'a> KeyGuard<'a> {
+    /// Create a guard for the lock of a key.
+    ///
+    /// The lock is released when `held` is dropped, which happens when the
+    /// guard is dropped. Kvs outside this crate that implement `Cave::lock`
+    /// can use it to wrap their own guards.
+    pub fn new<G: 'a>(name: &str, held: G) -> Self {
+        Self {
+            name: name.to_string(),
+            _held: Box::new(held),
+        }
+    }
+
+    /// Return the name of the locked key.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl fmt::Debug for KeyGuard<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyGuard")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+/// A registry of the keys that are locked in this process.
+#[derive(Debug, Default)]
+pub(crate) struct KeyLocks {
+    locked: sync::Mutex<collections::HashSet<String>>,
+    /// Signaled when a key is unlocked.
+    released: sync::Condvar,
+}
+
+/// Unlocks a key in the registry when it's dropped.
+struct Release<'a> {
+    locks: &'a KeyLocks,
+    name: String,
+}
+
+impl Drop for Release<'_> {
+    fn drop(&mut self) {
+        let _ = self.locks.locked.lock().unwrap().remove(&self.name);
+        self.locks.released.notify_all();
+    }
+}
+
+impl KeyLocks {
+    /// Lock a key, and wait until it's unlocked if it's already locked.
+    pub(crate) fn lock(&self, name: &str) -> KeyGuard<'_> {
+        let mut locked = self.locked.lock().unwrap();
+        while locked.contains(name) {
+            locked = self.released.wait(locked).unwrap();
+        }
+        let _ = locked.insert(name.to_string());
+
+        let release = Release {
+            locks: self,
+            name: name.to_string(),
+        };
+        KeyGuard::new(name, release)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.locked.lock().unwrap().is_empty()
+    }
+}
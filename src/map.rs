@@ -0,0 +1,169 @@
+//! Map-like access to a Cave
+//!
+//! In this module, we define an adapter that exposes a `Cave` with the method
+//! names and signatures of `std::collections::HashMap`, so that code written
+//! against a map can switch to a `Cave` with minimal changes.
+
+use std::fmt;
+
+use crate::errors::Error;
+use crate::Cave;
+
+/// An adapter that exposes a `Cave` like a `HashMap<String, Vec<u8>>`.
+///
+/// The methods mirror the ones of `HashMap`, e.g., [`insert`] returns the
+/// previous value and [`remove`] returns the removed one, with the following
+/// differences:
+///
+/// * Every method returns a `Result`, since a `Cave` can fail, e.g., due to
+///   I/O errors. A missing key is not an error, and is reported as `None`,
+///   like in a `HashMap`. The methods never panic.
+/// * The writes take `&self`, since a `Cave` can be shared between threads.
+///   The changes are visible to anyone who uses the same `Cave`, and they
+///   persist after the adapter is dropped, if the `Cave` is persistent.
+/// * The values are returned as owned copies, since a `Cave` cannot lend
+///   references to its contents.
+///
+/// ## Usage
+///
+/// ```
+/// use caves::{CaveMap, MemoryCave};
+///
+/// let b = MemoryCave::new();
+/// let map = CaveMap::new(&b);
+///
+/// assert_eq!(map.insert("key", b"value").unwrap(), None);
+/// assert!(map.contains_key("key").unwrap());
+/// assert_eq!(map.get("key").unwrap(), Some(b"value".to_vec()));
+/// assert_eq!(map.remove("key").unwrap(), Some(b"value".to_vec()));
+/// assert_eq!(map.get("key").unwrap(), None);
+/// ```
+///
+/// [`insert`]: #method.insert
+/// [`remove`]: #method.remove
+#[derive(Clone, Copy)]
+pub struct CaveMap<'a> {
+    cave: &'a dyn Cave,
+}
+
+impl<'a> CaveMap<'a> {
+    /// Create a new adapter for the provided `Cave`.
+    pub fn new(cave: &'a dyn Cave) -> Self {
+        Self { cave }
+    }
+
+    /// Return the contents of a key, or `None` if it doesn't exist.
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        match self.cave.get(key) {
+            Ok(data) => Ok(Some(data)),
+            Err(Error::NotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Return whether a key exists.
+    ///
+    /// This uses [`Cave::exists_many`], so kvs that can check for a key
+    /// without reading it don't read its contents.
+    ///
+    /// [`Cave::exists_many`]: trait.Cave.html#method.exists_many
+    pub fn contains_key(&self, key: &str) -> Result<bool, Error> {
+        self.cave.exists_many(&[key]).map(|exist| exist[0])
+    }
+
+    /// Set the contents of a key, and return its previous contents, or
+    /// `None` if it didn't exist.
+    pub fn insert(&self, key: &str, value: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.cave.replace(key, value)
+    }
+
+    /// Delete a key, and return its contents, or `None` if it didn't exist.
+    pub fn remove(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        match self.cave.take(key) {
+            Ok(data) => Ok(Some(data)),
+            Err(Error::NotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Return the names of the keys, in no particular order.
+    pub fn keys(&self) -> Result<Vec<String>, Error> {
+        self.cave.keys()
+    }
+
+    /// Return the number of keys.
+    pub fn len(&self) -> Result<usize, Error> {
+        self.cave.count_prefix("")
+    }
+
+    /// Return whether there are no keys.
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
+}
+
+impl fmt::Debug for CaveMap<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CaveMap").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::res::empty_ok;
+    use crate::{FileCave, MemoryCave};
+
+    #[test]
+    fn test_cave_map() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let caves: Vec<Box<dyn Cave>> = vec![
+            Box::new(MemoryCave::new()),
+            Box::new(FileCave::new(temp_dir.path()).unwrap()),
+        ];
+
+        for b in caves {
+            let map = CaveMap::new(&b);
+            assert_eq!(map.len(), Ok(0));
+            assert_eq!(map.is_empty(), Ok(true));
+            assert_eq!(map.get("key"), Ok(None));
+            assert_eq!(map.contains_key("key"), Ok(false));
+            assert_eq!(map.remove("key"), Ok(None));
+
+            assert_eq!(map.insert("key", b"value"), Ok(None));
+            assert_eq!(map.insert("key", b"value2"), Ok(Some(b"value".to_vec())));
+            assert_eq!(map.get("key"), Ok(Some(b"value2".to_vec())));
+            assert_eq!(map.contains_key("key"), Ok(true));
+            assert_eq!(map.keys().unwrap(), vec!["key"]);
+            assert_eq!(map.len(), Ok(1));
+            assert_eq!(map.is_empty(), Ok(false));
+
+            // The changes are made in the underlying `Cave`.
+            assert_eq!(b.get("key"), Ok(b"value2".to_vec()));
+            assert_eq!(b.set("key2", b"value"), empty_ok());
+            assert_eq!(map.len(), Ok(2));
+
+            assert_eq!(map.remove("key"), Ok(Some(b"value2".to_vec())));
+            assert_eq!(map.get("key"), Ok(None));
+            assert_eq!(map.keys().unwrap(), vec!["key2"]);
+        }
+    }
+
+    #[test]
+    fn test_cave_map_errors() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let b = FileCave::new(temp_dir.path()).unwrap();
+        let map = CaveMap::new(&b);
+
+        // Errors other than a missing key are propagated.
+        std::fs::create_dir(temp_dir.path().join("dir")).unwrap();
+        let invalid_name_err = || Error::InvalidName {
+            name: "dir".to_string(),
+            reason: "",
+        };
+        assert_eq!(map.get("dir"), Err(invalid_name_err()));
+        assert_eq!(map.contains_key("dir"), Err(invalid_name_err()));
+        assert_eq!(map.remove("dir"), Err(invalid_name_err()));
+    }
+}
@@ -0,0 +1,324 @@
+//! Error mapping
+//!
+//! In this module, we define a wrapper that passes the errors of a `Cave`
+//! through a user-defined function, so that applications can translate them
+//! to their own taxonomy, or enrich them, e.g., with the subsystem that the
+//! `Cave` belongs to.
+
+use std::collections;
+use std::fmt;
+
+use crate::errors::Error;
+use crate::res::Res;
+use crate::{Capabilities, Cave, ChangeReceiver, KeyGuard, Metadata, VisitFn};
+
+/// A wrapper that applies a function to the errors that another `Cave`
+/// returns.
+///
+/// Every error that a method of the inner `Cave` returns is replaced by the
+/// result of the function, except for [`Error::NotFound`] errors, which are
+/// returned as is, since callers commonly match on them. In order to map them
+/// as well, use [`with_not_found_mapping`]. Note that the errors of the
+/// functions that are passed to the wrapper, e.g., to `for_each`, are mapped
+/// as well, since they are returned through the inner `Cave`.
+///
+/// ## Usage
+///
+/// ```
+/// use caves::errors::Error;
+/// use caves::{Cave, MemoryCave, MapErrCave};
+///
+/// let b = MapErrCave::new(MemoryCave::new().with_max_value_size(4), |e: Error| {
+///     Error::Internal(anyhow::anyhow!("sessions: {}", e))
+/// });
+///
+/// let err = b.set("key", b"value").unwrap_err();
+/// assert!(err.to_string().contains("sessions: "));
+/// assert_eq!(b.get("key"), Err(Error::NotFound("key".to_string())));
+/// ```
+///
+/// [`Error::NotFound`]: errors/enum.Error.html#variant.NotFound
+/// [`with_not_found_mapping`]: #method.with_not_found_mapping
+pub struct MapErrCave<C: Cave, F: Fn(Error) -> Error + Send + Sync> {
+    inner: C,
+    f: F,
+    map_not_found: bool,
+}
+
+impl<C: Cave, F: Fn(Error) -> Error + Send + Sync> MapErrCave<C, F> {
+    /// Create a new instance that wraps the provided `Cave`, and maps its
+    /// errors with `f`.
+    pub fn new(inner: C, f: F) -> Self {
+        Self {
+            inner,
+            f,
+            map_not_found: false,
+        }
+    }
+
+    /// Map the [`Error::NotFound`] errors as well.
+    ///
+    /// [`Error::NotFound`]: errors/enum.Error.html#variant.NotFound
+    pub fn with_not_found_mapping(mut self) -> Self {
+        self.map_not_found = true;
+        self
+    }
+
+    /// Return a reference to the inner `Cave`.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    fn map<T>(&self, res: Result<T, Error>) -> Result<T, Error> {
+        res.map_err(|e| map_error(&self.f, self.map_not_found, e))
+    }
+}
+
+fn map_error<F: Fn(Error) -> Error>(f: &F, map_not_found: bool, e: Error) -> Error {
+    match e {
+        Error::NotFound(_) if !map_not_found => e,
+        e => f(e),
+    }
+}
+
+/// Print the inner `Cave` and the options, since functions cannot be
+/// printed.
+impl<C: Cave + fmt::Debug, F: Fn(Error) -> Error + Send + Sync> fmt::Debug for MapErrCave<C, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MapErrCave")
+            .field("inner", &self.inner)
+            .field("map_not_found", &self.map_not_found)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<C: Cave, F: Fn(Error) -> Error + Send + Sync> Cave for MapErrCave<C, F> {
+    fn get(&self, name: &str) -> Res {
+        self.map(self.inner.get(name))
+    }
+
+    fn set(&self, name: &str, data: &[u8]) -> Res {
+        self.map(self.inner.set(name, data))
+    }
+
+    fn delete(&self, name: &str) -> Res {
+        self.map(self.inner.delete(name))
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        self.map(self.inner.keys())
+    }
+
+    fn get_or_load(&self, name: &str, loader: &mut dyn FnMut(&str) -> Res) -> Res {
+        self.map(self.inner.get_or_load(name, loader))
+    }
+
+    fn get_bytes(&self, name: &[u8]) -> Res {
+        self.map(self.inner.get_bytes(name))
+    }
+
+    fn set_bytes(&self, name: &[u8], data: &[u8]) -> Res {
+        self.map(self.inner.set_bytes(name, data))
+    }
+
+    fn delete_bytes(&self, name: &[u8]) -> Res {
+        self.map(self.inner.delete_bytes(name))
+    }
+
+    fn get_existing(&self, names: &[&str]) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+        self.map(self.inner.get_existing(names))
+    }
+
+    fn exists_many(&self, names: &[&str]) -> Result<Vec<bool>, Error> {
+        self.map(self.inner.exists_many(names))
+    }
+
+    fn version(&self, name: &str) -> Result<String, Error> {
+        self.map(self.inner.version(name))
+    }
+
+    fn get_if_changed(
+        &self,
+        name: &str,
+        known_version: &str,
+    ) -> Result<Option<(Vec<u8>, String)>, Error> {
+        self.map(self.inner.get_if_changed(name, known_version))
+    }
+
+    fn stat(&self, name: &str) -> Result<Metadata, Error> {
+        self.map(self.inner.stat(name))
+    }
+
+    fn delete_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        self.map(self.inner.delete_prefix(prefix))
+    }
+
+    fn rename_prefix(&self, old: &str, new: &str) -> Result<usize, Error> {
+        self.map(self.inner.rename_prefix(old, new))
+    }
+
+    fn take(&self, name: &str) -> Res {
+        self.map(self.inner.take(name))
+    }
+
+    fn update_with(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(Option<Vec<u8>>) -> Option<Vec<u8>>,
+    ) -> Res {
+        self.map(self.inner.update_with(name, f))
+    }
+
+    fn replace(&self, name: &str, data: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.map(self.inner.replace(name, data))
+    }
+
+    fn create(&self, name: &str, data: &[u8]) -> Res {
+        self.map(self.inner.create(name, data))
+    }
+
+    fn watch(&self, name: &str) -> Result<ChangeReceiver, Error> {
+        self.map(self.inner.watch(name))
+    }
+
+    fn watch_prefix(&self, prefix: &str) -> Result<ChangeReceiver, Error> {
+        self.map(self.inner.watch_prefix(prefix))
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn Cave + '_>, Error> {
+        let snapshot = self.map(self.inner.snapshot())?;
+        // A trait object keeps the type of the snapshot of a snapshot the
+        // same, instead of nesting references to the function.
+        let f: &(dyn Fn(Error) -> Error + Send + Sync) = &self.f;
+        Ok(Box::new(MapErrCave {
+            inner: snapshot,
+            f,
+            map_not_found: self.map_not_found,
+        }))
+    }
+
+    fn first_key(&self) -> Result<Option<String>, Error> {
+        self.map(self.inner.first_key())
+    }
+
+    fn last_key(&self) -> Result<Option<String>, Error> {
+        self.map(self.inner.last_key())
+    }
+
+    fn range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        self.map(self.inner.range(start, end))
+    }
+
+    fn swap(&self, a: &str, b: &str) -> Res {
+        self.map(self.inner.swap(a, b))
+    }
+
+    fn count_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        self.map(self.inner.count_prefix(prefix))
+    }
+
+    fn for_each(&self, f: &mut VisitFn<'_>) -> Result<(), Error> {
+        self.map(self.inner.for_each(f))
+    }
+
+    fn keys_sorted(&self) -> Result<Vec<String>, Error> {
+        self.map(self.inner.keys_sorted())
+    }
+
+    fn for_each_sorted(&self, f: &mut VisitFn<'_>) -> Result<(), Error> {
+        self.map(self.inner.for_each_sorted(f))
+    }
+
+    fn dump(&self) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+        self.map(self.inner.dump())
+    }
+
+    fn flush(&self) -> Res {
+        self.map(self.inner.flush())
+    }
+
+    fn close(self) -> Result<(), Error> {
+        let Self {
+            inner,
+            f,
+            map_not_found,
+        } = self;
+        inner.close().map_err(|e| map_error(&f, map_not_found, e))
+    }
+
+    fn maintain(&self) -> Res {
+        self.map(self.inner.maintain())
+    }
+
+    fn disk_usage(&self) -> Result<u64, Error> {
+        self.map(self.inner.disk_usage())
+    }
+
+    fn lock(&self, name: &str) -> Result<KeyGuard<'_>, Error> {
+        self.map(self.inner.lock(name))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::errors::ErrorKind;
+    use crate::res::empty_ok;
+    use crate::tests::{_test_keys, _test_simple};
+    use crate::MemoryCave;
+
+    /// Tag the errors with the name of a subsystem.
+    fn tag(e: Error) -> Error {
+        Error::Internal(anyhow!("sessions: {}", e))
+    }
+
+    #[test]
+    fn test_map_err_simple() {
+        _test_simple(Box::new(MapErrCave::new(MemoryCave::new(), tag)));
+        _test_keys(Box::new(MapErrCave::new(MemoryCave::new(), tag)));
+    }
+
+    #[test]
+    fn test_map_err_errors() {
+        let b = MapErrCave::new(MemoryCave::new().with_max_value_size(4), tag);
+        assert_eq!(b.set("key", b"val"), empty_ok());
+        let err = b.set("key", b"value").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Internal);
+        assert!(err.to_string().contains("sessions: "), "{}", err);
+        let err = b.delete_prefix("").unwrap_err();
+        assert!(err.to_string().contains("sessions: "), "{}", err);
+
+        // Errors that are returned by callbacks should be mapped as well.
+        let err = b
+            .for_each(&mut |_, _| Err(Error::internal_from_msg("stop".to_string())))
+            .unwrap_err();
+        assert!(err.to_string().ends_with("stop"), "{}", err);
+        assert!(err.to_string().contains("sessions: "), "{}", err);
+
+        // NotFound errors should be left intact, unless requested.
+        assert_eq!(b.get("missing"), b.not_found("missing"));
+        assert_eq!(b.delete("missing"), b.not_found("missing"));
+        let snapshot = b.snapshot().unwrap();
+        assert_eq!(snapshot.get("missing"), b.not_found("missing"));
+        let err = snapshot.delete_prefix("").unwrap_err();
+        assert!(err.to_string().contains("sessions: "), "{}", err);
+        drop(snapshot);
+
+        let b = b.with_not_found_mapping();
+        let err = b.get("missing").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Internal);
+        assert!(err.to_string().contains("sessions: "), "{}", err);
+        assert_eq!(b.get("key"), Ok(b"val".to_vec()));
+        assert_eq!(
+            format!("{:?}", b),
+            "MapErrCave { inner: MemoryCave { keys: 1, max_value_size: Some(4), .. }, \
+             map_not_found: true, .. }"
+        );
+        assert_eq!(b.close(), Ok(()));
+    }
+}
@@ -0,0 +1,197 @@
+//! Memcached backend
+//!
+//! In this module, we define a `Cave` that stores keys in one or more
+//! [memcached] servers.
+//!
+//! [memcached]: https://memcached.org/
+
+use std::fmt;
+
+use crate::errors::Error;
+use crate::res::{empty_ok, Res};
+use crate::{check_value_size, Capabilities, Cave};
+
+/// The maximum length of a key name, in bytes, that memcached accepts.
+pub const MEMCACHED_MAX_KEY_LEN: usize = 250;
+
+/// The maximum size of a value, in bytes, that memcached accepts by default.
+pub const MEMCACHED_MAX_VALUE_SIZE: usize = 1024 * 1024;
+
+/// The number of connections that we keep open per server.
+const POOL_SIZE: u32 = 4;
+
+/// A key-value store that stores keys in memcached servers.
+///
+/// This kv maps `get`/`set`/`delete` to the respective memcached commands.
+/// If it's connected to more than one servers, each key is stored in one of
+/// them, according to the hash of its name.
+///
+/// This kv is available with the `with-memcached` feature.
+///
+/// ## Caveats
+///
+/// This kv has the following caveats:
+///
+/// * Memcached is a cache, so it may evict keys at any time, e.g., when it
+///   runs out of memory. An evicted key is treated as a key that does not
+///   exist.
+/// * Memcached does not support listing its keys, so `keys()` always returns
+///   an [`Error::Unsupported`] error.
+/// * Key names must be at most [`MEMCACHED_MAX_KEY_LEN`] bytes long, and must
+///   not contain whitespace or control characters.
+/// * Values must be at most [`MEMCACHED_MAX_VALUE_SIZE`] bytes long, unless
+///   the servers are configured otherwise (`-I` option), in which case the
+///   limit can be changed with [`with_max_value_size`].
+///
+/// Requests that violate these limits are rejected before reaching the
+/// servers. Invalid key names result to an [`Error::InvalidName`] error,
+/// while large values result to an [`Error::ValueTooLarge`] error.
+///
+/// [`with_max_value_size`]: #method.with_max_value_size
+/// [`Error::InvalidName`]: errors/enum.Error.html#variant.InvalidName
+/// [`Error::ValueTooLarge`]: errors/enum.Error.html#variant.ValueTooLarge
+/// [`Error::Unsupported`]: errors/enum.Error.html#variant.Unsupported
+///
+/// [`MEMCACHED_MAX_KEY_LEN`]: constant.MEMCACHED_MAX_KEY_LEN.html
+/// [`MEMCACHED_MAX_VALUE_SIZE`]: constant.MEMCACHED_MAX_VALUE_SIZE.html
+pub struct MemcachedCave {
+    urls: Vec<String>,
+    client: memcache::Client,
+    max_value_size: usize,
+}
+
+impl MemcachedCave {
+    /// Create a new instance, which connects to the provided servers.
+    ///
+    /// The servers are specified as URLs, e.g., `memcache://127.0.0.1:11211`.
+    /// If a server is not reachable, return an error.
+    pub fn new(urls: &[&str]) -> Result<Self, Error> {
+        let urls: Vec<String> = urls.iter().map(|url| url.to_string()).collect();
+        match memcache::Client::with_pool_size(urls.clone(), POOL_SIZE) {
+            Ok(client) => Ok(Self {
+                urls,
+                client,
+                max_value_size: MEMCACHED_MAX_VALUE_SIZE,
+            }),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    /// Reject values that are larger than `limit` bytes, instead of
+    /// [`MEMCACHED_MAX_VALUE_SIZE`].
+    ///
+    /// The limit should match the one that the servers are configured with.
+    ///
+    /// [`MEMCACHED_MAX_VALUE_SIZE`]: constant.MEMCACHED_MAX_VALUE_SIZE.html
+    pub fn with_max_value_size(mut self, limit: usize) -> Self {
+        self.max_value_size = limit;
+        self
+    }
+
+    fn check_name(name: &str) -> Result<(), Error> {
+        if name.is_empty() || name.len() > MEMCACHED_MAX_KEY_LEN {
+            return Err(Error::InvalidName {
+                name: name.to_string(),
+                reason: "the name must be between 1 and 250 bytes long",
+            });
+        }
+
+        if name.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(Error::InvalidName {
+                name: name.to_string(),
+                reason: "the name must not contain whitespace or control characters",
+            });
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for MemcachedCave {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemcachedCave")
+            .field("urls", &self.urls)
+            .field("max_value_size", &self.max_value_size)
+            .finish()
+    }
+}
+
+impl Cave for MemcachedCave {
+    fn get(&self, name: &str) -> Res {
+        Self::check_name(name)?;
+        match self.client.get::<Vec<u8>>(name) {
+            Ok(Some(buf)) => Ok(buf),
+            Ok(None) => self.not_found(name),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    fn set(&self, name: &str, data: &[u8]) -> Res {
+        Self::check_name(name)?;
+        check_value_size(data, Some(self.max_value_size))?;
+
+        // An expiration time of 0 means that the key never expires.
+        match self.client.set(name, data, 0) {
+            Ok(_) => empty_ok(),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    fn delete(&self, name: &str) -> Res {
+        Self::check_name(name)?;
+        match self.client.delete(name) {
+            Ok(true) => empty_ok(),
+            Ok(false) => self.not_found(name),
+            Err(e) => Err(Error::Internal(e.into())),
+        }
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        Err(Error::Unsupported { operation: "keys" })
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            keys: false,
+            ..Capabilities::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::_test_simple;
+
+    #[test]
+    fn test_memcached_backend_names() {
+        let invalid_name_err = |name: &str| {
+            Err(Error::InvalidName {
+                name: name.to_string(),
+                reason: "",
+            })
+        };
+
+        assert_eq!(MemcachedCave::check_name("key"), Ok(()));
+        let long_name = "k".repeat(MEMCACHED_MAX_KEY_LEN);
+        assert_eq!(MemcachedCave::check_name(&long_name), Ok(()));
+
+        let long_name = "k".repeat(MEMCACHED_MAX_KEY_LEN + 1);
+        for name in [long_name.as_str(), "", "a key", "a\nkey"].iter() {
+            assert_eq!(MemcachedCave::check_name(name), invalid_name_err(name));
+        }
+    }
+
+    // This test requires a memcached server that listens on the default port.
+    #[test]
+    #[ignore]
+    fn test_memcached_backend_simple() {
+        let mb = MemcachedCave::new(&["memcache://127.0.0.1:11211"]).unwrap();
+        let _ = mb.delete("test");
+        let res = mb.keys();
+        assert_eq!(res, Err(Error::Unsupported { operation: "keys" }));
+        assert!(!mb.capabilities().keys);
+
+        _test_simple(Box::new(mb));
+    }
+}
@@ -0,0 +1,233 @@
+//! Copy-on-write overlays
+//!
+//! In this module, we define a wrapper that keeps the writes to a `Cave` in
+//! memory, without modifying it, so that changes can be tried out, e.g., in
+//! tests or in dry runs of migrations, and then either discarded or applied.
+
+use std::collections;
+use std::fmt;
+use std::sync;
+
+use crate::errors::Error;
+use crate::res::{empty_ok, Res};
+use crate::{Cave, MemoryCave};
+
+/// A wrapper that layers in-memory changes over another `Cave`, which is
+/// never modified.
+///
+/// The keys that are written through the wrapper are stored in an in-memory
+/// overlay, and the keys that are deleted are recorded, so that they are
+/// hidden from then on. A `get` returns the key from the overlay, if it has
+/// been written, or from the base `Cave` otherwise, unless it has been
+/// deleted.
+///
+/// The changes can be discarded by dropping the wrapper, or applied to any
+/// `Cave` with [`commit`], e.g., to the base `Cave` itself. In order to keep
+/// using the base `Cave` after a commit, wrap a reference to it.
+///
+/// ## Usage
+///
+/// ```
+/// use caves::{Cave, MemoryCave, OverlayCave};
+///
+/// let base = MemoryCave::new();
+/// base.set("key1", b"value").unwrap();
+/// base.set("key2", b"value").unwrap();
+///
+/// let b = OverlayCave::new(&base);
+/// b.set("key1", b"new value").unwrap();
+/// b.delete("key2").unwrap();
+/// assert_eq!(b.get("key1").unwrap(), b"new value");
+/// assert!(b.get("key2").is_err());
+///
+/// // The base is not modified, until the changes are committed.
+/// assert_eq!(base.get("key2").unwrap(), b"value");
+/// b.commit(&base).unwrap();
+/// assert_eq!(base.get("key1").unwrap(), b"new value");
+/// assert!(base.get("key2").is_err());
+/// ```
+///
+/// [`commit`]: #method.commit
+pub struct OverlayCave<C: Cave> {
+    base: C,
+    overlay: MemoryCave,
+    /// The names of the keys of the base `Cave` that have been deleted.
+    ///
+    /// The lock is held for writing during every write, so that the overlay
+    /// and the deletions are always updated together.
+    deletions: sync::RwLock<collections::HashSet<String>>,
+}
+
+impl<C: Cave> OverlayCave<C> {
+    /// Create a new instance with no changes over the provided `Cave`.
+    pub fn new(base: C) -> Self {
+        Self {
+            base,
+            overlay: MemoryCave::new(),
+            deletions: sync::RwLock::new(collections::HashSet::new()),
+        }
+    }
+
+    /// Return a reference to the base `Cave`.
+    pub fn base(&self) -> &C {
+        &self.base
+    }
+
+    /// Apply the changes to a `Cave`.
+    ///
+    /// The deleted keys are deleted from `dst`, if they exist, and then the
+    /// written keys are set in it, and `dst` is flushed. The changes are not
+    /// applied atomically, so if an error occurs, some of them may have been
+    /// applied already.
+    pub fn commit(self, dst: &dyn Cave) -> Result<(), Error> {
+        let deletions = self.deletions.into_inner().unwrap();
+        for name in &deletions {
+            match dst.delete(name) {
+                Ok(_) | Err(Error::NotFound(_)) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        let _ = self.overlay.persist_to(dst)?;
+        Ok(())
+    }
+
+    /// Return whether a key of the base `Cave` has not been deleted, and is
+    /// not shadowed by the overlay.
+    fn in_base(&self, deletions: &collections::HashSet<String>, name: &str) -> bool {
+        !deletions.contains(name) && self.overlay.stat(name).is_err()
+    }
+}
+
+/// Print the number of changes, instead of their contents, so that it's safe
+/// to log.
+impl<C: Cave + fmt::Debug> fmt::Debug for OverlayCave<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OverlayCave")
+            .field("base", &self.base)
+            .field("overlay", &self.overlay)
+            .field("deletions", &self.deletions.read().unwrap().len())
+            .finish()
+    }
+}
+
+impl<C: Cave> Cave for OverlayCave<C> {
+    fn get(&self, name: &str) -> Res {
+        let deletions = self.deletions.read().unwrap();
+        if deletions.contains(name) {
+            return self.not_found(name);
+        }
+        match self.overlay.get(name) {
+            Err(Error::NotFound(_)) => self.base.get(name),
+            res => res,
+        }
+    }
+
+    fn set(&self, name: &str, data: &[u8]) -> Res {
+        let mut deletions = self.deletions.write().unwrap();
+        let _ = self.overlay.set(name, data)?;
+        let _ = deletions.remove(name);
+        empty_ok()
+    }
+
+    fn delete(&self, name: &str) -> Res {
+        let mut deletions = self.deletions.write().unwrap();
+        if deletions.contains(name) {
+            return self.not_found(name);
+        }
+        let in_overlay = match self.overlay.delete(name) {
+            Ok(_) => true,
+            Err(Error::NotFound(_)) => false,
+            Err(e) => return Err(e),
+        };
+        let in_base = self.base.exists_many(&[name])?[0];
+        if in_base {
+            let _ = deletions.insert(name.to_string());
+        }
+        if in_overlay || in_base {
+            empty_ok()
+        } else {
+            self.not_found(name)
+        }
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        let deletions = self.deletions.read().unwrap();
+        let mut keys = self.overlay.keys()?;
+        for name in self.base.keys()? {
+            if self.in_base(&deletions, &name) {
+                keys.push(name);
+            }
+        }
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::{_test_concurrency, _test_keys, _test_simple};
+    use crate::FileCave;
+
+    #[test]
+    fn test_overlay_simple() {
+        _test_simple(Box::new(OverlayCave::new(MemoryCave::new())));
+        _test_keys(Box::new(OverlayCave::new(MemoryCave::new())));
+        _test_concurrency(Box::new(OverlayCave::new(MemoryCave::new())));
+    }
+
+    #[test]
+    fn test_overlay_changes() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let base = FileCave::new(temp_dir.path()).unwrap();
+        assert_eq!(base.set("key1", b"value"), empty_ok());
+        assert_eq!(base.set("key2", b"value"), empty_ok());
+        assert_eq!(base.set("key3", b"value"), empty_ok());
+
+        let b = OverlayCave::new(&base);
+        assert_eq!(b.get("key1"), Ok(b"value".to_vec()));
+        assert_eq!(b.set("key1", b"value1"), empty_ok());
+        assert_eq!(b.set("key4", b"value4"), empty_ok());
+        assert_eq!(b.delete("key2"), empty_ok());
+        assert_eq!(b.delete("key2"), b.not_found("key2"));
+        assert_eq!(b.delete("missing"), b.not_found("missing"));
+
+        // A key that was written and then deleted should be hidden, even
+        // though it exists in the base.
+        assert_eq!(b.set("key3", b"value3"), empty_ok());
+        assert_eq!(b.delete("key3"), empty_ok());
+        assert_eq!(b.get("key3"), b.not_found("key3"));
+        assert_eq!(b.delete("key3"), b.not_found("key3"));
+
+        // A deleted key should be visible again once it's written.
+        assert_eq!(b.set("key2", b"value2"), empty_ok());
+        assert_eq!(b.delete("key4"), empty_ok());
+        assert_eq!(b.set("key4", b"value4"), empty_ok());
+
+        assert_eq!(b.get("key1"), Ok(b"value1".to_vec()));
+        assert_eq!(b.get("key2"), Ok(b"value2".to_vec()));
+        assert_eq!(b.get("key4"), Ok(b"value4".to_vec()));
+        let mut keys = b.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["key1", "key2", "key4"]);
+        assert_eq!(
+            format!("{:?}", OverlayCave::new(MemoryCave::new())),
+            "OverlayCave { base: MemoryCave { keys: 0, max_value_size: None, .. }, \
+             overlay: MemoryCave { keys: 0, max_value_size: None, .. }, deletions: 0 }"
+        );
+
+        // The base should not be modified, until the changes are committed.
+        let mut keys = base.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["key1", "key2", "key3"]);
+        assert_eq!(base.get("key1"), Ok(b"value".to_vec()));
+
+        assert_eq!(b.delete("key2"), empty_ok());
+        assert_eq!(b.commit(&base), Ok(()));
+        let mut keys = base.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["key1", "key4"]);
+        assert_eq!(base.get("key1"), Ok(b"value1".to_vec()));
+        assert_eq!(base.get("key4"), Ok(b"value4".to_vec()));
+    }
+}
@@ -0,0 +1,122 @@
+//! Parallel batch operations
+//!
+//! In this module, we define helpers that run a batch of operations on a
+//! `Cave` from multiple threads, for kvs where each operation is a separate,
+//! blocking call, e.g., a syscall for `FileCave`.
+
+use rayon::prelude::*;
+
+use crate::errors::Error;
+use crate::res::Res;
+use crate::Cave;
+
+fn build_pool(threads: usize) -> Result<rayon::ThreadPool, Error> {
+    match rayon::ThreadPoolBuilder::new().num_threads(threads).build() {
+        Ok(pool) => Ok(pool),
+        Err(e) => Err(Error::Internal(e.into())),
+    }
+}
+
+/// Create or update multiple keys in parallel, and return the result of
+/// each `set`.
+///
+/// The entries are split across a pool of `threads` threads, which is
+/// created for this call. If `threads` is 0, the number of threads is picked
+/// automatically, based on the number of CPUs. The results are returned in
+/// the same order as the entries. Writes to the same key race with each
+/// other, so the order in which they are applied is not defined.
+///
+/// An error is returned only if the thread pool cannot be created.
+///
+/// This function is available with the `with-rayon` feature.
+///
+/// ## Usage
+///
+/// ```
+/// use caves::{set_many_parallel, Cave, MemoryCave};
+///
+/// let b = MemoryCave::new();
+/// let entries = [("key1", &b"value1"[..]), ("key2", &b"value2"[..])];
+/// let results = set_many_parallel(&b, &entries, 2).unwrap();
+/// assert!(results.iter().all(|res| res.is_ok()));
+/// assert_eq!(b.get("key2").unwrap(), b"value2");
+/// ```
+pub fn set_many_parallel(
+    cave: &dyn Cave,
+    entries: &[(&str, &[u8])],
+    threads: usize,
+) -> Result<Vec<Res>, Error> {
+    let pool = build_pool(threads)?;
+    Ok(pool.install(|| {
+        entries
+            .par_iter()
+            .map(|(name, data)| cave.set(name, data))
+            .collect()
+    }))
+}
+
+/// Get multiple keys in parallel, and return the result of each `get`.
+///
+/// This function works like [`set_many_parallel`], and returns the results
+/// in the same order as the names.
+///
+/// This function is available with the `with-rayon` feature.
+///
+/// [`set_many_parallel`]: fn.set_many_parallel.html
+pub fn get_many_parallel(
+    cave: &dyn Cave,
+    names: &[&str],
+    threads: usize,
+) -> Result<Vec<Res>, Error> {
+    let pool = build_pool(threads)?;
+    Ok(pool.install(|| names.par_iter().map(|name| cave.get(name)).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::res::empty_ok;
+    use crate::{FileCave, MemoryCave};
+
+    fn _test_parallel(b: Box<dyn Cave>) {
+        let names: Vec<String> = (0..100).map(|i| format!("key{}", i)).collect();
+        let entries: Vec<(&str, &[u8])> = names
+            .iter()
+            .map(|name| (name.as_str(), name.as_bytes()))
+            .collect();
+
+        let results = set_many_parallel(&*b, &entries, 4).unwrap();
+        assert_eq!(results.len(), entries.len());
+        assert!(results.into_iter().all(|res| res == empty_ok()));
+
+        // The results should be in the same order as the names.
+        let mut names: Vec<&str> = names.iter().map(String::as_str).collect();
+        names.push("missing");
+        let results = get_many_parallel(&*b, &names, 4).unwrap();
+        for (name, res) in names.iter().zip(results) {
+            match *name {
+                "missing" => assert_eq!(res, Err(Error::NotFound("missing".to_string()))),
+                _ => assert_eq!(res, Ok(name.as_bytes().to_vec())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parallel_memory() {
+        _test_parallel(Box::new(MemoryCave::new()));
+    }
+
+    #[test]
+    fn test_parallel_file() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        _test_parallel(Box::new(FileCave::new(temp_dir.path()).unwrap()));
+    }
+
+    #[test]
+    fn test_parallel_default_threads() {
+        let b = MemoryCave::new();
+        let results = set_many_parallel(&b, &[("key", b"value")], 0).unwrap();
+        assert_eq!(results, vec![empty_ok()]);
+    }
+}
@@ -0,0 +1,519 @@
+//! Storage quotas
+//!
+//! In this module, we define a wrapper that limits the total size of the
+//! contents and the number of the keys of a `Cave`, so that a tenant of a
+//! multi-tenant application cannot use more than its share.
+
+use std::collections;
+use std::fmt;
+use std::sync;
+
+use crate::errors::Error;
+use crate::res::{empty_ok, Res};
+use crate::{Capabilities, Cave, ChangeReceiver, KeyGuard, Metadata, VisitFn};
+
+/// The total size of the contents and the number of the keys of a `Cave`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Usage {
+    bytes: u64,
+    keys: u64,
+}
+
+impl Usage {
+    /// Return the usage after a key of size `old`, or a missing key, is
+    /// replaced by contents of size `new`, or is deleted.
+    ///
+    /// The usage never drops below zero, in case it was under-reported.
+    fn after(self, old: Option<u64>, new: Option<u64>) -> Self {
+        let bytes = self.bytes.saturating_sub(old.unwrap_or(0));
+        let keys = self.keys.saturating_sub(old.is_some() as u64);
+        Self {
+            bytes: bytes.saturating_add(new.unwrap_or(0)),
+            keys: keys.saturating_add(new.is_some() as u64),
+        }
+    }
+}
+
+/// Measure the usage of a `Cave`, from the sizes of its keys.
+fn scan<C: Cave + ?Sized>(cave: &C) -> Result<Usage, Error> {
+    let mut usage = Usage::default();
+    for name in cave.keys()? {
+        match cave.stat(&name) {
+            Ok(md) => usage = usage.after(None, Some(md.size)),
+            Err(Error::NotFound(_)) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(usage)
+}
+
+/// A wrapper that enforces a quota on the total size of the contents and the
+/// number of the keys of another `Cave`.
+///
+/// The wrapper keeps track of the usage of the inner `Cave`, and rejects any
+/// write that would exceed a quota (see [`with_max_bytes`] and
+/// [`with_max_keys`]) with an [`Error::QuotaExceeded`] error, leaving the kv
+/// intact. Replacing the contents of a key counts only the difference in
+/// their size, so writes that shrink a key are always accepted, even if the
+/// usage is already above the quota.
+///
+/// The usage is measured by scanning the inner `Cave` when the wrapper is
+/// created, unless it's provided by the caller (see [`with_usage`]). Since
+/// the wrapper updates it as it forwards the writes, the inner `Cave` must
+/// not be modified by other means in the meantime. Also, the writes are
+/// serialized, so that they are accounted for correctly. A `delete_prefix`
+/// or a `rename_prefix` measures the usage of the inner `Cave` anew.
+///
+/// ## Usage
+///
+/// ```
+/// use caves::errors::ErrorKind;
+/// use caves::{Cave, MemoryCave, QuotaCave};
+///
+/// let b = QuotaCave::new(MemoryCave::new()).unwrap().with_max_bytes(10);
+/// b.set("key1", b"12345678").unwrap();
+/// let err = b.set("key2", b"123").unwrap_err();
+/// assert_eq!(err.kind(), ErrorKind::QuotaExceeded);
+///
+/// // Shrinking a key frees space.
+/// b.set("key1", b"1234").unwrap();
+/// b.set("key2", b"123").unwrap();
+/// assert_eq!(b.used_bytes(), 7);
+/// ```
+///
+/// [`with_max_bytes`]: #method.with_max_bytes
+/// [`with_max_keys`]: #method.with_max_keys
+/// [`with_usage`]: #method.with_usage
+/// [`Error::QuotaExceeded`]: errors/enum.Error.html#variant.QuotaExceeded
+pub struct QuotaCave<C: Cave> {
+    inner: C,
+    max_bytes: Option<u64>,
+    max_keys: Option<u64>,
+    /// The lock is held during every write.
+    usage: sync::Mutex<Usage>,
+}
+
+impl<C: Cave> QuotaCave<C> {
+    /// Create a new instance that wraps the provided `Cave`, and measure its
+    /// usage by listing its keys and their sizes.
+    pub fn new(inner: C) -> Result<Self, Error> {
+        let usage = scan(&inner)?;
+        Ok(Self::with_usage(inner, usage.bytes, usage.keys))
+    }
+
+    /// Create a new instance that wraps the provided `Cave`, whose contents
+    /// have a total size of `bytes`, in `keys` keys.
+    ///
+    /// This is useful for large kvs, where scanning is expensive, and the
+    /// usage is known by other means. If the provided usage is lower than
+    /// the actual one, it drops to zero as keys are deleted, instead of
+    /// below it.
+    pub fn with_usage(inner: C, bytes: u64, keys: u64) -> Self {
+        Self {
+            inner,
+            max_bytes: None,
+            max_keys: None,
+            usage: sync::Mutex::new(Usage { bytes, keys }),
+        }
+    }
+
+    /// Limit the total size of the contents of the keys.
+    pub fn with_max_bytes(mut self, limit: u64) -> Self {
+        self.max_bytes = Some(limit);
+        self
+    }
+
+    /// Limit the number of the keys.
+    pub fn with_max_keys(mut self, limit: u64) -> Self {
+        self.max_keys = Some(limit);
+        self
+    }
+
+    /// Return the total size of the contents of the keys.
+    pub fn used_bytes(&self) -> u64 {
+        self.usage.lock().unwrap().bytes
+    }
+
+    /// Return the number of the keys.
+    pub fn used_keys(&self) -> u64 {
+        self.usage.lock().unwrap().keys
+    }
+
+    /// Return a reference to the inner `Cave`.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Return the size of a key, or `None` if it does not exist.
+    fn size(&self, name: &str) -> Result<Option<u64>, Error> {
+        match self.inner.stat(name) {
+            Ok(md) => Ok(Some(md.size)),
+            Err(Error::NotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Check that a write that changes the usage from `current` to `next` is
+    /// within the quotas, or that it at least does not increase the usage.
+    fn check(&self, current: Usage, next: Usage) -> Result<(), Error> {
+        if let Some(limit) = self.max_bytes {
+            if next.bytes > limit && next.bytes > current.bytes {
+                return Err(Error::QuotaExceeded {
+                    resource: "bytes",
+                    limit,
+                });
+            }
+        }
+        if let Some(limit) = self.max_keys {
+            if next.keys > limit && next.keys > current.keys {
+                return Err(Error::QuotaExceeded {
+                    resource: "keys",
+                    limit,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Print the usage and the quotas, instead of the contents of the kv, so
+/// that it's safe to log.
+impl<C: Cave + fmt::Debug> fmt::Debug for QuotaCave<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let usage = *self.usage.lock().unwrap();
+        f.debug_struct("QuotaCave")
+            .field("inner", &self.inner)
+            .field("bytes", &usage.bytes)
+            .field("keys", &usage.keys)
+            .field("max_bytes", &self.max_bytes)
+            .field("max_keys", &self.max_keys)
+            .finish()
+    }
+}
+
+impl<C: Cave> Cave for QuotaCave<C> {
+    fn get(&self, name: &str) -> Res {
+        self.inner.get(name)
+    }
+
+    fn set(&self, name: &str, data: &[u8]) -> Res {
+        let mut usage = self.usage.lock().unwrap();
+        let next = usage.after(self.size(name)?, Some(data.len() as u64));
+        self.check(*usage, next)?;
+        let _ = self.inner.set(name, data)?;
+        *usage = next;
+        empty_ok()
+    }
+
+    fn delete(&self, name: &str) -> Res {
+        let mut usage = self.usage.lock().unwrap();
+        let old = self.size(name)?;
+        let _ = self.inner.delete(name)?;
+        *usage = usage.after(old, None);
+        empty_ok()
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        self.inner.keys()
+    }
+
+    fn get_existing(&self, names: &[&str]) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+        self.inner.get_existing(names)
+    }
+
+    fn exists_many(&self, names: &[&str]) -> Result<Vec<bool>, Error> {
+        self.inner.exists_many(names)
+    }
+
+    fn version(&self, name: &str) -> Result<String, Error> {
+        self.inner.version(name)
+    }
+
+    fn get_if_changed(
+        &self,
+        name: &str,
+        known_version: &str,
+    ) -> Result<Option<(Vec<u8>, String)>, Error> {
+        self.inner.get_if_changed(name, known_version)
+    }
+
+    fn stat(&self, name: &str) -> Result<Metadata, Error> {
+        self.inner.stat(name)
+    }
+
+    fn delete_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        let mut usage = self.usage.lock().unwrap();
+        let res = self.inner.delete_prefix(prefix);
+        *usage = scan(&self.inner)?;
+        res
+    }
+
+    fn rename_prefix(&self, old: &str, new: &str) -> Result<usize, Error> {
+        let mut usage = self.usage.lock().unwrap();
+        let res = self.inner.rename_prefix(old, new);
+        *usage = scan(&self.inner)?;
+        res
+    }
+
+    fn take(&self, name: &str) -> Res {
+        let mut usage = self.usage.lock().unwrap();
+        let data = self.inner.take(name)?;
+        *usage = usage.after(Some(data.len() as u64), None);
+        Ok(data)
+    }
+
+    fn update_with(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(Option<Vec<u8>>) -> Option<Vec<u8>>,
+    ) -> Res {
+        let mut usage = self.usage.lock().unwrap();
+        let current = *usage;
+        // The closure cannot return an error, so it stores it here, along
+        // with the usage after the update, and leaves the key as is. This may
+        // write the current contents back, but since every write holds the
+        // lock of the usage, no concurrent write can be overwritten.
+        let mut error = None;
+        let mut next = current;
+        let data = self.inner.update_with(name, &mut |data| {
+            let old = data.as_ref().map(|data| data.len() as u64);
+            let new_data = f(data.clone());
+            next = current.after(old, new_data.as_ref().map(|data| data.len() as u64));
+            error = self.check(current, next).err();
+            match error {
+                Some(_) => data,
+                None => new_data,
+            }
+        })?;
+
+        match error {
+            Some(e) => Err(e),
+            None => {
+                *usage = next;
+                Ok(data)
+            }
+        }
+    }
+
+    fn replace(&self, name: &str, data: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let mut usage = self.usage.lock().unwrap();
+        let next = usage.after(self.size(name)?, Some(data.len() as u64));
+        self.check(*usage, next)?;
+        let old = self.inner.replace(name, data)?;
+        *usage = next;
+        Ok(old)
+    }
+
+    fn create(&self, name: &str, data: &[u8]) -> Res {
+        let mut usage = self.usage.lock().unwrap();
+        // Report that the key exists before checking the quota, same as the
+        // inner `Cave` would.
+        if self.size(name)?.is_some() {
+            return Err(Error::AlreadyExists(name.to_string()));
+        }
+        let next = usage.after(None, Some(data.len() as u64));
+        self.check(*usage, next)?;
+        let _ = self.inner.create(name, data)?;
+        *usage = next;
+        empty_ok()
+    }
+
+    fn watch(&self, name: &str) -> Result<ChangeReceiver, Error> {
+        self.inner.watch(name)
+    }
+
+    fn watch_prefix(&self, prefix: &str) -> Result<ChangeReceiver, Error> {
+        self.inner.watch_prefix(prefix)
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn Cave + '_>, Error> {
+        self.inner.snapshot()
+    }
+
+    fn first_key(&self) -> Result<Option<String>, Error> {
+        self.inner.first_key()
+    }
+
+    fn last_key(&self) -> Result<Option<String>, Error> {
+        self.inner.last_key()
+    }
+
+    fn range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        self.inner.range(start, end)
+    }
+
+    /// Swap the contents of two keys, which leaves the usage intact.
+    fn swap(&self, a: &str, b: &str) -> Res {
+        let _usage = self.usage.lock().unwrap();
+        self.inner.swap(a, b)
+    }
+
+    fn count_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        self.inner.count_prefix(prefix)
+    }
+
+    fn for_each(&self, f: &mut VisitFn<'_>) -> Result<(), Error> {
+        self.inner.for_each(f)
+    }
+
+    fn keys_sorted(&self) -> Result<Vec<String>, Error> {
+        self.inner.keys_sorted()
+    }
+
+    fn for_each_sorted(&self, f: &mut VisitFn<'_>) -> Result<(), Error> {
+        self.inner.for_each_sorted(f)
+    }
+
+    fn dump(&self) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+        self.inner.dump()
+    }
+
+    fn flush(&self) -> Res {
+        self.inner.flush()
+    }
+
+    fn maintain(&self) -> Res {
+        self.inner.maintain()
+    }
+
+    fn disk_usage(&self) -> Result<u64, Error> {
+        self.inner.disk_usage()
+    }
+
+    fn lock(&self, name: &str) -> Result<KeyGuard<'_>, Error> {
+        self.inner.lock(name)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            binary_keys: false,
+            ..self.inner.capabilities()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::errors::ErrorKind;
+    use crate::tests::{_test_concurrency, _test_keys, _test_simple, _test_take};
+    use crate::{FileCave, MemoryCave};
+
+    fn quota_exceeded(resource: &'static str, limit: u64) -> Res {
+        Err(Error::QuotaExceeded { resource, limit })
+    }
+
+    #[test]
+    fn test_quota_simple() {
+        _test_simple(Box::new(QuotaCave::new(MemoryCave::new()).unwrap()));
+        _test_keys(Box::new(QuotaCave::new(MemoryCave::new()).unwrap()));
+        _test_take(Box::new(QuotaCave::new(MemoryCave::new()).unwrap()));
+        _test_concurrency(Box::new(QuotaCave::new(MemoryCave::new()).unwrap()));
+    }
+
+    #[test]
+    fn test_quota_limits() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let inner = FileCave::new(temp_dir.path()).unwrap();
+        assert_eq!(inner.set("key1", b"1234"), empty_ok());
+
+        // The usage of the inner cave should be measured on creation.
+        let b = QuotaCave::new(inner)
+            .unwrap()
+            .with_max_bytes(10)
+            .with_max_keys(3);
+        assert_eq!((b.used_bytes(), b.used_keys()), (4, 1));
+        assert_eq!(b.set("key2", b"1234"), empty_ok());
+        assert_eq!(b.set("key3", b"123"), quota_exceeded("bytes", 10));
+        assert_eq!(b.get("key3"), b.not_found("key3"));
+        assert_eq!(b.set("key3", b"12"), empty_ok());
+        assert_eq!((b.used_bytes(), b.used_keys()), (10, 3));
+
+        // Replacing a key should count only the difference in size, so
+        // shrinking a key should free space.
+        assert_eq!(b.set("key1", b"12345"), quota_exceeded("bytes", 10));
+        assert_eq!(b.set("key1", b"1"), empty_ok());
+        assert_eq!(b.used_bytes(), 7);
+        assert_eq!(b.set("key2", b"1234567"), empty_ok());
+        assert_eq!(b.used_bytes(), 10);
+        assert_eq!(b.replace("key2", b"123"), Ok(Some(b"1234567".to_vec())));
+        assert_eq!(b.used_bytes(), 6);
+        assert_eq!(
+            b.create("key2", b"1"),
+            Err(Error::AlreadyExists("key2".to_string()))
+        );
+        assert_eq!(b.used_bytes(), 6);
+
+        // The number of keys should be limited as well.
+        assert_eq!(b.set("key4", b""), quota_exceeded("keys", 3));
+        assert_eq!(b.delete("key3"), empty_ok());
+        assert_eq!(b.delete("key3"), b.not_found("key3"));
+        assert_eq!(b.set("key4", b""), empty_ok());
+        assert_eq!((b.used_bytes(), b.used_keys()), (4, 3));
+
+        // Updates should be checked against the quotas, and leave the key
+        // intact if they exceed them.
+        let res = b.update_with("key4", &mut |_| Some(b"1234567".to_vec()));
+        assert_eq!(res, quota_exceeded("bytes", 10));
+        assert_eq!(b.get("key4"), Ok(b"".to_vec()));
+        let res = b.update_with("key4", &mut |_| Some(b"123456".to_vec()));
+        assert_eq!(res, Ok(b"123456".to_vec()));
+        assert_eq!(b.update_with("key4", &mut |_| None), Ok(vec![]));
+        assert_eq!((b.used_bytes(), b.used_keys()), (4, 2));
+
+        assert_eq!(b.take("key1"), Ok(b"1".to_vec()));
+        assert_eq!(b.swap("key2", "key2"), empty_ok());
+        assert_eq!((b.used_bytes(), b.used_keys()), (3, 1));
+        assert_eq!(b.rename_prefix("key", "new"), Ok(1));
+        assert_eq!(b.delete_prefix("new"), Ok(1));
+        assert_eq!((b.used_bytes(), b.used_keys()), (0, 0));
+        assert_eq!(
+            format!("{:?}", b).split_once(", bytes").unwrap().1,
+            ": 0, keys: 0, max_bytes: Some(10), max_keys: Some(3) }"
+        );
+    }
+
+    #[test]
+    fn test_quota_given_usage() {
+        let inner = MemoryCave::new();
+        assert_eq!(inner.set("key1", b"1234"), empty_ok());
+        assert_eq!(inner.set("key2", b"1234"), empty_ok());
+
+        // A usage above the quota should reject growing writes, but accept
+        // shrinking ones.
+        let b = QuotaCave::with_usage(inner, 8, 2)
+            .with_max_bytes(6)
+            .with_max_keys(1);
+        assert_eq!(b.set("key1", b"12345"), quota_exceeded("bytes", 6));
+        assert_eq!(b.set("key3", b""), quota_exceeded("keys", 1));
+        assert_eq!(b.set("key1", b"12"), empty_ok());
+        assert_eq!((b.used_bytes(), b.used_keys()), (6, 2));
+        assert_eq!(b.set("key1", b"1"), empty_ok());
+        assert_eq!(b.used_bytes(), 5);
+        let err = b.set("key3", b"").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::QuotaExceeded);
+        assert_eq!(err.to_string(), "The write exceeds the quota of 1 keys");
+    }
+
+    #[test]
+    fn test_quota_under_reported_usage() {
+        let inner = MemoryCave::new();
+        assert_eq!(inner.set("key1", b"12345"), empty_ok());
+        assert_eq!(inner.set("key2", b"12345"), empty_ok());
+
+        // Deleting or shrinking keys that the usage does not account for
+        // should not underflow it.
+        let b = QuotaCave::with_usage(inner, 0, 0).with_max_keys(1);
+        assert_eq!(b.delete("key1"), empty_ok());
+        assert_eq!((b.used_bytes(), b.used_keys()), (0, 0));
+        assert_eq!(b.set("key2", b"1"), empty_ok());
+        assert_eq!((b.used_bytes(), b.used_keys()), (1, 1));
+        assert_eq!(b.take("key2"), Ok(b"1".to_vec()));
+        assert_eq!((b.used_bytes(), b.used_keys()), (0, 0));
+
+        // The wrapper should keep working afterwards.
+        assert_eq!(b.set("key3", b"123"), empty_ok());
+        assert_eq!((b.used_bytes(), b.used_keys()), (3, 1));
+    }
+}
@@ -0,0 +1,286 @@
+//! HTTP server
+//!
+//! In this module, we define a server that exposes any `Cave` over HTTP. The
+//! server speaks the same protocol as the [`HttpCave`] client:
+//!
+//! * `GET /{name}` returns the contents of a key.
+//! * `PUT /{name}` creates or updates a key, with the request body as its
+//!   contents.
+//! * `DELETE /{name}` deletes a key.
+//! * `GET /_keys` returns the names of all the keys, each terminated by a
+//!   newline.
+//!
+//! The names are percent-encoded. The errors of the `Cave` are mapped to the
+//! following status codes:
+//!
+//! * `NotFound`: `404 Not Found`
+//! * `InvalidName`: `400 Bad Request`
+//! * `AlreadyExists`: `409 Conflict`
+//! * `ValueTooLarge`: `413 Payload Too Large`
+//! * `Unsupported`: `501 Not Implemented`
+//! * `Busy`: `503 Service Unavailable`
+//! * `QuotaExceeded`: `507 Insufficient Storage`
+//! * Any other error: `500 Internal Server Error`
+//!
+//! The server also responds with `413 Payload Too Large` to `PUT` requests
+//! whose body is larger than [`MAX_BODY_SIZE`].
+//!
+//! This module is available with the `with-http-server` feature.
+//!
+//! ## Usage
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//! use caves::MemoryCave;
+//! use caves::server;
+//!
+//! let b = Arc::new(MemoryCave::new());
+//! server::serve(b, "127.0.0.1:8000".parse().unwrap()).unwrap();
+//! ```
+//!
+//! [`HttpCave`]: ../struct.HttpCave.html
+//! [`MAX_BODY_SIZE`]: constant.MAX_BODY_SIZE.html
+
+use std::io::Read;
+use std::net;
+use std::sync;
+use std::thread;
+
+use crate::errors::{Error, ErrorKind};
+use crate::http::{decode_key, encode_key, KEYS_PATH};
+use crate::res::Res;
+use crate::Cave;
+
+/// The number of threads that handle requests.
+const WORKERS: usize = 4;
+
+/// The maximum size of the body of a `PUT` request, in bytes.
+pub const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+/// Serve a `Cave` over HTTP, on the provided address.
+///
+/// This function blocks forever, unless it fails to bind to the address.
+pub fn serve(cave: sync::Arc<dyn Cave>, addr: net::SocketAddr) -> Result<(), Error> {
+    match net::TcpListener::bind(addr) {
+        Ok(listener) => serve_listener(cave, listener),
+        Err(e) => Err(Error::Internal(e.into())),
+    }
+}
+
+/// Serve a `Cave` over HTTP, on an already bound listener.
+///
+/// This function blocks forever, unless it fails to use the listener.
+pub fn serve_listener(cave: sync::Arc<dyn Cave>, listener: net::TcpListener) -> Result<(), Error> {
+    serve_with_limit(cave, listener, MAX_BODY_SIZE)
+}
+
+/// Serve a `Cave` over HTTP, and reject the bodies that are larger than
+/// `max_body_size` bytes.
+fn serve_with_limit(
+    cave: sync::Arc<dyn Cave>,
+    listener: net::TcpListener,
+    max_body_size: usize,
+) -> Result<(), Error> {
+    let server = match tiny_http::Server::from_listener(listener, None) {
+        Ok(server) => sync::Arc::new(server),
+        Err(e) => return Err(Error::internal_from_msg(e.to_string())),
+    };
+
+    let workers: Vec<_> = (0..WORKERS)
+        .map(|_| {
+            let server = server.clone();
+            let cave = cave.clone();
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    handle(&*cave, request, max_body_size);
+                }
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        if worker.join().is_err() {
+            return Err(Error::Bug(anyhow!("An HTTP worker panicked")));
+        }
+    }
+    Ok(())
+}
+
+/// Return the status code that an error is reported with.
+fn status_code(e: &Error) -> u16 {
+    match e.kind() {
+        ErrorKind::InvalidName => 400,
+        ErrorKind::NotFound => 404,
+        ErrorKind::AlreadyExists => 409,
+        ErrorKind::ValueTooLarge => 413,
+        ErrorKind::Unsupported => 501,
+        ErrorKind::Busy => 503,
+        ErrorKind::QuotaExceeded => 507,
+        _ => 500,
+    }
+}
+
+fn handle(cave: &dyn Cave, mut request: tiny_http::Request, max_body_size: usize) {
+    // Strip the leading slash and the query string, if any.
+    let url = request.url().to_string();
+    let path = url.trim_start_matches('/');
+    let path = path.split('?').next().unwrap_or_default();
+
+    let res = dispatch(cave, &mut request, path, max_body_size);
+
+    let response = match res {
+        Ok(Some(buf)) => tiny_http::Response::from_data(buf),
+        Ok(None) => tiny_http::Response::from_data(Vec::new()).with_status_code(405),
+        Err(e) => tiny_http::Response::from_data(e.to_string()).with_status_code(status_code(&e)),
+    };
+
+    // The client may have disconnected, in which case there's nobody to
+    // report the error to.
+    let _ = request.respond(response);
+}
+
+/// Run the operation that a request asks for, and return its result, or
+/// `None` if the request method is not allowed.
+///
+/// The path of the request is the percent-encoded name of a key, unless it's
+/// one of the special paths of the server.
+fn dispatch(
+    cave: &dyn Cave,
+    request: &mut tiny_http::Request,
+    path: &str,
+    max_body_size: usize,
+) -> Result<Option<Vec<u8>>, Error> {
+    if path == KEYS_PATH {
+        if *request.method() != tiny_http::Method::Get {
+            return Ok(None);
+        }
+        let mut buf = String::new();
+        for name in cave.keys()? {
+            buf.push_str(&encode_key(&name));
+            buf.push('\n');
+        }
+        return Ok(Some(buf.into_bytes()));
+    }
+
+    let name = decode_key(path)?;
+    let name = name.as_str();
+    let res: Res = match request.method() {
+        tiny_http::Method::Get => cave.get(name),
+        tiny_http::Method::Put => {
+            // Read one more byte than the limit, to find out if the body
+            // exceeds it.
+            let mut data = Vec::new();
+            let mut reader = request.as_reader().take(max_body_size as u64 + 1);
+            if let Err(e) = reader.read_to_end(&mut data) {
+                return Err(Error::Internal(e.into()));
+            }
+            if data.len() > max_body_size {
+                return Err(Error::ValueTooLarge {
+                    size: request.body_length().unwrap_or(data.len()),
+                    limit: max_body_size,
+                });
+            }
+            cave.set(name, &data)
+        }
+        tiny_http::Method::Delete => cave.delete(name),
+        _ => return Ok(None),
+    };
+    res.map(Some)
+}
+
+#[cfg(all(test, feature = "with-http-client"))]
+mod tests {
+    use super::*;
+
+    use crate::res::empty_ok;
+    use crate::tests::{_test_keys, _test_simple};
+    use crate::{HttpCave, MemoryCave};
+
+    fn spawn_server() -> (sync::Arc<MemoryCave>, HttpCave) {
+        spawn_server_with_limit(MAX_BODY_SIZE)
+    }
+
+    fn spawn_server_with_limit(max_body_size: usize) -> (sync::Arc<MemoryCave>, HttpCave) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mb = sync::Arc::new(MemoryCave::new());
+        let cave = mb.clone();
+        let _ = thread::spawn(move || serve_with_limit(cave, listener, max_body_size));
+
+        let hb = HttpCave::new(&format!("http://{}", addr)).unwrap();
+        (mb, hb)
+    }
+
+    #[test]
+    fn test_http_server_simple() {
+        let (_, hb) = spawn_server();
+        _test_simple(Box::new(hb));
+
+        let (_, hb) = spawn_server();
+        _test_keys(Box::new(hb));
+    }
+
+    #[test]
+    fn test_http_server_names() {
+        let (mb, hb) = spawn_server();
+
+        let names = [
+            "a/b c",
+            "?&=#%",
+            "ünïcödé",
+            "...",
+            "line\nbreak",
+            "",
+            " ",
+            "%20",
+            KEYS_PATH,
+        ];
+        for name in names.iter() {
+            assert_eq!(hb.set(name, name.as_bytes()), empty_ok());
+            assert_eq!(mb.get(name), Ok(name.as_bytes().to_vec()));
+            assert_eq!(hb.get(name), Ok(name.as_bytes().to_vec()));
+        }
+
+        let mut keys = hb.keys().unwrap();
+        keys.sort();
+        let mut expected = mb.keys().unwrap();
+        expected.sort();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_http_server_errors() {
+        let errors = [
+            (Error::NotFound("key".to_string()), 404),
+            (
+                Error::InvalidName {
+                    name: "key".to_string(),
+                    reason: "reason",
+                },
+                400,
+            ),
+            (Error::AlreadyExists("key".to_string()), 409),
+            (Error::ValueTooLarge { size: 2, limit: 1 }, 413),
+            (Error::Unsupported { operation: "get" }, 501),
+            (Error::Busy { limit: 1 }, 503),
+            (
+                Error::QuotaExceeded {
+                    resource: "bytes",
+                    limit: 1,
+                },
+                507,
+            ),
+            (Error::internal_from_msg("error".to_string()), 500),
+        ];
+        for (e, status) in errors.iter() {
+            assert_eq!(status_code(e), *status, "Unexpected status for {}", e);
+        }
+
+        // Bodies that are larger than the limit should be rejected.
+        let (mb, hb) = spawn_server_with_limit(4);
+        assert_eq!(hb.set("key", b"abcd"), empty_ok());
+        let err = hb.set("key", b"abcde").unwrap_err();
+        assert!(err.to_string().contains("413"), "Unexpected error: {}", err);
+        assert_eq!(mb.get("key"), Ok(b"abcd".to_vec()));
+    }
+}
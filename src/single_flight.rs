@@ -0,0 +1,499 @@
+//! Request coalescing
+//!
+//! In this module, we define a wrapper that coalesces concurrent `get`s of
+//! the same key into a single `get` to another `Cave`, so that a burst of
+//! reads for a missing or expired key, i.e., a cache stampede, does not
+//! overload a slow `Cave`. Concurrent `get_or_load`s of the same key are
+//! coalesced in the same way, so that the key is loaded only once.
+
+use std::collections;
+use std::fmt;
+use std::sync;
+use std::sync::atomic;
+
+use crate::errors::Error;
+use crate::res::Res;
+use crate::{Capabilities, Cave, ChangeReceiver, KeyGuard, Metadata, VisitFn};
+
+/// A `get` that is in progress, whose result is shared with the `get`s of the
+/// same key that arrive in the meantime.
+#[derive(Default)]
+struct Flight {
+    result: sync::Mutex<Option<Res>>,
+    /// Signaled when the result is available.
+    done: sync::Condvar,
+}
+
+/// Return a copy of the result of a `get`, for the callers that share it.
+///
+/// Errors cannot be cloned, since they may wrap arbitrary errors, so the
+/// wrapped errors are copied by their message.
+fn share(res: &Res) -> Res {
+    match res {
+        Ok(data) => Ok(data.clone()),
+        Err(Error::NotFound(name)) => Err(Error::NotFound(name.clone())),
+        Err(Error::AlreadyExists(name)) => Err(Error::AlreadyExists(name.clone())),
+        Err(Error::Internal(e)) => Err(Error::Internal(anyhow!("{:#}", e))),
+        Err(Error::InvalidName { name, reason }) => Err(Error::InvalidName {
+            name: name.clone(),
+            reason,
+        }),
+        Err(Error::ValueTooLarge { size, limit }) => Err(Error::ValueTooLarge {
+            size: *size,
+            limit: *limit,
+        }),
+        Err(Error::Unsupported { operation }) => Err(Error::Unsupported { operation }),
+        Err(Error::QuotaExceeded { resource, limit }) => Err(Error::QuotaExceeded {
+            resource,
+            limit: *limit,
+        }),
+        Err(Error::Busy { limit }) => Err(Error::Busy { limit: *limit }),
+        Err(Error::Bug(e)) => Err(Error::Bug(anyhow!("{:#}", e))),
+    }
+}
+
+/// A wrapper that coalesces concurrent `get`s of the same key.
+///
+/// The first `get` of a key is forwarded to the inner `Cave`. Any `get` of
+/// the same key that arrives while the first one is in progress waits for
+/// it, and returns a copy of its result, instead of reaching the inner
+/// `Cave`. So, no matter how many threads read a key at the same time, the
+/// inner `Cave` serves at most one `get` per key at a time.
+///
+/// Only `get`, and the methods that are built on it, e.g., `get_or`, are
+/// coalesced. The same goes for `get_or_load`, so that concurrent calls for
+/// a missing key call only the first loader, and share its result. The rest
+/// of the operations, including the writes, are forwarded to the inner `Cave`
+/// as is. Once a write returns, subsequent `get`s of the key don't join a
+/// `get` that started before the write, so they always observe it.
+///
+/// ## Usage
+///
+/// ```
+/// use caves::{Cave, MemoryCave, SingleFlightCave};
+///
+/// let b = SingleFlightCave::new(MemoryCave::new());
+/// b.set("key", b"value").unwrap();
+/// assert_eq!(b.get("key").unwrap(), b"value");
+/// ```
+pub struct SingleFlightCave<C: Cave> {
+    inner: C,
+    /// The `get`s in progress.
+    flights: Flights,
+    /// The `get_or_load`s in progress.
+    loads: Flights,
+    coalesced: atomic::AtomicUsize,
+}
+
+type Flights = sync::Mutex<collections::HashMap<String, sync::Arc<Flight>>>;
+
+/// Publishes the result of a `get` to the callers that wait for it, when
+/// it's dropped, even if the `get` panicked.
+struct Landing<'a> {
+    flights: &'a Flights,
+    name: &'a str,
+    flight: sync::Arc<Flight>,
+}
+
+impl Drop for Landing<'_> {
+    fn drop(&mut self) {
+        let mut result = self.flight.result.lock().unwrap();
+        if result.is_none() {
+            *result = Some(Err(Error::Bug(anyhow!(
+                "The `get` of key `{}` panicked",
+                self.name
+            ))));
+        }
+        drop(result);
+
+        // A write may have already replaced our flight with a new one.
+        let mut flights = self.flights.lock().unwrap();
+        if let Some(flight) = flights.get(self.name) {
+            if sync::Arc::ptr_eq(flight, &self.flight) {
+                let _ = flights.remove(self.name);
+            }
+        }
+        drop(flights);
+        self.flight.done.notify_all();
+    }
+}
+
+impl<C: Cave> SingleFlightCave<C> {
+    /// Create a new instance that wraps the provided `Cave`.
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            flights: sync::Mutex::new(collections::HashMap::new()),
+            loads: sync::Mutex::new(collections::HashMap::new()),
+            coalesced: atomic::AtomicUsize::new(0),
+        }
+    }
+
+    /// Return the number of `get`s and `get_or_load`s that shared the result
+    /// of another call, instead of reaching the inner `Cave`.
+    pub fn coalesced(&self) -> usize {
+        self.coalesced.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Return a reference to the inner `Cave`.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Make the subsequent `get`s of the keys that match a filter start anew,
+    /// instead of joining the `get`s that are in progress.
+    fn forget<F: Fn(&str) -> bool>(&self, matches: F) {
+        for flights in &[&self.flights, &self.loads] {
+            flights.lock().unwrap().retain(|name, _| !matches(name));
+        }
+    }
+
+    /// Call a function, unless a call for the same key is already in
+    /// progress, in which case wait for it and share its result.
+    fn fly<F: FnOnce() -> Res>(&self, flights: &Flights, name: &str, f: F) -> Res {
+        let (flight, leader) = {
+            let mut flights = flights.lock().unwrap();
+            match flights.get(name) {
+                Some(flight) => (flight.clone(), false),
+                None => {
+                    let flight = sync::Arc::new(Flight::default());
+                    let _ = flights.insert(name.to_string(), flight.clone());
+                    (flight, true)
+                }
+            }
+        };
+
+        if !leader {
+            let _ = self.coalesced.fetch_add(1, atomic::Ordering::Relaxed);
+            let mut result = flight.result.lock().unwrap();
+            while result.is_none() {
+                result = flight.done.wait(result).unwrap();
+            }
+            return share(result.as_ref().unwrap());
+        }
+
+        let landing = Landing {
+            flights,
+            name,
+            flight,
+        };
+        let res = f();
+        *landing.flight.result.lock().unwrap() = Some(share(&res));
+        drop(landing);
+        res
+    }
+}
+
+/// Print the number of `get`s in progress, instead of their results, so that
+/// it's safe to log.
+impl<C: Cave + fmt::Debug> fmt::Debug for SingleFlightCave<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SingleFlightCave")
+            .field("inner", &self.inner)
+            .field("flights", &self.flights.lock().unwrap().len())
+            .field("loads", &self.loads.lock().unwrap().len())
+            .field("coalesced", &self.coalesced())
+            .finish()
+    }
+}
+
+impl<C: Cave> Cave for SingleFlightCave<C> {
+    fn get(&self, name: &str) -> Res {
+        self.fly(&self.flights, name, || self.inner.get(name))
+    }
+
+    fn get_or_load(&self, name: &str, loader: &mut dyn FnMut(&str) -> Res) -> Res {
+        self.fly(&self.loads, name, || self.inner.get_or_load(name, loader))
+    }
+
+    fn set(&self, name: &str, data: &[u8]) -> Res {
+        let res = self.inner.set(name, data);
+        self.forget(|n| n == name);
+        res
+    }
+
+    fn delete(&self, name: &str) -> Res {
+        let res = self.inner.delete(name);
+        self.forget(|n| n == name);
+        res
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        self.inner.keys()
+    }
+
+    fn get_existing(&self, names: &[&str]) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+        self.inner.get_existing(names)
+    }
+
+    fn exists_many(&self, names: &[&str]) -> Result<Vec<bool>, Error> {
+        self.inner.exists_many(names)
+    }
+
+    fn stat(&self, name: &str) -> Result<Metadata, Error> {
+        self.inner.stat(name)
+    }
+
+    fn delete_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        let res = self.inner.delete_prefix(prefix);
+        self.forget(|n| n.starts_with(prefix));
+        res
+    }
+
+    fn rename_prefix(&self, old: &str, new: &str) -> Result<usize, Error> {
+        let res = self.inner.rename_prefix(old, new);
+        self.forget(|n| n.starts_with(old) || n.starts_with(new));
+        res
+    }
+
+    fn take(&self, name: &str) -> Res {
+        let res = self.inner.take(name);
+        self.forget(|n| n == name);
+        res
+    }
+
+    fn update_with(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(Option<Vec<u8>>) -> Option<Vec<u8>>,
+    ) -> Res {
+        let res = self.inner.update_with(name, f);
+        self.forget(|n| n == name);
+        res
+    }
+
+    fn replace(&self, name: &str, data: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let res = self.inner.replace(name, data);
+        self.forget(|n| n == name);
+        res
+    }
+
+    fn create(&self, name: &str, data: &[u8]) -> Res {
+        let res = self.inner.create(name, data);
+        self.forget(|n| n == name);
+        res
+    }
+
+    fn watch(&self, name: &str) -> Result<ChangeReceiver, Error> {
+        self.inner.watch(name)
+    }
+
+    fn watch_prefix(&self, prefix: &str) -> Result<ChangeReceiver, Error> {
+        self.inner.watch_prefix(prefix)
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn Cave + '_>, Error> {
+        self.inner.snapshot()
+    }
+
+    fn first_key(&self) -> Result<Option<String>, Error> {
+        self.inner.first_key()
+    }
+
+    fn last_key(&self) -> Result<Option<String>, Error> {
+        self.inner.last_key()
+    }
+
+    fn range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        self.inner.range(start, end)
+    }
+
+    fn swap(&self, a: &str, b: &str) -> Res {
+        let res = self.inner.swap(a, b);
+        self.forget(|n| n == a || n == b);
+        res
+    }
+
+    fn count_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        self.inner.count_prefix(prefix)
+    }
+
+    fn keys_sorted(&self) -> Result<Vec<String>, Error> {
+        self.inner.keys_sorted()
+    }
+
+    fn for_each_sorted(&self, f: &mut VisitFn<'_>) -> Result<(), Error> {
+        self.inner.for_each_sorted(f)
+    }
+
+    fn for_each(&self, f: &mut VisitFn<'_>) -> Result<(), Error> {
+        self.inner.for_each(f)
+    }
+
+    fn dump(&self) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+        self.inner.dump()
+    }
+
+    fn flush(&self) -> Res {
+        self.inner.flush()
+    }
+
+    fn maintain(&self) -> Res {
+        self.inner.maintain()
+    }
+
+    fn disk_usage(&self) -> Result<u64, Error> {
+        self.inner.disk_usage()
+    }
+
+    fn lock(&self, name: &str) -> Result<KeyGuard<'_>, Error> {
+        self.inner.lock(name)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            binary_keys: false,
+            ..self.inner.capabilities()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+    use std::time;
+
+    use crate::res::empty_ok;
+    use crate::tests::{_test_concurrency, _test_keys, _test_simple};
+    use crate::MemoryCave;
+
+    /// A `Cave` whose `get`s are slow, and counted.
+    #[derive(Debug, Default)]
+    struct SlowCave {
+        inner: MemoryCave,
+        gets: atomic::AtomicUsize,
+    }
+
+    impl Cave for SlowCave {
+        fn get(&self, name: &str) -> Res {
+            let _ = self.gets.fetch_add(1, atomic::Ordering::SeqCst);
+            thread::sleep(time::Duration::from_millis(100));
+            self.inner.get(name)
+        }
+
+        fn set(&self, name: &str, data: &[u8]) -> Res {
+            self.inner.set(name, data)
+        }
+
+        fn delete(&self, name: &str) -> Res {
+            self.inner.delete(name)
+        }
+
+        fn keys(&self) -> Result<Vec<String>, Error> {
+            self.inner.keys()
+        }
+    }
+
+    #[test]
+    fn test_single_flight_simple() {
+        let b = SingleFlightCave::new(MemoryCave::new());
+        _test_simple(Box::new(b));
+        let b = SingleFlightCave::new(MemoryCave::new());
+        _test_keys(Box::new(b));
+        let b = SingleFlightCave::new(MemoryCave::new());
+        _test_concurrency(Box::new(b));
+    }
+
+    #[test]
+    fn test_single_flight_coalescing() {
+        let b = SingleFlightCave::new(SlowCave::default());
+        assert_eq!(b.set("test", b"value"), empty_ok());
+
+        // Concurrent gets of the same key should reach the inner cave once,
+        // and share its result. Gets of other keys should not be affected.
+        let threads = 16;
+        thread::scope(|s| {
+            for _ in 0..threads {
+                let _ = s.spawn(|| assert_eq!(b.get("test"), Ok(b"value".to_vec())));
+            }
+            let _ = s.spawn(|| assert_eq!(b.get("missing"), b.not_found("missing")));
+        });
+        let gets = b.inner().gets.load(atomic::Ordering::SeqCst);
+        assert!(gets < threads, "gets: {}", gets);
+        assert_eq!(gets + b.coalesced(), threads + 1);
+        assert!(b.flights.lock().unwrap().is_empty());
+
+        // Errors should be shared as well.
+        let start = b.coalesced();
+        thread::scope(|s| {
+            for _ in 0..threads {
+                let _ = s.spawn(|| assert_eq!(b.get("missing"), b.not_found("missing")));
+            }
+        });
+        assert!(b.coalesced() > start);
+
+        // A get that starts after a write should not share the result of a
+        // get that started before it, which may or may not see the write.
+        thread::scope(|s| {
+            let _ = s.spawn(|| assert!(b.get("test").is_ok()));
+            thread::sleep(time::Duration::from_millis(20));
+            assert_eq!(b.set("test", b"value2"), empty_ok());
+            assert_eq!(b.get("test"), Ok(b"value2".to_vec()));
+        });
+    }
+
+    #[test]
+    fn test_single_flight_get_or_load() {
+        let b = SingleFlightCave::new(SlowCave::default());
+
+        // Concurrent loads of the same key should call a single loader.
+        let threads = 16;
+        let loads = atomic::AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..threads {
+                let _ = s.spawn(|| {
+                    let res = b.get_or_load("test", &mut |_| {
+                        let _ = loads.fetch_add(1, atomic::Ordering::SeqCst);
+                        Ok(b"value".to_vec())
+                    });
+                    assert_eq!(res, Ok(b"value".to_vec()));
+                });
+            }
+        });
+        assert_eq!(loads.load(atomic::Ordering::SeqCst), 1);
+        assert_eq!(b.get("test"), Ok(b"value".to_vec()));
+        assert!(b.loads.lock().unwrap().is_empty());
+
+        // The errors of the loader should be shared as well.
+        thread::scope(|s| {
+            for _ in 0..threads {
+                let _ = s.spawn(|| {
+                    let res = b.get_or_load("missing", &mut |_| {
+                        Err(Error::internal_from_msg("error".to_string()))
+                    });
+                    assert_eq!(res, Err(Error::Internal(anyhow!(""))));
+                });
+            }
+        });
+        assert_eq!(b.get("missing"), b.not_found("missing"));
+    }
+
+    #[test]
+    fn test_single_flight_share() {
+        let errors = vec![
+            Error::NotFound("test".to_string()),
+            Error::AlreadyExists("test".to_string()),
+            Error::Internal(anyhow!("internal")),
+            Error::InvalidName {
+                name: "test".to_string(),
+                reason: "reason",
+            },
+            Error::ValueTooLarge { size: 2, limit: 1 },
+            Error::Unsupported { operation: "get" },
+            Error::QuotaExceeded {
+                resource: "keys",
+                limit: 1,
+            },
+            Error::Busy { limit: 1 },
+            Error::Bug(anyhow!("bug")),
+        ];
+        for e in errors {
+            let message = e.to_string();
+            let shared = share(&Err(e)).unwrap_err();
+            assert_eq!(shared.to_string(), message);
+        }
+        assert_eq!(share(&Ok(b"value".to_vec())), Ok(b"value".to_vec()));
+    }
+}
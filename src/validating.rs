@@ -0,0 +1,397 @@
+//! User-defined naming rules
+//!
+//! In this module, we define a wrapper that checks the names of the keys
+//! against the rules of an application, e.g., a maximum length or an allowed
+//! set of characters, before they reach any `Cave`.
+
+use std::collections;
+use std::fmt;
+use std::sync;
+
+use crate::errors::Error;
+use crate::res::Res;
+use crate::{replace_prefix, Capabilities, Cave, ChangeReceiver, KeyGuard, Metadata, VisitFn};
+
+/// A function that checks the name of a key, and returns an error if it's not
+/// allowed.
+pub type Validator = sync::Arc<dyn Fn(&str) -> Result<(), Error> + Send + Sync>;
+
+/// A wrapper that checks the names of the keys of another `Cave` with
+/// user-defined validators.
+///
+/// Every name that is passed to the wrapper is checked by every validator
+/// (see [`with_validator`]), in the order that they were added, before it's
+/// passed to the inner `Cave`. The first error that a validator returns is
+/// returned to the caller as is, so validators should typically return an
+/// [`Error::InvalidName`] error. A `rename_prefix` is rejected if any of the
+/// names that it would create is not allowed.
+///
+/// Prefixes, e.g., in `delete_prefix`, and range bounds are not names, so
+/// they are not checked. Also, names that are not valid UTF-8 are not
+/// supported.
+///
+/// ## Usage
+///
+/// ```
+/// use std::sync::Arc;
+/// use caves::errors::Error;
+/// use caves::{Cave, MemoryCave, ValidatingCave};
+///
+/// let b = ValidatingCave::new(MemoryCave::new()).with_validator(Arc::new(|name: &str| {
+///     if name.len() > 8 {
+///         return Err(Error::InvalidName {
+///             name: name.to_string(),
+///             reason: "the name must be at most 8 bytes long",
+///         });
+///     }
+///     Ok(())
+/// }));
+///
+/// b.set("key", b"value").unwrap();
+/// assert!(b.set("a-long-key", b"value").is_err());
+/// ```
+///
+/// [`with_validator`]: #method.with_validator
+/// [`Error::InvalidName`]: errors/enum.Error.html#variant.InvalidName
+pub struct ValidatingCave<C: Cave> {
+    inner: C,
+    validators: Vec<Validator>,
+}
+
+impl<C: Cave> ValidatingCave<C> {
+    /// Create a new instance that wraps the provided `Cave`, and accepts any
+    /// name, until a validator is added.
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            validators: Vec::new(),
+        }
+    }
+
+    /// Add a validator that every name must pass.
+    pub fn with_validator(mut self, validator: Validator) -> Self {
+        self.validators.push(validator);
+        self
+    }
+
+    /// Return a reference to the inner `Cave`.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Check a name with every validator.
+    fn check(&self, name: &str) -> Result<(), Error> {
+        for validator in &self.validators {
+            validator(name)?;
+        }
+        Ok(())
+    }
+
+    fn check_all(&self, names: &[&str]) -> Result<(), Error> {
+        for name in names {
+            self.check(name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Print the number of validators, since functions cannot be printed.
+impl<C: Cave + fmt::Debug> fmt::Debug for ValidatingCave<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ValidatingCave")
+            .field("inner", &self.inner)
+            .field("validators", &self.validators.len())
+            .finish()
+    }
+}
+
+impl<C: Cave> Cave for ValidatingCave<C> {
+    fn get(&self, name: &str) -> Res {
+        self.check(name)?;
+        self.inner.get(name)
+    }
+
+    fn set(&self, name: &str, data: &[u8]) -> Res {
+        self.check(name)?;
+        self.inner.set(name, data)
+    }
+
+    fn delete(&self, name: &str) -> Res {
+        self.check(name)?;
+        self.inner.delete(name)
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        self.inner.keys()
+    }
+
+    fn get_or_load(&self, name: &str, loader: &mut dyn FnMut(&str) -> Res) -> Res {
+        self.check(name)?;
+        self.inner.get_or_load(name, loader)
+    }
+
+    fn get_existing(&self, names: &[&str]) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+        self.check_all(names)?;
+        self.inner.get_existing(names)
+    }
+
+    fn exists_many(&self, names: &[&str]) -> Result<Vec<bool>, Error> {
+        self.check_all(names)?;
+        self.inner.exists_many(names)
+    }
+
+    fn version(&self, name: &str) -> Result<String, Error> {
+        self.check(name)?;
+        self.inner.version(name)
+    }
+
+    fn get_if_changed(
+        &self,
+        name: &str,
+        known_version: &str,
+    ) -> Result<Option<(Vec<u8>, String)>, Error> {
+        self.check(name)?;
+        self.inner.get_if_changed(name, known_version)
+    }
+
+    fn stat(&self, name: &str) -> Result<Metadata, Error> {
+        self.check(name)?;
+        self.inner.stat(name)
+    }
+
+    fn delete_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        self.inner.delete_prefix(prefix)
+    }
+
+    fn rename_prefix(&self, old: &str, new: &str) -> Result<usize, Error> {
+        if !old.is_empty() {
+            for name in self.inner.keys()? {
+                if name.starts_with(old) {
+                    self.check(&replace_prefix(&name, old, new))?;
+                }
+            }
+        }
+        self.inner.rename_prefix(old, new)
+    }
+
+    fn take(&self, name: &str) -> Res {
+        self.check(name)?;
+        self.inner.take(name)
+    }
+
+    fn update_with(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(Option<Vec<u8>>) -> Option<Vec<u8>>,
+    ) -> Res {
+        self.check(name)?;
+        self.inner.update_with(name, f)
+    }
+
+    fn replace(&self, name: &str, data: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.check(name)?;
+        self.inner.replace(name, data)
+    }
+
+    fn create(&self, name: &str, data: &[u8]) -> Res {
+        self.check(name)?;
+        self.inner.create(name, data)
+    }
+
+    fn watch(&self, name: &str) -> Result<ChangeReceiver, Error> {
+        self.check(name)?;
+        self.inner.watch(name)
+    }
+
+    fn watch_prefix(&self, prefix: &str) -> Result<ChangeReceiver, Error> {
+        self.inner.watch_prefix(prefix)
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn Cave + '_>, Error> {
+        Ok(Box::new(ValidatingCave {
+            inner: self.inner.snapshot()?,
+            validators: self.validators.clone(),
+        }))
+    }
+
+    fn first_key(&self) -> Result<Option<String>, Error> {
+        self.inner.first_key()
+    }
+
+    fn last_key(&self) -> Result<Option<String>, Error> {
+        self.inner.last_key()
+    }
+
+    fn range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        self.inner.range(start, end)
+    }
+
+    fn swap(&self, a: &str, b: &str) -> Res {
+        self.check(a)?;
+        self.check(b)?;
+        self.inner.swap(a, b)
+    }
+
+    fn count_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        self.inner.count_prefix(prefix)
+    }
+
+    fn keys_sorted(&self) -> Result<Vec<String>, Error> {
+        self.inner.keys_sorted()
+    }
+
+    fn for_each_sorted(&self, f: &mut VisitFn<'_>) -> Result<(), Error> {
+        self.inner.for_each_sorted(f)
+    }
+
+    fn for_each(&self, f: &mut VisitFn<'_>) -> Result<(), Error> {
+        self.inner.for_each(f)
+    }
+
+    fn dump(&self) -> Result<collections::HashMap<String, Vec<u8>>, Error> {
+        self.inner.dump()
+    }
+
+    fn flush(&self) -> Res {
+        self.inner.flush()
+    }
+
+    fn maintain(&self) -> Res {
+        self.inner.maintain()
+    }
+
+    fn disk_usage(&self) -> Result<u64, Error> {
+        self.inner.disk_usage()
+    }
+
+    fn lock(&self, name: &str) -> Result<KeyGuard<'_>, Error> {
+        self.check(name)?;
+        self.inner.lock(name)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            binary_keys: false,
+            ..self.inner.capabilities()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::errors::ErrorKind;
+    use crate::res::empty_ok;
+    use crate::tests::{_test_keys, _test_rename_prefix, _test_simple, _test_swap, _test_take};
+    use crate::{FileCave, MemoryCave};
+
+    /// Reject names that are longer than 8 bytes.
+    fn max_len(name: &str) -> Result<(), Error> {
+        if name.len() > 8 {
+            return Err(Error::InvalidName {
+                name: name.to_string(),
+                reason: "the name must be at most 8 bytes long",
+            });
+        }
+        Ok(())
+    }
+
+    /// Reject names with uppercase characters.
+    fn lowercase(name: &str) -> Result<(), Error> {
+        if name.chars().any(char::is_uppercase) {
+            return Err(Error::InvalidName {
+                name: name.to_string(),
+                reason: "the name must be lowercase",
+            });
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_validating_simple() {
+        let b = ValidatingCave::new(MemoryCave::new());
+        _test_simple(Box::new(b));
+        let b = ValidatingCave::new(MemoryCave::new());
+        _test_keys(Box::new(b));
+        let b = ValidatingCave::new(MemoryCave::new());
+        _test_take(Box::new(b));
+        let b = ValidatingCave::new(MemoryCave::new());
+        _test_swap(Box::new(b));
+        let b = ValidatingCave::new(MemoryCave::new());
+        _test_rename_prefix(Box::new(b));
+    }
+
+    #[test]
+    fn test_validating_names() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let caves: Vec<Box<dyn Cave>> = vec![
+            Box::new(
+                ValidatingCave::new(MemoryCave::new())
+                    .with_validator(sync::Arc::new(max_len))
+                    .with_validator(sync::Arc::new(lowercase)),
+            ),
+            Box::new(
+                ValidatingCave::new(FileCave::new(temp_dir.path()).unwrap())
+                    .with_validator(sync::Arc::new(max_len))
+                    .with_validator(sync::Arc::new(lowercase)),
+            ),
+        ];
+
+        for b in caves {
+            assert_eq!(b.set("key", b"value"), empty_ok());
+            assert_eq!(b.get("key"), Ok(b"value".to_vec()));
+
+            // Every validator should be consulted, in order.
+            let res = b.set("too-long-key", b"value");
+            assert_eq!(res.unwrap_err().kind(), ErrorKind::InvalidName);
+            match b.set("Key", b"value") {
+                Err(Error::InvalidName { reason, .. }) => {
+                    assert_eq!(reason, "the name must be lowercase")
+                }
+                res => panic!("Unexpected result: {:?}", res),
+            }
+            match b.get("TOO-LONG-KEY") {
+                Err(Error::InvalidName { reason, .. }) => {
+                    assert_eq!(reason, "the name must be at most 8 bytes long")
+                }
+                res => panic!("Unexpected result: {:?}", res),
+            }
+
+            // Invalid names should never reach the inner cave.
+            let invalid = Err(Error::InvalidName {
+                name: "Key".to_string(),
+                reason: "",
+            });
+            assert_eq!(b.delete("Key"), invalid);
+            assert_eq!(b.take("Key"), invalid);
+            let res = b.exists_many(&["key", "Key"]);
+            assert_eq!(res.unwrap_err().kind(), ErrorKind::InvalidName);
+            assert_eq!(
+                b.swap("key", "Key").unwrap_err().kind(),
+                ErrorKind::InvalidName
+            );
+            assert_eq!(b.keys().unwrap(), vec!["key"]);
+
+            // Renames should be rejected if they create invalid names.
+            let res = b.rename_prefix("k", "a-long-");
+            assert_eq!(res.unwrap_err().kind(), ErrorKind::InvalidName);
+            assert_eq!(b.rename_prefix("k", "n"), Ok(1));
+            assert_eq!(b.get("ney"), Ok(b"value".to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_validating_snapshot() {
+        let b = ValidatingCave::new(MemoryCave::new()).with_validator(sync::Arc::new(lowercase));
+        assert_eq!(b.set("key", b"value"), empty_ok());
+        let snapshot = b.snapshot().unwrap();
+        assert_eq!(snapshot.get("key"), Ok(b"value".to_vec()));
+        let res = snapshot.get("Key");
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::InvalidName);
+        assert_eq!(
+            format!("{:?}", b),
+            "ValidatingCave { inner: MemoryCave { keys: 1, max_value_size: None, .. }, validators: 1 }"
+        );
+    }
+}
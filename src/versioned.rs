@@ -0,0 +1,330 @@
+//! Value history
+//!
+//! In this module, we define a wrapper that keeps the previous versions of
+//! the keys of a `Cave`, so that applications can inspect or restore them.
+
+use std::fmt;
+use std::str;
+use std::sync;
+
+use crate::errors::Error;
+use crate::res::{empty_ok, Res};
+use crate::Cave;
+
+/// The separator between the name of a key and the sequence number of a
+/// version.
+const VERSION_SEPARATOR: char = '@';
+
+/// The sequence numbers of the first and last stored versions of a key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Versions {
+    first: u64,
+    last: u64,
+}
+
+impl Versions {
+    fn encode(self) -> Vec<u8> {
+        format!("{} {}", self.first, self.last).into_bytes()
+    }
+
+    fn decode(name: &str, data: &[u8]) -> Result<Self, Error> {
+        let parse = || {
+            let mut parts = str::from_utf8(data).ok()?.split(' ');
+            let first = parts.next()?.parse().ok()?;
+            let last = parts.next()?.parse().ok()?;
+            match parts.next() {
+                None if first <= last => Some(Self { first, last }),
+                _ => None,
+            }
+        };
+        match parse() {
+            Some(versions) => Ok(versions),
+            None => Err(Error::internal_from_msg(format!(
+                "The version pointer of key `{}` is corrupted",
+                name
+            ))),
+        }
+    }
+}
+
+/// Return the name under which a version of a key is stored.
+fn version_name(name: &str, seq: u64) -> String {
+    format!("{}{}{:020}", name, VERSION_SEPARATOR, seq)
+}
+
+/// A wrapper that keeps the last versions of the keys of another `Cave`.
+///
+/// Every `set` stores the new contents of a key as a new version, under the
+/// name `{name}@{seq}` in the inner `Cave`, where `seq` is a sequence number
+/// that starts from 1 and increases with each version. Then, it updates a
+/// pointer, which is stored under the name of the key, and records the first
+/// and last stored versions. Once a key has more than `max_versions`
+/// versions, the oldest ones are deleted.
+///
+/// A `get` returns the latest version of a key, and a `delete` deletes the
+/// key along with all of its versions. The stored versions of a key can be
+/// listed with [`history`], and read with [`get_version`]. Since the
+/// versions are regular keys, this wrapper works over any `Cave`. Names that
+/// contain `@` are rejected with an [`Error::InvalidName`] error, so that
+/// they can't be confused with versions.
+///
+/// ## Storage amplification
+///
+/// Each key is stored up to `max_versions` times, plus a small pointer, so
+/// the inner `Cave` may need up to `max_versions` times more space. Also,
+/// each `set` writes the inner `Cave` two or three times, and each `get`
+/// reads it twice.
+///
+/// The writes are serialized, so that the versions of a key are numbered
+/// correctly. Therefore, the inner `Cave` must not be written by other
+/// means, e.g., by another process, while it's wrapped.
+///
+/// ## Usage
+///
+/// ```
+/// use caves::{Cave, MemoryCave, VersionedCave};
+///
+/// let b = VersionedCave::new(MemoryCave::new(), 2);
+/// b.set("key", b"value1").unwrap();
+/// b.set("key", b"value2").unwrap();
+/// b.set("key", b"value3").unwrap();
+/// assert_eq!(b.get("key").unwrap(), b"value3");
+///
+/// // Only the last two versions are kept.
+/// assert_eq!(b.history("key").unwrap(), vec![2, 3]);
+/// assert_eq!(b.get_version("key", 2).unwrap(), b"value2");
+/// assert!(b.get_version("key", 1).is_err());
+/// ```
+///
+/// [`history`]: #method.history
+/// [`get_version`]: #method.get_version
+/// [`Error::InvalidName`]: errors/enum.Error.html#variant.InvalidName
+pub struct VersionedCave<C: Cave> {
+    inner: C,
+    max_versions: u64,
+    /// The lock is held during every write.
+    write_lock: sync::Mutex<()>,
+}
+
+impl<C: Cave> VersionedCave<C> {
+    /// Create a new instance that wraps the provided `Cave`, and keeps up to
+    /// `max_versions` versions of each key. A limit of 0 is treated as 1.
+    pub fn new(inner: C, max_versions: usize) -> Self {
+        Self {
+            inner,
+            max_versions: max_versions.max(1) as u64,
+            write_lock: sync::Mutex::new(()),
+        }
+    }
+
+    /// Return a reference to the inner `Cave`.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Return the sequence numbers of the stored versions of a key, from the
+    /// oldest to the latest.
+    ///
+    /// If the key does not exist, return an error.
+    pub fn history(&self, name: &str) -> Result<Vec<u64>, Error> {
+        let versions = self.versions(name)?;
+        Ok((versions.first..=versions.last).collect())
+    }
+
+    /// Get a version of a key by its sequence number.
+    ///
+    /// If the key or the version does not exist, e.g., because it has been
+    /// pruned, return an [`Error::NotFound`] error for `{name}@{seq}`.
+    ///
+    /// [`Error::NotFound`]: errors/enum.Error.html#variant.NotFound
+    pub fn get_version(&self, name: &str, seq: u64) -> Res {
+        let versions = match self.versions(name) {
+            Err(Error::NotFound(_)) => None,
+            res => Some(res?),
+        };
+        match versions {
+            Some(v) if v.first <= seq && seq <= v.last => self.inner.get(&version_name(name, seq)),
+            _ => Err(Error::NotFound(format!(
+                "{}{}{}",
+                name, VERSION_SEPARATOR, seq
+            ))),
+        }
+    }
+
+    fn check(&self, name: &str) -> Result<(), Error> {
+        if name.contains(VERSION_SEPARATOR) {
+            return Err(Error::InvalidName {
+                name: name.to_string(),
+                reason: "the name contains the version separator `@`",
+            });
+        }
+        Ok(())
+    }
+
+    /// Read the pointer of a key.
+    fn versions(&self, name: &str) -> Result<Versions, Error> {
+        self.check(name)?;
+        Versions::decode(name, &self.inner.get(name)?)
+    }
+}
+
+/// Print the inner `Cave` and the number of versions, but not the contents.
+impl<C: Cave + fmt::Debug> fmt::Debug for VersionedCave<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VersionedCave")
+            .field("inner", &self.inner)
+            .field("max_versions", &self.max_versions)
+            .finish()
+    }
+}
+
+impl<C: Cave> Cave for VersionedCave<C> {
+    fn get(&self, name: &str) -> Res {
+        let versions = self.versions(name)?;
+        self.inner.get(&version_name(name, versions.last))
+    }
+
+    fn set(&self, name: &str, data: &[u8]) -> Res {
+        self.check(name)?;
+        let _guard = self.write_lock.lock().unwrap();
+        let old = match self.versions(name) {
+            Ok(versions) => Some(versions),
+            Err(Error::NotFound(_)) => None,
+            Err(e) => return Err(e),
+        };
+
+        // Write the new version before the pointer, so that the pointer never
+        // refers to a missing version.
+        let last = old.map_or(1, |v| v.last + 1);
+        let first = old
+            .map_or(1, |v| v.first)
+            .max(last.saturating_sub(self.max_versions - 1));
+        let _ = self.inner.set(&version_name(name, last), data)?;
+        let _ = self.inner.set(name, &Versions { first, last }.encode())?;
+
+        if let Some(old) = old {
+            for seq in old.first..first {
+                match self.inner.delete(&version_name(name, seq)) {
+                    Ok(_) | Err(Error::NotFound(_)) => (),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        empty_ok()
+    }
+
+    fn delete(&self, name: &str) -> Res {
+        let _guard = self.write_lock.lock().unwrap();
+        let versions = self.versions(name)?;
+        // Delete the pointer first, so that the key disappears at once.
+        let _ = self.inner.delete(name)?;
+        for seq in versions.first..=versions.last {
+            match self.inner.delete(&version_name(name, seq)) {
+                Ok(_) | Err(Error::NotFound(_)) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        empty_ok()
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        let mut keys = self.inner.keys()?;
+        keys.retain(|name| !name.contains(VERSION_SEPARATOR));
+        Ok(keys)
+    }
+
+    fn flush(&self) -> Res {
+        self.inner.flush()
+    }
+
+    fn maintain(&self) -> Res {
+        self.inner.maintain()
+    }
+
+    fn disk_usage(&self) -> Result<u64, Error> {
+        self.inner.disk_usage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::{_test_create, _test_keys, _test_simple};
+    use crate::{FileCave, MemoryCave};
+
+    #[test]
+    fn test_versioned_simple() {
+        _test_simple(Box::new(VersionedCave::new(MemoryCave::new(), 3)));
+        _test_keys(Box::new(VersionedCave::new(MemoryCave::new(), 3)));
+        _test_create(Box::new(VersionedCave::new(MemoryCave::new(), 3)));
+    }
+
+    #[test]
+    fn test_versioned_history() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let b = VersionedCave::new(FileCave::new(temp_dir.path()).unwrap(), 3);
+        assert_eq!(b.history("key"), Err(Error::NotFound("key".to_string())));
+        assert_eq!(
+            b.get_version("key", 1),
+            Err(Error::NotFound("key@1".to_string()))
+        );
+
+        for i in 1..=5 {
+            assert_eq!(b.set("key", format!("value{}", i).as_bytes()), empty_ok());
+        }
+        assert_eq!(b.get("key"), Ok(b"value5".to_vec()));
+        assert_eq!(b.history("key"), Ok(vec![3, 4, 5]));
+        assert_eq!(b.get_version("key", 3), Ok(b"value3".to_vec()));
+        assert_eq!(
+            b.get_version("key", 2),
+            Err(Error::NotFound("key@2".to_string()))
+        );
+        assert_eq!(
+            b.get_version("key", 6),
+            Err(Error::NotFound("key@6".to_string()))
+        );
+
+        // The pruned versions should be deleted from the inner cave, and the
+        // versions should be hidden from the keys.
+        let mut inner_keys = b.inner().keys().unwrap();
+        inner_keys.sort();
+        assert_eq!(inner_keys.len(), 4);
+        assert_eq!(inner_keys[1], version_name("key", 3));
+        assert_eq!(b.keys(), Ok(vec!["key".to_string()]));
+
+        // Deleting a key should delete its history, and a new key should
+        // start from scratch.
+        assert_eq!(b.delete("key"), empty_ok());
+        assert_eq!(b.get("key"), b.not_found("key"));
+        assert_eq!(b.inner().keys(), Ok(vec![]));
+        assert_eq!(b.set("key", b"value"), empty_ok());
+        assert_eq!(b.history("key"), Ok(vec![1]));
+
+        // Creating an existing key should not write a new version.
+        assert_eq!(
+            b.create("key", b"other"),
+            Err(Error::AlreadyExists("key".to_string()))
+        );
+        assert_eq!(b.history("key"), Ok(vec![1]));
+
+        // Names that look like versions should be rejected.
+        let res = b.set("key@1", b"value");
+        assert_eq!(
+            res,
+            Err(Error::InvalidName {
+                name: "key@1".to_string(),
+                reason: ""
+            })
+        );
+
+        // A corrupted pointer should be reported.
+        assert_eq!(b.inner().set("key", b"1"), empty_ok());
+        assert_eq!(b.get("key"), Err(Error::Internal(anyhow!(""))));
+        assert_eq!(
+            format!("{:?}", VersionedCave::new(MemoryCave::new(), 0)),
+            "VersionedCave { inner: MemoryCave { keys: 0, max_value_size: None, .. }, \
+             max_versions: 1 }"
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! Change notifications
+//!
+//! In this module, we define the events that a `Cave` sends to its watchers
+//! when its keys change, as well as a registry of watchers that backends can
+//! use to send them.
+
+use std::ops;
+use std::sync;
+use std::sync::mpsc;
+
+/// The maximum number of events that a watcher can fall behind by. Further
+/// events are dropped until the watcher receives some of them.
+pub const WATCH_CAPACITY: usize = 1024;
+
+/// The kind of change that happened to a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChangeKind {
+    /// The key was created or updated.
+    Set,
+
+    /// The key was deleted.
+    Delete,
+}
+
+/// An event that notifies a watcher about a change in a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    /// The name of the key that changed.
+    pub key: String,
+
+    /// The kind of the change.
+    pub kind: ChangeKind,
+}
+
+/// The receiving end of a watcher, which receives a [`ChangeEvent`] for each
+/// change.
+///
+/// It derefs to an [`mpsc::Receiver`], so events can be received with its
+/// methods. Dropping it stops watching.
+///
+/// [`ChangeEvent`]: struct.ChangeEvent.html
+/// [`mpsc::Receiver`]: https://doc.rust-lang.org/std/sync/mpsc/struct.Receiver.html
+#[derive(Debug)]
+pub struct ChangeReceiver {
+    receiver: mpsc::Receiver<ChangeEvent>,
+    /// A token that lets the kv know that the receiver is still alive,
+    /// without sending it an event.
+    _alive: sync::Arc<()>,
+}
+
+impl ops::Deref for ChangeReceiver {
+    type Target = mpsc::Receiver<ChangeEvent>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.receiver
+    }
+}
+
+#[derive(Debug)]
+struct Watcher {
+    /// The name of the watched key, or the prefix of the watched keys.
+    pattern: String,
+    is_prefix: bool,
+    sender: mpsc::SyncSender<ChangeEvent>,
+    alive: sync::Weak<()>,
+}
+
+impl Watcher {
+    fn is_alive(&self) -> bool {
+        self.alive.strong_count() > 0
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        if self.is_prefix {
+            key.starts_with(&self.pattern)
+        } else {
+            key == self.pattern
+        }
+    }
+}
+
+/// A registry of watchers, which sends them the changes they are interested
+/// in.
+#[derive(Debug, Default)]
+pub(crate) struct Watchers {
+    watchers: sync::Mutex<Vec<Watcher>>,
+}
+
+impl Watchers {
+    /// Register a watcher for a key, or for the keys under a prefix, and
+    /// return the receiving end of its channel.
+    ///
+    /// Watchers whose receivers have been dropped are unregistered.
+    pub(crate) fn watch(&self, pattern: &str, is_prefix: bool) -> ChangeReceiver {
+        let (sender, receiver) = mpsc::sync_channel(WATCH_CAPACITY);
+        let alive = sync::Arc::new(());
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.retain(Watcher::is_alive);
+        watchers.push(Watcher {
+            pattern: pattern.to_string(),
+            is_prefix,
+            sender,
+            alive: sync::Arc::downgrade(&alive),
+        });
+        ChangeReceiver {
+            receiver,
+            _alive: alive,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.watchers.lock().unwrap().is_empty()
+    }
+
+    /// Send a change to the interested watchers.
+    ///
+    /// Watchers whose receivers have been dropped are unregistered. If a
+    /// watcher has fallen behind by `WATCH_CAPACITY` events, the change is
+    /// dropped for it, so that a slow watcher can't block the kv.
+    pub(crate) fn notify(&self, key: &str, kind: ChangeKind) {
+        let mut watchers = self.watchers.lock().unwrap();
+        if watchers.is_empty() {
+            return;
+        }
+
+        watchers.retain(|watcher| {
+            if !watcher.is_alive() {
+                return false;
+            }
+            if !watcher.matches(key) {
+                return true;
+            }
+            let event = ChangeEvent {
+                key: key.to_string(),
+                kind,
+            };
+            !matches!(
+                watcher.sender.try_send(event),
+                Err(mpsc::TrySendError::Disconnected(_))
+            )
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchers() {
+        let watchers = Watchers::default();
+
+        // A dropped receiver should be unregistered, even if its key never
+        // changes.
+        drop(watchers.watch("key1", false));
+        watchers.notify("key2", ChangeKind::Set);
+        assert!(watchers.is_empty());
+        drop(watchers.watch("key1", false));
+        let _rx = watchers.watch("key2", false);
+        assert_eq!(watchers.watchers.lock().unwrap().len(), 1);
+
+        // A slow receiver should miss the events that don't fit in its
+        // channel, but stay registered.
+        let rx = watchers.watch("key", true);
+        for _ in 0..WATCH_CAPACITY + 10 {
+            watchers.notify("key1", ChangeKind::Set);
+        }
+        assert_eq!(rx.try_iter().count(), WATCH_CAPACITY);
+        watchers.notify("key1", ChangeKind::Delete);
+        let event = ChangeEvent {
+            key: "key1".to_string(),
+            kind: ChangeKind::Delete,
+        };
+        assert_eq!(rx.try_recv(), Ok(event));
+    }
+}
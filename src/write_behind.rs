@@ -0,0 +1,509 @@
+//! Write-behind buffering
+//!
+//! In this module, we define a wrapper that buffers the writes to another
+//! `Cave` in memory, and applies them in the background, so that bursts of
+//! writes don't have to wait for a slow `Cave`.
+
+use std::collections;
+use std::fmt;
+use std::sync;
+use std::thread;
+
+use crate::errors::Error;
+use crate::res::{empty_ok, Res};
+use crate::{Capabilities, Cave, KeyGuard};
+
+/// What a write does when the buffer of a [`WriteBehindCave`] is full.
+///
+/// See [`WriteBehindCave::with_max_pending`] for more info.
+///
+/// [`WriteBehindCave`]: struct.WriteBehindCave.html
+/// [`WriteBehindCave::with_max_pending`]: struct.WriteBehindCave.html#method.with_max_pending
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backpressure {
+    /// Wait until the background thread applies the buffered writes. This is
+    /// the default behavior.
+    #[default]
+    Block,
+
+    /// Return an [`Error::Busy`] error immediately.
+    ///
+    /// [`Error::Busy`]: errors/enum.Error.html#variant.Busy
+    Reject,
+}
+
+/// A buffered write. `None` stands for a deletion.
+type BufferedWrite = Option<Vec<u8>>;
+
+#[derive(Default)]
+struct State {
+    /// Writes that have not been picked up by the worker yet.
+    pending: collections::HashMap<String, BufferedWrite>,
+    /// Writes that the worker is currently applying to the inner `Cave`.
+    in_flight: collections::HashMap<String, BufferedWrite>,
+    /// The first error that the worker encountered since the last flush.
+    error: Option<Error>,
+    shutdown: bool,
+}
+
+impl State {
+    /// Return the latest buffered write for a key, if any.
+    fn lookup(&self, name: &str) -> Option<&BufferedWrite> {
+        self.pending.get(name).or_else(|| self.in_flight.get(name))
+    }
+
+    fn is_flushed(&self) -> bool {
+        self.pending.is_empty() && self.in_flight.is_empty()
+    }
+
+    /// Return the number of writes that have not been applied yet.
+    fn len(&self) -> usize {
+        self.pending.len() + self.in_flight.len()
+    }
+}
+
+/// Print the number of buffered writes, instead of their contents, so that
+/// it's safe to log.
+impl fmt::Debug for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("State")
+            .field("pending", &self.pending.len())
+            .field("in_flight", &self.in_flight.len())
+            .field("error", &self.error)
+            .field("shutdown", &self.shutdown)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+struct Shared<C: Cave> {
+    inner: C,
+    state: sync::Mutex<State>,
+    /// Signaled when there are new writes, or when the wrapper is dropped.
+    work: sync::Condvar,
+    /// Signaled when the worker has applied a batch of writes.
+    done: sync::Condvar,
+}
+
+/// A wrapper that buffers the writes to another `Cave` and applies them in
+/// the background.
+///
+/// The `set`/`delete` operations are stored in an in-memory buffer and return
+/// immediately. A background thread applies them to the inner `Cave`, in
+/// batches. If a key is written multiple times before the thread picks it up,
+/// only its last write reaches the inner `Cave`.
+///
+/// Reads reflect the buffered writes (read-your-writes), since `get` and
+/// `keys` consult the buffer before the inner `Cave`.
+///
+/// The buffer can be flushed explicitly with [`flush`], and is flushed
+/// implicitly when the wrapper is dropped. By default, the buffer is
+/// unbounded, so if the inner `Cave` can't keep up with the writes, the
+/// buffer keeps growing. In order to bound it, use [`with_max_pending`].
+///
+/// ## Caveats
+///
+/// This wrapper has the following caveats:
+///
+/// * A successful `set`/`delete` does not mean that the write has reached
+///   the inner `Cave`. If the process crashes before the buffer is flushed,
+///   the un-flushed writes are lost. Call [`flush`] to make sure that the
+///   writes have been applied.
+/// * Errors of the inner `Cave` are not returned by the `set`/`delete` that
+///   caused them, but by the next [`flush`]. The failed writes are dropped.
+/// * In order to return an error for keys that don't exist, `delete` reads
+///   the key from the inner `Cave`, if it's not in the buffer.
+///
+/// ## Usage
+///
+/// ```
+/// use caves::{Cave, MemoryCave, WriteBehindCave};
+///
+/// let b = WriteBehindCave::new(MemoryCave::new());
+///
+/// // Writes are visible immediately.
+/// b.set("key", b"value").unwrap();
+/// assert_eq!(b.get("key").unwrap(), b"value");
+///
+/// // Wait until the writes reach the inner cave.
+/// b.flush().unwrap();
+/// assert_eq!(b.inner().get("key").unwrap(), b"value");
+/// ```
+///
+/// [`flush`]: trait.Cave.html#method.flush
+/// [`with_max_pending`]: #method.with_max_pending
+#[derive(Debug)]
+pub struct WriteBehindCave<C: Cave + 'static> {
+    shared: sync::Arc<Shared<C>>,
+    worker: Option<thread::JoinHandle<()>>,
+    max_pending: Option<usize>,
+    backpressure: Backpressure,
+}
+
+impl<C: Cave + 'static> WriteBehindCave<C> {
+    /// Create a new instance that buffers the writes to the provided `Cave`.
+    ///
+    /// This spawns the background thread that applies the writes.
+    pub fn new(inner: C) -> Self {
+        let shared = sync::Arc::new(Shared {
+            inner,
+            state: sync::Mutex::new(State::default()),
+            work: sync::Condvar::new(),
+            done: sync::Condvar::new(),
+        });
+
+        let worker_shared = sync::Arc::clone(&shared);
+        let worker = thread::spawn(move || Self::run(&worker_shared));
+
+        Self {
+            shared,
+            worker: Some(worker),
+            max_pending: None,
+            backpressure: Backpressure::default(),
+        }
+    }
+
+    /// Limit the number of buffered writes to `limit`.
+    ///
+    /// A write that would exceed the limit either waits until the background
+    /// thread applies the buffered writes, or fails with an [`Error::Busy`]
+    /// error, depending on `backpressure`. The writes to keys that already
+    /// have a pending write replace it, so they are always accepted. The
+    /// writes that the background thread is applying count towards the
+    /// limit as well, so the buffer never holds more than `limit` writes. A
+    /// limit of 0 is treated as 1.
+    ///
+    /// [`Error::Busy`]: errors/enum.Error.html#variant.Busy
+    pub fn with_max_pending(mut self, limit: usize, backpressure: Backpressure) -> Self {
+        self.max_pending = Some(limit.max(1));
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Return the number of buffered writes that have not been applied to
+    /// the inner `Cave` yet.
+    pub fn pending(&self) -> usize {
+        self.shared.state.lock().unwrap().len()
+    }
+
+    /// Return a reference to the inner `Cave`.
+    ///
+    /// Note that the inner `Cave` does not reflect the writes that are still
+    /// buffered.
+    pub fn inner(&self) -> &C {
+        &self.shared.inner
+    }
+
+    fn buffer(&self, name: &str, write: BufferedWrite) -> Res {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(limit) = self.max_pending {
+            while state.len() >= limit && !state.pending.contains_key(name) {
+                if self.backpressure == Backpressure::Reject {
+                    return Err(Error::Busy { limit });
+                }
+                state = self.shared.done.wait(state).unwrap();
+            }
+        }
+        let _ = state.pending.insert(name.to_string(), write);
+        self.shared.work.notify_one();
+        empty_ok()
+    }
+
+    /// Apply the buffered writes to the inner `Cave`, until the wrapper is
+    /// dropped.
+    fn run(shared: &Shared<C>) {
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            while state.pending.is_empty() && !state.shutdown {
+                state = shared.work.wait(state).unwrap();
+            }
+            if state.pending.is_empty() {
+                // We have been asked to shut down, and there's nothing left
+                // to flush.
+                return;
+            }
+
+            // Keep the writes in the buffer while they are being applied, so
+            // that reads can find them.
+            state.in_flight = std::mem::take(&mut state.pending);
+            let batch = state.in_flight.clone();
+            drop(state);
+
+            let mut error = None;
+            for (name, write) in batch {
+                let res = match write {
+                    Some(data) => shared.inner.set(&name, &data),
+                    None => match shared.inner.delete(&name) {
+                        // The key may have been deleted behind our back.
+                        Err(Error::NotFound(_)) => empty_ok(),
+                        res => res,
+                    },
+                };
+                if let Err(e) = res {
+                    error = error.or(Some(e));
+                }
+            }
+
+            state = shared.state.lock().unwrap();
+            state.in_flight.clear();
+            if state.error.is_none() {
+                state.error = error;
+            }
+            shared.done.notify_all();
+        }
+    }
+}
+
+impl<C: Cave + 'static> Drop for WriteBehindCave<C> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.work.notify_one();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl<C: Cave + 'static> Cave for WriteBehindCave<C> {
+    fn get(&self, name: &str) -> Res {
+        if let Some(write) = self.shared.state.lock().unwrap().lookup(name) {
+            return match write {
+                Some(data) => Ok(data.clone()),
+                None => self.not_found(name),
+            };
+        }
+        self.shared.inner.get(name)
+    }
+
+    fn set(&self, name: &str, data: &[u8]) -> Res {
+        self.buffer(name, Some(data.to_vec()))
+    }
+
+    fn delete(&self, name: &str) -> Res {
+        let buffered = self.shared.state.lock().unwrap().lookup(name).cloned();
+        match buffered {
+            Some(Some(_)) => (),
+            Some(None) => return self.not_found(name),
+            None => {
+                let _ = self.shared.inner.get(name)?;
+            }
+        }
+
+        self.buffer(name, None)
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        let mut keys: collections::HashSet<String> =
+            self.shared.inner.keys()?.into_iter().collect();
+
+        // Apply the writes that are in flight first, since the pending ones
+        // are more recent.
+        let state = self.shared.state.lock().unwrap();
+        for (name, write) in state.in_flight.iter().chain(state.pending.iter()) {
+            match write {
+                Some(_) => {
+                    let _ = keys.insert(name.clone());
+                }
+                None => {
+                    let _ = keys.remove(name);
+                }
+            }
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    /// Lock a key of the inner `Cave`.
+    ///
+    /// The lock is advisory, so it does not matter that the writes to the
+    /// inner `Cave` are buffered.
+    fn lock(&self, name: &str) -> Result<KeyGuard<'_>, Error> {
+        self.shared.inner.lock(name)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let inner = self.shared.inner.capabilities();
+        Capabilities {
+            keys: inner.keys,
+            lock: inner.lock,
+            ..Capabilities::default()
+        }
+    }
+
+    /// Wait until all the buffered writes have been applied to the inner
+    /// `Cave`, and then flush it.
+    ///
+    /// If any of the writes since the last flush failed, return the first
+    /// error.
+    fn flush(&self) -> Res {
+        let mut state = self.shared.state.lock().unwrap();
+        while !state.is_flushed() {
+            state = self.shared.done.wait(state).unwrap();
+        }
+
+        if let Some(e) = state.error.take() {
+            return Err(e);
+        }
+        drop(state);
+        self.shared.inner.flush()
+    }
+
+    fn maintain(&self) -> Res {
+        self.shared.inner.maintain()
+    }
+
+    /// Return the disk usage of the inner `Cave`, which does not include the
+    /// buffered writes.
+    fn disk_usage(&self) -> Result<u64, Error> {
+        self.shared.inner.disk_usage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::{
+        _test_bytes, _test_concurrency, _test_exists_many, _test_keys, _test_simple,
+    };
+    use crate::{FileCave, MemoryCave};
+
+    #[test]
+    fn test_write_behind_simple() {
+        let b = WriteBehindCave::new(MemoryCave::new());
+        _test_simple(Box::new(b));
+    }
+
+    #[test]
+    fn test_write_behind_bytes() {
+        // The keys whose names are not valid UTF-8 cannot be buffered, so they
+        // are rejected, even if the inner `Cave` supports them.
+        let b = WriteBehindCave::new(MemoryCave::new());
+        _test_bytes(Box::new(b));
+    }
+
+    #[test]
+    fn test_write_behind_exists_many() {
+        let b = WriteBehindCave::new(MemoryCave::new());
+        _test_exists_many(Box::new(b));
+    }
+
+    #[test]
+    fn test_write_behind_keys() {
+        let b = WriteBehindCave::new(MemoryCave::new());
+        _test_keys(Box::new(b));
+    }
+
+    #[test]
+    fn test_write_behind_concurrency() {
+        let b = WriteBehindCave::new(MemoryCave::new());
+        _test_concurrency(Box::new(b));
+    }
+
+    #[test]
+    fn test_write_behind_debug() {
+        // Neither the buffered writes nor the contents of the inner `Cave`
+        // should be printed.
+        let b = WriteBehindCave::new(MemoryCave::new());
+        assert_eq!(b.set("key", b"secret"), empty_ok());
+        assert!(!format!("{:?}", b).contains("secret"));
+        assert_eq!(b.flush(), empty_ok());
+        assert!(!format!("{:?}", b).contains("secret"));
+    }
+
+    #[test]
+    fn test_write_behind_flush() {
+        let b = WriteBehindCave::new(MemoryCave::new());
+        let not_found_err = Err(Error::NotFound("test".to_string()));
+
+        assert_eq!(b.set("test", b"value1"), empty_ok());
+        assert_eq!(b.set("test", b"value2"), empty_ok());
+        assert_eq!(b.get("test"), Ok(b"value2".to_vec()));
+        assert_eq!(b.flush(), empty_ok());
+        assert_eq!(b.inner().get("test"), Ok(b"value2".to_vec()));
+
+        assert_eq!(b.delete("test"), empty_ok());
+        assert_eq!(b.get("test"), not_found_err);
+        assert_eq!(b.delete("test"), not_found_err);
+        assert_eq!(b.flush(), empty_ok());
+        assert_eq!(b.inner().get("test"), not_found_err);
+    }
+
+    #[test]
+    fn test_write_behind_max_pending() {
+        // Keep the writes buffered, until the lock of the inner cave is
+        // released.
+        let inner = sync::Arc::new(MemoryCave::new());
+        let guard = inner.hash_map.write().unwrap();
+        let b = WriteBehindCave::new(sync::Arc::clone(&inner))
+            .with_max_pending(2, Backpressure::Reject);
+        assert_eq!(b.set("key1", b"value"), empty_ok());
+        assert_eq!(b.set("key2", b"value"), empty_ok());
+        assert_eq!(b.pending(), 2);
+        assert_eq!(b.set("key3", b"value"), Err(Error::Busy { limit: 2 }));
+        drop(guard);
+        assert_eq!(b.flush(), empty_ok());
+        assert_eq!(b.pending(), 0);
+        assert_eq!(b.set("key3", b"value"), empty_ok());
+        assert_eq!(b.flush(), empty_ok());
+        drop(b);
+        assert_eq!(inner.keys().unwrap().len(), 3);
+
+        // Blocked writes should proceed once the buffered writes are
+        // applied, and the buffer should never exceed the limit.
+        let b = WriteBehindCave::new(MemoryCave::new()).with_max_pending(4, Backpressure::Block);
+        for i in 0..100 {
+            assert_eq!(b.set(&format!("key{}", i), b"value"), empty_ok());
+            assert!(b.pending() <= 4);
+        }
+        assert_eq!(b.delete("key0"), empty_ok());
+        assert_eq!(b.flush(), empty_ok());
+        assert_eq!(b.inner().keys().unwrap().len(), 99);
+    }
+
+    #[test]
+    fn test_write_behind_drop() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+
+        let b = WriteBehindCave::new(FileCave::new(temp_dir.path()).unwrap());
+        for i in 0..10 {
+            assert_eq!(b.set(&format!("key{}", i), b"value"), empty_ok());
+        }
+        drop(b);
+
+        // Dropping the wrapper should flush every write.
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        assert_eq!(fb.keys().unwrap().len(), 10);
+    }
+
+    #[test]
+    fn test_write_behind_close() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+
+        let b = WriteBehindCave::new(FileCave::new(temp_dir.path()).unwrap());
+        assert_eq!(b.set("test", b"value"), empty_ok());
+        assert_eq!(b.close(), Ok(()));
+
+        let fb = FileCave::new(temp_dir.path()).unwrap();
+        assert_eq!(fb.get("test"), Ok(b"value".to_vec()));
+    }
+
+    #[cfg(feature = "test-util")]
+    #[test]
+    fn test_write_behind_errors() {
+        let b = WriteBehindCave::new(crate::FaultInjectingCave::new(MemoryCave::new()));
+
+        b.inner().fail_matching("bad");
+        assert_eq!(b.set("bad_key", b"value"), empty_ok());
+        assert_eq!(b.set("good_key", b"value"), empty_ok());
+        assert_eq!(b.flush(), Err(Error::Internal(anyhow!(""))));
+        assert_eq!(b.inner().inner().keys().unwrap(), vec!["good_key"]);
+
+        // The error should be returned only once.
+        assert_eq!(b.flush(), empty_ok());
+
+        // Closing the wrapper should report the errors that dropping it
+        // would swallow.
+        assert_eq!(b.set("bad_key", b"value"), empty_ok());
+        assert_eq!(b.close(), Err(Error::Internal(anyhow!(""))));
+    }
+}